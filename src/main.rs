@@ -4,25 +4,29 @@
 //! providing detailed statistics about code, comment, and blank line distribution.
 //!
 //! Supported languages: Rust, Go, Python, Java, C/C++, C#, JavaScript, TypeScript,
-//! PHP, Perl, Ruby, Shell, Pascal, Scala, YAML, XML, JSON, HTML, TOML,
-//! Makefile, Dockerfile, INI, HCL, CMake, PowerShell, Batch, TCL,
-//! ReStructuredText, Velocity, Mustache, Protobuf, SVG, XSL,
-//! Algol, COBOL, Fortran, Assembly, DCL, IPLAN.
-
-use clap::{ArgAction, Parser};
-use std::collections::{HashMap, HashSet};
+//! PHP, Perl, Ruby, Shell, Pascal, SQL, PL/SQL, Elixir, Erlang, R, R Markdown, Scala,
+//! Groovy, Kotlin, YAML, XML, JSON, JSONC, JSON5, HTML, TOML, Makefile, Dockerfile, INI,
+//! Dotenv, HCL, CMake, Starlark, PowerShell, Batch, TCL, ReStructuredText, Velocity, Mustache,
+//! Jinja2, ERB, Protobuf, GraphQL, VHDL, Verilog, Lua, MoonScript, SVG, XSL, Algol, COBOL,
+//! Fortran, Assembly, DCL, IPLAN, OCaml, F#, Objective-C, Objective-C++, MATLAB.
+
+use clap::{ArgAction, Parser, ValueEnum};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::env;
 use std::ffi::OsString;
 use std::fmt::Write as FmtWrite;
 use std::fs;
 use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use colored::*;
 use glob::Pattern;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use terminal_size::{terminal_size, Width};
 
 #[cfg(test)]
@@ -30,27 +34,99 @@ use std::sync::OnceLock;
 
 // Fixed width for the directory column.
 const DIR_WIDTH: usize = 40;
-const LANG_WIDTH: usize = 16;
+/// Fits the longest built-in language name, `TypeScript (decl)`.
+const LANG_WIDTH: usize = 17;
+/// Language-column label of per-directory subtotal rows.
+const SUBTOTAL_LABEL: &str = "(subtotal)";
+/// Narrowest columns `--report-width` will shrink to and `--dir-width` /
+/// `--lang-width` accept; the language column still fits the subtotal label.
+const MIN_DIR_WIDTH: usize = 12;
+const MIN_LANG_WIDTH: usize = SUBTOTAL_LABEL.len();
 
 const METADATA_FAIL_TAG: &str = "__mdkloc_metadata_fail__";
 const READ_DIR_FAIL_TAG: &str = "__mdkloc_read_dir_fail__";
 const ENTRY_ITER_FAIL_TAG: &str = "__mdkloc_entry_iter_fail__";
 const FILE_TYPE_FAIL_TAG: &str = "__mdkloc_file_type_fail__.rs";
+const CANONICALIZE_FAIL_TAG: &str = "__mdkloc_canonicalize_fail__.rs";
 const FAULT_ENV_VAR: &str = "MDKLOC_ENABLE_FAULTS";
 
 // Performance metrics structure
-const CODE_ROLE_COUNT: usize = 2;
+const CODE_ROLE_COUNT: usize = 3;
 
 struct PerformanceMetrics {
     files_processed: Arc<AtomicU64>,
     lines_processed: Arc<AtomicU64>,
     start_time: Instant,
     last_update: Instant,
+    /// Progress lines and the performance summary; stderr by default so a
+    /// redirected stdout holds only the report.
     writer: Box<dyn Write + Send>,
     progress_enabled: bool,
     role_files: [AtomicU64; CODE_ROLE_COUNT],
     role_lines: [AtomicU64; CODE_ROLE_COUNT],
     role_code_lines: [AtomicU64; CODE_ROLE_COUNT],
+    runtime_exhausted: bool,
+    duplicate_files: u64,
+    /// Files with no recognised language, keyed by extension: count and
+    /// the first path seen (populated only with `--print-skipped`).
+    skipped_files: HashMap<String, (u64, PathBuf)>,
+    /// `--compat-check`: files checked and those breaking the line invariant.
+    compat_checked: u64,
+    compat_mismatches: Vec<(PathBuf, Vec<String>)>,
+    /// `--strict-roles`: Rust files whose role detection looks unreliable.
+    role_warnings: Vec<(PathBuf, Vec<String>)>,
+    /// Files whose read failed part-way; their lines up to the failure are
+    /// still counted.
+    partial_reads: Vec<(PathBuf, String)>,
+    /// Traversal counters for `--entries-report`.
+    entry_counts: EntryCounts,
+    /// `--flag-empty`: files with no lines, and files with only blank lines.
+    empty_files: u64,
+    whitespace_only_files: u64,
+    /// `--checksum`: one (path, language, code, comment, blank) per counted file.
+    checksum_records: Vec<ChecksumRecord>,
+    /// Wall-clock time per phase, reported with `--profile`.
+    phase_times: PhaseTimes,
+    /// `--blame`: code lines attributed to each author.
+    blame_authors: HashMap<String, u64>,
+    /// `--largest-files`: the top files so far as (code lines, path). The
+    /// heap's top is the file that would be dropped next.
+    largest_files: BinaryHeap<(Reverse<u64>, String)>,
+    /// `--group-by-extension`: (files, stats) per (extension, language).
+    extension_totals: HashMap<(String, String), (u64, LanguageStats)>,
+    /// `--blank-runs`: (path, longest run, number of runs) per file with any
+    /// blank lines.
+    blank_runs: Vec<(PathBuf, u64, u64)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct PhaseTimes {
+    /// Walking directories and filtering entries, excluding counting.
+    traversal: Duration,
+    /// Reading and classifying file contents.
+    counting: Duration,
+    /// Rendering the final report.
+    report: Duration,
+}
+
+type ChecksumRecord = (String, String, u64, u64, u64);
+
+/// Why files were passed over, and how much of the tree was walked.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct EntryCounts {
+    directories: u64,
+    /// Rejected by `--filespec`.
+    filtered: u64,
+    /// No language recognised for the file name.
+    unrecognised: u64,
+    /// Symlinked duplicates of an already counted file.
+    duplicate_targets: u64,
+    /// `--hardlink-dedupe`: further names for an already counted inode.
+    hardlink_duplicates: u64,
+    /// Git submodule checkouts not descended into (no `--follow-gitlinks`).
+    submodules_skipped: u64,
+    /// Files that look minified; still counted unless `--exclude-minified`.
+    minified: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -58,7 +134,7 @@ struct PerformanceMetrics {
     author,
     version,
     about = "Source code analyser for multiple programming languages",
-    long_about = "Supported languages: Rust, Go, Python, Java, C/C++, C#, JavaScript, TypeScript, PHP, Perl, Ruby, Shell, Pascal, Scala, YAML, XML, JSON, HTML, TOML, Makefile, Dockerfile, INI, HCL, CMake, PowerShell, Batch, TCL, ReStructuredText, Velocity, Mustache, Protobuf, SVG, XSL, Algol, COBOL, Fortran, Assembly, DCL, IPLAN, mdhavers.",
+    long_about = "Supported languages: Rust, Go, Python, Java, C/C++, C#, JavaScript, TypeScript (incl. .d.ts declarations), PHP, Perl, Ruby, Shell, Pascal, SQL, PL/SQL, Elixir, Erlang, R, R Markdown, Scala, Groovy, Kotlin, YAML, XML, JSON, JSONC, JSON5, HTML, TOML, Makefile, Dockerfile, INI, Dotenv, HCL, CMake, Starlark, PowerShell, Batch, TCL, ReStructuredText, Velocity, Mustache, Jinja2, ERB, Protobuf, GraphQL, VHDL, Verilog, Lua, MoonScript, SVG, XSL, Algol, COBOL, Fortran, Assembly, DCL, IPLAN, OCaml, F#, Objective-C, Objective-C++, MATLAB, Lockfile, mdhavers.",
     color = clap::ColorChoice::Always
 )]
 struct Args {
@@ -68,15 +144,60 @@ struct Args {
     #[arg(short, long, action = ArgAction::Append)]
     ignore: Vec<String>,
 
+    /// Count files directly inside matching directories but do not descend
+    /// into their subdirectories (glob on the name or root-relative path; repeatable)
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append)]
+    no_recurse_into: Vec<String>,
+
+    /// Descend into git submodule checkouts, which are skipped by default
+    #[arg(long)]
+    follow_gitlinks: bool,
+
+    /// Also skip vendored dependency directories: vendor, third_party,
+    /// third-party, Pods, Carthage, .terraform, bower_components,
+    /// jspm_packages, .venv and site-packages
+    #[arg(long)]
+    auto_exclude_vendor: bool,
+
+    /// Report matching files (glob on the name or root-relative path; repeatable)
+    /// with all their lines as ignored, keeping them out of code totals
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append)]
+    exclude_fixtures: Vec<String>,
+
+    /// Count `#region` / `#endregion` folding markers (C#, PowerShell) as
+    /// ignored rather than code or comments
+    #[arg(long)]
+    exclude_regions: bool,
+
+    /// Leave minified JavaScript/TypeScript files (very long average lines) out of the report
+    #[arg(long)]
+    exclude_minified: bool,
+
     #[arg(short, long)]
     verbose: bool,
 
+    /// Suppress the banner, progress and performance summary; print only the report
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print only a single `key=value` line with the grand totals
+    #[arg(long, conflicts_with = "verbose")]
+    totals: bool,
+
+    /// Print only the languages found, one per line, by code lines descending
+    #[arg(long, conflicts_with_all = ["verbose", "totals"])]
+    languages_with_counts: bool,
+
     #[arg(short, long, default_value = "1000000")]
     max_entries: usize,
 
     #[arg(short = 'd', long, default_value = "100")]
     max_depth: usize,
 
+    /// Directory walk order; both give the same counts
+    #[arg(long, value_enum, default_value_t = Traversal::Dfs)]
+    traversal: Traversal,
+
     #[arg(short = 'n', long)]
     non_recursive: bool,
 
@@ -86,8 +207,688 @@ struct Args {
     #[arg(short = 'r', long)]
     role_breakdown: bool,
 
+    /// Developer check: list files whose counts do not add up to their line count
+    #[arg(long)]
+    compat_check: bool,
+
+    /// After the report, print entries visited, directories traversed, files counted and skipped, and errors
+    #[arg(long)]
+    entries_report: bool,
+
+    /// List files skipped for having no recognised language, grouped by extension
+    #[arg(long)]
+    print_skipped: bool,
+
+    /// After the report, print a stable hash of every file's per-language counts
+    #[arg(long)]
+    checksum: bool,
+
+    /// Print wall-clock time spent traversing, counting and building the report
+    #[arg(long)]
+    profile: bool,
+
+    /// Attribute code lines of git-tracked files to authors via `git blame` (slow)
+    #[arg(long)]
+    blame: bool,
+
+    /// Warn about Rust files whose test/mainline role detection looks unreliable
+    #[arg(long)]
+    strict_roles: bool,
+
+    /// After the report, count empty files and files holding only whitespace
+    #[arg(long)]
+    flag_empty: bool,
+
+    /// After the report, rank the N files with the most code lines
+    #[arg(long, value_name = "N")]
+    largest_files: Option<usize>,
+
+    /// After the report, total counts per file extension rather than per language
+    #[arg(long)]
+    group_by_extension: bool,
+
+    /// After the report, list the files with the longest runs of consecutive blank lines
+    #[arg(long)]
+    blank_runs: bool,
+
+    /// Count Markdown files, routing fenced code blocks to the fence's language
+    #[arg(long)]
+    markdown_fences: bool,
+
+    /// Count byte-identical files only once, no matter where they live
+    #[arg(long)]
+    dedupe_by_content: bool,
+
+    /// Count hardlinked files once (same device and inode; Unix only)
+    #[arg(long)]
+    hardlink_dedupe: bool,
+
+    /// Hide directory rows with fewer than N total lines (totals are unaffected)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_lines: u64,
+
+    /// Show the role breakdown as one table with Mainline and Test columns side by side
+    #[arg(long)]
+    group_roles: bool,
+
+    /// Count only mainline code, dropping lines detected as test code
+    #[arg(long, conflicts_with = "only_test")]
+    exclude_test: bool,
+
+    /// Count only lines detected as test code (languages without role detection yield nothing)
+    #[arg(long)]
+    only_test: bool,
+
     #[arg(short = 'l', long)]
     languages: bool,
+
+    /// Print the JSON Schema of the `--format json` report, then exit
+    #[arg(long)]
+    print_schema: bool,
+
+    /// Print each line of one Rust file with its detected role, then exit
+    #[arg(long, value_name = "FILE.rs", hide = true)]
+    dump_roles: Option<PathBuf>,
+
+    /// Exit with an error after the report if any scan errors occurred
+    #[arg(long)]
+    fail_on_error: bool,
+
+    /// Merge report rows below this many directory levels into their ancestor
+    #[arg(long, value_name = "N")]
+    aggregate_depth: Option<usize>,
+
+    /// Report directory paths under this virtual root instead of the real
+    /// checkout location, e.g. `repo`, so reports match across machines
+    #[arg(long, value_name = "VIRTUAL")]
+    relocate_root: Option<String>,
+
+    /// Merge directory rows whose paths differ only in letter case
+    #[arg(long)]
+    merge_case_insensitive_dirs: bool,
+
+    /// Fold every file straight into per-language totals, keeping no per-directory detail
+    #[arg(long)]
+    streaming_totals: bool,
+
+    /// How counts are reconciled with each file's line count
+    #[arg(long, value_enum, default_value_t = NormalizePolicy::Default)]
+    normalize: NormalizePolicy,
+
+    /// Count physical code lines, or logical ones where a multi-line call or
+    /// list counts once (Rust and C-style languages)
+    #[arg(long, value_enum, default_value_t = CountMode::Physical)]
+    count_mode: CountMode,
+
+    /// Omit the per-directory "(subtotal)" rows from the report table
+    #[arg(long)]
+    no_subtotals: bool,
+
+    /// Add a "Doc" column counting Rust `///` and `//!` lines (already included in Comments)
+    #[arg(long)]
+    doc_comments: bool,
+
+    /// Add an "Avg" column with the average code lines per file
+    #[arg(long)]
+    averages: bool,
+
+    /// Append per-language line vs block comment counts (C-style, Rust, PowerShell, HTML/XML)
+    #[arg(long)]
+    comment_types: bool,
+
+    /// Width of the directory column in report tables
+    #[arg(long, value_name = "N", default_value_t = DIR_WIDTH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(MIN_DIR_WIDTH as u64..))]
+    dir_width: usize,
+
+    /// Width of the language column in report tables
+    #[arg(long, value_name = "N", default_value_t = LANG_WIDTH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(MIN_LANG_WIDTH as u64..))]
+    lang_width: usize,
+
+    /// Count code lines with comments as code only and drop the Mixed column
+    #[arg(long)]
+    no_mixed: bool,
+
+    /// Total table width, or `auto` for the terminal's; sizes the directory and language columns
+    #[arg(long, value_name = "N|auto", value_parser = parse_report_width, conflicts_with_all = ["dir_width", "lang_width"])]
+    report_width: Option<ReportWidth>,
+
+    /// Report format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Key JSON directories relative to the scan root; `--relative-paths=false` keeps absolute paths
+    #[arg(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = ArgAction::Set
+    )]
+    relative_paths: bool,
+
+    /// Field separator for `--format csv`: one character, or `\t` for tab
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_csv_delimiter)]
+    csv_delimiter: char,
+
+    /// Name the scan in the report heading (and as `label` in JSON) instead of the scanned path
+    #[arg(long, value_name = "TEXT")]
+    root_label: Option<String>,
+
+    /// Reuse per-file counts stored in this file when size and mtime are unchanged
+    #[arg(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+
+    /// INI-style config file defining custom languages (`[language.<name>]` sections)
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Count an extension with these line-comment tokens for this run, e.g. `conf=//,#` (repeatable)
+    #[arg(long = "comment-style", value_name = "EXT=TOKENS", value_parser = parse_comment_style, action = ArgAction::Append)]
+    comment_styles: Vec<CustomLanguage>,
+
+    /// Use the language named by a file's `#!` line over its extension
+    #[arg(long)]
+    prefer_shebang: bool,
+
+    /// Guess the language of files with no known extension from their first lines
+    #[arg(long)]
+    sniff_content: bool,
+
+    /// Count an extra extension as a built-in language for this run, e.g. `rsx=Rust` (repeatable)
+    #[arg(long = "include-ext", value_name = "EXT=LANG", value_parser = parse_extension_mapping, action = ArgAction::Append)]
+    include_ext: Vec<ExtensionMapping>,
+
+    /// Language of the source read from stdin when PATH is `-` (a built-in
+    /// name such as `rust`, matched case-insensitively)
+    #[arg(long, value_name = "LANG", value_parser = parse_builtin_language)]
+    lang: Option<&'static str>,
+
+    /// Populated from `--comment-style` and `--config` before scanning.
+    #[arg(skip)]
+    custom_languages: Vec<CustomLanguage>,
+
+    /// Also report total bytes and UTF-8 characters per language
+    #[arg(long)]
+    bytes: bool,
+
+    /// Honour `//` and `/* */` comments in plain `.json` files (tsconfig.json, .vscode/*.json)
+    #[arg(long)]
+    jsonc: bool,
+
+    /// Count lockfiles (Cargo.lock, package-lock.json, ...) under their own
+    /// language instead of the `Lockfile` bucket
+    #[arg(long)]
+    count_lockfiles: bool,
+
+    /// Stop scanning after this many seconds and report partial results
+    #[arg(long, value_name = "SECS", value_parser = parse_runtime_budget)]
+    max_runtime: Option<Duration>,
+
+    /// Only count files changed relative to this git ref (`git diff --name-only <REF>`)
+    #[arg(long, value_name = "GIT_REF")]
+    since: Option<String>,
+
+    /// How much of a `#[cfg(test)]` module counts as test code
+    #[arg(long, value_enum, default_value_t = TestScope::Cfg)]
+    test_scope: TestScope,
+
+    /// Report Rust items under `#[cfg(feature = ...)]` as a separate Feature role
+    #[arg(long)]
+    feature_roles: bool,
+
+    /// Language to assume for `.m` files
+    #[arg(long = "m-is", value_enum, default_value_t = MFileLanguage::Objc)]
+    m_is: MFileLanguage,
+}
+
+fn parse_runtime_budget(value: &str) -> Result<Duration, String> {
+    let secs: f64 = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number of seconds", value))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("'{}' is not a valid duration", value))
+}
+
+/// Parse a `--comment-style EXT=TOKEN[,TOKEN...]` override. The extension
+/// keeps its usual language name (if any) but is counted with only the given
+/// line-comment tokens.
+fn parse_comment_style(value: &str) -> Result<CustomLanguage, String> {
+    let (ext, tokens) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form EXT=TOKENS", value))?;
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() || ext.contains(['.', '/', '\\']) {
+        return Err(format!("'{}' does not name a file extension", value));
+    }
+    let line_comments: Vec<String> = tokens
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+        .collect();
+    if line_comments.is_empty() {
+        return Err(format!("'{}' lists no comment tokens", value));
+    }
+    let name = get_language_from_extension(&format!("file.{}", ext))
+        .map_or_else(|| ext.to_uppercase(), str::to_string);
+    Ok(CustomLanguage {
+        name,
+        extensions: vec![ext],
+        line_comments,
+        ..Default::default()
+    })
+}
+
+/// An `--include-ext` mapping: files ending in `.ext` are counted exactly
+/// like files with the language's usual extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExtensionMapping {
+    ext: String,
+    language: &'static str,
+    builtin_ext: &'static str,
+}
+
+/// Parse an `--include-ext EXT=LANG` mapping; `LANG` is a built-in language
+/// name, matched case-insensitively.
+fn parse_extension_mapping(value: &str) -> Result<ExtensionMapping, String> {
+    let (ext, language) = value
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not of the form EXT=LANG", value))?;
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() || ext.contains(['.', '/', '\\']) {
+        return Err(format!("'{}' does not name a file extension", value));
+    }
+    let (language, builtin_ext) = builtin_language(language)?;
+    Ok(ExtensionMapping {
+        ext,
+        language,
+        builtin_ext,
+    })
+}
+
+/// A built-in language name (case-insensitive) and its representative extension.
+fn builtin_language(name: &str) -> Result<(&'static str, &'static str), String> {
+    REPRESENTATIVE_EXTENSIONS
+        .iter()
+        .copied()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name.trim()))
+        .ok_or_else(|| format!("'{}' is not a built-in language", name.trim()))
+}
+
+fn parse_builtin_language(name: &str) -> Result<&'static str, String> {
+    builtin_language(name).map(|(language, _)| language)
+}
+
+/// One usual extension per built-in language, for `--include-ext` and `--lang`.
+/// Languages recognised only by file name (Dockerfile, Lockfile) or by a
+/// flag (MATLAB, Markdown) are left out.
+const REPRESENTATIVE_EXTENSIONS: &[(&str, &str)] = &[
+    ("Rust", "rs"),
+    ("Go", "go"),
+    ("Dart", "dart"),
+    ("Python", "py"),
+    ("Java", "java"),
+    ("C/C++", "cpp"),
+    ("Objective-C", "m"),
+    ("Objective-C++", "mm"),
+    ("C#", "cs"),
+    ("JavaScript", "js"),
+    ("TypeScript", "ts"),
+    ("JSX", "jsx"),
+    ("TSX", "tsx"),
+    ("PHP", "php"),
+    ("Perl", "pl"),
+    ("Ruby", "rb"),
+    ("Lua", "lua"),
+    ("MoonScript", "moon"),
+    ("Shell", "sh"),
+    ("Pascal", "pas"),
+    ("R", "r"),
+    ("R Markdown", "rmd"),
+    ("Elixir", "ex"),
+    ("Erlang", "erl"),
+    ("SQL", "sql"),
+    ("PL/SQL", "pls"),
+    ("OCaml", "ml"),
+    ("F#", "fs"),
+    ("Scala", "scala"),
+    ("Groovy", "groovy"),
+    ("Kotlin", "kt"),
+    ("YAML", "yaml"),
+    ("JSON", "json"),
+    ("JSONC", "jsonc"),
+    ("JSON5", "json5"),
+    ("XML", "xml"),
+    ("HTML", "html"),
+    ("TOML", "toml"),
+    ("Makefile", "mk"),
+    ("INI", "ini"),
+    ("Dotenv", "env"),
+    ("HCL", "hcl"),
+    ("CMake", "cmake"),
+    ("Starlark", "bzl"),
+    ("PowerShell", "ps1"),
+    ("Batch", "bat"),
+    ("TCL", "tcl"),
+    ("ReStructuredText", "rst"),
+    ("Velocity", "vm"),
+    ("Mustache", "mustache"),
+    ("Jinja2", "j2"),
+    ("ERB", "erb"),
+    ("Protobuf", "proto"),
+    ("GraphQL", "graphql"),
+    ("VHDL", "vhd"),
+    ("Verilog", "v"),
+    ("SVG", "svg"),
+    ("XSL", "xsl"),
+    ("Algol", "alg"),
+    ("COBOL", "cob"),
+    ("Fortran", "f"),
+    ("Assembly", "asm"),
+    ("DCL", "com"),
+    ("IPLAN", "ipl"),
+    ("mdhavers", "braw"),
+];
+
+fn extension_mapping_for<'a>(file_name: &str, args: &'a Args) -> Option<&'a ExtensionMapping> {
+    let (stem, ext) = file_name.rsplit_once('.')?;
+    if stem.is_empty() {
+        return None;
+    }
+    args.include_ext
+        .iter()
+        .find(|mapping| mapping.ext.eq_ignore_ascii_case(ext))
+}
+
+/// The language named by a `#!` line, e.g. `#!/usr/bin/env python3`, with a
+/// representative extension for counting it.
+fn shebang_language(file_path: &Path) -> Option<(&'static str, &'static str)> {
+    let mut head = Vec::new();
+    fs::File::open(file_path)
+        .ok()?
+        .take(256)
+        .read_to_end(&mut head)
+        .ok()?;
+    let head = String::from_utf8_lossy(head.strip_prefix(UTF8_BOM).unwrap_or(&head));
+    let line = head.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    let name = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let language = match name {
+        "python" | "pypy" => "Python",
+        "perl" => "Perl",
+        "ruby" => "Ruby",
+        "sh" | "bash" | "zsh" | "ksh" | "dash" => "Shell",
+        "node" | "nodejs" => "JavaScript",
+        "php" => "PHP",
+        "lua" => "Lua",
+        "tclsh" | "wish" => "TCL",
+        "Rscript" => "R",
+        "pwsh" => "PowerShell",
+        _ => return None,
+    };
+    REPRESENTATIVE_EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == language)
+        .map(|(_, ext)| (language, *ext))
+}
+
+/// Lines of a file inspected by `--sniff-content`.
+const SNIFF_LINES: usize = 40;
+
+/// Conservatively guess the language of an unclassified file from its
+/// content: a recognised shebang wins, otherwise the first [`SNIFF_LINES`]
+/// lines must contain at least three Python or three shell indicators and
+/// none of the other kind. Binary content is never guessed.
+fn sniff_language(file_path: &Path) -> Option<(&'static str, &'static str)> {
+    if let Some(guess) = shebang_language(file_path) {
+        return Some(guess);
+    }
+    let mut head = Vec::new();
+    fs::File::open(file_path)
+        .ok()?
+        .take(8 * 1024)
+        .read_to_end(&mut head)
+        .ok()?;
+    if head.contains(&0) {
+        return None;
+    }
+    let head = std::str::from_utf8(head.strip_prefix(UTF8_BOM).unwrap_or(&head)).ok()?;
+    let (mut python, mut shell) = (0, 0);
+    for line in head.lines().take(SNIFF_LINES) {
+        let line = line.trim();
+        let python_line = (line.starts_with("import ") && !line.ends_with(';'))
+            || (line.starts_with("from ") && line.contains(" import "))
+            || ((line.starts_with("def ") || line.starts_with("class ")) && line.ends_with(':'))
+            || line.starts_with("if __name__ ==");
+        let shell_line = matches!(line, "fi" | "done" | "esac" | "then" | "do")
+            || line.starts_with("if [")
+            || line.starts_with("export ") && line.contains('=')
+            || line.starts_with("set -")
+            || line.ends_with("; then")
+            || line.ends_with("; do")
+            || line.ends_with("() {");
+        python += usize::from(python_line);
+        shell += usize::from(shell_line);
+    }
+    let language = match (python, shell) {
+        (3.., 0) => "Python",
+        (0, 3..) => "Shell",
+        _ => return None,
+    };
+    REPRESENTATIVE_EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == language)
+        .map(|(_, ext)| (language, *ext))
+}
+
+/// A language defined in a `--config` file or by `--comment-style`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CustomLanguage {
+    name: String,
+    /// Extensions without the leading dot, matched case-insensitively.
+    extensions: Vec<String>,
+    line_comments: Vec<String>,
+    /// (open, close) block comment delimiter pairs.
+    block_comments: Vec<(String, String)>,
+    nested: bool,
+}
+
+/// Load custom language definitions from an INI-style config file:
+///
+/// ```text
+/// [language.Lisp DSL]
+/// extensions = dsl lsp
+/// line_comment = ;;
+/// block_comment = #| |#
+/// nested = true
+/// ```
+///
+/// List values are whitespace-separated; `line_comment` and `block_comment`
+/// may repeat. Lines starting with `#` or `;` outside a value are comments.
+fn load_custom_languages(config_path: &Path) -> io::Result<Vec<CustomLanguage>> {
+    let text = fs::read_to_string(config_path)?;
+    parse_custom_languages(&text).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", config_path.display(), err),
+        )
+    })
+}
+
+fn parse_custom_languages(text: &str) -> Result<Vec<CustomLanguage>, String> {
+    fn finish(
+        languages: &mut Vec<CustomLanguage>,
+        current: Option<CustomLanguage>,
+    ) -> Result<(), String> {
+        if let Some(lang) = current {
+            if lang.extensions.is_empty() {
+                return Err(format!("language '{}' has no extensions", lang.name));
+            }
+            languages.push(lang);
+        }
+        Ok(())
+    }
+
+    let mut languages = Vec::new();
+    let mut current: Option<CustomLanguage> = None;
+    for (idx, raw) in text.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            finish(&mut languages, current.take())?;
+            let name = section
+                .trim()
+                .strip_prefix("language.")
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| format!("line {}: expected [language.<name>]", line_no))?;
+            current = Some(CustomLanguage {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .ok_or_else(|| format!("line {}: expected key = value", line_no))?;
+        let lang = current.as_mut().ok_or_else(|| {
+            format!(
+                "line {}: '{}' outside a [language.<name>] section",
+                line_no, key
+            )
+        })?;
+        let tokens: Vec<String> = value.split_whitespace().map(str::to_string).collect();
+        match key {
+            "extensions" => lang.extensions.extend(
+                tokens
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_lowercase()),
+            ),
+            "line_comment" => lang.line_comments.extend(tokens),
+            "block_comment" => match tokens.as_slice() {
+                [open, close] => lang.block_comments.push((open.clone(), close.clone())),
+                _ => {
+                    return Err(format!(
+                        "line {}: block_comment needs an opening and a closing delimiter",
+                        line_no
+                    ))
+                }
+            },
+            "nested" => {
+                lang.nested = value
+                    .parse()
+                    .map_err(|_| format!("line {}: nested must be true or false", line_no))?
+            }
+            _ => return Err(format!("line {}: unknown key '{}'", line_no, key)),
+        }
+    }
+    finish(&mut languages, current)?;
+    Ok(languages)
+}
+
+/// Shape of the final report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable tables.
+    Text,
+    /// A single JSON document.
+    Json,
+    /// One JSON object per counted file, streamed as files are processed.
+    Jsonl,
+    /// One RFC 4180 row per directory and language.
+    Csv,
+}
+
+/// `--report-width`: a fixed table width or the terminal's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportWidth {
+    Auto,
+    Columns(usize),
+}
+
+fn parse_report_width(value: &str) -> Result<ReportWidth, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(ReportWidth::Auto);
+    }
+    let width: usize = value
+        .parse()
+        .map_err(|_| format!("'{}' is neither a column count nor 'auto'", value))?;
+    // The count columns never shrink, so narrower tables cannot be drawn.
+    let numeric = TableLayout {
+        dir_width: 0,
+        lang_width: 0,
+        mixed_column: false,
+        ..TableLayout::default()
+    }
+    .rule_width();
+    if width < numeric {
+        return Err(format!(
+            "{} columns cannot fit the count columns; use at least {}",
+            width, numeric
+        ));
+    }
+    Ok(ReportWidth::Columns(width))
+}
+
+/// Interpretation of the ambiguous `.m` extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MFileLanguage {
+    /// Objective-C source.
+    Objc,
+    /// MATLAB script or function.
+    Matlab,
+}
+
+/// Granularity of Rust test attribution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TestScope {
+    /// Whole `#[cfg(test)]` items (modules, helpers) count as test code.
+    Cfg,
+    /// Only bodies of `#[test]`-style functions count as test code.
+    Fn,
+}
+
+/// How `normalize_stats` reconciles a counter's tallies with the line count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+enum NormalizePolicy {
+    /// Trim surplus from blanks, then record it as mixed; pad shortfalls as blank.
+    #[default]
+    Default,
+    /// Like `default`, but never fabricate blank lines for a shortfall.
+    Strict,
+    /// Report the counter's tallies unchanged.
+    #[value(name = "none")]
+    Off,
+}
+
+/// `--traversal`: the order directories are walked in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+enum Traversal {
+    /// Descend into each subdirectory as it is found.
+    #[default]
+    Dfs,
+    /// Finish each level before the next, so top-level directories
+    /// complete first.
+    Bfs,
+}
+
+/// What `--count-mode` tallies as a code line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+enum CountMode {
+    /// Every physical line holding code.
+    #[default]
+    Physical,
+    /// Lines continuing an unclosed `(` or `[` join the line that opened it
+    /// (Rust and C-style languages).
+    Logical,
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -96,6 +897,26 @@ struct LanguageStats {
     comment_lines: u64,
     blank_lines: u64,
     overlap_lines: u64,
+    /// Rust `///` and `//!` lines; a subset of `comment_lines`.
+    doc_comment_lines: u64,
+    /// Comment lines holding only `//`-style line comments, from counters
+    /// that tell comment kinds apart; a subset of `comment_lines`.
+    line_comment_lines: u64,
+    /// Comment lines touching a block comment, from the same counters.
+    block_comment_lines: u64,
+    /// `#region` / `#endregion` folding markers; a subset of `code_lines` in
+    /// C# and of `comment_lines` in PowerShell, where they are comments.
+    region_marker_lines: u64,
+    /// Code-only lines continuing an unclosed `(` or `[` from an earlier
+    /// line; a subset of `code_lines`, from the Rust and C-style counters.
+    continuation_lines: u64,
+    /// Lines inside `mdkloc:ignore-start` / `mdkloc:ignore-end` ranges, or
+    /// every line of an `--exclude-fixtures` file.
+    ignored_lines: u64,
+    /// Raw file size; only populated with `--bytes`.
+    bytes: u64,
+    /// UTF-8 characters (after lossy decoding); only populated with `--bytes`.
+    chars: u64,
 }
 
 impl LanguageStats {
@@ -104,6 +925,90 @@ impl LanguageStats {
         self.comment_lines += other.comment_lines;
         self.blank_lines += other.blank_lines;
         self.overlap_lines += other.overlap_lines;
+        self.doc_comment_lines += other.doc_comment_lines;
+        self.line_comment_lines += other.line_comment_lines;
+        self.block_comment_lines += other.block_comment_lines;
+        self.region_marker_lines += other.region_marker_lines;
+        self.continuation_lines += other.continuation_lines;
+        self.ignored_lines += other.ignored_lines;
+        self.bytes += other.bytes;
+        self.chars += other.chars;
+    }
+
+    /// Record one physical line. A line carrying both code and a comment
+    /// counts towards each and is also tallied as mixed.
+    fn record_line(&mut self, has_code: bool, has_comment: bool) {
+        if has_code {
+            self.code_lines += 1;
+        }
+        if has_comment {
+            self.comment_lines += 1;
+        }
+        if has_code && has_comment {
+            self.overlap_lines += 1;
+        }
+    }
+
+    /// [`record_line`](Self::record_line) that also records the comment
+    /// kind: a line touching a block comment counts as a block comment
+    /// line, and a line with only a line comment counts as a line comment line.
+    fn record_typed_line(&mut self, has_code: bool, line_comment: bool, block_comment: bool) {
+        self.record_line(has_code, line_comment || block_comment);
+        if block_comment {
+            self.block_comment_lines += 1;
+        } else if line_comment {
+            self.line_comment_lines += 1;
+        }
+    }
+
+    /// `--no-mixed`: mixed lines are already counted as code, so they only
+    /// leave the comment count; the line total is unchanged.
+    fn fold_mixed_into_code(self) -> LanguageStats {
+        LanguageStats {
+            comment_lines: self.comment_lines - self.overlap_lines.min(self.comment_lines),
+            overlap_lines: 0,
+            ..self
+        }
+    }
+
+    /// `--exclude-regions`: folding markers leave code (or, where they are
+    /// comments, the comment counts) and are reported as ignored instead.
+    fn exclude_region_markers(self, markers_are_comments: bool) -> LanguageStats {
+        let markers = self.region_marker_lines;
+        let mut stats = LanguageStats {
+            region_marker_lines: 0,
+            ignored_lines: self.ignored_lines + markers,
+            ..self
+        };
+        if markers_are_comments {
+            stats.comment_lines = stats.comment_lines.saturating_sub(markers);
+            stats.line_comment_lines = stats.line_comment_lines.saturating_sub(markers);
+        } else {
+            stats.code_lines = stats.code_lines.saturating_sub(markers);
+        }
+        stats
+    }
+
+    /// `--count-mode logical`: continuation lines fold into the line that
+    /// opened their group, so they leave the code count.
+    fn into_logical(self) -> LanguageStats {
+        LanguageStats {
+            code_lines: self.code_lines.saturating_sub(self.continuation_lines),
+            continuation_lines: 0,
+            ..self
+        }
+    }
+
+    /// The same lines, all reclassified as ignored (sizes are kept).
+    fn into_ignored(self) -> LanguageStats {
+        LanguageStats {
+            ignored_lines: self.code_lines + self.comment_lines + self.blank_lines
+                - self.overlap_lines
+                + self.ignored_lines,
+            bytes: self.bytes,
+            chars: self.chars,
+            ..LanguageStats::default()
+        }
     }
 }
 
@@ -117,10 +1022,13 @@ enum FileRoleHint {
 enum CodeRole {
     Mainline = 0,
     Test = 1,
+    /// Rust items under `#[cfg(feature = ...)]`, with `--feature-roles`.
+    FeatureGated = 2,
 }
 
 impl CodeRole {
-    const ALL: [CodeRole; CODE_ROLE_COUNT] = [CodeRole::Mainline, CodeRole::Test];
+    const ALL: [CodeRole; CODE_ROLE_COUNT] =
+        [CodeRole::Mainline, CodeRole::Test, CodeRole::FeatureGated];
 
     fn as_index(self) -> usize {
         self as usize
@@ -130,6 +1038,7 @@ impl CodeRole {
         match self {
             CodeRole::Mainline => "Mainline",
             CodeRole::Test => "Test",
+            CodeRole::FeatureGated => "Feature",
         }
     }
 }
@@ -144,6 +1053,8 @@ struct RoleBucket {
 struct RoleSplit {
     buckets: [Option<RoleBucket>; CODE_ROLE_COUNT],
     total_lines: u64,
+    /// `--strict-roles`: why the Rust role detector's split may be unreliable.
+    warnings: Vec<String>,
 }
 
 impl Default for RoleSplit {
@@ -151,6 +1062,7 @@ impl Default for RoleSplit {
         Self {
             buckets: [None; CODE_ROLE_COUNT],
             total_lines: 0,
+            warnings: Vec::new(),
         }
     }
 }
@@ -174,6 +1086,17 @@ impl RoleSplit {
             .filter_map(|role| self.buckets[role.as_index()].map(|bucket| (role, bucket)))
     }
 
+    /// Drop the buckets of roles for which `keep` is false.
+    fn retain(&mut self, keep: impl Fn(CodeRole) -> bool) {
+        for role in CodeRole::ALL {
+            let slot = &mut self.buckets[role.as_index()];
+            if let Some(bucket) = slot.filter(|_| !keep(role)) {
+                self.total_lines -= bucket.total_lines;
+                *slot = None;
+            }
+        }
+    }
+
     #[cfg(test)]
     fn bucket(&self, role: CodeRole) -> Option<RoleBucket> {
         self.buckets[role.as_index()]
@@ -409,6 +1332,8 @@ struct RustRoleTracker {
     scope_stack: Vec<CodeRole>,
     pending_scope_role: Option<CodeRole>,
     pending_line_role: Option<CodeRole>,
+    /// Closing braces seen at file scope, for `--strict-roles`.
+    unmatched_closes: u64,
 }
 
 impl RustRoleTracker {
@@ -422,6 +1347,7 @@ impl RustRoleTracker {
             scope_stack: vec![base_role],
             pending_scope_role: None,
             pending_line_role: None,
+            unmatched_closes: 0,
         }
     }
 
@@ -429,8 +1355,7 @@ impl RustRoleTracker {
         *self.scope_stack.last().unwrap()
     }
 
-    fn mark_pending_test(&mut self) {
-        let role = CodeRole::Test;
+    fn mark_pending(&mut self, role: CodeRole) {
         self.pending_scope_role = Some(role);
         self.pending_line_role = Some(role);
     }
@@ -454,26 +1379,113 @@ impl RustRoleTracker {
     fn close_scope(&mut self) {
         if self.scope_stack.len() > 1 {
             self.scope_stack.pop();
+        } else {
+            self.unmatched_closes += 1;
         }
     }
 }
 
-fn attribute_indicates_test(attr: &str) -> bool {
+fn attribute_indicates_test(attr: &str, scope: TestScope) -> bool {
     let lower = attr.trim().to_ascii_lowercase();
     if lower.starts_with("#[cfg(") {
-        if lower.contains("not(test") {
+        if scope == TestScope::Fn || lower.contains("not(test") {
             return false;
         }
         return lower.contains("test");
     }
-    lower.starts_with("#[test") || lower.contains("::test]")
+    if lower.starts_with("#[test") {
+        return true;
+    }
+    // Runtime test macros such as `#[tokio::test]` or `#[async_std::test(...)]`.
+    lower
+        .strip_prefix("#[")
+        .and_then(|rest| rest.split(['(', ']']).next())
+        .is_some_and(|path| path.trim_end().ends_with("::test"))
+}
+
+/// `#[cfg(...)]` whose predicate sets a Cargo `feature = ...` key outside any
+/// `not(...)`; `target_feature` and negated features do not gate on the feature.
+fn attribute_indicates_feature(attr: &str) -> bool {
+    let lower = attr.trim().to_ascii_lowercase();
+    let Some(predicate) = lower.strip_prefix("#[cfg(") else {
+        return false;
+    };
+    let bytes = predicate.as_bytes();
+    // Whether each open parenthesis sits under a `not(...)`.
+    let mut negated = vec![false];
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let b = bytes[idx];
+        if b == b'"' {
+            idx += 1;
+            while idx < bytes.len() && bytes[idx] != b'"' {
+                idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+            }
+            idx += 1;
+        } else if b == b'(' {
+            let inherited = negated.last().copied().unwrap_or(false);
+            negated.push(inherited);
+            idx += 1;
+        } else if b == b')' {
+            negated.pop();
+            if negated.is_empty() {
+                break;
+            }
+            idx += 1;
+        } else if b.is_ascii_alphabetic() || b == b'_' {
+            let start = idx;
+            while idx < bytes.len() && (bytes[idx].is_ascii_alphanumeric() || bytes[idx] == b'_') {
+                idx += 1;
+            }
+            let ident = &predicate[start..idx];
+            let mut next = idx;
+            while next < bytes.len() && bytes[next].is_ascii_whitespace() {
+                next += 1;
+            }
+            let under_not = negated.last().copied().unwrap_or(false);
+            match (ident, bytes.get(next)) {
+                ("not", Some(b'(')) => {
+                    negated.push(true);
+                    idx = next + 1;
+                }
+                ("feature", Some(b'=')) if !under_not => return true,
+                _ => {}
+            }
+        } else {
+            idx += 1;
+        }
+    }
+    false
 }
 
-fn detect_rust_line_roles(lines: &[String], hint: FileRoleHint) -> Vec<CodeRole> {
-    let mut tracker = RustRoleTracker::new(hint);
-    let mut brace_state = BraceScanState::default();
-    let mut roles = Vec::with_capacity(lines.len());
-    for line in lines {
+/// Streaming role detector: feed lines in order and get each line's role back.
+/// Keeps the scope tracker and brace/string/comment scan state between calls, so files
+/// never need to be buffered in full.
+struct RustRoleDetector {
+    tracker: RustRoleTracker,
+    brace_state: BraceScanState,
+    scope: TestScope,
+    /// Attribute `#[cfg(feature = ...)]` items to `CodeRole::FeatureGated`.
+    feature_roles: bool,
+}
+
+impl RustRoleDetector {
+    fn new(hint: FileRoleHint, scope: TestScope) -> Self {
+        Self {
+            tracker: RustRoleTracker::new(hint),
+            brace_state: BraceScanState::default(),
+            scope,
+            feature_roles: false,
+        }
+    }
+
+    fn with_feature_roles(mut self, enabled: bool) -> Self {
+        self.feature_roles = enabled;
+        self
+    }
+
+    fn next_role(&mut self, line: &str) -> CodeRole {
+        let tracker = &mut self.tracker;
         let trimmed = line.trim();
         let mut role = if trimmed.is_empty() {
             tracker.current_role()
@@ -482,18 +1494,360 @@ fn detect_rust_line_roles(lines: &[String], hint: FileRoleHint) -> Vec<CodeRole>
                 .take_line_role()
                 .unwrap_or_else(|| tracker.current_role())
         };
-        if trimmed.starts_with("#[") && attribute_indicates_test(trimmed) {
-            tracker.mark_pending_test();
+        if trimmed.starts_with("#[") && attribute_indicates_test(trimmed, self.scope) {
+            tracker.mark_pending(CodeRole::Test);
             role = CodeRole::Test;
+        } else if self.feature_roles
+            && tracker.current_role() == CodeRole::Mainline
+            && attribute_indicates_feature(trimmed)
+        {
+            // Test code stays test code even when feature-gated.
+            tracker.mark_pending(CodeRole::FeatureGated);
+            role = CodeRole::FeatureGated;
         }
-        roles.push(role);
         if tracker.pending_scope_role.is_some() && trimmed.ends_with(';') && !trimmed.contains('{')
         {
             tracker.clear_pending_scope();
         }
-        brace_state.scan_line(line, &mut tracker);
+        self.brace_state.scan_line(line, tracker);
+        role
+    }
+
+    /// Reasons the roles assigned so far may be unreliable, once the whole
+    /// file has been fed: braces that never balanced, or a test attribute
+    /// whose item never opened a scope.
+    fn warnings(&self) -> Vec<String> {
+        let tracker = &self.tracker;
+        let mut warnings = Vec::new();
+        if tracker.unmatched_closes > 0 {
+            warnings.push(format!(
+                "{} closing brace(s) without a matching open",
+                tracker.unmatched_closes
+            ));
+        }
+        let open = tracker.scope_stack.len() - 1;
+        if open > 0 {
+            warnings.push(format!("{} scope(s) still open at end of file", open));
+        }
+        if let Some(role) = tracker.pending_scope_role {
+            warnings.push(format!(
+                "{} attribute not followed by an item body",
+                role.label().to_lowercase()
+            ));
+        }
+        warnings
+    }
+}
+
+fn detect_rust_line_roles(
+    lines: &[String],
+    hint: FileRoleHint,
+    scope: TestScope,
+    feature_roles: bool,
+) -> Vec<CodeRole> {
+    let mut detector = RustRoleDetector::new(hint, scope).with_feature_roles(feature_roles);
+    lines.iter().map(|line| detector.next_role(line)).collect()
+}
+
+/// `--dump-roles`: every line of a Rust file prefixed with its line number
+/// and detected role, for debugging the role heuristic.
+fn format_role_dump(
+    file_path: &Path,
+    hint: FileRoleHint,
+    scope: TestScope,
+    feature_roles: bool,
+) -> io::Result<String> {
+    let mut reader = read_file_lines_lossy(file_path)?;
+    let lines = reader.by_ref().collect::<io::Result<Vec<_>>>()?;
+    if let Some(err) = reader.take_error() {
+        return Err(err);
+    }
+    let roles = detect_rust_line_roles(&lines, hint, scope, feature_roles);
+    let mut output = String::new();
+    for (index, (line, role)) in lines.iter().zip(roles).enumerate() {
+        let _ = writeln!(output, "{:>5} {:<8} | {}", index + 1, role.label(), line);
+    }
+    Ok(output)
+}
+
+/// Files already counted in this scan: canonical paths (so symlinks count
+/// once) and, with `--dedupe-by-content`, hashes of their bytes. Also holds
+/// the `--cache` entries, which are keyed by the same canonical paths.
+#[derive(Debug, Default)]
+struct VisitedFiles {
+    real_paths: HashSet<PathBuf>,
+    /// `--hardlink-dedupe`: (device, inode) of every counted file.
+    inodes: HashSet<(u64, u64)>,
+    content_hashes: HashSet<u64>,
+    cache: Option<ScanCache>,
+    /// `--blame`: the repository top level and its tracked files.
+    blame_repo: Option<(PathBuf, HashSet<PathBuf>)>,
+    /// Submodule paths listed in the scan root's `.gitmodules`, whether or
+    /// not they are checked out.
+    gitmodule_paths: HashSet<PathBuf>,
+    /// Compiled `--no-recurse-into` globs.
+    no_recurse_into: Vec<Pattern>,
+    /// Compiled `--exclude-fixtures` globs.
+    exclude_fixtures: Vec<Pattern>,
+}
+
+const CACHE_HEADER: &str = "mdkloc-cache v6";
+
+/// Size and modification time deciding whether a cached count still holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    stamp: FileStamp,
+    role_hint: FileRoleHint,
+    /// UTF-8 characters in the file; its byte length is `stamp.size`.
+    chars: u64,
+    splits: Vec<(String, RoleSplit)>,
+}
+
+/// `--cache` state: raw per-file counts keyed by canonical path. Entries
+/// from the previous run are reused while the file's stamp is unchanged;
+/// only files seen in this run are written back, so deleted files drop out.
+#[derive(Debug, Default)]
+struct ScanCache {
+    options: String,
+    previous: HashMap<PathBuf, CacheEntry>,
+    current: HashMap<PathBuf, CacheEntry>,
+}
+
+impl ScanCache {
+    /// A missing, unreadable or malformed cache starts empty; a cache written
+    /// under different counting options is discarded.
+    fn load(path: &Path, options: String) -> Self {
+        let previous = match fs::read_to_string(path) {
+            Ok(text) => parse_cache(&text, &options).unwrap_or_else(|err| {
+                eprintln!("Ignoring cache {}: {}", path.display(), err);
+                HashMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                eprintln!("Ignoring cache {}: {}", path.display(), err);
+                HashMap::new()
+            }
+        };
+        ScanCache {
+            options,
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    fn lookup(
+        &self,
+        real_path: &Path,
+        stamp: FileStamp,
+        role_hint: FileRoleHint,
+    ) -> Option<CacheEntry> {
+        self.previous
+            .get(real_path)
+            .filter(|entry| entry.stamp == stamp && entry.role_hint == role_hint)
+            .cloned()
+    }
+
+    fn store(&mut self, real_path: PathBuf, entry: CacheEntry) {
+        self.current.insert(real_path, entry);
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "{}", CACHE_HEADER);
+        let _ = writeln!(output, "options\t{}", self.options);
+        let mut paths: Vec<_> = self.current.keys().collect();
+        paths.sort();
+        for path in paths {
+            let entry = &self.current[path];
+            // Tab-separated and line-based: skip anything that cannot round-trip.
+            let Some(path_str) = path.to_str().filter(|p| !p.contains('\n')) else {
+                continue;
+            };
+            if entry
+                .splits
+                .iter()
+                .any(|(lang, _)| lang.contains(['\t', '\n']))
+            {
+                continue;
+            }
+            let stamp = entry.stamp;
+            let hint = match entry.role_hint {
+                FileRoleHint::Unknown => "unknown",
+                FileRoleHint::TestFile => "test",
+            };
+            let _ = writeln!(
+                output,
+                "file\t{}\t{}\t{}\t{}\t{}\t{}",
+                stamp.size, stamp.modified_secs, stamp.modified_nanos, entry.chars, hint, path_str
+            );
+            for (language, split) in &entry.splits {
+                let _ = writeln!(output, "lang\t{}", language);
+                for (role, bucket) in split.iter() {
+                    let stats = bucket.stats;
+                    let _ = writeln!(
+                        output,
+                        "bucket\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        role.label(),
+                        bucket.total_lines,
+                        stats.code_lines,
+                        stats.comment_lines,
+                        stats.blank_lines,
+                        stats.overlap_lines,
+                        stats.ignored_lines,
+                        stats.doc_comment_lines,
+                        stats.line_comment_lines,
+                        stats.block_comment_lines,
+                        stats.region_marker_lines,
+                        stats.continuation_lines
+                    );
+                }
+            }
+        }
+        output
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.render())
+    }
+}
+
+/// Counting options baked into cached stats; a change invalidates the cache.
+fn cache_options_key(args: &Args) -> String {
+    let custom = format!("{:?} {:?}", args.custom_languages, args.include_ext);
+    format!(
+        "test_scope={:?} feature_roles={} m_is={:?} jsonc={} count_lockfiles={} markdown_fences={} prefer_shebang={} sniff_content={} custom={:016x}",
+        args.test_scope,
+        args.feature_roles,
+        args.m_is,
+        args.jsonc,
+        args.count_lockfiles,
+        args.markdown_fences,
+        args.prefer_shebang,
+        args.sniff_content,
+        fnv1a_64(custom.as_bytes())
+    )
+}
+
+fn parse_cache(text: &str, options: &str) -> Result<HashMap<PathBuf, CacheEntry>, String> {
+    let mut entries = HashMap::new();
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line) != Some(CACHE_HEADER) {
+        return Err("unrecognised cache header".to_string());
+    }
+    match lines.next() {
+        Some((_, line)) if line.strip_prefix("options\t") == Some(options) => {}
+        _ => return Ok(entries),
+    }
+    let number = |field: Option<&str>, line_no: usize| -> Result<u64, String> {
+        field
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("line {}: expected a number", line_no + 1))
+    };
+    let mut current: Option<(PathBuf, CacheEntry)> = None;
+    for (line_no, line) in lines {
+        let mut fields = line.split('\t');
+        match fields.next() {
+            Some("file") => {
+                let mut fields = line.splitn(7, '\t').skip(1);
+                let size = number(fields.next(), line_no)?;
+                let modified_secs = number(fields.next(), line_no)?;
+                let modified_nanos = u32::try_from(number(fields.next(), line_no)?)
+                    .map_err(|_| format!("line {}: nanoseconds out of range", line_no + 1))?;
+                let chars = number(fields.next(), line_no)?;
+                let role_hint = match fields.next() {
+                    Some("unknown") => FileRoleHint::Unknown,
+                    Some("test") => FileRoleHint::TestFile,
+                    _ => return Err(format!("line {}: unknown role hint", line_no + 1)),
+                };
+                let path = fields
+                    .next()
+                    .ok_or_else(|| format!("line {}: missing path", line_no + 1))?;
+                if let Some((path, entry)) = current.take() {
+                    entries.insert(path, entry);
+                }
+                current = Some((
+                    PathBuf::from(path),
+                    CacheEntry {
+                        stamp: FileStamp {
+                            size,
+                            modified_secs,
+                            modified_nanos,
+                        },
+                        role_hint,
+                        chars,
+                        splits: Vec::new(),
+                    },
+                ));
+            }
+            Some("lang") => {
+                let (_, entry) = current
+                    .as_mut()
+                    .ok_or_else(|| format!("line {}: language before file", line_no + 1))?;
+                let language = fields.next().unwrap_or_default().to_string();
+                entry.splits.push((language, RoleSplit::default()));
+            }
+            Some("bucket") => {
+                let split = current
+                    .as_mut()
+                    .and_then(|(_, entry)| entry.splits.last_mut())
+                    .map(|(_, split)| split)
+                    .ok_or_else(|| format!("line {}: bucket before language", line_no + 1))?;
+                let label = fields.next();
+                let role = CodeRole::ALL
+                    .into_iter()
+                    .find(|role| Some(role.label()) == label)
+                    .ok_or_else(|| format!("line {}: unknown role", line_no + 1))?;
+                let total_lines = number(fields.next(), line_no)?;
+                let stats = LanguageStats {
+                    code_lines: number(fields.next(), line_no)?,
+                    comment_lines: number(fields.next(), line_no)?,
+                    blank_lines: number(fields.next(), line_no)?,
+                    overlap_lines: number(fields.next(), line_no)?,
+                    ignored_lines: number(fields.next(), line_no)?,
+                    doc_comment_lines: number(fields.next(), line_no)?,
+                    line_comment_lines: number(fields.next(), line_no)?,
+                    block_comment_lines: number(fields.next(), line_no)?,
+                    region_marker_lines: number(fields.next(), line_no)?,
+                    continuation_lines: number(fields.next(), line_no)?,
+                    ..Default::default()
+                };
+                split.push(role, stats, total_lines);
+            }
+            _ => return Err(format!("line {}: unrecognised record", line_no + 1)),
+        }
+    }
+    if let Some((path, entry)) = current {
+        entries.insert(path, entry);
     }
-    roles
+    Ok(entries)
+}
+
+/// 64-bit FNV-1a; fast and dependency-free, sufficient for spotting copies.
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
 }
 
 // Internal processing context to shorten repetitive call sites in scanning.
@@ -504,7 +1858,7 @@ struct ProcCtx<'a> {
     stats: &'a mut HashMap<PathBuf, DirectoryStats>,
     error_count: &'a mut usize,
     filespec: Option<&'a Pattern>,
-    visited_real_paths: &'a mut HashSet<PathBuf>,
+    visited: &'a mut VisitedFiles,
 }
 
 fn process_entry_file(ctx: &mut ProcCtx<'_>, p: &Path) -> io::Result<()> {
@@ -516,16 +1870,29 @@ fn process_entry_file(ctx: &mut ProcCtx<'_>, p: &Path) -> io::Result<()> {
         ctx.stats,
         ctx.error_count,
         ctx.filespec,
-        ctx.visited_real_paths,
+        ctx.visited,
     )
 }
 
+/// Print one `--verbose` note. It shares stdout with the text report, but goes
+/// to stderr for the machine-readable formats so their stdout stays parseable.
+fn print_verbose(args: &Args, note: &str) {
+    if args.format == OutputFormat::Text {
+        println!("{}", note);
+    } else {
+        eprintln!("{}", note);
+    }
+}
+
 fn handle_symlink(ctx: &mut ProcCtx<'_>, entry_path: &Path) -> io::Result<()> {
     match fetch_metadata(entry_path) {
         Ok(target_metadata) => {
             if target_metadata.is_dir() {
                 if ctx.args.verbose {
-                    println!("Skipping symlinked directory: {}", entry_path.display());
+                    print_verbose(
+                        ctx.args,
+                        &format!("Skipping symlinked directory: {}", entry_path.display()),
+                    );
                 }
                 Ok(())
             } else if target_metadata.is_file() {
@@ -546,28 +1913,114 @@ fn handle_symlink(ctx: &mut ProcCtx<'_>, entry_path: &Path) -> io::Result<()> {
     }
 }
 
-fn normalize_stats(mut stats: LanguageStats, total_lines: u64) -> LanguageStats {
-    if total_lines == 0 {
+/// Reconcile a counter's tallies with the file's physical line count.
+///
+/// Counters that detect mixed lines report them in `overlap_lines`; any
+/// remaining surplus (from counters that only double-count) is trimmed from
+/// blanks first and otherwise treated as extra mixed lines.
+fn normalize_stats(stats: LanguageStats, total_lines: u64) -> LanguageStats {
+    normalize_stats_with(stats, total_lines, NormalizePolicy::Default)
+}
+
+/// `normalize_stats` under an explicit `--normalize` policy.
+fn normalize_stats_with(
+    mut stats: LanguageStats,
+    total_lines: u64,
+    policy: NormalizePolicy,
+) -> LanguageStats {
+    if total_lines == 0 || policy == NormalizePolicy::Off {
         return stats;
     }
-    let sum = stats.code_lines + stats.comment_lines + stats.blank_lines;
-    if sum > total_lines {
-        let mut overlap = sum - total_lines;
+    let sum = stats.code_lines + stats.comment_lines + stats.blank_lines + stats.ignored_lines;
+    stats.overlap_lines = stats.overlap_lines.min(sum);
+    let classified = sum - stats.overlap_lines;
+    if classified > total_lines {
+        let mut overlap = classified - total_lines;
         if stats.blank_lines > 0 {
             let blank_reduce = stats.blank_lines.min(overlap);
             stats.blank_lines -= blank_reduce;
             overlap -= blank_reduce;
         }
-        stats.overlap_lines = overlap;
-    } else if sum < total_lines && sum > 0 {
-        stats.blank_lines += total_lines - sum;
-        stats.overlap_lines = 0;
-    } else {
-        stats.overlap_lines = 0;
+        stats.overlap_lines += overlap;
+    } else if classified < total_lines && sum > 0 && policy == NormalizePolicy::Default {
+        stats.blank_lines += total_lines - classified;
     }
     stats
 }
 
+/// Byte offset of a `//` line comment outside double-quoted string literals.
+fn find_slash_line_comment(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut in_string = false;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\\' if in_string => idx += 1,
+            b'"' => in_string = !in_string,
+            // A `'"'` character literal must not open a string.
+            b'\'' if !in_string && bytes.get(idx + 1) == Some(&b'"') => idx += 2,
+            b'/' if !in_string && bytes.get(idx + 1) == Some(&b'/') => return Some(idx),
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Byte offset of a `#` comment that starts a word outside quotes, so that
+/// `$#`, `${#var}` and `a#b` stay code.
+fn find_hash_line_comment(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let b = bytes[idx];
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(b'"') if b == b'\\' => idx += 1,
+            Some(_) => {}
+            None => match b {
+                b'\\' => idx += 1,
+                b'"' | b'\'' => quote = Some(b),
+                b'#' if idx == 0 || bytes[idx - 1].is_ascii_whitespace() => return Some(idx),
+                _ => {}
+            },
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// Byte offset of an assembly comment outside string and character literals:
+/// `;`, `//`, or a `#` standing alone as a word, so `#1` immediates stay code.
+fn find_asm_line_comment(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let b = bytes[idx];
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) if b == b'\\' => idx += 1,
+            Some(_) => {}
+            None => match b {
+                b'"' | b'\'' | b'`' => quote = Some(b),
+                b';' => return Some(idx),
+                b'/' if bytes.get(idx + 1) == Some(&b'/') => return Some(idx),
+                b'#' if idx == 0
+                    || (bytes[idx - 1].is_ascii_whitespace()
+                        && bytes.get(idx + 1).is_none_or(u8::is_ascii_whitespace)) =>
+                {
+                    return Some(idx)
+                }
+                _ => {}
+            },
+        }
+        idx += 1;
+    }
+    None
+}
+
 fn merge_directory_stats(
     target: &mut HashMap<PathBuf, DirectoryStats>,
     dir: PathBuf,
@@ -589,6 +2042,52 @@ fn merge_directory_stats(
     }
 }
 
+/// Roll every directory deeper than `depth` components below `root` into
+/// its depth-`depth` ancestor; depth 0 collapses everything into `root`.
+/// Directories outside `root` keep their own rows.
+fn aggregate_to_depth(
+    stats: HashMap<PathBuf, DirectoryStats>,
+    root: &Path,
+    depth: usize,
+) -> HashMap<PathBuf, DirectoryStats> {
+    let mut rolled = HashMap::new();
+    for (dir, stat) in stats {
+        let key = match dir.strip_prefix(root) {
+            Ok(relative) => relative
+                .components()
+                .take(depth)
+                .fold(root.to_path_buf(), |key, part| key.join(part)),
+            Err(_) => dir,
+        };
+        merge_directory_stats(&mut rolled, key, stat);
+    }
+    rolled
+}
+
+/// Fold directory keys differing only in case into one entry, keyed by the
+/// variant that sorts first, for case-insensitive filesystems.
+fn merge_case_variants(
+    stats: HashMap<PathBuf, DirectoryStats>,
+) -> HashMap<PathBuf, DirectoryStats> {
+    let mut canonical: HashMap<String, PathBuf> = HashMap::new();
+    for dir in stats.keys() {
+        canonical
+            .entry(dir.to_string_lossy().to_lowercase())
+            .and_modify(|key| {
+                if dir < key {
+                    *key = dir.clone();
+                }
+            })
+            .or_insert_with(|| dir.clone());
+    }
+    let mut merged = HashMap::new();
+    for (dir, stat) in stats {
+        let key = canonical[&dir.to_string_lossy().to_lowercase()].clone();
+        merge_directory_stats(&mut merged, key, stat);
+    }
+    merged
+}
+
 fn find_powershell_line_comment(s: &str) -> Option<usize> {
     let bytes = s.as_bytes();
     for (idx, &b) in bytes.iter().enumerate() {
@@ -605,7 +2104,7 @@ fn find_powershell_line_comment(s: &str) -> Option<usize> {
 
 impl PerformanceMetrics {
     fn new() -> Self {
-        PerformanceMetrics::with_writer(Box::new(io::stdout()), true)
+        PerformanceMetrics::with_writer(Box::new(io::stderr()), true)
     }
 
     fn with_writer(writer: Box<dyn Write + Send>, progress_enabled: bool) -> Self {
@@ -619,7 +2118,32 @@ impl PerformanceMetrics {
             role_files: std::array::from_fn(|_| AtomicU64::new(0)),
             role_lines: std::array::from_fn(|_| AtomicU64::new(0)),
             role_code_lines: std::array::from_fn(|_| AtomicU64::new(0)),
-        }
+            runtime_exhausted: false,
+            duplicate_files: 0,
+            skipped_files: HashMap::new(),
+            compat_checked: 0,
+            compat_mismatches: Vec::new(),
+            role_warnings: Vec::new(),
+            partial_reads: Vec::new(),
+            entry_counts: EntryCounts::default(),
+            empty_files: 0,
+            whitespace_only_files: 0,
+            checksum_records: Vec::new(),
+            phase_times: PhaseTimes::default(),
+            blame_authors: HashMap::new(),
+            largest_files: BinaryHeap::new(),
+            extension_totals: HashMap::new(),
+            blank_runs: Vec::new(),
+        }
+    }
+
+    /// True once `budget` has elapsed since the scan started; latches so the
+    /// whole traversal unwinds after the first expired check.
+    fn runtime_exceeded(&mut self, budget: Option<Duration>) -> bool {
+        if !self.runtime_exhausted {
+            self.runtime_exhausted = budget.is_some_and(|b| self.start_time.elapsed() >= b);
+        }
+        self.runtime_exhausted
     }
 
     fn update(&mut self, new_lines: u64) {
@@ -707,10 +2231,89 @@ impl PerformanceMetrics {
     }
 }
 
+/// How a counter classified one physical line, read off what it added to
+/// its stats while on that line; `role` is `None` when it added nothing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct LineRecord {
+    role: Option<CodeRole>,
+    code: bool,
+    comment: bool,
+    blank: bool,
+    ignored: bool,
+    continuation: bool,
+    region_marker: bool,
+}
+
+impl LineRecord {
+    /// The record of a line whose counting took the role buckets from
+    /// `before` to `after`.
+    fn between(before: &[LanguageStats], after: &[LanguageStats]) -> Self {
+        let zero = LanguageStats::default();
+        for (role, now) in CodeRole::ALL.into_iter().zip(after) {
+            let was = before.get(role.as_index()).unwrap_or(&zero);
+            let record = LineRecord {
+                role: Some(role),
+                code: now.code_lines > was.code_lines,
+                comment: now.comment_lines > was.comment_lines,
+                blank: now.blank_lines > was.blank_lines,
+                ignored: now.ignored_lines > was.ignored_lines,
+                continuation: now.continuation_lines > was.continuation_lines,
+                region_marker: now.region_marker_lines > was.region_marker_lines,
+            };
+            if record.code || record.comment || record.blank || record.ignored {
+                return record;
+            }
+        }
+        LineRecord::default()
+    }
+
+    /// Counted as a blank line and nothing else.
+    fn is_blank(self) -> bool {
+        self.blank && !self.code && !self.comment && !self.ignored
+    }
+}
+
+/// A counter's running stats, one entry per role bucket in
+/// [`CodeRole::as_index`] order.
+trait LineTally {
+    fn buckets(&self) -> &[LanguageStats];
+}
+
+impl LineTally for LanguageStats {
+    fn buckets(&self) -> &[LanguageStats] {
+        std::slice::from_ref(self)
+    }
+}
+
+impl LineTally for [LanguageStats; CODE_ROLE_COUNT] {
+    fn buckets(&self) -> &[LanguageStats] {
+        self
+    }
+}
+
+/// Byte order mark some editors put at the start of UTF-8 files.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
 /// Reads a file’s entire content as lines, converting invalid UTF‑8 sequences using replacement characters.
 struct LossyLineReader {
     reader: BufReader<Box<dyn Read + Send>>,
     buffer: Vec<u8>,
+    /// No line yielded yet; a leading UTF-8 BOM is dropped from the first
+    /// line so shebangs and column-based rules see column 0.
+    at_start: bool,
+    /// The read error that ended iteration after some lines were yielded.
+    error: Option<io::Error>,
+    /// Raw bytes read so far, BOM and line endings included.
+    bytes: u64,
+    /// UTF-8 characters in those bytes; each invalid sequence counts as one
+    /// replacement character.
+    chars: u64,
+    /// One record per line yielded, once `recording` asked for them.
+    records: Option<Vec<LineRecord>>,
+    /// The last line yielded still awaits its record.
+    pending: bool,
+    /// The counter's stats at the last `next_tallied` call.
+    tally: Vec<LanguageStats>,
 }
 
 impl LossyLineReader {
@@ -722,9 +2325,70 @@ impl LossyLineReader {
         Self {
             reader: BufReader::new(reader),
             buffer: Vec::with_capacity(8 * 1024),
+            at_start: true,
+            error: None,
+            bytes: 0,
+            chars: 0,
+            records: None,
+            pending: false,
+            tally: Vec::new(),
+        }
+    }
+
+    /// Keep a [`LineRecord`] for every line the counter tallies.
+    fn recording(mut self) -> Self {
+        self.records = Some(Vec::new());
+        self
+    }
+
+    /// The next line, after recording how the counter classified the
+    /// previous one from the change in its stats since the last call.
+    fn next_tallied(&mut self, tally: &impl LineTally) -> Option<io::Result<String>> {
+        if let Some(records) = self.records.as_mut() {
+            let buckets = tally.buckets();
+            if std::mem::take(&mut self.pending) {
+                if let Some(record) = records.last_mut() {
+                    *record = LineRecord::between(&self.tally, buckets);
+                }
+            }
+            self.tally.clear();
+            self.tally.extend_from_slice(buckets);
+        }
+        self.next()
+    }
+
+    /// Lines yielded so far, while recording.
+    fn recorded_lines(&self) -> usize {
+        self.records.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Overwrite the records from line index `start` on, for lines counted
+    /// later from a buffered copy (Markdown fences).
+    fn splice_records(&mut self, start: usize, records: &[LineRecord]) {
+        if let Some(target) = self.records.as_mut().and_then(|own| own.get_mut(start..)) {
+            for (slot, record) in target.iter_mut().zip(records) {
+                *slot = *record;
+            }
         }
     }
 
+    fn take_records(&mut self) -> Vec<LineRecord> {
+        self.records.take().unwrap_or_default()
+    }
+
+    /// The error that cut the input short, if any. Lines yielded before it
+    /// are valid, so callers choose between keeping them and failing.
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Byte length and character count of the input, reading whatever the
+    /// counter left unread so the totals cover the whole file.
+    fn finish_size(&mut self) -> (u64, u64) {
+        while let Some(Ok(_)) = self.next() {}
+        (self.bytes, self.chars)
+    }
+
     #[cfg(test)]
     fn with_reader<R: Read + Send + 'static>(reader: R) -> Self {
         Self::from_reader(Box::new(reader))
@@ -738,11 +2402,30 @@ impl Iterator for LossyLineReader {
         self.buffer.clear();
         match self.reader.read_until(b'\n', &mut self.buffer) {
             Ok(0) => None,
-            Ok(_) => {
-                let text = String::from_utf8_lossy(&self.buffer);
+            Ok(read) => {
+                self.bytes += read as u64;
+                let mut bytes = self.buffer.as_slice();
+                if std::mem::take(&mut self.at_start) {
+                    if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+                        bytes = rest;
+                        self.chars += 1;
+                    }
+                }
+                let text = String::from_utf8_lossy(bytes);
+                self.chars += text.chars().count() as u64;
+                if let Some(records) = self.records.as_mut() {
+                    records.push(LineRecord::default());
+                    self.pending = true;
+                }
                 let line = text.trim_end_matches(['\n', '\r']).to_string();
                 Some(Ok(line))
             }
+            // Once a line is out, stop here and keep the error for
+            // `take_error`, so the lines counted so far survive.
+            Err(err) if !self.at_start => {
+                self.error = Some(err);
+                None
+            }
             Err(err) => Some(Err(err)),
         }
     }
@@ -754,8 +2437,134 @@ fn read_file_lines_lossy(file_path: &Path) -> io::Result<LossyLineReader> {
     Ok(LossyLineReader::new(file))
 }
 
-fn read_file_lines_vec(file_path: &Path) -> io::Result<Vec<String>> {
-    read_file_lines_lossy(file_path)?.collect()
+/// Count `lines` as a file called `file_name` in `language`; `detected` is
+/// the language and extension its content chose over its name, if any.
+fn count_file_splits(
+    file_name: &str,
+    lines: &mut LossyLineReader,
+    language: &str,
+    detected: Option<(&str, &str)>,
+    role_hint: FileRoleHint,
+    args: &Args,
+) -> io::Result<Vec<(String, RoleSplit)>> {
+    if language == "Markdown" && args.markdown_fences {
+        return count_markdown_with_fences(lines, args);
+    }
+    match detected {
+        Some((_, ext)) => count_lines_as(file_name, lines, role_hint, args, Some(ext)),
+        None => count_source_with_roles(file_name, lines, role_hint, args),
+    }
+    .map(|role_split| vec![(language.to_string(), role_split)])
+}
+
+/// A counted bucket after the report-wide adjustments: region markers,
+/// `--normalize`, logical counting, `--no-mixed` and fixture files.
+fn adjust_bucket_stats(
+    language: &str,
+    bucket: RoleBucket,
+    args: &Args,
+    fixture: bool,
+) -> LanguageStats {
+    let mut stats = bucket.stats;
+    if args.exclude_regions {
+        stats = stats.exclude_region_markers(language == "PowerShell");
+    }
+    let mut stats = normalize_stats_with(stats, bucket.total_lines, args.normalize);
+    // After normalizing, which would pad the folded lines back as blanks.
+    if args.count_mode == CountMode::Logical {
+        stats = stats.into_logical();
+    }
+    if args.no_mixed {
+        stats = stats.fold_mixed_into_code();
+    }
+    if fixture {
+        stats = stats.into_ignored();
+    }
+    stats
+}
+
+/// Count an in-memory snippet of `outer`, starting at its line index
+/// `first_line`, with the counter a file called `virtual_name` would get.
+fn count_embedded_source(
+    virtual_name: &str,
+    contents: Vec<u8>,
+    args: &Args,
+    outer: &mut LossyLineReader,
+    first_line: usize,
+) -> io::Result<RoleSplit> {
+    let mut lines = LossyLineReader::from_reader(Box::new(io::Cursor::new(contents)));
+    if outer.records.is_some() {
+        lines = lines.recording();
+    }
+    let split = count_source_with_roles(virtual_name, &mut lines, FileRoleHint::Unknown, args)?;
+    outer.splice_records(first_line, &lines.take_records());
+    Ok(split)
+}
+
+/// Count source read from `reader` with the counter of the built-in
+/// `language`, summing its role buckets. Returns the stats and line count.
+fn count_lines_with_stats_from_reader(
+    language: &str,
+    reader: Box<dyn Read + Send>,
+    args: &Args,
+) -> io::Result<(LanguageStats, u64)> {
+    let (_, ext) = builtin_language(language)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+    let mut lines = LossyLineReader::from_reader(reader);
+    let result = count_source_with_roles(
+        &format!("stdin.{}", ext),
+        &mut lines,
+        FileRoleHint::Unknown,
+        args,
+    );
+    let split = result?;
+    if let Some(err) = lines.take_error() {
+        return Err(err);
+    }
+    let mut stats = LanguageStats::default();
+    for (_, bucket) in split.iter() {
+        stats.add_assign(&bucket.stats);
+    }
+    Ok((stats, split.total_lines))
+}
+
+/// Spread a file's size over its role buckets in proportion to their line
+/// counts; the last bucket takes the rounding remainder.
+fn apportion_file_size(pending: &mut [(CodeRole, LanguageStats)], bytes: u64, chars: u64) {
+    let lines: Vec<u64> = pending
+        .iter()
+        .map(|(_, stats)| {
+            stats.code_lines + stats.comment_lines + stats.blank_lines + stats.ignored_lines
+        })
+        .collect();
+    let total: u64 = lines.iter().sum();
+    let (mut bytes_left, mut chars_left) = (bytes, chars);
+    let last = pending.len().saturating_sub(1);
+    for (idx, (_, stats)) in pending.iter_mut().enumerate() {
+        if idx == last || total == 0 {
+            stats.bytes = bytes_left;
+            stats.chars = chars_left;
+            bytes_left = 0;
+            chars_left = 0;
+        } else {
+            stats.bytes = bytes * lines[idx] / total;
+            stats.chars = chars * lines[idx] / total;
+            bytes_left -= stats.bytes;
+            chars_left -= stats.chars;
+        }
+    }
+}
+
+/// `.env` and its variants (`.env.local`, `.env.production`); `app.env`
+/// is matched by extension instead.
+fn is_dotenv_file(lower_name: &str) -> bool {
+    lower_name == ".env" || lower_name.starts_with(".env.")
+}
+
+/// Bazel's extensionless Starlark files; case-sensitive, since lowercase
+/// `build` is as often a shell script.
+fn is_bazel_file(file_name: &str) -> bool {
+    file_name == "BUILD" || file_name == "WORKSPACE"
 }
 
 /// Identify the language based on filename and/or extension (case-insensitive).
@@ -772,6 +2581,15 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
     if lower == "cmakelists.txt" {
         return Some("CMake");
     }
+    if is_bazel_file(file_name) {
+        return Some("Starlark");
+    }
+    if is_dotenv_file(&lower) {
+        return Some("Dotenv");
+    }
+    if lower == ".editorconfig" {
+        return Some("INI");
+    }
     // Common shell dotfiles
     match lower.as_str() {
         ".bashrc" | ".bash_profile" | ".profile" | ".zshrc" | ".zprofile" | ".zshenv"
@@ -781,6 +2599,19 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
         _ => {}
     }
 
+    // Compound extensions must win over their final component (`.d.ts` vs `.ts`)
+    if lower.len() > ".d.ts".len() && lower.ends_with(".d.ts") {
+        return Some("TypeScript (decl)");
+    }
+    // Gradle's Kotlin DSL; checked here so `.gradle` never claims it.
+    if lower.len() > ".gradle.kts".len() && lower.ends_with(".gradle.kts") {
+        return Some("Kotlin");
+    }
+    // Terraform's JSON syntax belongs with the rest of the HCL code.
+    if lower.len() > ".tf.json".len() && lower.ends_with(".tf.json") {
+        return Some("HCL");
+    }
+
     // Extract extension if present
     let (_stem, ext) = match file_name.rsplit_once('.') {
         Some((s, e)) if !s.is_empty() => (s, e.to_lowercase()),
@@ -794,7 +2625,10 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
         "dart" => Some("Dart"),
         "py" => Some("Python"),
         "java" => Some("Java"),
+        // `.h` stays C/C++ even though Objective-C headers share it.
         "cpp" | "c" | "h" | "hpp" => Some("C/C++"),
+        "m" => Some("Objective-C"),
+        "mm" => Some("Objective-C++"),
         "cs" => Some("C#"),
         "js" => Some("JavaScript"),
         "ts" => Some("TypeScript"),
@@ -803,12 +2637,28 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
         "php" => Some("PHP"),
         "pl" | "pm" | "t" => Some("Perl"),
         "rb" => Some("Ruby"),
+        "lua" => Some("Lua"),
+        "moon" => Some("MoonScript"),
         "sh" => Some("Shell"),
         "pas" => Some("Pascal"),
+        "r" => Some("R"),
+        "rmd" => Some("R Markdown"),
+        "ex" | "exs" => Some("Elixir"),
+        "erl" | "hrl" => Some("Erlang"),
+        "sql" => Some("SQL"),
+        // Oracle package specs/bodies
+        "pls" | "pks" | "pkb" => Some("PL/SQL"),
+        // ML family
+        "ml" | "mli" => Some("OCaml"),
+        "fs" | "fsi" | "fsx" => Some("F#"),
         // Newly supported
         "scala" | "sbt" => Some("Scala"),
+        "groovy" | "gradle" => Some("Groovy"),
+        "kt" | "kts" => Some("Kotlin"),
         "yaml" | "yml" => Some("YAML"),
         "json" => Some("JSON"),
+        "jsonc" => Some("JSONC"),
+        "json5" => Some("JSON5"),
         // XML family (SVG/XSL handled separately)
         "xml" | "xsd" => Some("XML"),
         "html" | "htm" | "xhtml" => Some("HTML"),
@@ -817,10 +2667,13 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
         "mk" | "mak" => Some("Makefile"),
         // INI-like
         "ini" | "cfg" | "conf" | "properties" | "prop" => Some("INI"),
+        "env" => Some("Dotenv"),
         // HCL / Terraform
         "hcl" | "tf" | "tfvars" => Some("HCL"),
         // CMake modules
         "cmake" => Some("CMake"),
+        // Bazel `BUILD.bazel`, `WORKSPACE.bazel` and `.bzl` extensions
+        "bzl" | "bazel" | "star" => Some("Starlark"),
         // PowerShell
         "ps1" | "psm1" | "psd1" => Some("PowerShell"),
         // Batch / CMD
@@ -833,8 +2686,16 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
         "vm" | "vtl" => Some("Velocity"),
         // Mustache templates
         "mustache" => Some("Mustache"),
+        // Jinja2 and ERB templates
+        "j2" | "jinja" | "jinja2" => Some("Jinja2"),
+        "erb" => Some("ERB"),
         // Protobuf
         "proto" => Some("Protobuf"),
+        // GraphQL schemas and documents
+        "graphql" | "gql" => Some("GraphQL"),
+        // Hardware description languages
+        "vhd" | "vhdl" => Some("VHDL"),
+        "v" | "sv" | "svh" => Some("Verilog"),
         // SVG / XSL
         "svg" => Some("SVG"),
         "xsl" | "xslt" => Some("XSL"),
@@ -856,6 +2717,78 @@ fn get_language_from_extension(file_name: &str) -> Option<&'static str> {
     }
 }
 
+/// Language lookup honouring run-time overrides from the command line
+/// before falling back to `get_language_from_extension`.
+fn language_for_file<'a>(file_name: &str, args: &'a Args) -> Option<&'a str> {
+    if let Some(custom) = custom_language_for(file_name, args) {
+        return Some(custom.name.as_str());
+    }
+    if let Some(mapping) = extension_mapping_for(file_name, args) {
+        return Some(mapping.language);
+    }
+    if let Some(language) = lockfile_language(file_name, args) {
+        return Some(language);
+    }
+    if args.markdown_fences
+        && (has_extension(file_name, "md") || has_extension(file_name, "markdown"))
+    {
+        return Some("Markdown");
+    }
+    if args.m_is == MFileLanguage::Matlab && has_extension(file_name, "m") {
+        return Some("MATLAB");
+    }
+    get_language_from_extension(file_name)
+}
+
+fn custom_language_for<'a>(file_name: &str, args: &'a Args) -> Option<&'a CustomLanguage> {
+    let (stem, ext) = file_name.rsplit_once('.')?;
+    if stem.is_empty() {
+        return None;
+    }
+    args.custom_languages
+        .iter()
+        .find(|lang| lang.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+/// Package-manager lockfiles, matched case-insensitively by exact file name,
+/// with the built-in language of their syntax (`None` for formats of their
+/// own, which stay in the `Lockfile` bucket even with `--count-lockfiles`).
+const LOCKFILE_NAMES: &[(&str, Option<&str>)] = &[
+    ("cargo.lock", Some("TOML")),
+    ("composer.lock", Some("JSON")),
+    ("flake.lock", Some("JSON")),
+    ("gemfile.lock", None),
+    ("go.sum", None),
+    ("mix.lock", Some("Elixir")),
+    ("npm-shrinkwrap.json", Some("JSON")),
+    ("package-lock.json", Some("JSON")),
+    ("packages.lock.json", Some("JSON")),
+    ("pipfile.lock", Some("JSON")),
+    ("pnpm-lock.yaml", Some("YAML")),
+    ("podfile.lock", Some("YAML")),
+    ("poetry.lock", Some("TOML")),
+    ("uv.lock", Some("TOML")),
+    ("yarn.lock", Some("YAML")),
+];
+
+/// The language a lockfile is reported under: its native one with
+/// `--count-lockfiles`, otherwise `Lockfile`. `None` for other files.
+fn lockfile_language(file_name: &str, args: &Args) -> Option<&'static str> {
+    let file_name = file_name.to_lowercase();
+    let (_, native) = LOCKFILE_NAMES.iter().find(|(name, _)| *name == file_name)?;
+    Some(
+        native
+            .filter(|_| args.count_lockfiles)
+            .unwrap_or("Lockfile"),
+    )
+}
+
+fn has_extension(file_name: &str, ext: &str) -> bool {
+    file_name
+        .rsplit_once('.')
+        .is_some_and(|(stem, e)| !stem.is_empty() && e.eq_ignore_ascii_case(ext))
+}
+
 fn is_ignored_dir(path: &Path) -> bool {
     let dir_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
     let ignored = [
@@ -872,6 +2805,33 @@ fn is_ignored_dir(path: &Path) -> bool {
     ignored.contains(&dir_name)
 }
 
+/// Vendored dependency directories skipped by `--auto-exclude-vendor`.
+const VENDOR_DIRS: [&str; 10] = [
+    "vendor",
+    "third_party",
+    "third-party",
+    "Pods",
+    "Carthage",
+    ".terraform",
+    "bower_components",
+    "jspm_packages",
+    ".venv",
+    "site-packages",
+];
+
+fn is_vendor_dir(path: &Path) -> bool {
+    let dir_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    VENDOR_DIRS.contains(&dir_name)
+}
+
+/// Directories never descended into: the built-in defaults, `--ignore`
+/// entries and, with `--auto-exclude-vendor`, the vendor list.
+fn is_excluded_dir(args: &Args, path: &Path) -> bool {
+    is_ignored_dir(path)
+        || (args.auto_exclude_vendor && is_vendor_dir(path))
+        || args.ignore.iter().any(|d| path.ends_with(Path::new(d)))
+}
+
 /// Helper function that truncates the given string to a maximum number of characters by keeping the last characters.
 /// If truncation occurs, the returned string is prefixed with "..." so that its total length equals max_len.
 fn truncate_start(s: &str, max_len: usize) -> String {
@@ -879,7 +2839,10 @@ fn truncate_start(s: &str, max_len: usize) -> String {
     if char_count <= max_len {
         s.to_string()
     } else {
-        // More efficient implementation without multiple reverses and unnecessary allocations
+        // Widths too narrow for "..." plus any kept text get a clipped ellipsis.
+        if max_len <= 3 {
+            return ".".repeat(max_len);
+        }
         // Skip front chars to keep only the last (max_len - 3) chars, then prepend "..."
         let skip_count = char_count - (max_len - 3);
         let truncated: String = s.chars().skip(skip_count).collect();
@@ -939,13 +2902,31 @@ fn format_rate(rate: f64) -> String {
     s
 }
 
-fn format_directory_display(path: &Path, current_dir: &Path) -> String {
-    let raw = match path.strip_prefix(current_dir) {
+/// Windows `fs::canonicalize` returns verbatim paths (`\\?\C:\src`,
+/// `\\?\UNC\server\share`); drop the prefix so they match and display like
+/// ordinary paths.
+fn strip_verbatim_prefix(path: &Path) -> Cow<'_, Path> {
+    let Some(text) = path.to_str() else {
+        return Cow::Borrowed(path);
+    };
+    if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+        Cow::Owned(PathBuf::from(format!(r"\\{}", rest)))
+    } else if let Some(rest) = text.strip_prefix(r"\\?\") {
+        Cow::Owned(PathBuf::from(rest))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+fn format_directory_display(path: &Path, current_dir: &Path, width: usize) -> String {
+    let path = strip_verbatim_prefix(path);
+    let current_dir = strip_verbatim_prefix(current_dir);
+    let raw = match path.strip_prefix(&*current_dir) {
         Ok(p) if p.as_os_str().is_empty() => ".".to_string(),
         Ok(p) => p.to_string_lossy().into_owned(),
         Err(_) => path.to_string_lossy().into_owned(),
     };
-    truncate_start(&raw, DIR_WIDTH)
+    truncate_start(&raw, width)
 }
 
 fn failure_injection_enabled() -> bool {
@@ -977,6 +2958,29 @@ fn fetch_metadata(path: &Path) -> io::Result<fs::Metadata> {
     fs::metadata(path)
 }
 
+/// (device, inode) naming the file's storage, shared by all its hardlinks.
+#[cfg(unix)]
+fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|meta| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// `fs::canonicalize`, with a simulated path-too-long failure for tests.
+fn canonicalize_file(path: &Path) -> io::Result<PathBuf> {
+    if should_simulate_path_failure(path, CANONICALIZE_FAIL_TAG) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidFilename,
+            "simulated path too long",
+        ));
+    }
+    fs::canonicalize(path)
+}
+
 struct ReadDirStream {
     inner: fs::ReadDir,
     #[cfg(test)]
@@ -1039,6 +3043,14 @@ fn safe_rate(value: u64, elapsed_secs: f64) -> f64 {
     }
 }
 
+fn safe_average(total: u64, count: u64) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    }
+}
+
 fn safe_percentage(numerator: u64, denominator: u64) -> f64 {
     if denominator == 0 {
         0.0
@@ -1048,98 +3060,292 @@ fn safe_percentage(numerator: u64, denominator: u64) -> f64 {
 }
 
 /// Delegate counting to the appropriate parser based on file extension.
+#[cfg(test)]
 fn count_lines_with_stats(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let mut lines = read_file_lines_lossy(file_path)?;
+    let counted = count_source_lines(file_name, &mut lines)?;
+    lines.take_error().map_or(Ok(counted), Err)
+}
+
+/// Count `lines` with the parser a file called `file_name` gets.
+fn count_source_lines(
+    file_name: &str,
+    lines: &mut LossyLineReader,
+) -> io::Result<(LanguageStats, u64)> {
     // Inspect filename for special cases (Dockerfile*, Makefile variants)
-    let file_name_lower = file_path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .map(|s| s.to_lowercase())
-        .unwrap_or_default();
+    let file_name_lower = file_name.to_lowercase();
     if file_name_lower.starts_with("dockerfile") {
-        return count_dockerfile_lines(file_path);
+        return count_dockerfile_lines(lines);
     }
     if file_name_lower == "makefile"
         || file_name_lower == "gnumakefile"
         || file_name_lower == "bsdmakefile"
     {
-        return count_makefile_lines(file_path);
+        return count_makefile_lines(lines);
     }
     if file_name_lower == "cmakelists.txt" {
-        return count_cmake_lines(file_path);
+        return count_cmake_lines(lines);
+    }
+    if is_bazel_file(file_name) {
+        return count_hash_comment_lines(lines);
+    }
+    if is_dotenv_file(&file_name_lower) {
+        return count_dotenv_lines(lines);
+    }
+    if file_name_lower == ".editorconfig" {
+        return count_ini_lines(lines);
+    }
+    // `.tf.json` is reported as HCL but has JSON syntax (no comments).
+    if file_name_lower.len() > ".tf.json".len() && file_name_lower.ends_with(".tf.json") {
+        return count_json_lines(lines);
     }
     // Get extension in lowercase for case-insensitive matching.
-    let extension = file_path
+    let extension = Path::new(file_name)
         .extension()
         .and_then(|ext| ext.to_str())
         .unwrap_or("")
         .to_lowercase();
-    match extension.as_str() {
-        "rs" => count_rust_lines(file_path),
-        "go" => count_c_style_lines(file_path),
-        "dart" => count_c_style_lines(file_path),
-        "py" => count_python_lines(file_path),
-        "java" | "c" | "cpp" | "h" | "hpp" | "cs" => count_c_style_lines(file_path),
-        "js" | "ts" | "jsx" | "tsx" => count_javascript_lines(file_path),
-        "php" => count_php_lines(file_path),
-        "pl" | "pm" | "t" => count_perl_lines(file_path),
-        "rb" => count_ruby_lines(file_path),
-        "sh" => count_shell_lines(file_path),
-        "pas" => count_pascal_lines(file_path),
+    count_lines_for_extension(lines, &extension)
+}
+
+/// Dispatch to the counter for a (lowercase) extension, which need not be
+/// the file's own when an `--include-ext` mapping applies.
+fn count_lines_for_extension(
+    lines: &mut LossyLineReader,
+    extension: &str,
+) -> io::Result<(LanguageStats, u64)> {
+    match extension {
+        "rs" => count_rust_lines(lines),
+        "go" => count_c_style_lines(lines),
+        "dart" => count_c_style_lines(lines),
+        "py" => count_python_lines(lines),
+        "java" | "c" | "cpp" | "h" | "hpp" | "cs" => count_c_style_lines(lines),
+        "m" | "mm" => count_c_style_lines(lines),
+        "js" | "ts" | "jsx" | "tsx" => count_javascript_lines(lines),
+        "php" => count_php_lines(lines),
+        "pl" | "pm" | "t" => count_perl_lines(lines),
+        "rb" => count_ruby_lines(lines),
+        "sh" => count_shell_lines(lines),
+        "pas" => count_pascal_lines(lines),
+        "r" => count_hash_comment_lines(lines),
+        "rmd" => count_rmarkdown_lines(lines),
+        "ex" | "exs" => count_elixir_lines(lines),
+        "erl" | "hrl" => count_erlang_lines(lines),
+        "sql" | "pls" | "pks" | "pkb" => count_sql_lines(lines),
+        "ml" | "mli" => count_ml_lines(lines, None),
+        "fs" | "fsi" | "fsx" => count_ml_lines(lines, Some("//")),
         // Newly supported languages
-        "scala" | "sbt" => count_c_style_lines(file_path),
-        "yaml" | "yml" => count_yaml_lines(file_path),
-        "json" => count_json_lines(file_path),
-        "xml" | "xsd" => count_xml_like_lines(file_path),
-        "html" | "htm" | "xhtml" => count_xml_like_lines(file_path),
-        "toml" => count_toml_lines(file_path),
-        "mk" | "mak" => count_makefile_lines(file_path),
-        "ini" | "cfg" | "conf" | "properties" | "prop" => count_ini_lines(file_path),
-        "hcl" | "tf" | "tfvars" => count_hcl_lines(file_path),
-        "cmake" => count_cmake_lines(file_path),
-        "ps1" | "psm1" | "psd1" => count_powershell_lines(file_path),
-        "bat" | "cmd" => count_batch_lines(file_path),
-        "tcl" => count_tcl_lines(file_path),
-        "rst" | "rest" => count_rst_lines(file_path),
-        "vm" | "vtl" => count_velocity_lines(file_path),
-        "mustache" => count_mustache_lines(file_path),
-        "proto" => count_c_style_lines(file_path),
-        "svg" => count_xml_like_lines(file_path),
-        "xsl" | "xslt" => count_xml_like_lines(file_path),
+        "scala" | "sbt" => count_c_style_lines(lines),
+        "groovy" | "gradle" => count_c_style_lines(lines),
+        "kt" | "kts" => count_c_style_lines(lines),
+        "yaml" | "yml" => count_yaml_lines(lines),
+        "json" => count_json_lines(lines),
+        "jsonc" | "json5" => count_jsonc_lines(lines),
+        "xml" | "xsd" => count_xml_like_lines(lines),
+        "html" | "htm" | "xhtml" => count_xml_like_lines(lines),
+        "toml" => count_toml_lines(lines),
+        "mk" | "mak" => count_makefile_lines(lines),
+        "ini" | "cfg" | "conf" | "properties" | "prop" => count_ini_lines(lines),
+        "env" => count_dotenv_lines(lines),
+        "hcl" | "tf" | "tfvars" => count_hcl_lines(lines),
+        "cmake" => count_cmake_lines(lines),
+        "bzl" | "bazel" | "star" => count_hash_comment_lines(lines),
+        "ps1" | "psm1" | "psd1" => count_powershell_lines(lines),
+        "bat" | "cmd" => count_batch_lines(lines),
+        "tcl" => count_tcl_lines(lines),
+        "rst" | "rest" => count_rst_lines(lines),
+        "vm" | "vtl" => count_velocity_lines(lines),
+        "mustache" => count_mustache_lines(lines),
+        "j2" | "jinja" | "jinja2" => count_template_comment_lines(lines, "{#", "#}"),
+        "erb" => count_template_comment_lines(lines, "<%#", "%>"),
+        "proto" => count_c_style_lines(lines),
+        "graphql" | "gql" => count_graphql_lines(lines),
+        "vhd" | "vhdl" => count_vhdl_lines(lines),
+        "lua" => count_lua_lines(lines, true),
+        "moon" => count_lua_lines(lines, false),
+        "v" | "sv" | "svh" => count_c_style_lines(lines),
+        "svg" => count_xml_like_lines(lines),
+        "xsl" | "xslt" => count_xml_like_lines(lines),
         // New classic languages
-        "alg" | "algol" | "a60" | "a68" => count_algol_lines(file_path),
-        "cob" | "cbl" | "cobol" | "cpy" => count_cobol_lines(file_path),
-        "f" | "for" | "f77" | "f90" | "f95" | "f03" | "f08" | "f18" => {
-            count_fortran_lines(file_path)
-        }
-        "asm" | "s" => count_asm_lines(file_path),
-        "com" => count_dcl_lines(file_path),
-        "ipl" => count_iplan_lines(file_path),
+        "alg" | "algol" | "a60" | "a68" => count_algol_lines(lines),
+        "cob" | "cbl" | "cobol" | "cpy" => count_cobol_lines(lines),
+        "f" | "for" | "f77" | "f90" | "f95" | "f03" | "f08" | "f18" => count_fortran_lines(lines),
+        "asm" | "s" => count_asm_lines(lines),
+        "com" => count_dcl_lines(lines),
+        "ipl" => count_iplan_lines(lines),
         // mdhavers uses # for comments (like Python/Shell)
-        "braw" => count_mdhavers_lines(file_path),
-        _ => count_generic_lines(file_path),
+        "braw" => count_mdhavers_lines(lines),
+        _ => count_generic_lines(lines),
     }
 }
 
-fn count_lines_with_roles(file_path: &Path, role_hint: FileRoleHint) -> io::Result<RoleSplit> {
-    let extension = file_path
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("")
-        .to_lowercase();
+#[cfg(test)]
+fn count_lines_with_roles(
+    file_path: &Path,
+    role_hint: FileRoleHint,
+    args: &Args,
+) -> io::Result<RoleSplit> {
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let mut lines = read_file_lines_lossy(file_path)?;
+    let split = count_source_with_roles(file_name, &mut lines, role_hint, args)?;
+    lines.take_error().map_or(Ok(split), Err)
+}
+
+/// Count `lines` as the contents of a file called `file_name`, honouring
+/// `--config` languages and `--include-ext` mappings.
+fn count_source_with_roles(
+    file_name: &str,
+    lines: &mut LossyLineReader,
+    role_hint: FileRoleHint,
+    args: &Args,
+) -> io::Result<RoleSplit> {
+    if let Some(custom) = custom_language_for(file_name, args) {
+        let (stats, total_lines) = count_custom_lines(lines, custom)?;
+        return Ok(RoleSplit::single(CodeRole::Mainline, stats, total_lines));
+    }
+    let builtin_ext = extension_mapping_for(file_name, args).map(|mapping| mapping.builtin_ext);
+    count_lines_as(file_name, lines, role_hint, args, builtin_ext)
+}
+
+/// Count `lines` with the counter for `builtin_ext` (an `--include-ext` or
+/// `--prefer-shebang` override), or for `file_name`'s own extension.
+fn count_lines_as(
+    file_name: &str,
+    lines: &mut LossyLineReader,
+    role_hint: FileRoleHint,
+    args: &Args,
+    builtin_ext: Option<&str>,
+) -> io::Result<RoleSplit> {
+    let extension = match builtin_ext {
+        Some(ext) => ext.to_string(),
+        None => Path::new(file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_lowercase(),
+    };
     if extension == "rs" {
-        return count_rust_lines_role_aware(file_path, role_hint);
+        return count_rust_lines_role_aware(lines, role_hint, args.test_scope, args.feature_roles);
     }
     // TODO: Extend with Go/Python/JS-specific role splits once heuristics mature.
-    let (stats, total_lines) = count_lines_with_stats(file_path)?;
+    let lockfile = lockfile_language(file_name, args);
+    let (stats, total_lines) = if lockfile == Some("Lockfile") {
+        count_generic_lines(lines)?
+    } else if let Some((_, native_ext)) = lockfile.and_then(|lang| builtin_language(lang).ok()) {
+        count_lines_for_extension(lines, native_ext)?
+    } else if extension == "m" && args.m_is == MFileLanguage::Matlab {
+        count_matlab_lines(lines)?
+    } else if extension == "json" && args.jsonc {
+        count_jsonc_lines(lines)?
+    } else if builtin_ext.is_some() {
+        count_lines_for_extension(lines, &extension)?
+    } else {
+        count_source_lines(file_name, lines)?
+    };
     Ok(RoleSplit::single(CodeRole::Mainline, stats, total_lines))
 }
 
-fn count_generic_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+const IGNORE_START_MARKER: &str = "mdkloc:ignore-start";
+const IGNORE_END_MARKER: &str = "mdkloc:ignore-end";
+
+/// Tracks `mdkloc:ignore-start` / `mdkloc:ignore-end` suppression ranges.
+/// Marker lines count as comments; lines between them count as ignored.
+/// A marker is honoured only as the first word of a whole-line comment
+/// (`// mdkloc:ignore-start`), never inside code, strings or comment prose.
+#[derive(Debug)]
+struct IgnoreRegion<'a> {
+    active: bool,
+    /// The counter's comment openers (`//`, `#`, `/*`, ...).
+    comment_tokens: &'a [&'a str],
+}
+
+impl<'a> IgnoreRegion<'a> {
+    fn new(comment_tokens: &'a [&'a str]) -> Self {
+        Self {
+            active: false,
+            comment_tokens,
+        }
+    }
+
+    fn is_marker_line(&self, line: &str, marker: &str) -> bool {
+        let trimmed = line.trim_start();
+        self.comment_tokens.iter().any(|token| {
+            trimmed
+                .strip_prefix(token)
+                .is_some_and(|text| text.trim_start().starts_with(marker))
+        })
+    }
+
+    /// Returns true when the line was tallied here and the counter should skip it.
+    fn consume(&mut self, line: &str, stats: &mut LanguageStats) -> bool {
+        if self.active {
+            if self.is_marker_line(line, IGNORE_END_MARKER) {
+                self.active = false;
+                stats.comment_lines += 1;
+            } else {
+                stats.ignored_lines += 1;
+            }
+            return true;
+        }
+        if self.is_marker_line(line, IGNORE_START_MARKER) {
+            self.active = true;
+            stats.comment_lines += 1;
+            return true;
+        }
+        false
+    }
+}
+
+/// Tracks `(` / `[` nesting across code lines for `--count-mode logical`.
+/// Braces open blocks of statements rather than continue one, so they are
+/// not counted.
+#[derive(Debug, Default)]
+struct GroupDepth {
+    depth: u32,
+}
+
+impl GroupDepth {
+    /// Feed one line's code; returns true when it continues a group left
+    /// open by an earlier line. Strings, character literals and comments on
+    /// the line are skipped.
+    fn continues(&mut self, code: &str) -> bool {
+        let continues = self.depth > 0;
+        let bytes = code.as_bytes();
+        let mut in_string = false;
+        let mut idx = 0;
+        while idx < bytes.len() {
+            match bytes[idx] {
+                b'\\' if in_string => idx += 1,
+                b'"' => in_string = !in_string,
+                _ if in_string => {}
+                // `'('`-style character literals, not lifetimes.
+                b'\'' if bytes.get(idx + 2) == Some(&b'\'') => idx += 2,
+                b'/' if bytes.get(idx + 1) == Some(&b'/') => break,
+                b'/' if bytes.get(idx + 1) == Some(&b'*') => match code[idx + 2..].find("*/") {
+                    Some(end) => idx += end + 3,
+                    None => break,
+                },
+                b'(' | b'[' => self.depth += 1,
+                b')' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+            idx += 1;
+        }
+        continues
+    }
+}
+
+fn count_generic_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["//", "#", "/*", "--", ";", "<!--"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         if line.trim().is_empty() {
             stats.blank_lines += 1;
         } else {
@@ -1149,65 +3355,77 @@ fn count_generic_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
     Ok((stats, total_lines))
 }
 
-fn count_rust_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_rust_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["//", "/*"]);
+    let mut groups = GroupDepth::default();
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
             continue;
         }
+        let mut has_code = false;
+        let mut line_comment = false;
+        let mut block_comment = false;
+        let mut code = trimmed;
         if in_block_comment {
-            stats.comment_lines += 1;
+            block_comment = true;
             if trimmed.contains("*/") {
                 in_block_comment = false;
-                let after_comment = trimmed.split("*/").nth(1).unwrap_or("");
-                if !after_comment.trim().is_empty() && !after_comment.trim().starts_with("//") {
-                    stats.code_lines += 1;
-                }
+                let after_comment = trimmed.split("*/").nth(1).unwrap_or("").trim();
+                has_code = !after_comment.is_empty() && !after_comment.starts_with("//");
+                code = after_comment;
             }
-            continue;
-        }
-        if trimmed.starts_with("#[") {
-            stats.code_lines += 1;
-            continue;
-        }
-        if trimmed.contains("/*") {
-            stats.comment_lines += 1;
+        } else if trimmed.starts_with("#[") {
+            has_code = true;
+            line_comment = find_slash_line_comment(trimmed).is_some();
+        } else if trimmed.contains("/*") {
+            block_comment = true;
             let before_comment = trimmed.split("/*").next().unwrap_or("");
-            if !before_comment.trim().is_empty() {
-                stats.code_lines += 1;
-            }
+            has_code = !before_comment.trim().is_empty();
             if !trimmed.contains("*/") {
                 in_block_comment = true;
             } else {
-                let after_comment = trimmed.split("*/").nth(1).unwrap_or("");
-                if !after_comment.trim().is_empty() && !after_comment.trim().starts_with("//") {
-                    stats.code_lines += 1;
-                }
+                let after_comment = trimmed.split("*/").nth(1).unwrap_or("").trim();
+                has_code |= !after_comment.is_empty() && !after_comment.starts_with("//");
             }
-            continue;
+        } else if trimmed.starts_with("//") {
+            line_comment = true;
+            if is_rust_doc_comment(trimmed) {
+                stats.doc_comment_lines += 1;
+            }
+        } else {
+            has_code = true;
+            line_comment = find_slash_line_comment(trimmed).is_some();
         }
-        if trimmed.starts_with("///") || trimmed.starts_with("//!") || trimmed.starts_with("//") {
-            stats.comment_lines += 1;
-            continue;
+        if has_code && groups.continues(code) && !line_comment && !block_comment {
+            stats.continuation_lines += 1;
         }
-        stats.code_lines += 1;
+        stats.record_typed_line(has_code, line_comment, block_comment);
     }
     Ok((stats, total_lines))
 }
 
+/// `///` and `//!` introduce doc comments; `////` is an ordinary comment.
+fn is_rust_doc_comment(trimmed: &str) -> bool {
+    (trimmed.starts_with("///") && !trimmed.starts_with("////")) || trimmed.starts_with("//!")
+}
+
 /// Count lines for mdhavers (.braw files) - a Scots programming language.
 /// mdhavers uses # for single-line comments (like Python/Shell).
 /// https://github.com/0x4d44/mdhavers
-fn count_mdhavers_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_mdhavers_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let trimmed = line.trim();
@@ -1222,84 +3440,111 @@ fn count_mdhavers_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
     Ok((stats, total_lines))
 }
 
-fn count_rust_lines_role_aware(file_path: &Path, hint: FileRoleHint) -> io::Result<RoleSplit> {
-    let lines = read_file_lines_vec(file_path)?;
-    if lines.is_empty() {
-        let default_role = if matches!(hint, FileRoleHint::TestFile) {
-            CodeRole::Test
-        } else {
-            CodeRole::Mainline
-        };
-        return Ok(RoleSplit::single(default_role, LanguageStats::default(), 0));
-    }
-    let roles = detect_rust_line_roles(&lines, hint);
+fn count_rust_lines_role_aware(
+    lines: &mut LossyLineReader,
+    hint: FileRoleHint,
+    scope: TestScope,
+    feature_roles: bool,
+) -> io::Result<RoleSplit> {
+    let mut detector = RustRoleDetector::new(hint, scope).with_feature_roles(feature_roles);
+    let mut ignore = IgnoreRegion::new(&["//", "/*"]);
     let mut stats_per_role = [LanguageStats::default(); CODE_ROLE_COUNT];
     let mut in_block_comment = false;
-    for (line, &role) in lines.iter().zip(roles.iter()) {
+    let mut total_lines = 0u64;
+    let mut groups = GroupDepth::default();
+    while let Some(line_result) = lines.next_tallied(&stats_per_role) {
+        let line = line_result?;
+        total_lines += 1;
+        let role = detector.next_role(&line);
         let trimmed = line.trim();
         let bucket = &mut stats_per_role[role.as_index()];
+        if ignore.consume(&line, bucket) {
+            continue;
+        }
         if trimmed.is_empty() {
             bucket.blank_lines += 1;
             continue;
         }
+        let mut rest = trimmed;
+        let mut has_code = false;
+        let mut line_comment = false;
+        let mut block_comment = false;
         if in_block_comment {
-            bucket.comment_lines += 1;
-            if let Some(end) = trimmed.find("*/") {
-                in_block_comment = false;
-                let rest = trimmed[end + 2..].trim();
-                if rest.is_empty() {
-                    continue;
+            block_comment = true;
+            match trimmed.find("*/") {
+                Some(end) => {
+                    in_block_comment = false;
+                    rest = trimmed[end + 2..].trim();
                 }
-            } else {
-                continue;
+                None => rest = "",
             }
         }
-        if trimmed.starts_with("#[") {
-            bucket.code_lines += 1;
-            continue;
-        }
-        if let Some(pos) = trimmed.find("/*") {
-            bucket.comment_lines += 1;
-            let before = &trimmed[..pos];
-            if !before.trim().is_empty() {
-                bucket.code_lines += 1;
-            }
-            if !trimmed.contains("*/") {
+        if rest.is_empty() {
+            // Entirely inside a block comment.
+        } else if rest.starts_with("#[") {
+            has_code = true;
+            line_comment = find_slash_line_comment(rest).is_some();
+        } else if let Some(pos) = rest.find("/*") {
+            block_comment = true;
+            has_code = !rest[..pos].trim().is_empty();
+            if !rest.contains("*/") {
                 in_block_comment = true;
             } else {
-                let after_comment = trimmed.split("*/").nth(1).unwrap_or("");
-                if !after_comment.trim().is_empty() && !after_comment.trim().starts_with("//") {
-                    bucket.code_lines += 1;
-                }
+                let after_comment = rest.split("*/").nth(1).unwrap_or("").trim();
+                has_code |= !after_comment.is_empty() && !after_comment.starts_with("//");
             }
-            continue;
+        } else if rest.starts_with("//") {
+            line_comment = true;
+            if is_rust_doc_comment(rest) {
+                bucket.doc_comment_lines += 1;
+            }
+        } else {
+            has_code = true;
+            line_comment = find_slash_line_comment(rest).is_some();
         }
-        if trimmed.starts_with("///") || trimmed.starts_with("//!") || trimmed.starts_with("//") {
-            bucket.comment_lines += 1;
-            continue;
+        if has_code && groups.continues(rest) && !line_comment && !block_comment {
+            bucket.continuation_lines += 1;
         }
-        bucket.code_lines += 1;
+        bucket.record_typed_line(has_code, line_comment, block_comment);
+    }
+    if total_lines == 0 {
+        let default_role = if matches!(hint, FileRoleHint::TestFile) {
+            CodeRole::Test
+        } else {
+            CodeRole::Mainline
+        };
+        return Ok(RoleSplit::single(default_role, LanguageStats::default(), 0));
     }
     let mut split = RoleSplit::default();
     for role in CodeRole::ALL {
         let stats = stats_per_role[role.as_index()];
-        if stats.code_lines + stats.comment_lines + stats.blank_lines > 0 {
-            let role_total = stats.code_lines + stats.comment_lines + stats.blank_lines;
+        let role_total =
+            stats.code_lines + stats.comment_lines + stats.blank_lines + stats.ignored_lines
+                - stats.overlap_lines;
+        if role_total > 0 {
             split.push(role, stats, role_total);
         }
     }
+    split.warnings = detector.warnings();
     Ok(split)
 }
 
-fn count_python_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_python_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_multiline_string = false;
     let mut multiline_quote_char = '"';
+    // A triple-quoted string that is an expression (not a docstring) and is
+    // still open: its lines are code.
+    let mut open_code_string: Option<&'static str> = None;
     let mut prev_line_continued = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["#"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
@@ -1317,6 +3562,12 @@ fn count_python_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
             }
             continue;
         }
+        if open_code_string.is_some() {
+            stats.code_lines += 1;
+            open_code_string = scan_python_strings(trimmed, open_code_string);
+            prev_line_continued = open_code_string.is_none() && trimmed.ends_with('\\');
+            continue;
+        }
         if trimmed.starts_with("#") {
             stats.comment_lines += 1;
             continue;
@@ -1338,27 +3589,100 @@ fn count_python_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
         }
         prev_line_continued = trimmed.ends_with('\\');
         stats.code_lines += 1;
+        open_code_string = scan_python_strings(trimmed, None);
     }
     Ok((stats, total_lines))
 }
 
-fn count_c_style_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+/// Track Python string literals across a code line. `open` is a triple-quote
+/// delimiter left open by earlier lines; the delimiter still open at the end
+/// of this line is returned. Quotes inside strings and after a `#` comment
+/// are ignored, so an embedded `\"\"\"` never opens a docstring.
+fn scan_python_strings(line: &str, mut open: Option<&'static str>) -> Option<&'static str> {
+    let bytes = line.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if let Some(delim) = open {
+            if bytes[idx] == b'\\' {
+                idx += 2;
+            } else if bytes[idx..].starts_with(delim.as_bytes()) {
+                open = None;
+                idx += delim.len();
+            } else {
+                idx += 1;
+            }
+            continue;
+        }
+        match bytes[idx] {
+            b'#' => break,
+            quote @ (b'"' | b'\'') => {
+                let triple = if quote == b'"' { "\"\"\"" } else { "'''" };
+                if bytes[idx..].starts_with(triple.as_bytes()) {
+                    open = Some(triple);
+                    idx += triple.len();
+                    continue;
+                }
+                // Single-line string: skip to its closing quote.
+                idx += 1;
+                while idx < bytes.len() && bytes[idx] != quote {
+                    idx += if bytes[idx] == b'\\' { 2 } else { 1 };
+                }
+                idx += 1;
+            }
+            _ => idx += 1,
+        }
+    }
+    open
+}
+
+/// `#region` / `#endregion` folding markers (C#, PowerShell); `#` may be
+/// followed by spaces and the region name is free text.
+fn is_region_marker(trimmed: &str) -> bool {
+    let Some(directive) = trimmed.strip_prefix('#') else {
+        return false;
+    };
+    let directive = directive.trim_start();
+    ["region", "endregion"].iter().any(|marker| {
+        directive
+            .strip_prefix(marker)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    })
+}
+
+fn count_c_style_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["//", "/*"]);
+    let mut groups = GroupDepth::default();
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let mut s = line.as_str();
         let trimmed_line = s.trim();
         if trimmed_line.is_empty() {
             stats.blank_lines += 1;
             continue;
         }
+        if !in_block_comment && is_region_marker(trimmed_line) {
+            stats.code_lines += 1;
+            stats.region_marker_lines += 1;
+            continue;
+        }
+        let code = match in_block_comment {
+            true => trimmed_line.split_once("*/").map_or("", |(_, after)| after),
+            false => trimmed_line,
+        };
+        let mut has_code = false;
+        let mut line_comment = false;
+        let mut block_comment = false;
         loop {
             if in_block_comment {
                 if let Some(end) = s.find("*/") {
-                    stats.comment_lines += 1;
+                    block_comment = true;
                     s = &s[end + 2..];
                     in_block_comment = false;
                     if s.trim().is_empty() {
@@ -1367,7 +3691,7 @@ fn count_c_style_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
                         continue;
                     }
                 } else {
-                    stats.comment_lines += 1;
+                    block_comment = true;
                     break;
                 }
             } else {
@@ -1376,24 +3700,24 @@ fn count_c_style_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
                 match (p_line, p_block) {
                     (None, None) => {
                         if !s.trim().is_empty() {
-                            stats.code_lines += 1;
+                            has_code = true;
                         }
                         break;
                     }
                     (Some(pl), None) => {
                         let before = &s[..pl];
                         if !before.trim().is_empty() {
-                            stats.code_lines += 1;
+                            has_code = true;
                         }
-                        stats.comment_lines += 1; // rest of line is comment
+                        line_comment = true; // rest of line is comment
                         break;
                     }
                     (None, Some(pb)) => {
                         let before = &s[..pb];
                         if !before.trim().is_empty() {
-                            stats.code_lines += 1;
+                            has_code = true;
                         }
-                        stats.comment_lines += 1;
+                        block_comment = true;
                         s = &s[pb + 2..];
                         if let Some(end) = s.find("*/") {
                             s = &s[end + 2..];
@@ -1411,16 +3735,16 @@ fn count_c_style_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
                         if pl < pb {
                             let before = &s[..pl];
                             if !before.trim().is_empty() {
-                                stats.code_lines += 1;
+                                has_code = true;
                             }
-                            stats.comment_lines += 1;
+                            line_comment = true;
                             break; // rest is comment
                         } else {
                             let before = &s[..pb];
                             if !before.trim().is_empty() {
-                                stats.code_lines += 1;
+                                has_code = true;
                             }
-                            stats.comment_lines += 1;
+                            block_comment = true;
                             s = &s[pb + 2..];
                             if let Some(end) = s.find("*/") {
                                 s = &s[end + 2..];
@@ -1438,18 +3762,26 @@ fn count_c_style_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
                 }
             }
         }
+        if has_code && groups.continues(code) && !line_comment && !block_comment {
+            stats.continuation_lines += 1;
+        }
+        stats.record_typed_line(has_code, line_comment, block_comment);
     }
     Ok((stats, total_lines))
 }
 
-fn count_javascript_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_javascript_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block_comment = false;
     let mut in_jsx_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["//", "/*"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
@@ -1536,11 +3868,11 @@ fn count_javascript_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)>
     Ok((stats, total_lines))
 }
 
-fn count_php_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_php_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let trimmed = line.trim();
@@ -1597,13 +3929,20 @@ fn count_php_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
     Ok((stats, total_lines))
 }
 
-fn count_perl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+/// Perl: `#` comments and POD blocks. Everything after a column-0 `__END__`
+/// or `__DATA__` token is data, tallied as ignored lines.
+fn count_perl_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_pod_comment = false;
+    let mut in_data_section = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if in_data_section {
+            stats.ignored_lines += 1;
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
@@ -1623,6 +3962,11 @@ fn count_perl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
             stats.comment_lines += 1;
             continue;
         }
+        if matches!(line.trim_end(), "__END__" | "__DATA__") {
+            in_data_section = true;
+            stats.code_lines += 1;
+            continue;
+        }
         if trimmed.starts_with('#') && !trimmed.starts_with("#!") {
             stats.comment_lines += 1;
             continue;
@@ -1633,12 +3977,12 @@ fn count_perl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
 }
 
 /// Ruby: supports line comments (with a special case for shebang) and block comments delimited by "=begin" and "=end".
-fn count_ruby_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_ruby_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block_comment = false;
     let mut line_number = 0;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         line_number += 1;
@@ -1667,19 +4011,23 @@ fn count_ruby_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
             }
             continue;
         }
-        stats.code_lines += 1;
+        stats.record_line(true, find_hash_line_comment(trimmed).is_some());
     }
     Ok((stats, total_lines))
 }
 
 /// Shell: supports line comments (with a special case for shebang).
-fn count_shell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_shell_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut line_number = 0;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["#"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         line_number += 1;
         let trimmed = line.trim();
         if trimmed.is_empty() {
@@ -1694,22 +4042,23 @@ fn count_shell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
             }
             continue;
         }
-        stats.code_lines += 1;
+        stats.record_line(true, find_hash_line_comment(trimmed).is_some());
     }
     Ok((stats, total_lines))
 }
 
 /// Pascal: supports line comments ("//") and block comments delimited by "{" and "}" or "(*" and "*)".
-/// Improved to support nested block comments by tracking nesting level.
-fn count_pascal_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+/// Block comments nest per delimiter style. Comment openers inside single-quoted string
+/// literals (with `''` as the escaped quote) are treated as code.
+fn count_pascal_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
 
     // Track both comment type and nesting level
-    let mut brace_comment_level = 0; // For { } comments
-    let mut parenthesis_comment_level = 0; // For (* *) comments
+    let mut brace_comment_level = 0u32; // For { } comments
+    let mut parenthesis_comment_level = 0u32; // For (* *) comments
 
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let trimmed = line.trim();
@@ -1718,99 +4067,199 @@ fn count_pascal_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
             continue;
         }
 
-        // If in any block comment
-        if brace_comment_level > 0 || parenthesis_comment_level > 0 {
-            stats.comment_lines += 1;
-
-            // Count nested braces
+        let mut has_comment = brace_comment_level > 0 || parenthesis_comment_level > 0;
+        let mut has_code = false;
+        // String literals cannot span lines in Pascal.
+        let mut in_string = false;
+        let mut chars = trimmed.chars().peekable();
+        while let Some(c) = chars.next() {
             if brace_comment_level > 0 {
-                brace_comment_level += trimmed.matches("{").count() as i32;
-                brace_comment_level -= trimmed.matches("}").count() as i32;
-
-                // If we've closed all brace comments, check for code after the closing brace
-                if brace_comment_level == 0 {
-                    let after = trimmed.rsplit('}').next().unwrap_or("");
-                    if !after.trim().is_empty() && !after.trim().starts_with("//") {
-                        stats.code_lines += 1;
-                    }
+                has_comment = true;
+                match c {
+                    '{' => brace_comment_level += 1,
+                    '}' => brace_comment_level -= 1,
+                    _ => {}
                 }
+                continue;
             }
-
-            // Count nested parenthesis comments
             if parenthesis_comment_level > 0 {
-                parenthesis_comment_level += trimmed.matches("(*").count() as i32;
-                parenthesis_comment_level -= trimmed.matches("*)").count() as i32;
-
-                // If we've closed all parenthesis comments, check for code after
-                if parenthesis_comment_level == 0 {
-                    let after = trimmed.rsplit("*)").next().unwrap_or("");
-                    if !after.trim().is_empty() && !after.trim().starts_with("//") {
-                        stats.code_lines += 1;
+                has_comment = true;
+                if c == '(' && chars.peek() == Some(&'*') {
+                    chars.next();
+                    parenthesis_comment_level += 1;
+                } else if c == '*' && chars.peek() == Some(&')') {
+                    chars.next();
+                    parenthesis_comment_level -= 1;
+                }
+                continue;
+            }
+            if in_string {
+                if c == '\'' {
+                    // A doubled quote is an escaped quote, not the end of the literal.
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                    } else {
+                        in_string = false;
                     }
                 }
+                continue;
+            }
+            match c {
+                '\'' => {
+                    in_string = true;
+                    has_code = true;
+                }
+                '{' => {
+                    brace_comment_level = 1;
+                    has_comment = true;
+                }
+                '(' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    parenthesis_comment_level = 1;
+                    has_comment = true;
+                }
+                '/' if chars.peek() == Some(&'/') => {
+                    has_comment = true;
+                    break;
+                }
+                c if !c.is_whitespace() => has_code = true,
+                _ => {}
             }
-
-            continue;
         }
 
-        // Line comments
-        if trimmed.starts_with("//") {
+        if has_comment {
             stats.comment_lines += 1;
-            continue;
         }
+        if has_code {
+            stats.code_lines += 1;
+        }
+    }
 
-        // Start of brace comment
-        if trimmed.contains("{") {
-            stats.comment_lines += 1;
-
-            // Check for code before the comment
-            let before = trimmed.split('{').next().unwrap_or("");
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
-            }
-
-            brace_comment_level += trimmed.matches("{").count() as i32;
-            brace_comment_level -= trimmed.matches("}").count() as i32;
+    Ok((stats, total_lines))
+}
 
-            // If comment ends on same line
-            if brace_comment_level == 0 {
-                let after = trimmed.rsplit('}').next().unwrap_or("");
-                if !after.trim().is_empty() && !after.trim().starts_with("//") {
-                    stats.code_lines += 1;
-                }
+/// MATLAB: '%' line comments and "%{" ... "%}" block comments (block markers must sit alone
+/// on their line, as MATLAB requires). Trailing '%' after code is left as code because a
+/// quote may be a transpose operator, which makes string tracking unreliable.
+fn count_matlab_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    let mut block_depth = 0usize;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+        } else if trimmed == "%{" {
+            block_depth += 1;
+            stats.comment_lines += 1;
+        } else if block_depth > 0 {
+            if trimmed == "%}" {
+                block_depth -= 1;
             }
+            stats.comment_lines += 1;
+        } else if trimmed.starts_with('%') {
+            stats.comment_lines += 1;
+        } else {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
 
+/// OCaml/F#: nestable "(*" ... "*)" block comments, plus an optional line comment token ("//" for F#).
+/// Comment markers are scanned left to right so code after a closing "*)" is still counted.
+/// Markers inside double-quoted string literals (with `\"` escapes) are treated as code, and
+/// character literals such as `'"'` do not open a string.
+fn count_ml_lines(
+    lines: &mut LossyLineReader,
+    line_comment: Option<&str>,
+) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    let mut depth = 0usize;
+    let line_token: Vec<char> = line_comment.unwrap_or("").chars().collect();
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
             continue;
         }
-
-        // Start of parenthesis comment
-        if trimmed.contains("(*") {
-            stats.comment_lines += 1;
-
-            // Check for code before the comment
-            let before = trimmed.split("(*").next().unwrap_or("");
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
+        let mut has_code = false;
+        let mut has_comment = depth > 0;
+        // String literals are not tracked across lines.
+        let mut in_string = false;
+        let chars: Vec<char> = trimmed.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            if depth > 0 {
+                if c == '(' && next == Some('*') {
+                    depth += 1;
+                    i += 2;
+                } else if c == '*' && next == Some(')') {
+                    depth -= 1;
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+                continue;
             }
-
-            parenthesis_comment_level += trimmed.matches("(*").count() as i32;
-            parenthesis_comment_level -= trimmed.matches("*)").count() as i32;
-
-            // If comment ends on same line
-            if parenthesis_comment_level == 0 {
-                let after = trimmed.rsplit("*)").next().unwrap_or("");
-                if !after.trim().is_empty() && !after.trim().starts_with("//") {
-                    stats.code_lines += 1;
+            if in_string {
+                match c {
+                    '\\' => i += 2,
+                    '"' => {
+                        in_string = false;
+                        i += 1;
+                    }
+                    _ => i += 1,
+                }
+                continue;
+            }
+            if !line_token.is_empty() && chars[i..].starts_with(&line_token) {
+                has_comment = true;
+                break;
+            }
+            match c {
+                '"' => {
+                    in_string = true;
+                    has_code = true;
+                    i += 1;
+                }
+                '(' if next == Some('*') => {
+                    has_comment = true;
+                    depth = 1;
+                    i += 2;
+                }
+                // Character literals ('"', '\'') are skipped whole; a lone quote is a type
+                // variable or primed identifier and stays code.
+                '\'' if next == Some('\\') => {
+                    has_code = true;
+                    let body = (i + 3).min(chars.len());
+                    let close = chars[body..].iter().position(|&ch| ch == '\'');
+                    i = close.map_or(chars.len(), |p| body + p + 1);
+                }
+                '\'' if chars.get(i + 2) == Some(&'\'') => {
+                    has_code = true;
+                    i += 3;
+                }
+                c => {
+                    has_code |= !c.is_whitespace();
+                    i += 1;
                 }
             }
-
-            continue;
         }
-
-        // Regular code line
-        stats.code_lines += 1;
+        if has_code {
+            stats.code_lines += 1;
+        }
+        if has_comment {
+            stats.comment_lines += 1;
+        }
     }
-
     Ok((stats, total_lines))
 }
 
@@ -1818,12 +4267,16 @@ fn count_pascal_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
 // (removed duplicate count_toml_lines)
 
 /// Count lines for languages with hash-prefixed line comments only (e.g., YAML, TOML).
-fn count_hash_comment_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_hash_comment_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["#"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
@@ -1836,155 +4289,99 @@ fn count_hash_comment_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)
     Ok((stats, total_lines))
 }
 
-fn count_yaml_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    count_hash_comment_lines(file_path)
-}
-
-fn count_toml_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    count_hash_comment_lines(file_path)
-}
-
-fn count_makefile_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Make treats leading '#' as comment. We don’t parse recipe semantics; keep it simple.
-    count_hash_comment_lines(file_path)
+fn count_yaml_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    count_hash_comment_lines(lines)
 }
 
-fn count_dockerfile_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Dockerfile uses '#' for comments; everything else is code or blank.
-    count_hash_comment_lines(file_path)
+fn count_toml_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    count_hash_comment_lines(lines)
 }
 
-fn count_ini_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+/// R Markdown: lines inside ```` ```{r} ```` chunks (and the fences) are R code
+/// with `#` comments; non-blank prose outside chunks counts as comments.
+fn count_rmarkdown_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
+    let mut in_chunk = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
-        } else if trimmed.starts_with(';') || trimmed.starts_with('#') {
-            stats.comment_lines += 1;
-        } else {
+        } else if in_chunk {
+            if trimmed == "```" {
+                in_chunk = false;
+                stats.code_lines += 1;
+            } else if trimmed.starts_with('#') {
+                stats.comment_lines += 1;
+            } else {
+                stats.code_lines += 1;
+            }
+        } else if trimmed.starts_with("```{") {
+            in_chunk = true;
             stats.code_lines += 1;
+        } else {
+            stats.comment_lines += 1;
         }
     }
     Ok((stats, total_lines))
 }
 
-fn count_hcl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_elixir_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // `@doc`/`@moduledoc` heredocs are attribute values, so they count as code.
+    count_hash_comment_lines(lines)
+}
+
+/// Erlang: `%` line comments (`%%`, `%%%` by convention); no block comments.
+fn count_erlang_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
-    let mut in_block = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["%"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        let mut s = line.as_str();
-        let trimmed_line = s.trim();
-        if trimmed_line.is_empty() {
-            stats.blank_lines += 1;
+        if ignore.consume(&line, &mut stats) {
             continue;
         }
-        loop {
-            if in_block {
-                if let Some(end) = s.find("*/") {
-                    stats.comment_lines += 1;
-                    s = &s[end + 2..];
-                    in_block = false;
-                    let after_trimmed = s.trim_start();
-                    if after_trimmed.is_empty() {
-                        break;
-                    } else if after_trimmed.starts_with("##")
-                        || after_trimmed.starts_with("//")
-                        || after_trimmed.starts_with('#')
-                    {
-                        stats.comment_lines += 1;
-                        break;
-                    } else {
-                        s = after_trimmed;
-                        continue;
-                    }
-                } else {
-                    stats.comment_lines += 1;
-                    break;
-                }
-            } else {
-                let p_line1 = s.find("//");
-                let p_line2 = s.find('#');
-                let p_block = s.find("/*");
-                let mut next: Option<(&str, usize)> = None;
-                if let Some(i) = p_line1 {
-                    next = Some(("//", i));
-                }
-                if let Some(i) = p_line2 {
-                    next = match next {
-                        Some((k, j)) if j <= i => Some((k, j)),
-                        _ => Some(("#", i)),
-                    };
-                }
-                if let Some(i) = p_block {
-                    next = match next {
-                        Some((k, j)) if j <= i => Some((k, j)),
-                        _ => Some(("/*", i)),
-                    };
-                }
-                if let Some((token, i)) = next {
-                    if token == "//" || token == "#" {
-                        let before = &s[..i];
-                        if !before.trim().is_empty() {
-                            stats.code_lines += 1;
-                        }
-                        stats.comment_lines += 1;
-                        break;
-                    } else {
-                        debug_assert_eq!(token, "/*");
-                        let before = &s[..i];
-                        if !before.trim().is_empty() {
-                            stats.code_lines += 1;
-                        }
-                        stats.comment_lines += 1;
-                        s = &s[i + 2..];
-                        if let Some(end) = s.find("*/") {
-                            s = &s[end + 2..];
-                            let after_trimmed = s.trim_start();
-                            if after_trimmed.is_empty() {
-                                break;
-                            } else if after_trimmed.starts_with("##")
-                                || after_trimmed.starts_with("//")
-                                || after_trimmed.starts_with('#')
-                            {
-                                stats.comment_lines += 1;
-                                break;
-                            } else {
-                                s = after_trimmed;
-                                continue;
-                            }
-                        } else {
-                            in_block = true;
-                            break;
-                        }
-                    }
-                } else {
-                    if !s.trim().is_empty() {
-                        stats.code_lines += 1;
-                    }
-                    break;
-                }
-            }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+        } else if trimmed.starts_with('%') {
+            stats.comment_lines += 1;
+        } else {
+            stats.code_lines += 1;
         }
     }
     Ok((stats, total_lines))
 }
 
-fn count_rst_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Keep simple and in line with tokei: non-blank lines are code; no comments.
+/// GraphQL: `#` line comments; `"""` block descriptions document the
+/// schema and count as comments, including one-line `"""text"""` forms.
+fn count_graphql_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
+    let mut in_description = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["#"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        if line.trim().is_empty() {
+        if !in_description && ignore.consume(&line, &mut stats) {
+            continue;
+        }
+        let trimmed = line.trim();
+        if in_description {
+            stats.comment_lines += 1;
+            if trimmed.contains("\"\"\"") {
+                in_description = false;
+            }
+        } else if trimmed.is_empty() {
             stats.blank_lines += 1;
+        } else if trimmed.starts_with('#') {
+            stats.comment_lines += 1;
+        } else if let Some(rest) = trimmed.strip_prefix("\"\"\"") {
+            stats.comment_lines += 1;
+            in_description = !rest.contains("\"\"\"");
         } else {
             stats.code_lines += 1;
         }
@@ -1992,337 +4389,218 @@ fn count_rst_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
     Ok((stats, total_lines))
 }
 
-fn apply_velocity_tail(fragment: &str, stats: &mut LanguageStats) {
-    if fragment.is_empty() {
-        return;
-    }
-    if fragment.starts_with("##") {
-        stats.comment_lines += 1;
-    } else {
-        stats.code_lines += 1;
-    }
-}
-
-fn count_velocity_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Velocity: '##' line comments, '#* ... *#' block comments. Count code before/after markers.
+/// VHDL: `--` starts a comment anywhere outside a string or character
+/// literal (VHDL has no decrement operator), so trailing comments make the
+/// line mixed.
+fn count_vhdl_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
-    let mut in_block = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["--"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
             continue;
         }
-        if in_block {
-            stats.comment_lines += 1;
-            if let Some(pos) = trimmed.find("*#") {
-                in_block = false;
-                let after = &trimmed[(pos + 2)..];
-                let after_trimmed = after.trim_start();
-                apply_velocity_tail(after_trimmed, &mut stats);
-            }
-            continue;
-        }
-        if trimmed.starts_with("##") {
-            stats.comment_lines += 1;
-            continue;
-        }
-        if let Some(pos) = trimmed.find("#*") {
-            let before = &trimmed[..pos];
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
-            }
-            stats.comment_lines += 1;
-            if !trimmed[pos..].contains("*#") {
-                in_block = true;
-            } else if let Some(end) = trimmed[pos..].find("*#") {
-                let after = &trimmed[(pos + end + 2)..];
-                let after_trimmed = after.trim_start();
-                apply_velocity_tail(after_trimmed, &mut stats);
+        let chars: Vec<char> = trimmed.chars().collect();
+        let mut has_code = false;
+        let mut has_comment = false;
+        let mut in_string = false;
+        let mut idx = 0;
+        while idx < chars.len() {
+            let c = chars[idx];
+            if in_string {
+                // `""` inside a string is an escaped quote; toggling twice handles it.
+                in_string = c != '"';
+            } else if c == '-' && chars.get(idx + 1) == Some(&'-') {
+                has_comment = true;
+                break;
+            } else if c == '"' {
+                in_string = true;
+                has_code = true;
+            } else if c == '\'' && chars.get(idx + 2) == Some(&'\'') {
+                // Character literal such as '-'; a lone tick is an attribute.
+                has_code = true;
+                idx += 2;
+            } else if !c.is_whitespace() {
+                has_code = true;
             }
-            continue;
+            idx += 1;
         }
-        stats.code_lines += 1;
+        stats.record_line(has_code, has_comment);
     }
     Ok((stats, total_lines))
 }
 
-fn count_mustache_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Mustache: comments start with '{{!' and end at the next '}}' (may cross lines).
+/// Lua and MoonScript: `--` line comments outside strings. Lua also has
+/// `--[[ ]]` block comments whose brackets may carry an `=` level
+/// (`--[==[ ... ]==]`, closed only by the same level); `[[ ]]` long strings
+/// are code. MoonScript (`long_comments == false`) has line comments only.
+fn count_lua_lines(
+    lines: &mut LossyLineReader,
+    long_comments: bool,
+) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
-    let mut in_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["--"]);
+    // The open long bracket: whether it is a comment, and its level.
+    let mut open: Option<(bool, usize)> = None;
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
+        if open.is_none() && ignore.consume(&line, &mut stats) {
+            continue;
+        }
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
             continue;
         }
-        if in_comment {
-            stats.comment_lines += 1;
-            if let Some(pos) = trimmed.find("}}") {
-                // close
-                in_comment = false;
-                let after = &trimmed[(pos + 2)..];
-                if !after.trim().is_empty() {
-                    stats.code_lines += 1;
+        let chars: Vec<char> = trimmed.chars().collect();
+        let mut has_code = false;
+        let mut has_comment = false;
+        let mut idx = 0;
+        while idx < chars.len() {
+            if let Some((is_comment, level)) = open {
+                if is_comment {
+                    has_comment = true;
+                } else {
+                    has_code = true;
+                }
+                if chars[idx] == ']' && closes_long_bracket(&chars[idx..], level) {
+                    open = None;
+                    idx += level + 2;
+                } else {
+                    idx += 1;
                 }
+                continue;
             }
-            continue;
-        }
-        if let Some(pos) = trimmed.find("{{!") {
-            let before = &trimmed[..pos];
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
+            let c = chars[idx];
+            if c == '-' && chars.get(idx + 1) == Some(&'-') {
+                has_comment = true;
+                match long_bracket_level(&chars[idx + 2..]).filter(|_| long_comments) {
+                    Some(level) => {
+                        open = Some((true, level));
+                        idx += level + 4;
+                        continue;
+                    }
+                    None => break,
+                }
             }
-            stats.comment_lines += 1;
-            if !trimmed[pos..].contains("}}") {
-                in_comment = true;
-            } else {
-                let after = trimmed[pos..]
-                    .find("}}")
-                    .map(|end| &trimmed[(pos + end + 2)..])
-                    .unwrap_or("");
-                if !after.trim().is_empty() {
-                    stats.code_lines += 1;
+            if let Some(level) = long_bracket_level(&chars[idx..]) {
+                has_code = true;
+                open = Some((false, level));
+                idx += level + 2;
+                continue;
+            }
+            if c == '"' || c == '\'' {
+                has_code = true;
+                idx += 1;
+                while idx < chars.len() && chars[idx] != c {
+                    if chars[idx] == '\\' {
+                        idx += 1;
+                    }
+                    idx += 1;
                 }
+            } else if !c.is_whitespace() {
+                has_code = true;
             }
-            continue;
+            idx += 1;
         }
-        stats.code_lines += 1;
+        stats.record_line(has_code, has_comment);
     }
     Ok((stats, total_lines))
 }
 
-// --- New classic languages ---
-
-fn count_algol_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Approximate support for ALGOL 60/68 comment styles:
-    // - Lines beginning with 'COMMENT' (case-insensitive) treated as comment (until ';' on the same line).
-    // - Single-line forms like 'co ... co' and '# ... #' are treated as full-line comments if they start the line.
-    let mut stats = LanguageStats::default();
-    let mut in_comment_until_semicolon = false;
-    let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
-        let line = line_result?;
-        total_lines += 1;
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            stats.blank_lines += 1;
-            continue;
-        }
-        let lower = trimmed.to_lowercase();
-        if in_comment_until_semicolon {
-            stats.comment_lines += 1;
-            if lower.contains(';') {
-                in_comment_until_semicolon = false;
-            }
-            continue;
-        }
-        if lower.starts_with("comment") {
-            stats.comment_lines += 1;
-            if !lower.contains(';') {
-                in_comment_until_semicolon = true;
-            }
-            continue;
-        }
-        if lower.starts_with("co ") && lower.ends_with(" co") {
-            stats.comment_lines += 1;
-            continue;
-        }
-        if lower.starts_with('#') {
-            stats.comment_lines += 1;
-            continue;
-        }
-        stats.code_lines += 1;
+/// The level of a Lua opening long bracket (`[[` is 0, `[==[` is 2) at the
+/// start of `chars`.
+fn long_bracket_level(chars: &[char]) -> Option<usize> {
+    if chars.first() != Some(&'[') {
+        return None;
     }
-    Ok((stats, total_lines))
+    let level = chars[1..].iter().take_while(|&&c| c == '=').count();
+    (chars.get(level + 1) == Some(&'[')).then_some(level)
 }
 
-fn count_cobol_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // COBOL: fixed format comment indicator in column 7 ('*' or '/'),
-    // and free-format comment starting with '*>'. We treat lines accordingly.
-    let mut stats = LanguageStats::default();
-    let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
-        let line = line_result?;
-        total_lines += 1;
-        if line.trim().is_empty() {
-            stats.blank_lines += 1;
-            continue;
-        }
-        let trimmed = line.trim_start();
-        if trimmed.starts_with("*>") {
-            stats.comment_lines += 1;
-            continue;
-        }
-        // Column 7 indicator (index 6, 0-based) in the original line
-        let col7 = line.chars().nth(6);
-        if matches!(col7, Some('*') | Some('/')) {
-            stats.comment_lines += 1;
-            continue;
-        }
-        stats.code_lines += 1;
-    }
-    Ok((stats, total_lines))
+fn closes_long_bracket(chars: &[char], level: usize) -> bool {
+    chars.len() >= level + 2
+        && chars[1..=level].iter().all(|&c| c == '=')
+        && chars[level + 1] == ']'
 }
 
-fn count_fortran_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Fortran: fixed-form comment if first column is C/c/*/D/d; '!' creates inline comment in free-form.
+/// Makefile: `#` starts a make comment only on directive lines. Tab-indented
+/// recipe lines are handed to the shell, so a recipe `# ...` is code.
+fn count_makefile_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    let mut ignore = IgnoreRegion::new(&["#"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        if line.trim().is_empty() {
-            stats.blank_lines += 1;
-            continue;
-        }
-        let first = line.chars().next().unwrap_or(' ');
-        let trimmed = line.trim_start();
-        if matches!(first, 'C' | 'c' | '*' | 'D' | 'd') {
-            stats.comment_lines += 1;
-            continue;
-        }
-        if let Some(pos) = trimmed.find('!') {
-            // code before '!' counts as code; rest as comment
-            let before = &trimmed[..pos];
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
-            }
-            stats.comment_lines += 1;
+        if ignore.consume(&line, &mut stats) {
             continue;
         }
-        stats.code_lines += 1;
-    }
-    Ok((stats, total_lines))
-}
-
-fn count_asm_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Assembly (NASM/MASM ';' comments, GAS '#' comments). Full-line only.
-    let mut stats = LanguageStats::default();
-    let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
-        let line = line_result?;
-        total_lines += 1;
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
-            continue;
-        }
-        if trimmed.starts_with(';') || trimmed.starts_with('#') || trimmed.starts_with("//") {
+        } else if line.starts_with('\t') || !trimmed.starts_with('#') {
+            stats.code_lines += 1;
+        } else {
             stats.comment_lines += 1;
-            continue;
         }
-        stats.code_lines += 1;
     }
     Ok((stats, total_lines))
 }
 
-fn count_dcl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // OpenVMS DCL: comments start with '!' or '$!' on a line. Commands typically start with '$'.
-    let mut stats = LanguageStats::default();
-    let mut total_lines = 0;
-    let mut is_dcl: Option<bool> = None;
-
-    for line_result in read_file_lines_lossy(file_path)? {
-        let line = line_result?;
-        total_lines += 1;
-        if is_dcl.is_none() {
-            let trimmed_start = line.trim_start();
-            if !trimmed_start.is_empty() {
-                is_dcl = Some(trimmed_start.starts_with('$') || trimmed_start.starts_with('!'));
-            }
-        }
-        if matches!(is_dcl, Some(false)) {
-            continue;
-        }
+fn count_dockerfile_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Dockerfile uses '#' for comments; everything else is code or blank.
+    count_hash_comment_lines(lines)
+}
 
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            stats.blank_lines += 1;
-            continue;
-        }
-        if trimmed.starts_with("$!") || trimmed.starts_with('!') {
-            stats.comment_lines += 1;
-            continue;
-        }
-        stats.code_lines += 1;
-    }
+fn count_ini_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    count_key_value_lines(lines, &[';', '#'])
+}
 
-    if matches!(is_dcl, Some(false)) {
-        Ok((LanguageStats::default(), total_lines))
-    } else {
-        Ok((stats, total_lines))
-    }
+/// `.env` files: only `#` starts a comment, so `;`-lines are entries.
+fn count_dotenv_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    count_key_value_lines(lines, &['#'])
 }
 
-fn count_iplan_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // PSS/E IPLAN: supports C-style block comments /* ... */ and '!' full-line comments.
+/// `key = value` config files whose comments are whole lines starting with
+/// one of `markers`. A marker later in the line is part of the value, as
+/// with Python's `configparser` and most `.env` loaders: `color = #fff`,
+/// `url=http://host/#top` and `name = x ; y` are all plain code lines.
+fn count_key_value_lines(
+    lines: &mut LossyLineReader,
+    markers: &[char],
+) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
-    let mut in_block = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let trimmed = line.trim();
         if trimmed.is_empty() {
             stats.blank_lines += 1;
-            continue;
-        }
-        if in_block {
-            stats.comment_lines += 1;
-            if let Some(pos) = trimmed.find("*/") {
-                in_block = false;
-                let after = &trimmed[(pos + 2)..];
-                if !after.trim().is_empty() && !after.trim_start().starts_with('!') {
-                    stats.code_lines += 1;
-                }
-            }
-            continue;
-        }
-        if trimmed.starts_with('!') {
-            stats.comment_lines += 1;
-            continue;
-        }
-        if let Some(pos) = trimmed.find("/*") {
-            let before = &trimmed[..pos];
-            if !before.trim().is_empty() {
-                stats.code_lines += 1;
-            }
+        } else if trimmed.starts_with(markers) {
             stats.comment_lines += 1;
-            let after = &trimmed[(pos + 2)..];
-            let close = after.find("*/");
-            if close.is_none() {
-                in_block = true;
-            }
-            let trailing = close.map(|end| &after[(end + 2)..]).unwrap_or("");
-            if !trailing.trim().is_empty() && !trailing.trim_start().starts_with('!') {
-                stats.code_lines += 1;
-            }
-            continue;
+        } else {
+            stats.code_lines += 1;
         }
-        stats.code_lines += 1;
     }
     Ok((stats, total_lines))
 }
 
-fn count_cmake_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // CMake uses '#' for line comments; no block comment syntax.
-    count_hash_comment_lines(file_path)
-}
-
-fn count_powershell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // PowerShell supports '#' line comments and <# ... #> block comments.
+fn count_hcl_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_block = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
         let mut s = line.as_str();
@@ -2333,13 +4611,21 @@ fn count_powershell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)>
         }
         loop {
             if in_block {
-                if let Some(end) = s.find("#>") {
+                if let Some(end) = s.find("*/") {
                     stats.comment_lines += 1;
                     s = &s[end + 2..];
                     in_block = false;
-                    if s.trim().is_empty() {
+                    let after_trimmed = s.trim_start();
+                    if after_trimmed.is_empty() {
+                        break;
+                    } else if after_trimmed.starts_with("##")
+                        || after_trimmed.starts_with("//")
+                        || after_trimmed.starts_with('#')
+                    {
+                        stats.comment_lines += 1;
                         break;
                     } else {
+                        s = after_trimmed;
                         continue;
                     }
                 } else {
@@ -2347,35 +4633,54 @@ fn count_powershell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)>
                     break;
                 }
             } else {
-                let p_line = find_powershell_line_comment(s);
-                let p_block = s.find("<#");
-                match (p_line, p_block) {
-                    (None, None) => {
-                        if !s.trim().is_empty() {
-                            stats.code_lines += 1;
-                        }
-                        break;
-                    }
-                    (Some(pl), None) => {
-                        let before = &s[..pl];
+                let p_line1 = s.find("//");
+                let p_line2 = s.find('#');
+                let p_block = s.find("/*");
+                let mut next: Option<(&str, usize)> = None;
+                if let Some(i) = p_line1 {
+                    next = Some(("//", i));
+                }
+                if let Some(i) = p_line2 {
+                    next = match next {
+                        Some((k, j)) if j <= i => Some((k, j)),
+                        _ => Some(("#", i)),
+                    };
+                }
+                if let Some(i) = p_block {
+                    next = match next {
+                        Some((k, j)) if j <= i => Some((k, j)),
+                        _ => Some(("/*", i)),
+                    };
+                }
+                if let Some((token, i)) = next {
+                    if token == "//" || token == "#" {
+                        let before = &s[..i];
                         if !before.trim().is_empty() {
                             stats.code_lines += 1;
                         }
                         stats.comment_lines += 1;
                         break;
-                    }
-                    (None, Some(pb)) => {
-                        let before = &s[..pb];
+                    } else {
+                        debug_assert_eq!(token, "/*");
+                        let before = &s[..i];
                         if !before.trim().is_empty() {
                             stats.code_lines += 1;
                         }
                         stats.comment_lines += 1;
-                        s = &s[pb + 2..];
-                        if let Some(end) = s.find("#>") {
+                        s = &s[i + 2..];
+                        if let Some(end) = s.find("*/") {
                             s = &s[end + 2..];
-                            if s.trim().is_empty() {
+                            let after_trimmed = s.trim_start();
+                            if after_trimmed.is_empty() {
+                                break;
+                            } else if after_trimmed.starts_with("##")
+                                || after_trimmed.starts_with("//")
+                                || after_trimmed.starts_with('#')
+                            {
+                                stats.comment_lines += 1;
                                 break;
                             } else {
+                                s = after_trimmed;
                                 continue;
                             }
                         } else {
@@ -2383,34 +4688,11 @@ fn count_powershell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)>
                             break;
                         }
                     }
-                    (Some(pl), Some(pb)) => {
-                        if pl < pb {
-                            let before = &s[..pl];
-                            if !before.trim().is_empty() {
-                                stats.code_lines += 1;
-                            }
-                            stats.comment_lines += 1;
-                            break;
-                        } else {
-                            let before = &s[..pb];
-                            if !before.trim().is_empty() {
-                                stats.code_lines += 1;
-                            }
-                            stats.comment_lines += 1;
-                            s = &s[pb + 2..];
-                            if let Some(end) = s.find("#>") {
-                                s = &s[end + 2..];
-                                if s.trim().is_empty() {
-                                    break;
-                                } else {
-                                    continue;
-                                }
-                            } else {
-                                in_block = true;
-                                break;
-                            }
-                        }
+                } else {
+                    if !s.trim().is_empty() {
+                        stats.code_lines += 1;
                     }
+                    break;
                 }
             }
         }
@@ -2418,21 +4700,15 @@ fn count_powershell_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)>
     Ok((stats, total_lines))
 }
 
-fn count_batch_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // Batch files treat lines starting with REM (case-insensitive) or :: as comments.
+fn count_rst_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Keep simple and in line with tokei: non-blank lines are code; no comments.
     let mut stats = LanguageStats::default();
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
+        if line.trim().is_empty() {
             stats.blank_lines += 1;
-            continue;
-        }
-        let upper = trimmed.to_uppercase();
-        if upper.starts_with("REM ") || upper == "REM" || trimmed.starts_with("::") {
-            stats.comment_lines += 1;
         } else {
             stats.code_lines += 1;
         }
@@ -2440,479 +4716,3118 @@ fn count_batch_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
     Ok((stats, total_lines))
 }
 
-fn count_tcl_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
-    // TCL: '#' starts a comment; shebang on first line counts as code like shell.
-    let mut stats = LanguageStats::default();
-    let mut line_no = 0u64;
-    let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+/// Fence info strings that differ from the file extension they stand for.
+const FENCE_ALIASES: &[(&str, &str)] = &[
+    ("bash", "sh"),
+    ("c++", "cpp"),
+    ("csharp", "cs"),
+    ("golang", "go"),
+    ("javascript", "js"),
+    ("perl", "pl"),
+    ("python", "py"),
+    ("ruby", "rb"),
+    ("rust", "rs"),
+    ("shell", "sh"),
+    ("typescript", "ts"),
+    ("zsh", "sh"),
+];
+
+/// Virtual file name for a fence info string, if it names a known language.
+fn fence_file_name(info: &str, args: &Args) -> Option<String> {
+    let tag = info
+        .trim_start_matches(['{', '.'])
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '}')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    if tag.is_empty() {
+        return None;
+    }
+    let ext = FENCE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == tag)
+        .map_or(tag.as_str(), |(_, ext)| *ext);
+    [format!("fence.{}", ext), tag]
+        .into_iter()
+        .find(|name| language_for_file(name, args).is_some_and(|lang| lang != "Markdown"))
+}
+
+/// Markdown with `--markdown-fences`: prose counts as Markdown (non-blank
+/// lines are code, like ReStructuredText), while fenced blocks whose info
+/// string names a known language are counted by that language's counter.
+/// A leading `---` (YAML) or `+++` (TOML) front-matter block is counted as
+/// that language; its delimiters are Markdown code lines like fence markers.
+/// Returns one entry per language present in the file.
+fn count_markdown_with_fences(
+    lines: &mut LossyLineReader,
+    args: &Args,
+) -> io::Result<Vec<(String, RoleSplit)>> {
+    struct OpenFence {
+        marker: char,
+        width: usize,
+        file_name: Option<String>,
+        contents: Vec<u8>,
+        /// Line index of the first line inside the fence.
+        first_line: usize,
+    }
+
+    // Per fence language: summed stats and physical line count.
+    type Embedded = Vec<(String, LanguageStats, u64)>;
+
+    let mut prose = LanguageStats::default();
+    let mut prose_lines = 0u64;
+    let mut embedded: Embedded = Vec::new();
+    let mut fence: Option<OpenFence> = None;
+    let mut front_matter: Option<OpenFence> = None;
+    let mut first_line = true;
+
+    let flush =
+        |open: OpenFence, embedded: &mut Embedded, lines: &mut LossyLineReader| -> io::Result<()> {
+            let Some(name) = open.file_name else {
+                return Ok(());
+            };
+            let language = language_for_file(&name, args).unwrap_or("").to_string();
+            let split = count_embedded_source(&name, open.contents, args, lines, open.first_line)?;
+            let index = match embedded.iter().position(|(lang, _, _)| *lang == language) {
+                Some(index) => index,
+                None => {
+                    embedded.push((language, LanguageStats::default(), 0));
+                    embedded.len() - 1
+                }
+            };
+            let (_, stats, total_lines) = &mut embedded[index];
+            for (_, bucket) in split.iter() {
+                stats.add_assign(&bucket.stats);
+            }
+            *total_lines += split.total_lines();
+            Ok(())
+        };
+
+    while let Some(line_result) = lines.next_tallied(&prose) {
         let line = line_result?;
-        total_lines += 1;
-        line_no += 1;
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            stats.blank_lines += 1;
+        let at_start = std::mem::take(&mut first_line);
+        if let Some(open) = front_matter.as_mut() {
+            let closing = line.trim_end();
+            if closing.len() == open.width && closing.chars().all(|c| c == open.marker) {
+                prose_lines += 1;
+                prose.code_lines += 1;
+                if let Some(open) = front_matter.take() {
+                    flush(open, &mut embedded, lines)?;
+                }
+            } else {
+                open.contents.extend_from_slice(line.as_bytes());
+                open.contents.push(b'\n');
+            }
             continue;
         }
-        if trimmed.starts_with('#') {
-            if line_no == 1 && trimmed.starts_with("#!") {
-                stats.code_lines += 1;
+        if at_start {
+            let kind = match line.trim_end() {
+                "---" => Some(('-', "front-matter.yaml")),
+                "+++" => Some(('+', "front-matter.toml")),
+                _ => None,
+            };
+            if let Some((marker, name)) = kind {
+                prose_lines += 1;
+                prose.code_lines += 1;
+                front_matter = Some(OpenFence {
+                    marker,
+                    width: 3,
+                    file_name: Some(name.to_string()),
+                    contents: Vec::new(),
+                    first_line: lines.recorded_lines(),
+                });
+                continue;
+            }
+        }
+        let trimmed = line.trim_start();
+        if let Some(open) = fence.as_mut() {
+            let run = trimmed.chars().take_while(|&c| c == open.marker).count();
+            if run >= open.width && trimmed[run..].trim().is_empty() {
+                prose_lines += 1;
+                prose.code_lines += 1;
+                if let Some(open) = fence.take() {
+                    flush(open, &mut embedded, lines)?;
+                }
+            } else if open.file_name.is_some() {
+                open.contents.extend_from_slice(line.as_bytes());
+                open.contents.push(b'\n');
             } else {
-                stats.comment_lines += 1;
+                prose_lines += 1;
+                if line.trim().is_empty() {
+                    prose.blank_lines += 1;
+                } else {
+                    prose.code_lines += 1;
+                }
             }
             continue;
         }
+        prose_lines += 1;
+        let marker = trimmed.chars().next().unwrap_or(' ');
+        let width = trimmed.chars().take_while(|&c| c == marker).count();
+        if (marker == '`' || marker == '~') && width >= 3 {
+            prose.code_lines += 1;
+            fence = Some(OpenFence {
+                marker,
+                width,
+                file_name: fence_file_name(&trimmed[width..], args),
+                contents: Vec::new(),
+                first_line: lines.recorded_lines(),
+            });
+        } else if line.trim().is_empty() {
+            prose.blank_lines += 1;
+        } else {
+            prose.code_lines += 1;
+        }
+    }
+    // An unterminated fence (or front matter) runs to the end of the file.
+    if let Some(open) = fence.take().or(front_matter.take()) {
+        flush(open, &mut embedded, lines)?;
+    }
+
+    let mut splits = vec![(
+        "Markdown".to_string(),
+        RoleSplit::single(CodeRole::Mainline, prose, prose_lines),
+    )];
+    splits.extend(embedded.into_iter().map(|(language, stats, total_lines)| {
+        (
+            language,
+            RoleSplit::single(CodeRole::Mainline, stats, total_lines),
+        )
+    }));
+    Ok(splits)
+}
+
+fn apply_velocity_tail(fragment: &str, stats: &mut LanguageStats) {
+    if fragment.is_empty() {
+        return;
+    }
+    if fragment.starts_with("##") {
+        stats.comment_lines += 1;
+    } else {
         stats.code_lines += 1;
     }
-    Ok((stats, total_lines))
 }
 
-/// JSON has no comments per spec; count non-blank as code.
-fn count_json_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+fn count_velocity_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Velocity: '##' line comments, '#* ... *#' block comments. Count code before/after markers.
     let mut stats = LanguageStats::default();
+    let mut in_block = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        if line.trim().is_empty() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
             stats.blank_lines += 1;
-        } else {
-            stats.code_lines += 1;
+            continue;
+        }
+        if in_block {
+            stats.comment_lines += 1;
+            if let Some(pos) = trimmed.find("*#") {
+                in_block = false;
+                let after = &trimmed[(pos + 2)..];
+                let after_trimmed = after.trim_start();
+                apply_velocity_tail(after_trimmed, &mut stats);
+            }
+            continue;
+        }
+        if trimmed.starts_with("##") {
+            stats.comment_lines += 1;
+            continue;
+        }
+        if let Some(pos) = trimmed.find("#*") {
+            let before = &trimmed[..pos];
+            if !before.trim().is_empty() {
+                stats.code_lines += 1;
+            }
+            stats.comment_lines += 1;
+            if !trimmed[pos..].contains("*#") {
+                in_block = true;
+            } else if let Some(end) = trimmed[pos..].find("*#") {
+                let after = &trimmed[(pos + end + 2)..];
+                let after_trimmed = after.trim_start();
+                apply_velocity_tail(after_trimmed, &mut stats);
+            }
+            continue;
         }
+        stats.code_lines += 1;
     }
     Ok((stats, total_lines))
 }
 
-/// Shared XML/HTML style comment handling for <!-- ... -->. Everything else non-blank is code.
-fn count_xml_like_lines(file_path: &Path) -> io::Result<(LanguageStats, u64)> {
+/// Templates whose only comments are `open ... close` tags, which may span
+/// lines: Jinja2 `{# #}` and ERB `<%# %>`. Everything else, including the
+/// embedded statements and expressions, is code.
+fn count_template_comment_lines(
+    lines: &mut LossyLineReader,
+    open: &str,
+    close: &str,
+) -> io::Result<(LanguageStats, u64)> {
     let mut stats = LanguageStats::default();
     let mut in_comment = false;
     let mut total_lines = 0;
-    for line_result in read_file_lines_lossy(file_path)? {
+    while let Some(line_result) = lines.next_tallied(&stats) {
         let line = line_result?;
         total_lines += 1;
-        let mut s = line.as_str();
-        let trimmed_line = s.trim();
-        if trimmed_line.is_empty() {
+        if line.trim().is_empty() {
             stats.blank_lines += 1;
             continue;
         }
+        let mut rest = line.as_str();
+        let mut has_code = false;
+        let mut has_comment = false;
         loop {
             if in_comment {
-                if let Some(end) = s.find("-->") {
-                    stats.comment_lines += 1;
-                    s = &s[end + 3..];
-                    in_comment = false;
-                    if s.trim().is_empty() {
-                        break;
-                    } else {
-                        continue;
-                    }
-                } else {
-                    stats.comment_lines += 1;
-                    break;
-                }
-            } else if let Some(pos) = s.find("<!--") {
-                let before = &s[..pos];
-                if !before.trim().is_empty() {
-                    stats.code_lines += 1;
-                }
-                stats.comment_lines += 1;
-                s = &s[pos + 4..];
-                if let Some(end) = s.find("-->") {
-                    s = &s[end + 3..];
-                    if s.trim().is_empty() {
-                        break;
-                    } else {
-                        continue;
+                has_comment = true;
+                match rest.find(close) {
+                    Some(end) => {
+                        in_comment = false;
+                        rest = &rest[end + close.len()..];
                     }
-                } else {
-                    in_comment = true;
-                    break;
+                    None => break,
                 }
+            } else if let Some(start) = rest.find(open) {
+                has_code |= !rest[..start].trim().is_empty();
+                in_comment = true;
+                rest = &rest[start + open.len()..];
             } else {
-                if !s.trim().is_empty() {
-                    stats.code_lines += 1;
-                }
+                has_code |= !rest.trim().is_empty();
                 break;
             }
         }
+        stats.record_line(has_code, has_comment);
     }
     Ok((stats, total_lines))
 }
 
-/// Recursively scan directories and collect statistics.
-/// Added error tracking and directory depth limiting to prevent stack overflow.
-fn should_process_file(filespec: Option<&Pattern>, root_path: &Path, file_path: &Path) -> bool {
-    filespec
-        .map(|pattern| filespec_matches(pattern, root_path, file_path))
-        .unwrap_or(true)
-}
-
-fn filespec_matches(pattern: &Pattern, root_path: &Path, file_path: &Path) -> bool {
-    if file_path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .map(|name| pattern.matches(name))
-        .unwrap_or(false)
-    {
-        return true;
-    }
-
-    let relative = match file_path.strip_prefix(root_path) {
-        Ok(rel) => rel,
-        Err(_) => return false,
-    };
-
-    let rel_str = match relative.to_str() {
-        Some(s) => s.replace('\\', "/"),
+fn count_mustache_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Mustache: comments start with '{{!' and end at the next '}}' (may cross lines).
+    let mut stats = LanguageStats::default();
+    let mut in_comment = false;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        if in_comment {
+            stats.comment_lines += 1;
+            if let Some(pos) = trimmed.find("}}") {
+                // close
+                in_comment = false;
+                let after = &trimmed[(pos + 2)..];
+                if !after.trim().is_empty() {
+                    stats.code_lines += 1;
+                }
+            }
+            continue;
+        }
+        if let Some(pos) = trimmed.find("{{!") {
+            let before = &trimmed[..pos];
+            if !before.trim().is_empty() {
+                stats.code_lines += 1;
+            }
+            stats.comment_lines += 1;
+            if !trimmed[pos..].contains("}}") {
+                in_comment = true;
+            } else {
+                let after = trimmed[pos..]
+                    .find("}}")
+                    .map(|end| &trimmed[(pos + end + 2)..])
+                    .unwrap_or("");
+                if !after.trim().is_empty() {
+                    stats.code_lines += 1;
+                }
+            }
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+// --- New classic languages ---
+
+fn count_algol_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Approximate support for ALGOL 60/68 comment styles:
+    // - Lines beginning with 'COMMENT' (case-insensitive) treated as comment (until ';' on the same line).
+    // - Single-line forms like 'co ... co' and '# ... #' are treated as full-line comments if they start the line.
+    let mut stats = LanguageStats::default();
+    let mut in_comment_until_semicolon = false;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let lower = trimmed.to_lowercase();
+        if in_comment_until_semicolon {
+            stats.comment_lines += 1;
+            if lower.contains(';') {
+                in_comment_until_semicolon = false;
+            }
+            continue;
+        }
+        if lower.starts_with("comment") {
+            stats.comment_lines += 1;
+            if !lower.contains(';') {
+                in_comment_until_semicolon = true;
+            }
+            continue;
+        }
+        if lower.starts_with("co ") && lower.ends_with(" co") {
+            stats.comment_lines += 1;
+            continue;
+        }
+        if lower.starts_with('#') {
+            stats.comment_lines += 1;
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_cobol_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // COBOL: fixed format comment indicator in column 7 ('*' or '/'),
+    // and free-format comment starting with '*>'. We treat lines accordingly.
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if line.trim().is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("*>") {
+            stats.comment_lines += 1;
+            continue;
+        }
+        // Column 7 indicator (index 6, 0-based) in the original line
+        let col7 = line.chars().nth(6);
+        if matches!(col7, Some('*') | Some('/')) {
+            stats.comment_lines += 1;
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_fortran_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Fortran: fixed-form comment if first column is C/c/*/D/d; '!' creates inline comment in free-form.
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if line.trim().is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let first = line.chars().next().unwrap_or(' ');
+        let trimmed = line.trim_start();
+        if matches!(first, 'C' | 'c' | '*' | 'D' | 'd') {
+            stats.comment_lines += 1;
+            continue;
+        }
+        if let Some(pos) = trimmed.find('!') {
+            // code before '!' counts as code; rest as comment
+            let before = &trimmed[..pos];
+            if !before.trim().is_empty() {
+                stats.code_lines += 1;
+            }
+            stats.comment_lines += 1;
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_asm_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Assembly (NASM/MASM ';' comments, GAS '#' and '//' comments), full-line or trailing.
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        match find_asm_line_comment(trimmed) {
+            Some(idx) => stats.record_line(!trimmed[..idx].trim_end().is_empty(), true),
+            None => stats.record_line(true, false),
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_dcl_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // OpenVMS DCL: comments start with '!' or '$!' on a line. Commands typically start with '$'.
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    let mut is_dcl: Option<bool> = None;
+
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if is_dcl.is_none() {
+            let trimmed_start = line.trim_start();
+            if !trimmed_start.is_empty() {
+                is_dcl = Some(trimmed_start.starts_with('$') || trimmed_start.starts_with('!'));
+            }
+        }
+        if matches!(is_dcl, Some(false)) {
+            continue;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        if trimmed.starts_with("$!") || trimmed.starts_with('!') {
+            stats.comment_lines += 1;
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+
+    if matches!(is_dcl, Some(false)) {
+        Ok((LanguageStats::default(), total_lines))
+    } else {
+        Ok((stats, total_lines))
+    }
+}
+
+fn count_iplan_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // PSS/E IPLAN: supports C-style block comments /* ... */ and '!' full-line comments.
+    let mut stats = LanguageStats::default();
+    let mut in_block = false;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        if in_block {
+            stats.comment_lines += 1;
+            if let Some(pos) = trimmed.find("*/") {
+                in_block = false;
+                let after = &trimmed[(pos + 2)..];
+                if !after.trim().is_empty() && !after.trim_start().starts_with('!') {
+                    stats.code_lines += 1;
+                }
+            }
+            continue;
+        }
+        if trimmed.starts_with('!') {
+            stats.comment_lines += 1;
+            continue;
+        }
+        if let Some(pos) = trimmed.find("/*") {
+            let before = &trimmed[..pos];
+            if !before.trim().is_empty() {
+                stats.code_lines += 1;
+            }
+            stats.comment_lines += 1;
+            let after = &trimmed[(pos + 2)..];
+            let close = after.find("*/");
+            if close.is_none() {
+                in_block = true;
+            }
+            let trailing = close.map(|end| &after[(end + 2)..]).unwrap_or("");
+            if !trailing.trim().is_empty() && !trailing.trim_start().starts_with('!') {
+                stats.code_lines += 1;
+            }
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_cmake_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // CMake uses '#' for line comments; no block comment syntax.
+    count_hash_comment_lines(lines)
+}
+
+fn count_powershell_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // PowerShell supports '#' line comments and <# ... #> block comments.
+    let mut stats = LanguageStats::default();
+    let mut in_block = false;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let mut s = line.as_str();
+        let trimmed_line = s.trim();
+        if trimmed_line.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        if !in_block && is_region_marker(trimmed_line) {
+            stats.comment_lines += 1;
+            stats.line_comment_lines += 1;
+            stats.region_marker_lines += 1;
+            continue;
+        }
+        loop {
+            if in_block {
+                if let Some(end) = s.find("#>") {
+                    stats.comment_lines += 1;
+                    stats.block_comment_lines += 1;
+                    s = &s[end + 2..];
+                    in_block = false;
+                    if s.trim().is_empty() {
+                        break;
+                    } else {
+                        continue;
+                    }
+                } else {
+                    stats.comment_lines += 1;
+                    stats.block_comment_lines += 1;
+                    break;
+                }
+            } else {
+                let p_line = find_powershell_line_comment(s);
+                let p_block = s.find("<#");
+                match (p_line, p_block) {
+                    (None, None) => {
+                        if !s.trim().is_empty() {
+                            stats.code_lines += 1;
+                        }
+                        break;
+                    }
+                    (Some(pl), None) => {
+                        let before = &s[..pl];
+                        if !before.trim().is_empty() {
+                            stats.code_lines += 1;
+                        }
+                        stats.comment_lines += 1;
+                        stats.line_comment_lines += 1;
+                        break;
+                    }
+                    (None, Some(pb)) => {
+                        let before = &s[..pb];
+                        if !before.trim().is_empty() {
+                            stats.code_lines += 1;
+                        }
+                        stats.comment_lines += 1;
+                        stats.block_comment_lines += 1;
+                        s = &s[pb + 2..];
+                        if let Some(end) = s.find("#>") {
+                            s = &s[end + 2..];
+                            if s.trim().is_empty() {
+                                break;
+                            } else {
+                                continue;
+                            }
+                        } else {
+                            in_block = true;
+                            break;
+                        }
+                    }
+                    (Some(pl), Some(pb)) => {
+                        if pl < pb {
+                            let before = &s[..pl];
+                            if !before.trim().is_empty() {
+                                stats.code_lines += 1;
+                            }
+                            stats.comment_lines += 1;
+                            stats.line_comment_lines += 1;
+                            break;
+                        } else {
+                            let before = &s[..pb];
+                            if !before.trim().is_empty() {
+                                stats.code_lines += 1;
+                            }
+                            stats.comment_lines += 1;
+                            stats.block_comment_lines += 1;
+                            s = &s[pb + 2..];
+                            if let Some(end) = s.find("#>") {
+                                s = &s[end + 2..];
+                                if s.trim().is_empty() {
+                                    break;
+                                } else {
+                                    continue;
+                                }
+                            } else {
+                                in_block = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_batch_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // Batch files treat lines starting with REM (case-insensitive) or :: as comments.
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let upper = trimmed.to_uppercase();
+        if upper.starts_with("REM ") || upper == "REM" || trimmed.starts_with("::") {
+            stats.comment_lines += 1;
+        } else {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+fn count_tcl_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    // TCL: '#' starts a comment; shebang on first line counts as code like shell.
+    let mut stats = LanguageStats::default();
+    let mut line_no = 0u64;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        line_no += 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            if line_no == 1 && trimmed.starts_with("#!") {
+                stats.code_lines += 1;
+            } else {
+                stats.comment_lines += 1;
+            }
+            continue;
+        }
+        stats.code_lines += 1;
+    }
+    Ok((stats, total_lines))
+}
+
+/// JSON has no comments per spec; count non-blank as code.
+fn count_json_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if line.trim().is_empty() {
+            stats.blank_lines += 1;
+        } else {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+/// JSONC / JSON5: `//` and `/* */` comments, skipping comment markers inside
+/// string literals (double-quoted, plus JSON5's single-quoted strings).
+fn count_jsonc_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut in_block_comment = false;
+    let mut total_lines = 0;
+    let mut ignore = IgnoreRegion::new(&["//", "/*"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let mut has_comment = in_block_comment;
+        let mut has_code = false;
+        let mut string_quote: Option<char> = None;
+        let mut chars = trimmed.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    in_block_comment = false;
+                }
+                continue;
+            }
+            if let Some(quote) = string_quote {
+                if c == '\\' {
+                    chars.next();
+                } else if c == quote {
+                    string_quote = None;
+                }
+                continue;
+            }
+            match c {
+                '"' | '\'' => {
+                    string_quote = Some(c);
+                    has_code = true;
+                }
+                '/' if chars.peek() == Some(&'/') => {
+                    has_comment = true;
+                    break;
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    in_block_comment = true;
+                    has_comment = true;
+                }
+                c if !c.is_whitespace() => has_code = true,
+                _ => {}
+            }
+        }
+        if has_comment {
+            stats.comment_lines += 1;
+        }
+        if has_code {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+/// SQL and PL/SQL: `--` line comments and `/* */` blocks. Quoted literals
+/// (`'...'` with `''` escapes, and `"..."` identifiers) may span lines and
+/// hide comment markers.
+fn count_sql_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut in_block_comment = false;
+    let mut string_quote: Option<char> = None;
+    let mut total_lines = 0;
+    let mut ignore = IgnoreRegion::new(&["--", "/*"]);
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if string_quote.is_none() && ignore.consume(&line, &mut stats) {
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let mut has_comment = in_block_comment;
+        let mut has_code = string_quote.is_some();
+        let mut chars = trimmed.chars().peekable();
+        while let Some(c) = chars.next() {
+            if in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    in_block_comment = false;
+                }
+                continue;
+            }
+            if let Some(quote) = string_quote {
+                // A doubled quote is an escape; toggling twice handles it.
+                if c == quote {
+                    string_quote = None;
+                }
+                continue;
+            }
+            match c {
+                '\'' | '"' => {
+                    string_quote = Some(c);
+                    has_code = true;
+                }
+                '-' if chars.peek() == Some(&'-') => {
+                    has_comment = true;
+                    break;
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    in_block_comment = true;
+                    has_comment = true;
+                }
+                c if !c.is_whitespace() => has_code = true,
+                _ => {}
+            }
+        }
+        if has_comment {
+            stats.comment_lines += 1;
+        }
+        if has_code {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+/// Counter for languages defined in a `--config` file: configurable line
+/// comment tokens and block delimiters, optionally nesting.
+fn count_custom_lines(
+    lines: &mut LossyLineReader,
+    language: &CustomLanguage,
+) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    // Index of the open block delimiter pair and its nesting depth.
+    let mut open_block: Option<(usize, usize)> = None;
+    let mut total_lines = 0;
+    let comment_tokens: Vec<&str> = language
+        .line_comments
+        .iter()
+        .chain(language.block_comments.iter().map(|(open, _)| open))
+        .map(String::as_str)
+        .collect();
+    let mut ignore = IgnoreRegion::new(&comment_tokens);
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        if ignore.consume(&line, &mut stats) {
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        let mut has_comment = open_block.is_some();
+        let mut has_code = false;
+        let mut rest = trimmed;
+        while let Some(c) = rest.chars().next() {
+            if let Some((idx, depth)) = open_block {
+                let (open, close) = &language.block_comments[idx];
+                if rest.starts_with(close.as_str()) {
+                    rest = &rest[close.len()..];
+                    open_block = (depth > 1).then(|| (idx, depth - 1));
+                } else if language.nested && rest.starts_with(open.as_str()) {
+                    rest = &rest[open.len()..];
+                    open_block = Some((idx, depth + 1));
+                } else {
+                    rest = &rest[c.len_utf8()..];
+                }
+                continue;
+            }
+            if language
+                .line_comments
+                .iter()
+                .any(|token| rest.starts_with(token.as_str()))
+            {
+                has_comment = true;
+                break;
+            }
+            if let Some((idx, (open, _))) = language
+                .block_comments
+                .iter()
+                .enumerate()
+                .find(|(_, (open, _))| rest.starts_with(open.as_str()))
+            {
+                rest = &rest[open.len()..];
+                open_block = Some((idx, 1));
+                has_comment = true;
+                continue;
+            }
+            if !c.is_whitespace() {
+                has_code = true;
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+        if has_comment {
+            stats.comment_lines += 1;
+        }
+        if has_code {
+            stats.code_lines += 1;
+        }
+    }
+    Ok((stats, total_lines))
+}
+
+/// Shared XML/HTML style comment handling for <!-- ... -->. Everything else non-blank is code.
+fn count_xml_like_lines(lines: &mut LossyLineReader) -> io::Result<(LanguageStats, u64)> {
+    let mut stats = LanguageStats::default();
+    let mut in_comment = false;
+    let mut total_lines = 0;
+    while let Some(line_result) = lines.next_tallied(&stats) {
+        let line = line_result?;
+        total_lines += 1;
+        let mut s = line.as_str();
+        let trimmed_line = s.trim();
+        if trimmed_line.is_empty() {
+            stats.blank_lines += 1;
+            continue;
+        }
+        loop {
+            if in_comment {
+                if let Some(end) = s.find("-->") {
+                    stats.comment_lines += 1;
+                    s = &s[end + 3..];
+                    in_comment = false;
+                    if s.trim().is_empty() {
+                        break;
+                    } else {
+                        continue;
+                    }
+                } else {
+                    stats.comment_lines += 1;
+                    break;
+                }
+            } else if let Some(pos) = s.find("<!--") {
+                let before = &s[..pos];
+                if !before.trim().is_empty() {
+                    stats.code_lines += 1;
+                }
+                stats.comment_lines += 1;
+                s = &s[pos + 4..];
+                if let Some(end) = s.find("-->") {
+                    s = &s[end + 3..];
+                    if s.trim().is_empty() {
+                        break;
+                    } else {
+                        continue;
+                    }
+                } else {
+                    in_comment = true;
+                    break;
+                }
+            } else {
+                if !s.trim().is_empty() {
+                    stats.code_lines += 1;
+                }
+                break;
+            }
+        }
+    }
+    // `<!-- -->` is the only comment form.
+    stats.block_comment_lines = stats.comment_lines;
+    Ok((stats, total_lines))
+}
+
+/// True when `path` matches one of `patterns`, by its name or by its path
+/// relative to the scan root (`--no-recurse-into`, `--exclude-fixtures`).
+fn matches_path_glob(patterns: &[Pattern], root_path: &Path, path: &Path) -> bool {
+    if patterns.is_empty() {
+        return false;
+    }
+    let name = path.file_name().and_then(|name| name.to_str());
+    let relative = path
+        .strip_prefix(root_path)
+        .ok()
+        .map(|rel| rel.to_string_lossy().replace('\\', "/"));
+    patterns.iter().any(|pattern| {
+        name.is_some_and(|name| pattern.matches(name))
+            || relative.as_deref().is_some_and(|rel| pattern.matches(rel))
+    })
+}
+
+fn should_process_file(filespec: Option<&Pattern>, root_path: &Path, file_path: &Path) -> bool {
+    filespec
+        .map(|pattern| filespec_matches(pattern, root_path, file_path))
+        .unwrap_or(true)
+}
+
+fn filespec_matches(pattern: &Pattern, root_path: &Path, file_path: &Path) -> bool {
+    if file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| pattern.matches(name))
+        .unwrap_or(false)
+    {
+        return true;
+    }
+
+    let relative = match file_path.strip_prefix(root_path) {
+        Ok(rel) => rel,
+        Err(_) => return false,
+    };
+
+    let rel_str = match relative.to_str() {
+        Some(s) => s.replace('\\', "/"),
         None => return false,
     };
 
-    pattern.matches(&rel_str)
+    pattern.matches(&rel_str)
+}
+
+fn increment_entries(entries_count: &mut usize, args: &Args, entry_path: &Path) -> io::Result<()> {
+    *entries_count += 1;
+    if *entries_count > args.max_entries {
+        return Err(io::Error::other(format!(
+            "Maximum entry limit ({}) exceeded while scanning {}",
+            args.max_entries,
+            entry_path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Physical line count as `LossyLineReader` sees it: `\n`-terminated lines
+/// plus a final unterminated one.
+fn reference_line_count(bytes: &[u8]) -> u64 {
+    let newlines = bytes.iter().filter(|&&b| b == b'\n').count() as u64;
+    match bytes.last() {
+        Some(b'\n') | None => newlines,
+        Some(_) => newlines + 1,
+    }
+}
+
+/// `--compat-check` findings for one file: every physical line must be
+/// classified exactly once, i.e. code + comment + blank + ignored - mixed
+/// equals the file's line count after `normalize_stats`, and the counter
+/// must neither miss lines (padded as blank) nor see a different total.
+fn compat_issues(splits: &[(String, RoleSplit)], reference_lines: u64) -> Vec<String> {
+    let mut issues = Vec::new();
+    let mut counted_lines = 0;
+    let mut classified = 0;
+    let mut unclassified = 0;
+    for (_, split) in splits {
+        counted_lines += split.total_lines();
+        for (_, bucket) in split.iter() {
+            let raw = bucket.stats;
+            let raw_sum = raw.code_lines + raw.comment_lines + raw.blank_lines + raw.ignored_lines;
+            unclassified += bucket.total_lines.saturating_sub(raw_sum);
+            let normalized = normalize_stats(raw, bucket.total_lines);
+            classified += (normalized.code_lines
+                + normalized.comment_lines
+                + normalized.blank_lines
+                + normalized.ignored_lines)
+                .saturating_sub(normalized.overlap_lines);
+        }
+    }
+    if counted_lines != reference_lines {
+        issues.push(format!(
+            "counter saw {} line(s), file has {}",
+            counted_lines, reference_lines
+        ));
+    }
+    if unclassified > 0 {
+        issues.push(format!(
+            "{} line(s) left unclassified (padded as blank)",
+            unclassified
+        ));
+    }
+    if classified != reference_lines {
+        issues.push(format!(
+            "code+comment+blank+ignored-mixed = {}, expected {}",
+            classified, reference_lines
+        ));
+    }
+    issues
+}
+
+fn format_compat_report(checked: u64, mismatches: &[(PathBuf, Vec<String>)]) -> String {
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "\nCompat check: {} file(s) checked, {} mismatch(es)",
+        format_number(checked),
+        format_number(mismatches.len() as u64)
+    );
+    let mut sorted: Vec<_> = mismatches.iter().collect();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, issues) in sorted {
+        let _ = writeln!(output, "  {}: {}", path.display(), issues.join("; "));
+    }
+    output
+}
+
+/// The `--strict-roles` block, one line per flagged file.
+fn format_role_warnings(warnings: &[(PathBuf, Vec<String>)]) -> String {
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "\nRole detection warnings: {} file(s)",
+        format_number(warnings.len() as u64)
+    );
+    let mut sorted: Vec<_> = warnings.iter().collect();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, reasons) in sorted {
+        let _ = writeln!(output, "  {}: {}", path.display(), reasons.join("; "));
+    }
+    output
+}
+
+/// Files counted from a partial read, one line per file with its error.
+fn format_partial_reads(partial_reads: &[(PathBuf, String)]) -> String {
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "\nPartial reads (counted up to the failure): {} file(s)",
+        format_number(partial_reads.len() as u64)
+    );
+    let mut sorted: Vec<_> = partial_reads.iter().collect();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, error) in sorted {
+        let _ = writeln!(output, "  {}: {}", path.display(), error);
+    }
+    output
+}
+
+/// Apply `--exclude-test` / `--only-test`; languages left without buckets are
+/// dropped, so a file with nothing left is not counted at all.
+fn filter_roles(splits: Vec<(String, RoleSplit)>, args: &Args) -> Vec<(String, RoleSplit)> {
+    if !args.exclude_test && !args.only_test {
+        return splits;
+    }
+    splits
+        .into_iter()
+        .filter_map(|(language, mut split)| {
+            split.retain(|role| keeps_role(role, args));
+            (split.role_count() > 0).then_some((language, split))
+        })
+        .collect()
+}
+
+/// Whether `--exclude-test` / `--only-test` keep lines of `role`.
+fn keeps_role(role: CodeRole, args: &Args) -> bool {
+    (!args.exclude_test && !args.only_test) || (role == CodeRole::Test) == args.only_test
+}
+
+/// The `--entries-report` block: how much was walked and where files went.
+fn format_entries_report(
+    entries: usize,
+    counts: &EntryCounts,
+    files_counted: u64,
+    duplicate_content: u64,
+    errors: usize,
+) -> String {
+    let rows = [
+        ("Entries visited", entries as u64),
+        ("Directories traversed", counts.directories),
+        ("Files counted", files_counted),
+        ("Skipped (--filespec)", counts.filtered),
+        ("Skipped (no language)", counts.unrecognised),
+        ("Skipped (symlinked duplicate)", counts.duplicate_targets),
+        ("Skipped (hardlinked duplicate)", counts.hardlink_duplicates),
+        ("Skipped (git submodule)", counts.submodules_skipped),
+        ("Minified files", counts.minified),
+        ("Skipped (duplicate content)", duplicate_content),
+        ("Errors", errors as u64),
+    ];
+    let mut output = String::from("\nScan entries:\n");
+    for (label, value) in rows {
+        let _ = writeln!(output, "  {:<30} {:>10}", label, format_number(value));
+    }
+    output
+}
+
+/// Path relative to the scan root with `/` separators, so the checksum does
+/// not depend on where or on which platform the tree was scanned.
+fn checksum_path(file_path: &Path, root_path: &Path) -> String {
+    let relative = file_path.strip_prefix(root_path).unwrap_or(file_path);
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// FNV-1a over the sorted records, one `path\0language\0code\0comment\0blank\n`
+/// line each; sorting makes it independent of traversal order.
+fn scan_checksum(records: &[ChecksumRecord]) -> u64 {
+    let mut sorted: Vec<&ChecksumRecord> = records.iter().collect();
+    sorted.sort();
+    let mut canonical = String::new();
+    for (path, language, code, comment, blank) in sorted {
+        let _ = writeln!(canonical, "{path}\0{language}\0{code}\0{comment}\0{blank}");
+    }
+    fnv1a_64(canonical.as_bytes())
+}
+
+/// The `--profile` block: seconds and share of the measured total per phase.
+fn format_phase_times(times: &PhaseTimes) -> String {
+    let rows = [
+        ("Traversal", times.traversal),
+        ("Counting", times.counting),
+        ("Report", times.report),
+    ];
+    let total: f64 = rows.iter().map(|(_, time)| time.as_secs_f64()).sum();
+    let mut output = String::from("\nPhase timings:\n");
+    for (label, time) in rows {
+        let secs = time.as_secs_f64();
+        let share = if total > 0.0 {
+            secs / total * 100.0
+        } else {
+            0.0
+        };
+        let _ = writeln!(output, "  {:<10} {:>9.3}s {:>6.1}%", label, secs, share);
+    }
+    output
+}
+
+/// The `--flag-empty` block.
+fn format_empty_files(empty: u64, whitespace_only: u64) -> String {
+    format!(
+        "\nEmpty files: {}\nWhitespace-only files: {}\n",
+        format_number(empty),
+        format_number(whitespace_only)
+    )
+}
+
+/// The `--largest-files` ranking: most code lines first, ties by path.
+fn format_largest_files(largest: &BinaryHeap<(Reverse<u64>, String)>) -> String {
+    let mut output = String::from("\nLargest files by code lines:\n");
+    for (rank, (Reverse(code), path)) in largest.clone().into_sorted_vec().iter().enumerate() {
+        let _ = writeln!(
+            output,
+            "  {:>3}. {:>10}  {}",
+            rank + 1,
+            format_number(*code),
+            path
+        );
+    }
+    output
+}
+
+/// Smallest file considered minified, so short one-liners are not flagged.
+const MINIFIED_MIN_BYTES: u64 = 2 * 1024;
+/// Average bytes per line above which a file looks minified; hand-written
+/// code averages well under 100.
+const MINIFIED_AVG_LINE_BYTES: u64 = 300;
+
+/// Languages bundlers and minifiers emit; long lines are normal in prose,
+/// data and markup such as Markdown, JSON or SVG.
+const MINIFIABLE_LANGUAGES: &[&str] = &["JavaScript", "TypeScript", "JSX", "TSX"];
+
+/// Heuristic for bundler / minifier output: a sizeable script whose lines
+/// are far longer than any hand-written code.
+fn looks_minified(language: &str, bytes: u64, lines: u64) -> bool {
+    MINIFIABLE_LANGUAGES.contains(&language)
+        && bytes >= MINIFIED_MIN_BYTES
+        && bytes / lines.max(1) > MINIFIED_AVG_LINE_BYTES
+}
+
+/// Files listed in the `--blank-runs` block.
+const BLANK_RUN_FILE_LIMIT: usize = 10;
+
+/// (longest run, number of runs) of consecutive lines the counter recorded
+/// as blank; ignored and comment lines end a run.
+fn blank_line_runs(records: &[LineRecord]) -> (u64, u64) {
+    let (mut longest, mut runs, mut current) = (0u64, 0u64, 0u64);
+    for record in records {
+        if record.is_blank() {
+            current += 1;
+            if current == 1 {
+                runs += 1;
+            }
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    (longest, runs)
+}
+
+/// The `--blank-runs` block: overall run count, then the files with the
+/// longest runs (ties by path).
+fn format_blank_runs(blank_runs: &[(PathBuf, u64, u64)]) -> String {
+    let total: u64 = blank_runs.iter().map(|(_, _, runs)| runs).sum();
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "\nBlank-line runs: {} in {} file(s)",
+        format_number(total),
+        format_number(blank_runs.len() as u64)
+    );
+    let mut sorted: Vec<_> = blank_runs.iter().collect();
+    sorted.sort_by(|(a_path, a, _), (b_path, b, _)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+    for (path, longest, runs) in sorted.into_iter().take(BLANK_RUN_FILE_LIMIT) {
+        let _ = writeln!(
+            output,
+            "  longest {:>6}  runs {:>8}  {}",
+            format_number(*longest),
+            format_number(*runs),
+            path.display()
+        );
+    }
+    output
+}
+
+/// `--group-by-extension` key: the lowercase extension with its dot, or
+/// `(none)` for names like `Makefile` and `.env`.
+fn extension_key(file_path: &Path) -> String {
+    file_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map_or_else(
+            || "(none)".to_string(),
+            |ext| format!(".{}", ext.to_lowercase()),
+        )
+}
+
+/// The `--group-by-extension` table, grouped by language with each
+/// language's extensions largest first.
+fn format_extension_totals(totals: &HashMap<(String, String), (u64, LanguageStats)>) -> String {
+    let mut rows: Vec<_> = totals.iter().collect();
+    rows.sort_by(|((a_ext, a_lang), (_, a)), ((b_ext, b_lang), (_, b))| {
+        a_lang
+            .cmp(b_lang)
+            .then_with(|| b.code_lines.cmp(&a.code_lines))
+            .then_with(|| a_ext.cmp(b_ext))
+    });
+    let mut output = String::from("\nTotals by extension:\n");
+    let _ = writeln!(
+        output,
+        "  {:<12} {:<16} {:>8} {:>12} {:>12} {:>12}",
+        "Extension", "Language", "Files", "Code", "Comments", "Blank"
+    );
+    for ((extension, language), (files, stats)) in rows {
+        let _ = writeln!(
+            output,
+            "  {:<12} {:<16} {:>8} {:>12} {:>12} {:>12}",
+            extension,
+            language,
+            format_number(*files),
+            format_number(stats.code_lines),
+            format_number(stats.comment_lines),
+            format_number(stats.blank_lines)
+        );
+    }
+    output
+}
+
+/// The `--blame` table: code lines per author, largest first.
+fn format_blame_table(authors: &HashMap<String, u64>) -> String {
+    let mut rows: Vec<(&String, &u64)> = authors.iter().collect();
+    rows.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    let mut output = String::from("\nCode lines by author:\n");
+    for (author, lines) in rows {
+        let _ = writeln!(output, "  {:<30} {:>10}", author, format_number(*lines));
+    }
+    output
+}
+
+const SKIPPED_EXTENSION_LIMIT: usize = 20;
+
+fn record_skipped_file(metrics: &mut PerformanceMetrics, file_path: &Path, root_path: &Path) {
+    let key = file_path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy().to_lowercase()))
+        .unwrap_or_else(|| "(no extension)".to_string());
+    let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+    metrics
+        .skipped_files
+        .entry(key)
+        .or_insert_with(|| (0, display.to_path_buf()))
+        .0 += 1;
+}
+
+/// Skipped-file summary for `--print-skipped`: extensions by descending
+/// count, capped at `SKIPPED_EXTENSION_LIMIT` rows with a remainder line.
+fn format_skipped_files(skipped: &HashMap<String, (u64, PathBuf)>) -> String {
+    let mut output = String::new();
+    let total: u64 = skipped.values().map(|(count, _)| count).sum();
+    let _ = writeln!(
+        output,
+        "\nSkipped files (no recognised language): {}",
+        format_number(total)
+    );
+    let mut sorted: Vec<_> = skipped.iter().collect();
+    sorted.sort_by(|(a_ext, (a, _)), (b_ext, (b, _))| b.cmp(a).then_with(|| a_ext.cmp(b_ext)));
+    for (ext, (count, example)) in sorted.iter().take(SKIPPED_EXTENSION_LIMIT) {
+        let _ = writeln!(
+            output,
+            "  {:<20} {:>10}  e.g. {}",
+            ext,
+            format_number(*count),
+            example.display()
+        );
+    }
+    if sorted.len() > SKIPPED_EXTENSION_LIMIT {
+        let rest = &sorted[SKIPPED_EXTENSION_LIMIT..];
+        let rest_files: u64 = rest.iter().map(|(_, (count, _))| count).sum();
+        let _ = writeln!(
+            output,
+            "  ... and {} more extension(s) ({} file(s))",
+            rest.len(),
+            format_number(rest_files)
+        );
+    }
+    output
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_file(
+    file_path: &Path,
+    args: &Args,
+    root_path: &Path,
+    metrics: &mut PerformanceMetrics,
+    stats: &mut HashMap<PathBuf, DirectoryStats>,
+    error_count: &mut usize,
+    filespec: Option<&Pattern>,
+    visited: &mut VisitedFiles,
+) -> io::Result<()> {
+    if !should_process_file(filespec, root_path, file_path) {
+        metrics.entry_counts.filtered += 1;
+        return Ok(());
+    }
+
+    let real_path = match canonicalize_file(file_path) {
+        Ok(path) => path,
+        // Too long to resolve (e.g. deep generated trees): the file still
+        // exists, so dedupe on its absolute, unresolved path instead.
+        Err(err) if err.kind() == io::ErrorKind::InvalidFilename => {
+            let fallback = std::path::absolute(file_path).unwrap_or_else(|_| file_path.into());
+            if args.verbose {
+                print_verbose(
+                    args,
+                    &format!(
+                        "Could not resolve {} ({}); using the unresolved path",
+                        file_path.display(),
+                        err
+                    ),
+                );
+            }
+            fallback
+        }
+        Err(err) => {
+            eprintln!(
+                "Error resolving real path for {}: {}",
+                file_path.display(),
+                err
+            );
+            *error_count += 1;
+            return Ok(());
+        }
+    };
+
+    if !visited.real_paths.insert(real_path.clone()) {
+        metrics.entry_counts.duplicate_targets += 1;
+        if args.verbose {
+            print_verbose(
+                args,
+                &format!(
+                    "Skipping duplicate target for symlinked file: {} -> {}",
+                    file_path.display(),
+                    real_path.display()
+                ),
+            );
+        }
+        return Ok(());
+    }
+    if args.hardlink_dedupe {
+        if let Some(identity) = file_identity(&real_path) {
+            if !visited.inodes.insert(identity) {
+                metrics.entry_counts.hardlink_duplicates += 1;
+                if args.verbose {
+                    print_verbose(
+                        args,
+                        &format!("Skipping hardlinked duplicate: {}", file_path.display()),
+                    );
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    let by_name = file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| language_for_file(name, args));
+    // (language, representative extension) when the content overrides or
+    // supplies the language the name gives.
+    let detected = args
+        .prefer_shebang
+        .then(|| shebang_language(file_path))
+        .flatten()
+        .filter(|(language, _)| by_name != Some(*language))
+        .or_else(|| {
+            (args.sniff_content && by_name.is_none())
+                .then(|| sniff_language(file_path))
+                .flatten()
+        });
+    let Some(language) = detected.map(|(language, _)| language).or(by_name) else {
+        metrics.entry_counts.unrecognised += 1;
+        if args.print_skipped {
+            record_skipped_file(metrics, file_path, root_path);
+        }
+        return Ok(());
+    };
+
+    if args.dedupe_by_content {
+        match fs::read(file_path) {
+            Ok(bytes) => {
+                if !visited.content_hashes.insert(fnv1a_64(&bytes)) {
+                    metrics.duplicate_files += 1;
+                    if args.verbose {
+                        print_verbose(
+                            args,
+                            &format!("Skipping duplicate content: {}", file_path.display()),
+                        );
+                    }
+                    return Ok(());
+                }
+            }
+            Err(err) => {
+                eprintln!("Error reading {}: {}", file_path.display(), err);
+                *error_count += 1;
+                return Ok(());
+            }
+        }
+    }
+
+    let role_hint = infer_role_from_path(root_path, file_path);
+    let stamp = visited
+        .cache
+        .as_ref()
+        .and_then(|_| FileStamp::of(file_path));
+    let blame_tracked = visited
+        .blame_repo
+        .as_ref()
+        .is_some_and(|(_, tracked)| tracked.contains(&real_path));
+    let record_lines = args.blank_runs || blame_tracked;
+    // Role warnings and line records come from the counting pass, which a
+    // cache hit skips.
+    let cached = stamp
+        .filter(|_| !args.strict_roles && !record_lines)
+        .and_then(|stamp| visited.cache.as_ref()?.lookup(&real_path, stamp, role_hint));
+    let counting_started = Instant::now();
+    let file_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    // (bytes, chars) of the file and its line records, gathered while counting.
+    let (counted, partial_read, size, records) = match cached {
+        Some(entry) => (
+            Ok(entry.splits),
+            None,
+            (entry.stamp.size, entry.chars),
+            Vec::new(),
+        ),
+        None => match read_file_lines_lossy(file_path) {
+            Ok(lines) => {
+                let mut lines = if record_lines {
+                    lines.recording()
+                } else {
+                    lines
+                };
+                let counted =
+                    count_file_splits(file_name, &mut lines, language, detected, role_hint, args);
+                // The counts stand for the lines read before a failure.
+                let size = lines.finish_size();
+                let partial_read = lines.take_error().filter(|_| counted.is_ok());
+                (counted, partial_read, size, lines.take_records())
+            }
+            Err(err) => (Err(err), None, (0, 0), Vec::new()),
+        },
+    };
+    metrics.phase_times.counting += counting_started.elapsed();
+    if let Some(err) = &partial_read {
+        eprintln!(
+            "Warning: read of {} failed part-way ({}); counting the lines read so far",
+            file_path.display(),
+            err
+        );
+        let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+        metrics
+            .partial_reads
+            .push((display.to_path_buf(), err.to_string()));
+    }
+    if let (Ok(splits), Some(stamp), Some(cache), None) =
+        (&counted, stamp, visited.cache.as_mut(), &partial_read)
+    {
+        let entry = CacheEntry {
+            stamp,
+            role_hint,
+            chars: size.1,
+            splits: splits.clone(),
+        };
+        cache.store(real_path.clone(), entry);
+    }
+    if let Ok(splits) = &counted {
+        let lines = splits.iter().map(|(_, split)| split.total_lines()).sum();
+        if looks_minified(language, size.0, lines) {
+            metrics.entry_counts.minified += 1;
+            if args.verbose {
+                print_verbose(args, &format!("Minified file: {}", file_path.display()));
+            }
+            if args.exclude_minified {
+                return Ok(());
+            }
+        }
+    }
+    if let (true, Ok(splits)) = (args.compat_check, &counted) {
+        match fs::read(file_path) {
+            Ok(bytes) => {
+                metrics.compat_checked += 1;
+                let issues = compat_issues(splits, reference_line_count(&bytes));
+                if !issues.is_empty() {
+                    let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+                    metrics
+                        .compat_mismatches
+                        .push((display.to_path_buf(), issues));
+                }
+            }
+            Err(err) => {
+                eprintln!("Error reading {}: {}", file_path.display(), err);
+                *error_count += 1;
+            }
+        }
+    }
+    if let (true, Ok(splits)) = (args.flag_empty, &counted) {
+        let buckets = || {
+            splits
+                .iter()
+                .flat_map(|(_, split)| split.iter().map(|(_, b)| b))
+        };
+        if buckets().all(|bucket| bucket.total_lines == 0) {
+            metrics.empty_files += 1;
+        } else if buckets().all(|bucket| {
+            bucket.stats.code_lines + bucket.stats.comment_lines + bucket.stats.ignored_lines == 0
+        }) {
+            metrics.whitespace_only_files += 1;
+        }
+    }
+    if let (true, Ok(splits)) = (args.strict_roles && language == "Rust", &counted) {
+        let warnings: Vec<String> = splits
+            .iter()
+            .flat_map(|(_, split)| split.warnings.iter().cloned())
+            .collect();
+        if !warnings.is_empty() {
+            let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+            metrics
+                .role_warnings
+                .push((display.to_path_buf(), warnings));
+        }
+    }
+    if args.blank_runs && counted.is_ok() {
+        let (longest, runs) = blank_line_runs(&records);
+        if runs > 0 {
+            let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+            metrics
+                .blank_runs
+                .push((display.to_path_buf(), longest, runs));
+        }
+    }
+    match counted.map(|splits| filter_roles(splits, args)) {
+        Ok(splits) if splits.is_empty() => {}
+        Ok(splits) => {
+            metrics.update(splits.iter().map(|(_, split)| split.total_lines()).sum());
+            // With --streaming-totals every file lands in one entry, so memory
+            // stays proportional to the number of languages.
+            let dir_path = if args.streaming_totals {
+                root_path.to_path_buf()
+            } else {
+                file_path
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default()
+            };
+            let dir_stats = stats.entry(dir_path).or_default();
+            // (language, role, stats) per recorded bucket; one file may span languages.
+            let mut pending: Vec<(String, CodeRole, LanguageStats)> = Vec::new();
+            let fixture = matches_path_glob(&visited.exclude_fixtures, root_path, file_path);
+
+            for (bucket_language, role_split) in &splits {
+                let show_role = role_split.role_count() > 1;
+                for (role, bucket) in role_split.iter() {
+                    let normalized_stats =
+                        adjust_bucket_stats(bucket_language, bucket, args, fixture);
+                    let total_line_kinds = normalized_stats.code_lines
+                        + normalized_stats.comment_lines
+                        + normalized_stats.blank_lines
+                        + normalized_stats.ignored_lines;
+                    if total_line_kinds > 0 || bucket.total_lines == 0 {
+                        let normalized_total = total_line_kinds;
+                        metrics.record_role(role, normalized_total, normalized_stats.code_lines);
+                        pending.push((bucket_language.clone(), role, normalized_stats));
+
+                        if args.verbose {
+                            let mut detail = format!("File: {}\n", file_path.display());
+                            if splits.len() > 1 {
+                                let _ = writeln!(detail, "  Language: {}", bucket_language);
+                            }
+                            if show_role {
+                                let _ = writeln!(detail, "  Role: {:?}", role);
+                            }
+                            let _ = writeln!(
+                                detail,
+                                "  Code lines: {}",
+                                format_number(normalized_stats.code_lines)
+                            );
+                            let _ = writeln!(
+                                detail,
+                                "  Comment lines: {}",
+                                format_number(normalized_stats.comment_lines)
+                            );
+                            let _ = writeln!(
+                                detail,
+                                "  Blank lines: {}",
+                                format_number(normalized_stats.blank_lines)
+                            );
+                            let _ = writeln!(
+                                detail,
+                                "  Mixed code/comment lines: {}",
+                                format_number(normalized_stats.overlap_lines)
+                            );
+                            if normalized_stats.ignored_lines > 0 {
+                                let _ = writeln!(
+                                    detail,
+                                    "  Ignored lines: {}",
+                                    format_number(normalized_stats.ignored_lines)
+                                );
+                            }
+                            print_verbose(args, &detail);
+                        }
+                    }
+                }
+            }
+
+            if args.format == OutputFormat::Jsonl {
+                let path = match args.relocate_root.as_deref() {
+                    Some(virtual_root) => json_directory_key(
+                        &relocate_path(file_path, root_path, virtual_root),
+                        Path::new(virtual_root),
+                        args.relative_paths,
+                    ),
+                    None => json_directory_key(file_path, root_path, args.relative_paths),
+                };
+                let mut out = io::stdout().lock();
+                for (language, role, stats) in &pending {
+                    writeln!(
+                        out,
+                        "{}",
+                        format_jsonl_record(&path, language, *role, stats)
+                    )?;
+                }
+                out.flush()?;
+            }
+
+            if args.checksum {
+                let path = checksum_path(file_path, root_path);
+                for (bucket_language, _) in &splits {
+                    let (code, comment, blank) = pending
+                        .iter()
+                        .filter(|(lang, _, _)| lang == bucket_language)
+                        .fold((0, 0, 0), |(code, comment, blank), (_, _, stats)| {
+                            (
+                                code + stats.code_lines,
+                                comment + stats.comment_lines,
+                                blank + stats.blank_lines,
+                            )
+                        });
+                    metrics.checksum_records.push((
+                        path.clone(),
+                        bucket_language.clone(),
+                        code,
+                        comment,
+                        blank,
+                    ));
+                }
+            }
+
+            if let Some(limit) = args.largest_files.filter(|&limit| limit > 0) {
+                let code: u64 = pending.iter().map(|(_, _, stats)| stats.code_lines).sum();
+                let display = file_path.strip_prefix(root_path).unwrap_or(file_path);
+                let heap = &mut metrics.largest_files;
+                heap.push((Reverse(code), display.to_string_lossy().into_owned()));
+                if heap.len() > limit {
+                    heap.pop();
+                }
+            }
+
+            if args.group_by_extension {
+                let extension = extension_key(file_path);
+                for (bucket_language, _) in &splits {
+                    let (files, totals) = metrics
+                        .extension_totals
+                        .entry((extension.clone(), bucket_language.clone()))
+                        .or_default();
+                    *files += 1;
+                    for (_, _, stats) in pending
+                        .iter()
+                        .filter(|(lang, _, _)| lang == bucket_language)
+                    {
+                        totals.add_assign(stats);
+                    }
+                }
+            }
+
+            if let Some((toplevel, _)) = visited.blame_repo.as_ref().filter(|_| blame_tracked) {
+                match git_blame_authors(toplevel, &real_path) {
+                    Ok(blamed) if blamed.len() > records.len() => {
+                        eprintln!(
+                            "Error attributing {}: git blame reports {} lines, {} were counted",
+                            file_path.display(),
+                            blamed.len(),
+                            records.len()
+                        );
+                        *error_count += 1;
+                    }
+                    Ok(blamed) => {
+                        for (author, code) in
+                            blame_code_lines(&blamed, &records, language, args, fixture)
+                        {
+                            *metrics.blame_authors.entry(author).or_default() += code;
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!(
+                            "Error running git blame on {}: {}",
+                            file_path.display(),
+                            err
+                        );
+                        *error_count += 1;
+                    }
+                }
+            }
+
+            let mut role_stats: Vec<(CodeRole, LanguageStats)> = pending
+                .iter()
+                .map(|(_, role, stats)| (*role, *stats))
+                .collect();
+            if args.bytes && !role_stats.is_empty() {
+                apportion_file_size(&mut role_stats, size.0, size.1);
+            }
+
+            for (bucket_language, _) in &splits {
+                let buckets: Vec<(CodeRole, LanguageStats)> = pending
+                    .iter()
+                    .zip(&role_stats)
+                    .filter(|((lang, _, _), _)| lang == bucket_language)
+                    .map(|(_, sized)| *sized)
+                    .collect();
+                if !buckets.is_empty() {
+                    let entry = dir_stats
+                        .language_stats
+                        .entry(bucket_language.clone())
+                        .or_default();
+                    entry.record_roles(&buckets);
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("Error counting lines in {}: {}", file_path.display(), err);
+            *error_count += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively scan directories and collect statistics.
+/// Added error tracking and directory depth limiting to prevent stack overflow.
+#[allow(clippy::too_many_arguments)]
+fn scan_directory_impl(
+    path: &Path,
+    args: &Args,
+    root_path: &Path,
+    metrics: &mut PerformanceMetrics,
+    current_depth: usize,
+    entries_count: &mut usize,
+    error_count: &mut usize,
+    filespec: Option<&Pattern>,
+    visited: &mut VisitedFiles,
+    mut queue: Option<&mut VecDeque<(PathBuf, usize)>>,
+) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+    if current_depth > args.max_depth {
+        eprintln!(
+            "Warning: Maximum directory depth ({}) reached at {}",
+            args.max_depth,
+            path.display()
+        );
+        *error_count += 1;
+        return Ok(HashMap::new());
+    }
+
+    if args.non_recursive && current_depth > 0 {
+        return Ok(HashMap::new());
+    }
+
+    let mut stats: HashMap<PathBuf, DirectoryStats> =
+        HashMap::with_capacity(if path.is_dir() { 128 } else { 1 });
+
+    if is_excluded_dir(args, path) {
+        return Ok(stats);
+    }
+    let prune_children = matches_path_glob(&visited.no_recurse_into, root_path, path);
+
+    if metrics.runtime_exceeded(args.max_runtime) {
+        return Ok(stats);
+    }
+
+    let metadata = match fetch_metadata(path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            eprintln!("Error reading metadata for {}: {}", path.display(), err);
+            *error_count += 1;
+            return Ok(stats);
+        }
+    };
+
+    if metadata.is_file() {
+        increment_entries(entries_count, args, path)?;
+        {
+            let mut ctx = ProcCtx {
+                args,
+                root_path,
+                metrics,
+                stats: &mut stats,
+                error_count,
+                filespec,
+                visited,
+            };
+            process_entry_file(&mut ctx, path)?;
+        }
+        return Ok(stats);
+    } else if !metadata.is_dir() {
+        return Ok(stats);
+    }
+
+    let read_dir = match read_dir_stream(path) {
+        Ok(iter) => iter,
+        Err(err) => {
+            eprintln!("Error reading directory {}: {}", path.display(), err);
+            *error_count += 1;
+            return Ok(stats);
+        }
+    };
+    metrics.entry_counts.directories += 1;
+
+    for entry_result in read_dir {
+        if metrics.runtime_exceeded(args.max_runtime) {
+            break;
+        }
+        let entry = match entry_result {
+            Ok(entry) => entry,
+            Err(err) => {
+                eprintln!("Error reading entry in {}: {}", path.display(), err);
+                *error_count += 1;
+                continue;
+            }
+        };
+
+        let entry_path = entry.path();
+        let file_type = match entry_file_type(&entry) {
+            Ok(ft) => ft,
+            Err(err) => {
+                eprintln!("Error reading type for {}: {}", entry_path.display(), err);
+                *error_count += 1;
+                continue;
+            }
+        };
+
+        increment_entries(entries_count, args, &entry_path)?;
+
+        if file_type.is_dir() && !file_type.is_symlink() {
+            if args.non_recursive || prune_children {
+                continue;
+            }
+            if !args.follow_gitlinks && is_submodule_dir(&entry_path, visited) {
+                metrics.entry_counts.submodules_skipped += 1;
+                if args.verbose {
+                    print_verbose(
+                        args,
+                        &format!("Skipping git submodule: {}", entry_path.display()),
+                    );
+                }
+                continue;
+            }
+            if let Some(queue) = queue.as_deref_mut() {
+                queue.push_back((entry_path, current_depth + 1));
+                continue;
+            }
+            match scan_directory_impl(
+                &entry_path,
+                args,
+                root_path,
+                metrics,
+                current_depth + 1,
+                entries_count,
+                error_count,
+                filespec,
+                visited,
+                None,
+            ) {
+                Ok(sub_stats) => {
+                    for (dir, stat) in sub_stats {
+                        merge_directory_stats(&mut stats, dir, stat);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Error scanning directory {}: {}", entry_path.display(), err);
+                    *error_count += 1;
+                }
+            }
+        } else if file_type.is_file() && !file_type.is_symlink() {
+            let mut ctx = ProcCtx {
+                args,
+                root_path,
+                metrics,
+                stats: &mut stats,
+                error_count,
+                filespec,
+                visited,
+            };
+            process_entry_file(&mut ctx, &entry_path)?;
+        } else if file_type.is_symlink() {
+            let mut ctx = ProcCtx {
+                args,
+                root_path,
+                metrics,
+                stats: &mut stats,
+                error_count,
+                filespec,
+                visited,
+            };
+            handle_symlink(&mut ctx, &entry_path)?;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// `--traversal bfs`: `scan_directory_impl` one directory at a time from a
+/// queue, so each level finishes before the next. Limits and errors behave
+/// as in the recursive walk.
+#[allow(clippy::too_many_arguments)]
+fn scan_breadth_first(
+    root_path: &Path,
+    args: &Args,
+    metrics: &mut PerformanceMetrics,
+    root_depth: usize,
+    entries_count: &mut usize,
+    error_count: &mut usize,
+    filespec: Option<&Pattern>,
+    visited: &mut VisitedFiles,
+) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+    let mut queue = VecDeque::new();
+    let mut stats = scan_directory_impl(
+        root_path,
+        args,
+        root_path,
+        metrics,
+        root_depth,
+        entries_count,
+        error_count,
+        filespec,
+        visited,
+        Some(&mut queue),
+    )?;
+    while let Some((dir, depth)) = queue.pop_front() {
+        match scan_directory_impl(
+            &dir,
+            args,
+            root_path,
+            metrics,
+            depth,
+            entries_count,
+            error_count,
+            filespec,
+            visited,
+            Some(&mut queue),
+        ) {
+            Ok(sub_stats) => {
+                for (dir, stat) in sub_stats {
+                    merge_directory_stats(&mut stats, dir, stat);
+                }
+            }
+            Err(err) => {
+                eprintln!("Error scanning directory {}: {}", dir.display(), err);
+                *error_count += 1;
+                // The recursive walk gives up once the entry limit is hit.
+                if *entries_count > args.max_entries {
+                    return Err(err);
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// `path = ...` values from a `.gitmodules` file.
+fn parse_gitmodule_paths(text: &str) -> Vec<&str> {
+    text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "path").then(|| value.trim())
+        })
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+/// A submodule checkout has a `.git` file pointing at the superproject's
+/// object store rather than a `.git` directory.
+fn is_submodule_dir(dir: &Path, visited: &VisitedFiles) -> bool {
+    dir.join(".git").is_file() || visited.gitmodule_paths.contains(dir)
+}
+
+fn scan_directory(
+    path: &Path,
+    args: &Args,
+    _current_dir: &Path,
+    metrics: &mut PerformanceMetrics,
+    current_depth: usize,
+    entries_count: &mut usize,
+    error_count: &mut usize,
+) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+    let filespec_pattern = match args.filespec.as_deref() {
+        Some(spec) => Some(Pattern::new(spec).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid filespec pattern '{}': {}", spec, err),
+            )
+        })?),
+        None => None,
+    };
+
+    let compile_globs = |flag: &str, globs: &[String]| -> io::Result<Vec<Pattern>> {
+        globs
+            .iter()
+            .map(|glob| {
+                Pattern::new(glob).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid {} pattern '{}': {}", flag, glob, err),
+                    )
+                })
+            })
+            .collect()
+    };
+    let no_recurse_into = compile_globs("--no-recurse-into", &args.no_recurse_into)?;
+    let exclude_fixtures = compile_globs("--exclude-fixtures", &args.exclude_fixtures)?;
+
+    let glob_pattern = path_glob(path);
+    let scan_root = glob_pattern.map_or_else(|| path.to_path_buf(), glob_base);
+    let root_path = fs::canonicalize(&scan_root).unwrap_or(scan_root);
+    let changed = match args.since.as_deref() {
+        Some(git_ref) => Some(git_changed_files(&root_path, git_ref)?),
+        None => None,
+    };
+    let listed = match glob_pattern {
+        Some(pattern) => {
+            let mut files = expand_path_glob(pattern)?;
+            if let Some(changed) = &changed {
+                files.retain(|file| changed.contains(file));
+            }
+            Some(files)
+        }
+        None => changed,
+    };
+    let blame_repo = match args.blame {
+        true => Some(git_tracked_files(&root_path)?),
+        false => None,
+    };
+    let gitmodule_paths = match args.follow_gitlinks {
+        true => HashSet::new(),
+        false => fs::read_to_string(root_path.join(".gitmodules"))
+            .map(|text| {
+                parse_gitmodule_paths(&text)
+                    .into_iter()
+                    .map(|path| root_path.join(path))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    let mut visited = VisitedFiles {
+        cache: args
+            .cache
+            .as_deref()
+            .map(|path| ScanCache::load(path, cache_options_key(args))),
+        blame_repo,
+        gitmodule_paths,
+        no_recurse_into,
+        exclude_fixtures,
+        ..Default::default()
+    };
+
+    let scan_started = Instant::now();
+    let counting_before = metrics.phase_times.counting;
+    let result = match &listed {
+        Some(files) => scan_file_list(
+            files,
+            args,
+            &root_path,
+            metrics,
+            entries_count,
+            error_count,
+            filespec_pattern.as_ref(),
+            &mut visited,
+        ),
+        None if args.traversal == Traversal::Bfs => scan_breadth_first(
+            &root_path,
+            args,
+            metrics,
+            current_depth,
+            entries_count,
+            error_count,
+            filespec_pattern.as_ref(),
+            &mut visited,
+        ),
+        None => scan_directory_impl(
+            &root_path,
+            args,
+            &root_path,
+            metrics,
+            current_depth,
+            entries_count,
+            error_count,
+            filespec_pattern.as_ref(),
+            &mut visited,
+            None,
+        ),
+    };
+    let counting = metrics.phase_times.counting.saturating_sub(counting_before);
+    metrics.phase_times.traversal += scan_started.elapsed().saturating_sub(counting);
+
+    if let (Ok(_), Some(cache), Some(cache_path)) = (&result, &visited.cache, &args.cache) {
+        if let Err(err) = cache.save(cache_path) {
+            eprintln!("Error writing cache {}: {}", cache_path.display(), err);
+            *error_count += 1;
+        }
+    }
+    result
 }
 
-fn increment_entries(entries_count: &mut usize, args: &Args, entry_path: &Path) -> io::Result<()> {
-    *entries_count += 1;
-    if *entries_count > args.max_entries {
+/// The path argument as a glob pattern, when it is not an existing path but
+/// contains glob metacharacters (e.g. `"src/**/*.rs"`).
+fn path_glob(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|pattern| !path.exists() && pattern.contains(['*', '?', '[']))
+}
+
+/// The leading components of `pattern` free of metacharacters: the common
+/// ancestor of every match, used as the scan root.
+fn glob_base(pattern: &str) -> PathBuf {
+    let base: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|part| !part.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect();
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+/// Regular files matching a path-argument glob, canonicalised and sorted.
+fn expand_path_glob(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let matches = glob::glob(pattern).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid path pattern '{}': {}", pattern, err),
+        )
+    })?;
+    let mut files: Vec<PathBuf> = matches
+        .filter_map(Result::ok)
+        .filter(|file| file.is_file())
+        .filter_map(|file| fs::canonicalize(file).ok())
+        .collect();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Files under `root_path` that differ from `git_ref` according to
+/// `git diff --name-only`. Paths deleted since the ref are dropped.
+fn git_changed_files(root_path: &Path, git_ref: &str) -> io::Result<Vec<PathBuf>> {
+    let toplevel = git_toplevel(root_path, "--since")?;
+    let names = run_git(&toplevel, &["diff", "--name-only", "-z", git_ref, "--"])?;
+
+    let mut files: Vec<PathBuf> = names
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| toplevel.join(name))
+        .filter(|file| file.starts_with(root_path) && file.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The canonical top level of the repository containing `root_path`; `flag`
+/// names the option needing it in the error.
+fn git_toplevel(root_path: &Path, flag: &str) -> io::Result<PathBuf> {
+    let work_dir = if root_path.is_dir() {
+        root_path
+    } else {
+        root_path.parent().unwrap_or(root_path)
+    };
+    let toplevel = run_git(work_dir, &["rev-parse", "--show-toplevel"]).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} requires a git repository at {}: {}",
+                flag,
+                work_dir.display(),
+                err
+            ),
+        )
+    })?;
+    let toplevel = PathBuf::from(toplevel.trim());
+    Ok(fs::canonicalize(&toplevel).unwrap_or(toplevel))
+}
+
+/// The repository top level and the canonical paths of its tracked files,
+/// so `--blame` skips untracked ones.
+fn git_tracked_files(root_path: &Path) -> io::Result<(PathBuf, HashSet<PathBuf>)> {
+    let toplevel = git_toplevel(root_path, "--blame")?;
+    let names = run_git(&toplevel, &["ls-files", "-z"])?;
+    let tracked = names
+        .split('\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| toplevel.join(name))
+        .collect();
+    Ok((toplevel, tracked))
+}
+
+/// The author of each line of `file`, by line index, from `git blame
+/// --line-porcelain`.
+fn git_blame_authors(toplevel: &Path, file: &Path) -> io::Result<Vec<(usize, String)>> {
+    let file = file.to_string_lossy();
+    let porcelain = run_git(toplevel, &["blame", "--line-porcelain", "--", &file])?;
+    Ok(parse_blame_porcelain(&porcelain))
+}
+
+/// `(line index, author)` pairs; the index comes from each entry's header,
+/// `<sha> <original line> <final line> [<group size>]`.
+fn parse_blame_porcelain(porcelain: &str) -> Vec<(usize, String)> {
+    let mut blamed = Vec::new();
+    let mut line_no: usize = 0;
+    let mut author = "";
+    for line in porcelain.lines() {
+        if let Some(name) = line.strip_prefix("author ") {
+            author = name;
+        } else if line.starts_with('\t') {
+            blamed.push((line_no.saturating_sub(1), author.to_string()));
+        } else {
+            let mut fields = line.split(' ');
+            let sha = fields.next().unwrap_or("");
+            if !sha.is_empty() && sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+                if let Some(final_line) = fields.nth(1).and_then(|n| n.parse().ok()) {
+                    line_no = final_line;
+                }
+            }
+        }
+    }
+    blamed
+}
+
+/// Code lines per author, in order of first appearance: each blamed line
+/// is looked up in the counting pass's `records`, so a line keeps the
+/// classification it had in the whole file. The role filter and bucket
+/// adjustments apply to each author's lines as they do to the file's.
+fn blame_code_lines(
+    blamed: &[(usize, String)],
+    records: &[LineRecord],
+    language: &str,
+    args: &Args,
+    fixture: bool,
+) -> Vec<(String, u64)> {
+    let mut authors: Vec<(&str, [RoleBucket; CODE_ROLE_COUNT])> = Vec::new();
+    for (idx, author) in blamed {
+        let Some(record) = records.get(*idx) else {
+            continue;
+        };
+        let Some(role) = record.role.filter(|&role| keeps_role(role, args)) else {
+            continue;
+        };
+        let slot = match authors.iter().position(|(name, _)| name == author) {
+            Some(slot) => slot,
+            None => {
+                authors.push((author, Default::default()));
+                authors.len() - 1
+            }
+        };
+        let bucket = &mut authors[slot].1[role.as_index()];
+        let stats = &mut bucket.stats;
+        stats.code_lines += u64::from(record.code);
+        stats.comment_lines += u64::from(record.comment);
+        stats.blank_lines += u64::from(record.is_blank());
+        stats.overlap_lines += u64::from(record.code && record.comment);
+        stats.ignored_lines += u64::from(record.ignored);
+        stats.continuation_lines += u64::from(record.continuation);
+        stats.region_marker_lines += u64::from(record.region_marker);
+        bucket.total_lines += 1;
+    }
+    authors
+        .into_iter()
+        .map(|(author, buckets)| {
+            let code = buckets
+                .into_iter()
+                .filter(|bucket| bucket.total_lines > 0)
+                .map(|bucket| adjust_bucket_stats(language, bucket, args, fixture).code_lines)
+                .sum();
+            (author.to_string(), code)
+        })
+        .collect()
+}
+
+fn run_git(dir: &Path, git_args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(git_args)
+        .output()?;
+    if !output.status.success() {
         return Err(io::Error::other(format!(
-            "Maximum entry limit ({}) exceeded while scanning {}",
-            args.max_entries,
-            entry_path.display()
+            "git {} failed: {}",
+            git_args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
         )));
     }
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
+/// Count an explicit list of files instead of walking `root_path`.
+/// Ignore rules and `--filespec` still apply.
 #[allow(clippy::too_many_arguments)]
-fn process_file(
-    file_path: &Path,
+fn scan_file_list(
+    files: &[PathBuf],
     args: &Args,
     root_path: &Path,
     metrics: &mut PerformanceMetrics,
-    stats: &mut HashMap<PathBuf, DirectoryStats>,
+    entries_count: &mut usize,
     error_count: &mut usize,
     filespec: Option<&Pattern>,
-    visited_real_paths: &mut HashSet<PathBuf>,
-) -> io::Result<()> {
-    if !should_process_file(filespec, root_path, file_path) {
-        return Ok(());
+    visited: &mut VisitedFiles,
+) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+    let mut stats = HashMap::new();
+    for file in files {
+        if metrics.runtime_exceeded(args.max_runtime) {
+            break;
+        }
+        let ignored = file
+            .parent()
+            .into_iter()
+            .flat_map(Path::ancestors)
+            .take_while(|dir| dir.starts_with(root_path))
+            .any(|dir| is_excluded_dir(args, dir));
+        if ignored {
+            continue;
+        }
+        increment_entries(entries_count, args, file)?;
+        process_file(
+            file,
+            args,
+            root_path,
+            metrics,
+            &mut stats,
+            error_count,
+            filespec,
+            visited,
+        )?;
     }
+    Ok(stats)
+}
 
-    let real_path = match fs::canonicalize(file_path) {
-        Ok(path) => path,
-        Err(err) => {
-            eprintln!(
-                "Error resolving real path for {}: {}",
-                file_path.display(),
-                err
-            );
-            *error_count += 1;
-            return Ok(());
+/// Column layout of the report tables (`--dir-width`, `--lang-width`,
+/// `--doc-comments`, `--no-mixed`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TableLayout {
+    dir_width: usize,
+    lang_width: usize,
+    /// Append a "Doc" count column to stats rows.
+    doc_comments: bool,
+    /// Append an "Avg" code-lines-per-file column to stats rows.
+    averages: bool,
+    /// Show the "Mixed" count column.
+    mixed_column: bool,
+}
+
+impl Default for TableLayout {
+    fn default() -> Self {
+        Self {
+            dir_width: DIR_WIDTH,
+            lang_width: LANG_WIDTH,
+            doc_comments: false,
+            averages: false,
+            mixed_column: true,
         }
-    };
+    }
+}
 
-    if !visited_real_paths.insert(real_path.clone()) {
-        if args.verbose {
-            println!(
-                "Skipping duplicate target for symlinked file: {} -> {}",
-                file_path.display(),
-                real_path.display()
-            );
+impl TableLayout {
+    /// Width of a full stats row: directory, language, files and the code,
+    /// comment and blank counts plus the optional mixed, doc and average columns.
+    fn rule_width(self) -> usize {
+        let counts = 3
+            + usize::from(self.mixed_column)
+            + usize::from(self.doc_comments)
+            + usize::from(self.averages);
+        self.dir_width + 1 + self.lang_width + 1 + 8 + counts * (1 + 10)
+    }
+
+    fn rule(self) -> String {
+        "-".repeat(self.rule_width())
+    }
+
+    /// A layout whose rows are `width` columns wide: the numeric columns
+    /// keep their size, the language column takes a third of the rest (up
+    /// to [`LANG_WIDTH`]) and the directory column everything else. Neither
+    /// shrinks below its minimum, so very narrow widths still overflow.
+    fn fitting(self, width: usize) -> Self {
+        let fixed = Self {
+            dir_width: 0,
+            lang_width: 0,
+            ..self
+        }
+        .rule_width();
+        let flexible = width.saturating_sub(fixed);
+        let lang_width = (flexible / 3).clamp(MIN_LANG_WIDTH, LANG_WIDTH);
+        Self {
+            dir_width: flexible.saturating_sub(lang_width).max(MIN_DIR_WIDTH),
+            lang_width,
+            ..self
         }
-        return Ok(());
     }
 
-    let Some(language) = file_path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .and_then(get_language_from_extension)
-    else {
-        return Ok(());
-    };
-
-    let role_hint = infer_role_from_path(root_path, file_path);
-    match count_lines_with_roles(file_path, role_hint) {
-        Ok(role_split) => {
-            metrics.update(role_split.total_lines());
-            let dir_path = file_path
-                .parent()
-                .map(Path::to_path_buf)
-                .unwrap_or_default();
-            let dir_stats = stats.entry(dir_path).or_default();
-            let show_role = role_split.role_count() > 1;
-            let mut pending: Vec<(CodeRole, LanguageStats)> = Vec::new();
-
-            for (role, bucket) in role_split.iter() {
-                let normalized_stats = normalize_stats(bucket.stats, bucket.total_lines);
-                let total_line_kinds = normalized_stats.code_lines
-                    + normalized_stats.comment_lines
-                    + normalized_stats.blank_lines;
-                if total_line_kinds > 0 || bucket.total_lines == 0 {
-                    let normalized_total = normalized_stats.code_lines
-                        + normalized_stats.comment_lines
-                        + normalized_stats.blank_lines;
-                    metrics.record_role(role, normalized_total, normalized_stats.code_lines);
-                    pending.push((role, normalized_stats));
+    /// The layout for the given flags; `--report-width auto` without a
+    /// terminal keeps the default widths.
+    fn from_args(args: &Args) -> Self {
+        let width = match args.report_width {
+            Some(ReportWidth::Columns(width)) => Some(width),
+            Some(ReportWidth::Auto) => terminal_size().map(|(Width(w), _)| w as usize),
+            None => None,
+        };
+        let layout = Self {
+            dir_width: args.dir_width,
+            lang_width: args.lang_width,
+            doc_comments: args.doc_comments,
+            averages: args.averages,
+            mixed_column: !args.no_mixed,
+        };
+        match width {
+            Some(width) => layout.fitting(width),
+            None => layout,
+        }
+    }
+}
+
+/// Helper function to print stats for a language
+fn format_language_stats_line(
+    prefix: &str,
+    lang: &str,
+    file_count: u64,
+    stats: &LanguageStats,
+    layout: TableLayout,
+) -> String {
+    let mut line = format!(
+        "{:<dir_width$} {:<width$} {:>8} {:>10} {:>10}",
+        prefix,
+        lang,
+        format_number(file_count),
+        format_number(stats.code_lines),
+        format_number(stats.comment_lines),
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    );
+    if layout.mixed_column {
+        let _ = write!(line, " {:>10}", format_number(stats.overlap_lines));
+    }
+    let _ = write!(line, " {:>10}", format_number(stats.blank_lines));
+    if layout.doc_comments {
+        let _ = write!(line, " {:>10}", format_number(stats.doc_comment_lines));
+    }
+    if layout.averages {
+        let average = safe_average(stats.code_lines, file_count);
+        // Grouped like the count columns beside it.
+        let _ = write!(line, " {:>10}", format_rate(average));
+    }
+    line
+}
+
+fn append_size_section(
+    output: &mut String,
+    total_by_language: &HashMap<String, (u64, LanguageStats)>,
+    layout: TableLayout,
+) {
+    let _ = writeln!(output, "\nSize by language:");
+    let _ = writeln!(
+        output,
+        "{:<dir_width$} {:<width$} {:>14} {:>14}",
+        "",
+        "Language",
+        "Bytes",
+        "Chars",
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    );
+    let mut sorted: Vec<_> = total_by_language.iter().collect();
+    sorted.sort_by_key(|(lang, _)| *lang);
+    let mut total = LanguageStats::default();
+    for (lang, (_, stats)) in sorted {
+        total.add_assign(stats);
+        let _ = writeln!(
+            output,
+            "{:<dir_width$} {:<width$} {:>14} {:>14}",
+            "",
+            lang,
+            format_number(stats.bytes),
+            format_number(stats.chars),
+            dir_width = layout.dir_width,
+            width = layout.lang_width
+        );
+    }
+    let _ = writeln!(
+        output,
+        "{:<dir_width$} {:<width$} {:>14} {:>14}",
+        "",
+        "Total",
+        format_number(total.bytes),
+        format_number(total.chars),
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    );
+}
+
+fn write_language_table_header(output: &mut String, layout: TableLayout) {
+    let _ = writeln!(output, "{}", layout.rule());
+    let _ = write!(
+        output,
+        "{:<dir_width$} {:<width$} {:>8} {:>10} {:>10}",
+        "Directory",
+        "Language",
+        "Files",
+        "Code",
+        "Comments",
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    );
+    if layout.mixed_column {
+        let _ = write!(output, " {:>10}", "Mixed");
+    }
+    let _ = write!(output, " {:>10}", "Blank");
+    if layout.doc_comments {
+        let _ = write!(output, " {:>10}", "Doc");
+    }
+    if layout.averages {
+        let _ = write!(output, " {:>10}", "Avg");
+    }
+    let _ = writeln!(output);
+    let _ = writeln!(output, "{}", layout.rule());
+}
+
+/// Optional report sections, derived from the command line.
+#[derive(Debug, Default, Clone)]
+struct ReportOptions {
+    role_breakdown: bool,
+    group_roles: bool,
+    show_sizes: bool,
+    /// Directory rows with fewer total lines are hidden from the table.
+    min_lines: u64,
+    /// JSON directory keys are relative to the scan root.
+    relative_paths: bool,
+    layout: TableLayout,
+    /// Directories with several languages get a "(subtotal)" row.
+    subtotals: bool,
+    /// Append the per-role occurrence summary to the text report.
+    role_summary: bool,
+    /// Explicit `--root-label`; also emitted as JSON metadata.
+    root_label: Option<String>,
+    /// The path as given on the command line, shown in the heading when no
+    /// label is set.
+    scanned_root: String,
+    /// Only per-language totals are meaningful (`--streaming-totals`).
+    totals_only: bool,
+    csv_delimiter: char,
+    /// Append the `--comment-types` table to the text report.
+    comment_types: bool,
+}
 
-                    if args.verbose {
-                        println!("File: {}", file_path.display());
-                        if show_role {
-                            println!("  Role: {:?}", role);
-                        }
-                        println!(
-                            "  Code lines: {}",
-                            format_number(normalized_stats.code_lines)
-                        );
-                        println!(
-                            "  Comment lines: {}",
-                            format_number(normalized_stats.comment_lines)
-                        );
-                        println!(
-                            "  Blank lines: {}",
-                            format_number(normalized_stats.blank_lines)
-                        );
-                        println!(
-                            "  Mixed code/comment lines: {}",
-                            format_number(normalized_stats.overlap_lines)
-                        );
-                        println!();
-                    }
-                }
-            }
+impl ReportOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            role_breakdown: args.role_breakdown,
+            group_roles: args.group_roles,
+            show_sizes: args.bytes,
+            min_lines: args.min_lines,
+            relative_paths: args.relative_paths,
+            layout: TableLayout::from_args(args),
+            subtotals: !args.no_subtotals,
+            role_summary: args.role_breakdown || args.group_roles || args.verbose,
+            root_label: args.root_label.clone(),
+            scanned_root: args
+                .relocate_root
+                .clone()
+                .unwrap_or_else(|| args.path.clone()),
+            totals_only: args.streaming_totals,
+            csv_delimiter: args.csv_delimiter,
+            comment_types: args.comment_types,
+        }
+    }
+}
 
-            if !pending.is_empty() {
-                let entry = dir_stats
-                    .language_stats
-                    .entry(language.to_string())
-                    .or_default();
-                entry.record_roles(&pending);
+/// Quote and escape `value` as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", u32::from(c));
             }
-        }
-        Err(err) => {
-            eprintln!("Error counting lines in {}: {}", file_path.display(), err);
-            *error_count += 1;
+            c => quoted.push(c),
         }
     }
-
-    Ok(())
+    quoted.push('"');
+    quoted
 }
 
-#[allow(clippy::too_many_arguments)]
-fn scan_directory_impl(
-    path: &Path,
-    args: &Args,
-    root_path: &Path,
-    metrics: &mut PerformanceMetrics,
-    current_depth: usize,
-    entries_count: &mut usize,
-    error_count: &mut usize,
-    filespec: Option<&Pattern>,
-    visited_real_paths: &mut HashSet<PathBuf>,
-) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
-    if current_depth > args.max_depth {
-        eprintln!(
-            "Warning: Maximum directory depth ({}) reached at {}",
-            args.max_depth,
-            path.display()
+fn json_stats_object(files: u64, stats: &LanguageStats, show_sizes: bool) -> String {
+    let mut object = format!(
+        "{{\"files\": {}, \"code\": {}, \"comments\": {}, \"blank\": {}, \"mixed\": {}, \"ignored\": {}",
+        files,
+        stats.code_lines,
+        stats.comment_lines,
+        stats.blank_lines,
+        stats.overlap_lines,
+        stats.ignored_lines
+    );
+    if show_sizes {
+        let _ = write!(
+            object,
+            ", \"bytes\": {}, \"chars\": {}",
+            stats.bytes, stats.chars
         );
-        *error_count += 1;
-        return Ok(HashMap::new());
     }
+    object.push('}');
+    object
+}
 
-    if args.non_recursive && current_depth > 0 {
-        return Ok(HashMap::new());
+/// `path` re-expressed under `virtual_root` instead of `root` (`--relocate-root`);
+/// paths outside the scan root are left alone.
+fn relocate_path(path: &Path, root: &Path, virtual_root: &str) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) => Path::new(virtual_root).join(relative),
+        Err(_) => path.to_path_buf(),
     }
+}
 
-    let mut stats: HashMap<PathBuf, DirectoryStats> =
-        HashMap::with_capacity(if path.is_dir() { 128 } else { 1 });
-
-    if is_ignored_dir(path) || args.ignore.iter().any(|d| path.ends_with(Path::new(d))) {
-        return Ok(stats);
+/// `stats` with every directory moved under `virtual_root`.
+fn relocate_stats(
+    stats: HashMap<PathBuf, DirectoryStats>,
+    root: &Path,
+    virtual_root: &str,
+) -> HashMap<PathBuf, DirectoryStats> {
+    let mut relocated = HashMap::new();
+    for (dir, stat) in stats {
+        merge_directory_stats(
+            &mut relocated,
+            relocate_path(&dir, root, virtual_root),
+            stat,
+        );
     }
+    relocated
+}
 
-    let metadata = match fetch_metadata(path) {
-        Ok(meta) => meta,
-        Err(err) => {
-            eprintln!("Error reading metadata for {}: {}", path.display(), err);
-            *error_count += 1;
-            return Ok(stats);
-        }
-    };
-
-    if metadata.is_file() {
-        increment_entries(entries_count, args, path)?;
-        {
-            let mut ctx = ProcCtx {
-                args,
-                root_path,
-                metrics,
-                stats: &mut stats,
-                error_count,
-                filespec,
-                visited_real_paths,
-            };
-            process_entry_file(&mut ctx, path)?;
-        }
-        return Ok(stats);
-    } else if !metadata.is_dir() {
-        return Ok(stats);
+/// Directory key for JSON output: relative to `root_path` (`.` for the root
+/// itself) unless absolute keys were requested.
+fn json_directory_key(path: &Path, root_path: &Path, relative: bool) -> String {
+    if !relative {
+        return path.to_string_lossy().into_owned();
     }
+    match path.strip_prefix(root_path) {
+        Ok(p) if p.as_os_str().is_empty() => ".".to_string(),
+        Ok(p) => p.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    }
+}
 
-    let read_dir = match read_dir_stream(path) {
-        Ok(iter) => iter,
-        Err(err) => {
-            eprintln!("Error reading directory {}: {}", path.display(), err);
-            *error_count += 1;
-            return Ok(stats);
-        }
-    };
-
-    for entry_result in read_dir {
-        let entry = match entry_result {
-            Ok(entry) => entry,
-            Err(err) => {
-                eprintln!("Error reading entry in {}: {}", path.display(), err);
-                *error_count += 1;
-                continue;
-            }
-        };
+/// One `--format jsonl` line: a self-contained object for a counted file
+/// (or one language/role bucket of it).
+fn format_jsonl_record(
+    path: &str,
+    language: &str,
+    role: CodeRole,
+    stats: &LanguageStats,
+) -> String {
+    format!(
+        "{{\"path\": {}, \"language\": {}, \"role\": {}, \"code\": {}, \"comment\": {}, \"blank\": {}}}",
+        json_string(path),
+        json_string(language),
+        json_string(&role.label().to_ascii_lowercase()),
+        stats.code_lines,
+        stats.comment_lines,
+        stats.blank_lines
+    )
+}
 
-        let entry_path = entry.path();
-        let file_type = match entry_file_type(&entry) {
-            Ok(ft) => ft,
-            Err(err) => {
-                eprintln!("Error reading type for {}: {}", entry_path.display(), err);
-                *error_count += 1;
-                continue;
-            }
-        };
+/// JSON Schema for the document `build_json_report` writes; keep the two in
+/// step.
+const JSON_REPORT_SCHEMA: &str = include_str!("report.schema.json");
 
-        increment_entries(entries_count, args, &entry_path)?;
+/// The `--format json` report: run metadata, per-directory and per-language
+/// counts, and the grand total. Keys are sorted so output is diff-friendly.
+fn build_json_report(
+    root_path: &Path,
+    stats: &HashMap<PathBuf, DirectoryStats>,
+    files_processed: u64,
+    lines_processed: u64,
+    error_count: usize,
+    options: &ReportOptions,
+) -> String {
+    let mut output = String::new();
+    let _ = writeln!(output, "{{");
+    let _ = writeln!(
+        output,
+        "  \"version\": {},",
+        json_string(env!("CARGO_PKG_VERSION"))
+    );
+    if let Some(label) = &options.root_label {
+        let _ = writeln!(output, "  \"label\": {},", json_string(label));
+    }
+    if !options.relative_paths {
+        let _ = writeln!(
+            output,
+            "  \"root\": {},",
+            json_string(&root_path.to_string_lossy())
+        );
+    }
+    let _ = writeln!(output, "  \"files_processed\": {},", files_processed);
+    let _ = writeln!(output, "  \"lines_processed\": {},", lines_processed);
+    let _ = writeln!(output, "  \"errors\": {},", error_count);
 
-        if file_type.is_dir() && !file_type.is_symlink() {
-            if args.non_recursive {
+    let mut directories: Vec<_> = stats
+        .iter()
+        .map(|(path, dir_stats)| {
+            (
+                json_directory_key(path, root_path, options.relative_paths),
+                dir_stats,
+            )
+        })
+        .collect();
+    directories.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut total_by_language: HashMap<&str, (u64, LanguageStats)> = HashMap::new();
+    // --streaming-totals files everything under the root, so no directory
+    // entry would be true; the object stays empty.
+    let list_directories = !options.totals_only;
+    let _ = writeln!(output, "  \"directories\": {{");
+    for (dir_index, (key, dir_stats)) in directories.iter().enumerate() {
+        if list_directories {
+            let _ = writeln!(output, "    {}: {{", json_string(key));
+        }
+        let mut languages: Vec<_> = dir_stats.language_stats.iter().collect();
+        languages.sort_by_key(|(lang, _)| *lang);
+        for (lang_index, (lang, entry)) in languages.iter().enumerate() {
+            let (files, lang_stats) = entry.summary();
+            let (total_files, total_stats) = total_by_language.entry(lang.as_str()).or_default();
+            *total_files += files;
+            total_stats.add_assign(&lang_stats);
+            if !list_directories {
                 continue;
             }
-            match scan_directory_impl(
-                &entry_path,
-                args,
-                root_path,
-                metrics,
-                current_depth + 1,
-                entries_count,
-                error_count,
-                filespec,
-                visited_real_paths,
-            ) {
-                Ok(sub_stats) => {
-                    for (dir, stat) in sub_stats {
-                        merge_directory_stats(&mut stats, dir, stat);
-                    }
-                }
-                Err(err) => {
-                    eprintln!("Error scanning directory {}: {}", entry_path.display(), err);
-                    *error_count += 1;
-                }
-            }
-        } else if file_type.is_file() && !file_type.is_symlink() {
-            let mut ctx = ProcCtx {
-                args,
-                root_path,
-                metrics,
-                stats: &mut stats,
-                error_count,
-                filespec,
-                visited_real_paths,
+            let comma = if lang_index + 1 < languages.len() {
+                ","
+            } else {
+                ""
             };
-            process_entry_file(&mut ctx, &entry_path)?;
-        } else if file_type.is_symlink() {
-            let mut ctx = ProcCtx {
-                args,
-                root_path,
-                metrics,
-                stats: &mut stats,
-                error_count,
-                filespec,
-                visited_real_paths,
+            let _ = writeln!(
+                output,
+                "      {}: {}{}",
+                json_string(lang),
+                json_stats_object(files, &lang_stats, options.show_sizes),
+                comma
+            );
+        }
+        if list_directories {
+            let comma = if dir_index + 1 < directories.len() {
+                ","
+            } else {
+                ""
             };
-            handle_symlink(&mut ctx, &entry_path)?;
+            let _ = writeln!(output, "    }}{}", comma);
         }
     }
+    let _ = writeln!(output, "  }},");
 
-    Ok(stats)
+    let mut totals: Vec<_> = total_by_language.into_iter().collect();
+    totals.sort_by_key(|(lang, _)| *lang);
+    let mut grand_files = 0;
+    let mut grand_total = LanguageStats::default();
+    let _ = writeln!(output, "  \"languages\": {{");
+    for (index, (lang, (files, lang_stats))) in totals.iter().enumerate() {
+        grand_files += files;
+        grand_total.add_assign(lang_stats);
+        let comma = if index + 1 < totals.len() { "," } else { "" };
+        let _ = writeln!(
+            output,
+            "    {}: {}{}",
+            json_string(lang),
+            json_stats_object(*files, lang_stats, options.show_sizes),
+            comma
+        );
+    }
+    let _ = writeln!(output, "  }},");
+    let roles: Vec<_> = CodeRole::ALL
+        .into_iter()
+        .filter_map(|role| Some((role, role_totals(stats, role)?)))
+        .collect();
+    let _ = writeln!(output, "  \"roles\": {{");
+    for (index, (role, (files, role_stats))) in roles.iter().enumerate() {
+        let comma = if index + 1 < roles.len() { "," } else { "" };
+        let _ = writeln!(
+            output,
+            "    {}: {}{}",
+            json_string(&role.label().to_lowercase()),
+            json_stats_object(*files, role_stats, options.show_sizes),
+            comma
+        );
+    }
+    let _ = writeln!(output, "  }},");
+    let _ = writeln!(
+        output,
+        "  \"total\": {}",
+        json_stats_object(grand_files, &grand_total, options.show_sizes)
+    );
+    let _ = writeln!(output, "}}");
+    output
 }
 
-fn scan_directory(
-    path: &Path,
-    args: &Args,
-    _current_dir: &Path,
-    metrics: &mut PerformanceMetrics,
-    current_depth: usize,
-    entries_count: &mut usize,
-    error_count: &mut usize,
-) -> io::Result<HashMap<PathBuf, DirectoryStats>> {
-    let filespec_pattern = match args.filespec.as_deref() {
-        Some(spec) => Some(Pattern::new(spec).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Invalid filespec pattern '{}': {}", spec, err),
-            )
-        })?),
-        None => None,
+/// Parse `--csv-delimiter`: any single character other than a quote or a
+/// line break; `\t` is accepted for a tab.
+fn parse_csv_delimiter(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    let delimiter = match (value, chars.next(), chars.next()) {
+        ("\\t", _, _) => '\t',
+        (_, Some(c), None) => c,
+        _ => return Err(format!("'{}' is not a single character", value)),
     };
+    if matches!(delimiter, '"' | '\n' | '\r') {
+        return Err(format!("{:?} cannot separate CSV fields", delimiter));
+    }
+    Ok(delimiter)
+}
 
-    let root_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-    let mut visited_real_paths = HashSet::new();
+/// `field` as a CSV field: quoted, with embedded quotes doubled, when it
+/// contains the delimiter, a quote or a line break (RFC 4180).
+fn csv_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
 
-    scan_directory_impl(
-        &root_path,
-        args,
-        &root_path,
-        metrics,
-        current_depth,
-        entries_count,
-        error_count,
-        filespec_pattern.as_ref(),
-        &mut visited_real_paths,
-    )
+/// The `--format csv` report: a header, then one row per directory and
+/// language, directories keyed as in the JSON report.
+fn build_csv_report(
+    root_path: &Path,
+    stats: &HashMap<PathBuf, DirectoryStats>,
+    options: &ReportOptions,
+) -> String {
+    let delimiter = options.csv_delimiter.to_string();
+    let mut header = vec![
+        "directory",
+        "language",
+        "files",
+        "code",
+        "comments",
+        "blank",
+        "mixed",
+        "ignored",
+    ];
+    if options.show_sizes {
+        header.extend(["bytes", "chars"]);
+    }
+    let mut output = header.join(&delimiter);
+    output.push_str("\r\n");
+
+    let mut directories: Vec<_> = stats
+        .iter()
+        .map(|(path, dir_stats)| {
+            (
+                json_directory_key(path, root_path, options.relative_paths),
+                dir_stats,
+            )
+        })
+        .collect();
+    directories.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, dir_stats) in directories {
+        let mut languages: Vec<_> = dir_stats.language_stats.iter().collect();
+        languages.sort_by_key(|(lang, _)| *lang);
+        for (lang, entry) in languages {
+            let (files, lang_stats) = entry.summary();
+            let mut row = vec![
+                csv_field(&key, options.csv_delimiter),
+                csv_field(lang, options.csv_delimiter),
+            ];
+            let mut counts = vec![
+                files,
+                lang_stats.code_lines,
+                lang_stats.comment_lines,
+                lang_stats.blank_lines,
+                lang_stats.overlap_lines,
+                lang_stats.ignored_lines,
+            ];
+            if options.show_sizes {
+                counts.extend([lang_stats.bytes, lang_stats.chars]);
+            }
+            row.extend(counts.iter().map(u64::to_string));
+            output.push_str(&row.join(&delimiter));
+            output.push_str("\r\n");
+        }
+    }
+    output
+}
+
+/// One role's (files, stats) over every directory and language, as the
+/// `--role-breakdown` sections total them; `None` when no file has the role.
+fn role_totals(
+    stats: &HashMap<PathBuf, DirectoryStats>,
+    role: CodeRole,
+) -> Option<(u64, LanguageStats)> {
+    let mut totals: Option<(u64, LanguageStats)> = None;
+    for entry in stats
+        .values()
+        .flat_map(|dir_stats| dir_stats.language_stats.values())
+    {
+        if let Some((files, role_stats)) = entry.role_summary(role) {
+            let (total_files, total_stats) = totals.get_or_insert_with(Default::default);
+            *total_files += files;
+            total_stats.add_assign(&role_stats);
+        }
+    }
+    totals
 }
 
-/// Helper function to print stats for a language
-fn format_language_stats_line(
-    prefix: &str,
-    lang: &str,
-    file_count: u64,
-    stats: &LanguageStats,
-) -> String {
-    format!(
-        "{:<40} {:<width$} {:>8} {:>10} {:>10} {:>10} {:>10}",
-        prefix,
-        lang,
-        format_number(file_count),
-        format_number(stats.code_lines),
-        format_number(stats.comment_lines),
-        format_number(stats.overlap_lines),
-        format_number(stats.blank_lines),
-        width = LANG_WIDTH
-    )
+/// Per-language (files, stats) summed over every directory.
+fn totals_by_language(
+    stats: &HashMap<PathBuf, DirectoryStats>,
+) -> HashMap<String, (u64, LanguageStats)> {
+    let mut totals: HashMap<String, (u64, LanguageStats)> = HashMap::new();
+    for dir_stats in stats.values() {
+        for (lang, entry) in &dir_stats.language_stats {
+            let (file_count, lang_stats) = entry.summary();
+            let (total_count, total_stats) = totals.entry(lang.clone()).or_default();
+            *total_count += file_count;
+            total_stats.add_assign(&lang_stats);
+        }
+    }
+    totals
 }
 
-fn write_language_table_header(output: &mut String) {
-    let _ = writeln!(output, "{}", "-".repeat(112));
-    let _ = writeln!(
-        output,
-        "{:<40} {:<width$} {:>8} {:>10} {:>10} {:>10} {:>10}",
-        "Directory",
-        "Language",
-        "Files",
-        "Code",
-        "Comments",
-        "Mixed",
-        "Blank",
-        width = LANG_WIDTH
-    );
-    let _ = writeln!(output, "{}", "-".repeat(112));
+/// `--languages-with-counts`: languages found, most code lines first.
+fn format_language_leaderboard(stats: &HashMap<PathBuf, DirectoryStats>) -> String {
+    let mut totals: Vec<_> = totals_by_language(stats).into_iter().collect();
+    totals.sort_by(|(a_lang, (_, a)), (b_lang, (_, b))| {
+        b.code_lines
+            .cmp(&a.code_lines)
+            .then_with(|| a_lang.cmp(b_lang))
+    });
+    let mut output = String::new();
+    for (lang, (_, lang_stats)) in totals {
+        let _ = writeln!(
+            output,
+            "{:<20} {:>12}",
+            lang,
+            format_number(lang_stats.code_lines)
+        );
+    }
+    output
 }
 
 fn build_analysis_report(
@@ -2921,42 +7836,92 @@ fn build_analysis_report(
     files_processed: u64,
     lines_processed: u64,
     error_count: usize,
-    role_breakdown: bool,
+    options: &ReportOptions,
 ) -> String {
     let mut output = String::new();
     let mut sorted_stats: Vec<_> = stats.iter().collect();
     sorted_stats.sort_by(|(a, _), (b, _)| a.to_string_lossy().cmp(&b.to_string_lossy()));
 
-    let mut total_by_language: HashMap<String, (u64, LanguageStats)> = HashMap::new();
+    let total_by_language = totals_by_language(stats);
 
-    let _ = writeln!(output, "\n\nDetailed source code analysis:");
-    write_language_table_header(&mut output);
+    let heading = options
+        .root_label
+        .as_deref()
+        .unwrap_or(&options.scanned_root);
+    if heading.is_empty() {
+        let _ = writeln!(output, "\n\nDetailed source code analysis:");
+    } else {
+        let _ = writeln!(output, "\n\nDetailed source code analysis of {}:", heading);
+    }
+    let layout = options.layout;
+    write_language_table_header(&mut output, layout);
+    let mut suppressed_rows = 0usize;
 
     for (path, dir_stats) in &sorted_stats {
-        let display_path = format_directory_display(path, current_dir);
+        let display_path = format_directory_display(path, current_dir, layout.dir_width);
         let mut languages: Vec<_> = dir_stats.language_stats.iter().collect();
-        languages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        languages.sort_by_key(|(a, _)| *a);
+        let show_subtotal = options.subtotals && languages.len() > 1;
+        let mut subtotal = (0, LanguageStats::default());
 
         for (lang, entry) in languages {
             let (file_count, lang_stats) = entry.summary();
-            let line = format_language_stats_line(&display_path, lang, file_count, &lang_stats);
+            subtotal.0 += file_count;
+            subtotal.1.add_assign(&lang_stats);
+            let row_lines =
+                lang_stats.code_lines + lang_stats.comment_lines + lang_stats.blank_lines;
+            if options.totals_only {
+                // Directory rows are not meaningful; only totals are printed.
+            } else if row_lines < options.min_lines {
+                suppressed_rows += 1;
+            } else {
+                let line = format_language_stats_line(
+                    &display_path,
+                    lang,
+                    file_count,
+                    &lang_stats,
+                    layout,
+                );
+                let _ = writeln!(output, "{}", line);
+            }
+        }
+
+        let (subtotal_files, subtotal_stats) = subtotal;
+        let subtotal_lines =
+            subtotal_stats.code_lines + subtotal_stats.comment_lines + subtotal_stats.blank_lines;
+        if show_subtotal && !options.totals_only && subtotal_lines >= options.min_lines {
+            let line = format_language_stats_line(
+                &display_path,
+                SUBTOTAL_LABEL,
+                subtotal_files,
+                &subtotal_stats,
+                layout,
+            );
             let _ = writeln!(output, "{}", line);
-            let (total_count, total_stats) = total_by_language
-                .entry(lang.to_string())
-                .or_insert((0, LanguageStats::default()));
-            *total_count += file_count;
-            total_stats.add_assign(&lang_stats);
         }
     }
 
-    let _ = writeln!(output, "{:-<112}", "");
+    if suppressed_rows > 0 {
+        let _ = writeln!(
+            output,
+            "({} row(s) under --min-lines {} hidden; still included in totals)",
+            suppressed_rows, options.min_lines
+        );
+    }
+    if options.totals_only {
+        let _ = writeln!(
+            output,
+            "(per-directory detail not kept with --streaming-totals)"
+        );
+    }
+    let _ = writeln!(output, "{}", layout.rule());
     let _ = writeln!(output, "Totals by language:");
 
     let mut sorted_totals: Vec<_> = total_by_language.iter().collect();
-    sorted_totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+    sorted_totals.sort_by_key(|(a, _)| *a);
 
     for (lang, (file_count, stats)) in sorted_totals {
-        let line = format_language_stats_line("", lang, *file_count, stats);
+        let line = format_language_stats_line("", lang, *file_count, stats, layout);
         let _ = writeln!(output, "{}", line);
     }
 
@@ -2966,10 +7931,17 @@ fn build_analysis_report(
         grand_total.comment_lines += stats.comment_lines;
         grand_total.blank_lines += stats.blank_lines;
         grand_total.overlap_lines += stats.overlap_lines;
+        grand_total.ignored_lines += stats.ignored_lines;
+    }
+
+    if options.show_sizes {
+        append_size_section(&mut output, &total_by_language, layout);
     }
 
-    if role_breakdown {
-        append_role_breakdown_sections(&mut output, current_dir, &sorted_stats);
+    if options.group_roles {
+        append_grouped_role_section(&mut output, current_dir, &sorted_stats, layout);
+    } else if options.role_breakdown {
+        append_role_breakdown_sections(&mut output, current_dir, &sorted_stats, layout);
     }
 
     if files_processed > 0 || lines_processed > 0 {
@@ -3004,16 +7976,18 @@ fn build_analysis_report(
             )
             .bright_yellow()
         );
-        let _ = writeln!(
-            output,
-            "Mixed lines:    {} ({})",
-            format_number(grand_total.overlap_lines).bright_yellow(),
-            format!(
-                "{:.1}%",
-                safe_percentage(grand_total.overlap_lines, lines_processed)
-            )
-            .bright_yellow()
-        );
+        if layout.mixed_column {
+            let _ = writeln!(
+                output,
+                "Mixed lines:    {} ({})",
+                format_number(grand_total.overlap_lines).bright_yellow(),
+                format!(
+                    "{:.1}%",
+                    safe_percentage(grand_total.overlap_lines, lines_processed)
+                )
+                .bright_yellow()
+            );
+        }
         let _ = writeln!(
             output,
             "Blank lines:    {} ({})",
@@ -3024,6 +7998,18 @@ fn build_analysis_report(
             )
             .bright_yellow()
         );
+        if grand_total.ignored_lines > 0 {
+            let _ = writeln!(
+                output,
+                "Ignored lines:  {} ({})",
+                format_number(grand_total.ignored_lines).bright_yellow(),
+                format!(
+                    "{:.1}%",
+                    safe_percentage(grand_total.ignored_lines, lines_processed)
+                )
+                .bright_yellow()
+            );
+        }
 
         if error_count > 0 {
             let _ = writeln!(
@@ -3038,13 +8024,37 @@ fn build_analysis_report(
     output
 }
 
+/// Sum every directory/language entry into overall totals: (files, stats).
+fn compute_grand_totals(stats: &HashMap<PathBuf, DirectoryStats>) -> (u64, LanguageStats) {
+    let mut files = 0;
+    let mut totals = LanguageStats::default();
+    for dir_stats in stats.values() {
+        for entry in dir_stats.language_stats.values() {
+            let (file_count, lang_stats) = entry.summary();
+            files += file_count;
+            totals.add_assign(&lang_stats);
+        }
+    }
+    (files, totals)
+}
+
+/// Stable, script-friendly one-liner for `--totals` (raw integers, no separators or colour).
+fn format_totals_line(stats: &HashMap<PathBuf, DirectoryStats>) -> String {
+    let (files, totals) = compute_grand_totals(stats);
+    format!(
+        "code={} comments={} blank={} mixed={} files={}",
+        totals.code_lines, totals.comment_lines, totals.blank_lines, totals.overlap_lines, files
+    )
+}
+
 fn append_role_breakdown_sections(
     output: &mut String,
     current_dir: &Path,
     sorted_stats: &[(&PathBuf, &DirectoryStats)],
+    layout: TableLayout,
 ) {
     for role in CodeRole::ALL {
-        append_single_role_section(output, current_dir, sorted_stats, role);
+        append_single_role_section(output, current_dir, sorted_stats, role, layout);
     }
 }
 
@@ -3053,21 +8063,28 @@ fn append_single_role_section(
     current_dir: &Path,
     sorted_stats: &[(&PathBuf, &DirectoryStats)],
     role: CodeRole,
+    layout: TableLayout,
 ) {
     let mut totals_by_language: HashMap<String, (u64, LanguageStats)> = HashMap::new();
     let mut has_rows = false;
     for (path, dir_stats) in sorted_stats {
-        let display_path = format_directory_display(path, current_dir);
+        let display_path = format_directory_display(path, current_dir, layout.dir_width);
         let mut languages: Vec<_> = dir_stats.language_stats.iter().collect();
-        languages.sort_by(|(a, _), (b, _)| a.cmp(b));
+        languages.sort_by_key(|(a, _)| *a);
         for (lang, entry) in languages {
             if let Some((file_count, lang_stats)) = entry.role_summary(role) {
                 if !has_rows {
                     let _ = writeln!(output, "\nRole breakdown ({})", role.label());
-                    write_language_table_header(output);
+                    write_language_table_header(output, layout);
                     has_rows = true;
                 }
-                let line = format_language_stats_line(&display_path, lang, file_count, &lang_stats);
+                let line = format_language_stats_line(
+                    &display_path,
+                    lang,
+                    file_count,
+                    &lang_stats,
+                    layout,
+                );
                 let _ = writeln!(output, "{}", line);
                 let (total_count, total_stats) = totals_by_language
                     .entry(lang.to_string())
@@ -3079,20 +8096,97 @@ fn append_single_role_section(
     }
 
     if has_rows {
-        let _ = writeln!(output, "{:-<112}", "");
+        let _ = writeln!(output, "{}", layout.rule());
         let _ = writeln!(output, "Totals by language ({}):", role.label());
         let mut sorted_totals: Vec<_> = totals_by_language.iter().collect();
-        sorted_totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        sorted_totals.sort_by_key(|(a, _)| *a);
         for (lang, (file_count, stats)) in sorted_totals {
-            let line = format_language_stats_line("", lang, *file_count, stats);
+            let line = format_language_stats_line("", lang, *file_count, stats, layout);
             let _ = writeln!(output, "{}", line);
         }
-    } else {
+    } else if role != CodeRole::FeatureGated {
         let _ = writeln!(output, "\nRole breakdown ({})", role.label());
         let _ = writeln!(output, "No {} data collected.", role.label().to_lowercase());
     }
 }
 
+/// Code/comment counts for one language with both roles side by side;
+/// a role with no files reports zeros.
+fn format_grouped_role_line(
+    prefix: &str,
+    lang: &str,
+    entry: &LanguageEntry,
+    layout: TableLayout,
+) -> String {
+    let role_stats = |role| entry.role_summary(role).map(|(_, s)| s).unwrap_or_default();
+    let main = role_stats(CodeRole::Mainline);
+    let test = role_stats(CodeRole::Test);
+    format!(
+        "{:<dir_width$} {:<width$} {:>10} {:>10} {:>10} {:>10}",
+        prefix,
+        lang,
+        format_number(main.code_lines),
+        format_number(test.code_lines),
+        format_number(main.comment_lines),
+        format_number(test.comment_lines),
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    )
+}
+
+fn append_grouped_role_section(
+    output: &mut String,
+    current_dir: &Path,
+    sorted_stats: &[(&PathBuf, &DirectoryStats)],
+    layout: TableLayout,
+) {
+    let _ = writeln!(output, "\nRole breakdown (grouped)");
+    let _ = writeln!(output, "{}", layout.rule());
+    let _ = writeln!(
+        output,
+        "{:<dir_width$} {:<width$} {:>10} {:>10} {:>10} {:>10}",
+        "Directory",
+        "Language",
+        "Code main",
+        "Code test",
+        "Cmnt main",
+        "Cmnt test",
+        dir_width = layout.dir_width,
+        width = layout.lang_width
+    );
+    let _ = writeln!(output, "{}", layout.rule());
+
+    let mut totals_by_language: HashMap<String, LanguageEntry> = HashMap::new();
+    for (path, dir_stats) in sorted_stats {
+        let display_path = format_directory_display(path, current_dir, layout.dir_width);
+        let mut languages: Vec<_> = dir_stats.language_stats.iter().collect();
+        languages.sort_by_key(|(a, _)| *a);
+        for (lang, entry) in languages {
+            let _ = writeln!(
+                output,
+                "{}",
+                format_grouped_role_line(&display_path, lang, entry, layout)
+            );
+            totals_by_language
+                .entry(lang.to_string())
+                .or_default()
+                .absorb(entry.clone());
+        }
+    }
+
+    let _ = writeln!(output, "{}", layout.rule());
+    let _ = writeln!(output, "Totals by language (grouped):");
+    let mut sorted_totals: Vec<_> = totals_by_language.iter().collect();
+    sorted_totals.sort_by_key(|(a, _)| *a);
+    for (lang, entry) in sorted_totals {
+        let _ = writeln!(
+            output,
+            "{}",
+            format_grouped_role_line("", lang, entry, layout)
+        );
+    }
+}
+
 fn main() -> io::Result<()> {
     run_with_args(current_args())
 }
@@ -3148,35 +8242,61 @@ fn print_supported_languages() {
         ("COBOL", colored::Color::Blue),
         ("DCL", colored::Color::White),
         ("Dockerfile", colored::Color::Cyan),
+        ("Dotenv", colored::Color::White),
+        ("ERB", colored::Color::Red),
+        ("Elixir", colored::Color::Magenta),
+        ("Erlang", colored::Color::Red),
+        ("F#", colored::Color::Blue),
         ("Fortran", colored::Color::Magenta),
         ("Go", colored::Color::Cyan),
+        ("GraphQL", colored::Color::Magenta),
+        ("Groovy", colored::Color::Cyan),
         ("HCL", colored::Color::Magenta),
         ("HTML", colored::Color::Red),
         ("INI", colored::Color::White),
         ("IPLAN", colored::Color::White),
         ("JSON", colored::Color::Yellow),
+        ("JSON5", colored::Color::Yellow),
+        ("JSONC", colored::Color::Yellow),
         ("JSX", colored::Color::Yellow),
         ("Java", colored::Color::Red),
         ("JavaScript", colored::Color::Yellow),
+        ("Jinja2", colored::Color::Red),
+        ("Kotlin", colored::Color::Magenta),
+        ("Lockfile", colored::Color::White),
+        ("Lua", colored::Color::Blue),
         ("Makefile", colored::Color::Red),
+        ("MATLAB", colored::Color::Yellow),
+        ("MoonScript", colored::Color::Yellow),
         ("Mustache", colored::Color::Red),
+        ("OCaml", colored::Color::Yellow),
+        ("Objective-C", colored::Color::Blue),
+        ("Objective-C++", colored::Color::Blue),
         ("PHP", colored::Color::Magenta),
+        ("PL/SQL", colored::Color::Red),
         ("Pascal", colored::Color::Green),
         ("Perl", colored::Color::Cyan),
         ("PowerShell", colored::Color::Blue),
         ("Protobuf", colored::Color::Magenta),
         ("Python", colored::Color::Yellow),
+        ("R", colored::Color::Blue),
+        ("R Markdown", colored::Color::Blue),
         ("ReStructuredText", colored::Color::Green),
         ("Ruby", colored::Color::Red),
         ("Rust", colored::Color::Red),
+        ("SQL", colored::Color::Cyan),
         ("SVG", colored::Color::Yellow),
         ("Scala", colored::Color::Red),
         ("Shell", colored::Color::Green),
+        ("Starlark", colored::Color::Green),
         ("TCL", colored::Color::Magenta),
         ("TOML", colored::Color::Yellow),
         ("TSX", colored::Color::Blue),
         ("TypeScript", colored::Color::Blue),
+        ("TypeScript (decl)", colored::Color::Blue),
         ("Velocity", colored::Color::Cyan),
+        ("Verilog", colored::Color::Green),
+        ("VHDL", colored::Color::Green),
         ("XML", colored::Color::Yellow),
         ("XSL", colored::Color::Yellow),
         ("YAML", colored::Color::Green),
@@ -3213,30 +8333,122 @@ fn print_supported_languages() {
     println!();
 }
 
-fn run_cli_with_metrics(args: Args, metrics: &mut PerformanceMetrics) -> io::Result<()> {
+/// The single-buffer report for `mdkloc --lang LANG -`.
+fn format_stdin_report(
+    language: &str,
+    stats: &LanguageStats,
+    total_lines: u64,
+    format: OutputFormat,
+) -> io::Result<String> {
+    match format {
+        OutputFormat::Text => Ok(format!(
+            "<stdin> ({})\nLines:    {}\nCode:     {}\nComments: {}\nMixed:    {}\nBlank:    {}\n",
+            language,
+            total_lines,
+            stats.code_lines,
+            stats.comment_lines,
+            stats.overlap_lines,
+            stats.blank_lines
+        )),
+        OutputFormat::Json => Ok(format!(
+            "{{\n  \"version\": {},\n  \"language\": {},\n  \"lines_processed\": {},\n  \"total\": {}\n}}\n",
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(language),
+            total_lines,
+            json_stats_object(1, stats, false)
+        )),
+        OutputFormat::Jsonl => Ok(format!(
+            "{}\n",
+            format_jsonl_record("-", language, CodeRole::Mainline, stats)
+        )),
+        OutputFormat::Csv => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--format csv is not supported when reading stdin",
+        )),
+    }
+}
+
+fn run_cli_with_metrics(mut args: Args, metrics: &mut PerformanceMetrics) -> io::Result<()> {
     if args.languages {
         print_supported_languages();
         return Ok(());
     }
+    if args.print_schema {
+        print!("{}", JSON_REPORT_SCHEMA);
+        return Ok(());
+    }
+    if let Some(file) = &args.dump_roles {
+        let hint = infer_role_from_path(Path::new(&args.path), file);
+        print!(
+            "{}",
+            format_role_dump(file, hint, args.test_scope, args.feature_roles)?
+        );
+        return Ok(());
+    }
 
-    println!(
-        "{} {}",
-        env!("CARGO_PKG_NAME").bright_cyan().bold(),
-        format!("v{}", env!("CARGO_PKG_VERSION")).bright_yellow()
-    );
+    // `--comment-style` overrides take precedence over `--config` definitions.
+    args.custom_languages = args.comment_styles.clone();
+    if let Some(config_path) = &args.config {
+        args.custom_languages
+            .extend(load_custom_languages(config_path)?);
+    }
+
+    if args.path == "-" {
+        let language = args.lang.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reading stdin (`-`) needs --lang to pick the counter",
+            )
+        })?;
+        let (stats, total_lines) =
+            count_lines_with_stats_from_reader(language, Box::new(io::stdin()), &args)?;
+        print!(
+            "{}",
+            format_stdin_report(language, &stats, total_lines, args.format)?
+        );
+        return Ok(());
+    }
+    if args.lang.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--lang only applies when reading stdin (`-`)",
+        ));
+    }
+    if args.streaming_totals && args.format == OutputFormat::Csv {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--streaming-totals keeps no directory rows, which are all --format csv writes",
+        ));
+    }
+
+    let quiet = args.quiet
+        || args.totals
+        || args.languages_with_counts
+        || args.format != OutputFormat::Text;
+    if quiet {
+        metrics.progress_enabled = false;
+    } else {
+        println!(
+            "{} {}",
+            env!("CARGO_PKG_NAME").bright_cyan().bold(),
+            format!("v{}", env!("CARGO_PKG_VERSION")).bright_yellow()
+        );
+    }
 
     let path = Path::new(&args.path);
     let current_dir = env::current_dir()?;
     let mut error_count = 0;
 
-    if !path.exists() {
+    if !path.exists() && path_glob(path).is_none() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
             format!("Path does not exist: {}", path.display()),
         ));
     }
 
-    println!("Starting source code analysis...");
+    if !quiet {
+        println!("Starting source code analysis...");
+    }
     // Start with depth 0 and track errors
     let mut entries_count: usize = 0;
     let stats = scan_directory(
@@ -3248,62 +8460,392 @@ fn run_cli_with_metrics(args: Args, metrics: &mut PerformanceMetrics) -> io::Res
         &mut entries_count,
         &mut error_count,
     )?;
+    let scan_root = path_glob(path).map_or_else(|| path.to_path_buf(), glob_base);
+    let root_path = fs::canonicalize(&scan_root).unwrap_or(scan_root);
+    let root_dir = if root_path.is_dir() {
+        root_path.as_path()
+    } else {
+        root_path.parent().unwrap_or(&root_path)
+    };
+    let stats = match args.merge_case_insensitive_dirs {
+        true => merge_case_variants(stats),
+        false => stats,
+    };
+    let stats = match args.aggregate_depth {
+        Some(depth) => aggregate_to_depth(stats, root_dir, depth),
+        None => stats,
+    };
     let files_processed = metrics.files_processed.load(Ordering::Relaxed);
     let lines_processed = metrics.lines_processed.load(Ordering::Relaxed);
-
-    // If no source files were found, print a simple message and exit
-    if files_processed == 0 {
-        println!("\n{}", "No source code files found.".bright_yellow());
-        return Ok(());
+    let partial_note = args
+        .max_runtime
+        .filter(|_| metrics.runtime_exhausted)
+        .map(|budget| {
+            format!(
+                "Note: --max-runtime budget of {:.1}s elapsed; results are partial.",
+                budget.as_secs_f64()
+            )
+        });
+
+    let skipped_summary = args
+        .print_skipped
+        .then(|| format_skipped_files(&metrics.skipped_files));
+    let compat_summary = args
+        .compat_check
+        .then(|| format_compat_report(metrics.compat_checked, &metrics.compat_mismatches));
+    let entries_summary = args.entries_report.then(|| {
+        format_entries_report(
+            entries_count,
+            &metrics.entry_counts,
+            files_processed,
+            metrics.duplicate_files,
+            error_count,
+        )
+    });
+    let partial_read_summary =
+        (!metrics.partial_reads.is_empty()).then(|| format_partial_reads(&metrics.partial_reads));
+    let role_warning_summary = args
+        .strict_roles
+        .then(|| format_role_warnings(&metrics.role_warnings));
+    let empty_summary = args
+        .flag_empty
+        .then(|| format_empty_files(metrics.empty_files, metrics.whitespace_only_files));
+    let largest_summary = args
+        .largest_files
+        .map(|_| format_largest_files(&metrics.largest_files));
+    let blank_run_summary = args
+        .blank_runs
+        .then(|| format_blank_runs(&metrics.blank_runs));
+    let blame_summary = args
+        .blame
+        .then(|| format_blame_table(&metrics.blame_authors));
+    let extension_summary = args
+        .group_by_extension
+        .then(|| format_extension_totals(&metrics.extension_totals));
+    let checksum_summary = args.checksum.then(|| {
+        format!(
+            "\nChecksum: {:016x}\n",
+            scan_checksum(&metrics.checksum_records)
+        )
+    });
+
+    if args.totals || args.languages_with_counts {
+        let summary = if args.totals {
+            format!("{}\n", format_totals_line(&stats))
+        } else {
+            format_language_leaderboard(&stats)
+        };
+        // JSONL records already went to stdout during the scan.
+        if args.format == OutputFormat::Jsonl {
+            eprint!("{}", summary);
+        } else {
+            print!("{}", summary);
+        }
+        if let Some(summary) = &skipped_summary {
+            eprint!("{}", summary);
+        }
+        for summary in [
+            &partial_read_summary,
+            &compat_summary,
+            &role_warning_summary,
+            &entries_summary,
+            &empty_summary,
+            &largest_summary,
+            &extension_summary,
+            &blank_run_summary,
+            &blame_summary,
+            &checksum_summary,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            eprint!("{}", summary);
+        }
+        if args.profile {
+            eprint!("{}", format_phase_times(&metrics.phase_times));
+        }
+        if let Some(note) = &partial_note {
+            eprintln!("{}", note);
+        }
+        return scan_error_outcome(&args, error_count);
     }
 
-    metrics.print_final_stats();
-
-    // Print detailed analysis with fixed-width directory field.
-    let report = build_analysis_report(
-        &current_dir,
-        &stats,
+    // Report paths under the virtual root, independent of the checkout location.
+    let (root_dir, display_dir, stats) = match args.relocate_root.as_deref() {
+        Some(virtual_root) => (
+            PathBuf::from(virtual_root),
+            PathBuf::new(),
+            relocate_stats(stats, root_dir, virtual_root),
+        ),
+        None => (root_dir.to_path_buf(), current_dir, stats),
+    };
+    let result = AnalysisResult {
+        root_dir,
+        display_dir,
+        stats,
         files_processed,
         lines_processed,
         error_count,
-        args.role_breakdown,
-    );
-    print!("{}", report);
+        role_counters: metrics.has_role_data().then(|| metrics.role_counters()),
+        options: ReportOptions::from_args(&args),
+    };
+    let report_started = Instant::now();
+    let report = render_report(&result, args.format)?;
+    metrics.phase_times.report = report_started.elapsed();
 
-    if (args.role_breakdown || args.verbose) && metrics.has_role_data() {
-        println!("\n{}", "Role Summary:".blue().bold());
-        for (idx, (files, lines, code_lines)) in metrics.role_counters().iter().enumerate() {
-            let role = CodeRole::ALL[idx];
-            println!(
-                "{}: {} file occurrences, {} code lines of {} total",
-                role.label().bright_cyan(),
-                format_number(*files).bright_yellow(),
-                format_number(*code_lines).bright_yellow(),
-                format_number(*lines).bright_yellow()
-            );
+    if args.format == OutputFormat::Text && files_processed > 0 && !quiet {
+        metrics.print_final_stats();
+    }
+    if args.profile {
+        let timings = format_phase_times(&metrics.phase_times);
+        if args.format == OutputFormat::Text {
+            print!("{}", timings);
+        } else {
+            eprint!("{}", timings);
         }
-        let counters = metrics.role_counters();
-        let mainline_code = counters[CodeRole::Mainline.as_index()].2;
-        let test_code = counters[CodeRole::Test.as_index()].2;
-        if mainline_code > 0 && test_code > 0 {
-            let smaller = mainline_code.min(test_code) as f64;
-            let test_ratio = (test_code as f64 / smaller).round() as u64;
-            let main_ratio = (mainline_code as f64 / smaller).round() as u64;
-            println!(
-                "Test:Mainline code ratio of {}:{}",
-                format_number(test_ratio).bright_yellow(),
-                format_number(main_ratio).bright_yellow()
-            );
+    }
+    print!("{}", report);
+
+    // Diagnostics follow the report; with JSON they go to stderr so stdout
+    // stays machine-readable.
+    let to_stderr = args.format != OutputFormat::Text;
+    let highlight = |text: &str| {
+        if to_stderr {
+            text.to_string()
+        } else {
+            text.bright_yellow().to_string()
         }
-        if files_processed < counters.iter().map(|(f, _, _)| f).sum::<u64>() {
-            println!(
-                "{}",
-                "(Note: files can appear in multiple roles; counts above are per-role occurrences.)"
-                    .bright_black()
+    };
+    let mut diagnostics = String::new();
+    if args.dedupe_by_content && files_processed > 0 {
+        let _ = writeln!(
+            diagnostics,
+            "Duplicate files skipped (identical content): {}",
+            highlight(&format_number(metrics.duplicate_files))
+        );
+    }
+    for summary in [
+        &skipped_summary,
+        &partial_read_summary,
+        &compat_summary,
+        &role_warning_summary,
+        &entries_summary,
+        &empty_summary,
+        &largest_summary,
+        &extension_summary,
+        &blank_run_summary,
+        &blame_summary,
+        &checksum_summary,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        diagnostics.push_str(summary);
+    }
+    if args.exclude_minified && metrics.entry_counts.minified > 0 {
+        let _ = writeln!(
+            diagnostics,
+            "Minified files excluded: {}",
+            highlight(&format_number(metrics.entry_counts.minified))
+        );
+    }
+    if let Some(note) = &partial_note {
+        let _ = writeln!(diagnostics, "\n{}", highlight(note));
+    }
+    if to_stderr {
+        eprint!("{}", diagnostics);
+    } else {
+        print!("{}", diagnostics);
+    }
+
+    scan_error_outcome(&args, error_count)
+}
+
+/// A finished scan, ready to be rendered in any `OutputFormat`.
+struct AnalysisResult {
+    /// Scan root; JSON directory keys are relative to it.
+    root_dir: PathBuf,
+    /// Text report paths are displayed relative to this directory.
+    display_dir: PathBuf,
+    stats: HashMap<PathBuf, DirectoryStats>,
+    files_processed: u64,
+    lines_processed: u64,
+    error_count: usize,
+    /// Per-role (files, lines, code lines) occurrences, when any were recorded.
+    role_counters: Option<[(u64, u64, u64); CODE_ROLE_COUNT]>,
+    options: ReportOptions,
+}
+
+impl AnalysisResult {
+    /// Per-language (files, stats) summed over every directory, for callers
+    /// that assert on the totals rather than parse a rendered report.
+    fn language_totals(&self) -> HashMap<String, (u64, LanguageStats)> {
+        totals_by_language(&self.stats)
+    }
+}
+
+/// Render the final report as a string; printing is left to the caller.
+fn render_report(result: &AnalysisResult, format: OutputFormat) -> io::Result<String> {
+    match format {
+        OutputFormat::Text => {
+            if result.files_processed == 0 {
+                return Ok(format!(
+                    "\n{}\n",
+                    "No source code files found.".bright_yellow()
+                ));
+            }
+            let mut output = build_analysis_report(
+                &result.display_dir,
+                &result.stats,
+                result.files_processed,
+                result.lines_processed,
+                result.error_count,
+                &result.options,
             );
+            if let (true, Some(counters)) = (result.options.role_summary, &result.role_counters) {
+                output.push_str(&format_role_summary(counters, result.files_processed));
+            }
+            if let (true, Some(counters)) = (result.options.role_breakdown, &result.role_counters) {
+                output.push_str(&format_role_share(counters));
+            }
+            if result.options.comment_types {
+                output.push_str(&format_comment_types(result.language_totals()));
+            }
+            Ok(output)
+        }
+        OutputFormat::Json => Ok(build_json_report(
+            &result.root_dir,
+            &result.stats,
+            result.files_processed,
+            result.lines_processed,
+            result.error_count,
+            &result.options,
+        )),
+        OutputFormat::Csv => Ok(build_csv_report(
+            &result.root_dir,
+            &result.stats,
+            &result.options,
+        )),
+        // Records were already streamed by `process_file`.
+        OutputFormat::Jsonl => Ok(String::new()),
+    }
+}
+
+/// `--comment-types`: line vs block comment lines per language, for the
+/// languages whose counters tell them apart.
+fn format_comment_types(totals: HashMap<String, (u64, LanguageStats)>) -> String {
+    let mut totals: Vec<_> = totals
+        .into_iter()
+        .filter(|(_, (_, lang_stats))| {
+            lang_stats.line_comment_lines + lang_stats.block_comment_lines > 0
+        })
+        .collect();
+    totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut output = String::new();
+    let _ = writeln!(output, "\n{}", "Comment lines by type:".blue().bold());
+    if totals.is_empty() {
+        let _ = writeln!(output, "No typed comments found.");
+        return output;
+    }
+    let _ = writeln!(
+        output,
+        "{:<20} {:>12} {:>12} {:>7}",
+        "Language", "Line", "Block", "Block%"
+    );
+    for (lang, (_, lang_stats)) in totals {
+        let typed = lang_stats.line_comment_lines + lang_stats.block_comment_lines;
+        let _ = writeln!(
+            output,
+            "{:<20} {:>12} {:>12} {:>6.1}%",
+            lang,
+            format_number(lang_stats.line_comment_lines),
+            format_number(lang_stats.block_comment_lines),
+            safe_percentage(lang_stats.block_comment_lines, typed)
+        );
+    }
+    output
+}
+
+/// Mainline and Test are always listed; the Feature role only once
+/// `--feature-roles` has recorded something for it.
+fn role_reported(role: CodeRole, counters: &[(u64, u64, u64); CODE_ROLE_COUNT]) -> bool {
+    role != CodeRole::FeatureGated || counters[role.as_index()].0 > 0
+}
+
+/// `--role-breakdown`: each role's percentage of all code lines.
+fn format_role_share(counters: &[(u64, u64, u64); CODE_ROLE_COUNT]) -> String {
+    let total_code: u64 = counters.iter().map(|(_, _, code)| code).sum();
+    let mut output = String::new();
+    let _ = writeln!(output, "\n{}", "Role share of code lines:".blue().bold());
+    for role in CodeRole::ALL
+        .into_iter()
+        .filter(|role| role_reported(*role, counters))
+    {
+        let code = counters[role.as_index()].2;
+        let _ = writeln!(
+            output,
+            "{:<10} {} ({})",
+            format!("{}:", role.label()),
+            format!("{:>5.1}%", safe_percentage(code, total_code)).bright_yellow(),
+            format_number(code)
+        );
+    }
+    output
+}
+
+/// Per-role occurrence counts and the test:mainline code ratio.
+fn format_role_summary(
+    counters: &[(u64, u64, u64); CODE_ROLE_COUNT],
+    files_processed: u64,
+) -> String {
+    let mut output = String::new();
+    let _ = writeln!(output, "\n{}", "Role Summary:".blue().bold());
+    for (idx, (files, lines, code_lines)) in counters.iter().enumerate() {
+        let role = CodeRole::ALL[idx];
+        if !role_reported(role, counters) {
+            continue;
         }
+        let _ = writeln!(
+            output,
+            "{}: {} file occurrences, {} code lines of {} total",
+            role.label().bright_cyan(),
+            format_number(*files).bright_yellow(),
+            format_number(*code_lines).bright_yellow(),
+            format_number(*lines).bright_yellow()
+        );
+    }
+    let mainline_code = counters[CodeRole::Mainline.as_index()].2;
+    let test_code = counters[CodeRole::Test.as_index()].2;
+    if mainline_code > 0 && test_code > 0 {
+        let smaller = mainline_code.min(test_code) as f64;
+        let test_ratio = (test_code as f64 / smaller).round() as u64;
+        let main_ratio = (mainline_code as f64 / smaller).round() as u64;
+        let _ = writeln!(
+            output,
+            "Test:Mainline code ratio of {}:{}",
+            format_number(test_ratio).bright_yellow(),
+            format_number(main_ratio).bright_yellow()
+        );
+    }
+    if files_processed < counters.iter().map(|(f, _, _)| f).sum::<u64>() {
+        let _ = writeln!(
+            output,
+            "{}",
+            "(Note: files can appear in multiple roles; counts above are per-role occurrences.)"
+                .bright_black()
+        );
     }
+    output
+}
 
+/// With `--fail-on-error`, turn a non-zero error count into the process result.
+fn scan_error_outcome(args: &Args, error_count: usize) -> io::Result<()> {
+    if args.fail_on_error && error_count > 0 {
+        return Err(io::Error::other(format!(
+            "{} error(s) occurred during the scan",
+            error_count
+        )));
+    }
     Ok(())
 }
 
@@ -14,12 +14,77 @@
             path: String::from("."),
             ignore: Vec::new(),
             verbose: false,
+            quiet: false,
+            totals: false,
             max_entries: 1000000,
             max_depth: 100,
+            traversal: Traversal::Dfs,
             non_recursive: false,
             filespec: None,
             role_breakdown: false,
+            group_roles: false,
+            exclude_test: false,
+            only_test: false,
+            checksum: false,
+            profile: false,
+            blame: false,
+            strict_roles: false,
+            largest_files: None,
+            group_by_extension: false,
+            blank_runs: false,
+            flag_empty: false,
+            languages_with_counts: false,
+            normalize: NormalizePolicy::Default,
+            count_mode: CountMode::Physical,
+            merge_case_insensitive_dirs: false,
+            relocate_root: None,
+            feature_roles: false,
+            min_lines: 0,
+            dedupe_by_content: false,
+            hardlink_dedupe: false,
+            markdown_fences: false,
+            print_skipped: false,
+            root_label: None,
+            no_recurse_into: Vec::new(),
+            follow_gitlinks: false,
+            auto_exclude_vendor: false,
+            exclude_fixtures: Vec::new(),
+            exclude_regions: false,
+            exclude_minified: false,
+            compat_check: false,
+            entries_report: false,
+            cache: None,
+            format: OutputFormat::Text,
+            relative_paths: true,
+            csv_delimiter: ',',
+            dir_width: DIR_WIDTH,
+            lang_width: LANG_WIDTH,
+            report_width: None,
+            no_mixed: false,
+            no_subtotals: false,
+            streaming_totals: false,
+            doc_comments: false,
+            averages: false,
+            comment_types: false,
+            aggregate_depth: None,
             languages: false,
+            print_schema: false,
+            dump_roles: None,
+            fail_on_error: false,
+            config: None,
+            comment_styles: Vec::new(),
+            include_ext: Vec::new(),
+            lang: None,
+            prefer_shebang: false,
+            sniff_content: false,
+            custom_languages: Vec::new(),
+            bytes: false,
+            jsonc: false,
+            count_lockfiles: false,
+            max_runtime: None,
+            since: None,
+            test_scope: TestScope::Cfg,
+            m_is: MFileLanguage::Objc,
         }
     }
 
@@ -34,6 +99,11 @@
         Ok(())
     }
 
+    /// A line reader over a test file, for calling counters directly.
+    fn lines_of(path: &Path) -> LossyLineReader {
+        read_file_lines_lossy(path).expect("open test file")
+    }
+
     fn language_entry(files: u64, stats: LanguageStats) -> LanguageEntry {
         let mut entry = LanguageEntry::default();
         entry.record_aggregate(CodeRole::Mainline, files, stats);
@@ -62,6 +132,7 @@
                     comment_lines: 1,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
             (
@@ -71,6 +142,7 @@
                     comment_lines: 0,
                     blank_lines: 1,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         ]);
@@ -192,11 +264,21 @@
             .expect("expected first item")
             .expect("first read should succeed");
         assert_eq!(first_line, "ok");
-        let second = reader.next().expect("expected error result");
         assert!(
-            second.is_err(),
-            "lossy reader should surface the simulated failure"
+            reader.next().is_none(),
+            "a failure after the first line ends the iteration"
+        );
+        let error = reader.take_error().expect("the reader keeps the failure");
+        assert_eq!(error.to_string(), "simulated failure");
+        assert!(reader.take_error().is_none());
+
+        let mut reader = LossyLineReader::with_reader(FailAfterFirstRead { state: 1 });
+        let first = reader.next().expect("expected error result");
+        assert!(
+            first.is_err(),
+            "a failure before any line is surfaced as an error"
         );
+        assert!(reader.take_error().is_none());
     }
 
     #[test]
@@ -631,7 +713,7 @@
         let mut stats = std::collections::HashMap::new();
         let mut entries_count = 0usize;
         let mut error_count = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         increment_entries(&mut entries_count, &args, &missing)?;
         process_file(
@@ -666,7 +748,7 @@
         let mut stats = std::collections::HashMap::new();
         let mut entries_count = 0usize;
         let mut error_count = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let verbose_path = temp_dir.path().join("verbose.rs");
         increment_entries(&mut entries_count, &args, &verbose_path)?;
@@ -721,7 +803,7 @@ mod tests {
         let mut stats = std::collections::HashMap::new();
         let mut entries_count = 0usize;
         let mut error_count = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let verbose_path = temp_dir.path().join("verbose_split.rs");
         increment_entries(&mut entries_count, &args, &verbose_path)?;
@@ -886,6 +968,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_run_cli_with_metrics_quiet_suppresses_progress_and_summary() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n// comment\n")?;
+        let args = Args::parse_from([
+            "mdkloc",
+            temp_dir
+                .path()
+                .to_str()
+                .expect("temp dir path should be valid UTF-8"),
+            "--quiet",
+        ]);
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let writer = CaptureWriter::new(buffer.clone());
+        let mut metrics = PerformanceMetrics::with_writer(Box::new(writer), true);
+        metrics.last_update = metrics.start_time - Duration::from_secs(2);
+        run_cli_with_metrics(args, &mut metrics)?;
+        let output = CaptureWriter::into_string(buffer);
+        assert!(
+            output.is_empty(),
+            "quiet run should not emit progress or performance output: {output}"
+        );
+        assert!(!metrics.progress_enabled);
+        Ok(())
+    }
+
     #[test]
     fn test_run_cli_with_metrics_zero_files() -> io::Result<()> {
         control::set_override(false);
@@ -1009,6 +1118,7 @@ mod tests {
             comment_lines: 2,
             blank_lines: 0,
             overlap_lines: 0,
+            ..Default::default()
         };
         let normalized = normalize_stats(stats, 3);
         assert_eq!(
@@ -1027,6 +1137,7 @@ mod tests {
             comment_lines: 1,
             blank_lines: 3,
             overlap_lines: 0,
+            ..Default::default()
         };
         let normalized = normalize_stats(stats, 4);
         assert_eq!(
@@ -1051,6 +1162,7 @@ mod tests {
             comment_lines: 0,
             blank_lines: 0,
             overlap_lines: 0,
+            ..Default::default()
         };
         let normalized = normalize_stats(stats, 5);
         assert_eq!(normalized.code_lines, 0);
@@ -1066,6 +1178,7 @@ mod tests {
             comment_lines: 1,
             blank_lines: 0,
             overlap_lines: 0,
+            ..Default::default()
         };
         let normalized = normalize_stats(stats, 6);
         assert_eq!(
@@ -1132,6 +1245,61 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_typescript_declarations_bucketed_separately() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let args = Args {
+            path: temp_dir.path().to_string_lossy().to_string(),
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        create_test_file(
+            temp_dir.path(),
+            "foo.ts",
+            "// impl\nexport const foo = 1;\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "foo.d.ts",
+            "export declare const foo: number;\nexport declare function bar(): void;\n",
+        )?;
+        assert_eq!(get_language_from_extension("FOO.D.TS"), Some("TypeScript (decl)"));
+        assert_eq!(get_language_from_extension(".d.ts"), Some("TypeScript"));
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        let root_canon = fs::canonicalize(temp_dir.path())?;
+        let dir_stats = stats
+            .get(&root_canon)
+            .or_else(|| stats.get(temp_dir.path()))
+            .unwrap();
+        let (ts_files, ts_stats) = dir_stats.language_stats["TypeScript"].summary();
+        assert_eq!((ts_files, ts_stats.code_lines, ts_stats.comment_lines), (1, 1, 1));
+        let (decl_files, decl_stats) = dir_stats.language_stats["TypeScript (decl)"].summary();
+        assert_eq!((decl_files, decl_stats.code_lines), (1, 2));
+
+        // The label fits the default Language column, so its rows stay aligned.
+        let options = ReportOptions::default();
+        let report = build_analysis_report(temp_dir.path(), &stats, 2, 4, 0, &options);
+        let decl_rows: Vec<&str> = report
+            .lines()
+            .filter(|line| line.contains("TypeScript (decl)"))
+            .collect();
+        assert_eq!(decl_rows.len(), 2, "{report}");
+        for row in decl_rows {
+            assert_eq!(row.chars().count(), options.layout.rule_width(), "{row:?}");
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_scan_directory_respects_ignore_list() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -1454,7 +1622,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut entries_count = 0usize;
         let mut error_count = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
         let stats = scan_directory_impl(
             &socket_path,
             &args,
@@ -1465,6 +1633,7 @@ mod tests {
             &mut error_count,
             None,
             &mut visited_paths,
+            None,
         )?;
 
         assert!(
@@ -1494,7 +1663,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut entries = 0usize;
         let mut errors = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let stats = scan_directory_impl(
             root,
@@ -1506,6 +1675,7 @@ mod tests {
             &mut errors,
             None,
             &mut visited_paths,
+            None,
         )?;
 
         let canonical_root = fs::canonicalize(root)?;
@@ -1524,7 +1694,7 @@ mod tests {
         assert_eq!(entries, 2, "should count both the file and symlink entries");
         assert_eq!(errors, 0, "symlink processing should not add errors");
         assert_eq!(
-            visited_paths.len(),
+            visited_paths.real_paths.len(),
             1,
             "only the resolved canonical file should be tracked once"
         );
@@ -1585,7 +1755,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut entries = 0usize;
         let mut errors = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let filespec_pattern = args
             .filespec
@@ -1602,6 +1772,7 @@ mod tests {
             &mut errors,
             filespec_pattern.as_ref(),
             &mut visited_paths,
+            None,
         );
 
         match result {
@@ -1817,7 +1988,7 @@ mod tests {
         let mut merged: HashMap<PathBuf, DirectoryStats> = HashMap::new();
 
         for _ in 0..2 {
-            let mut visited_paths = HashSet::new();
+            let mut visited_paths = VisitedFiles::default();
             let sub_stats = scan_directory_impl(
                 &shared,
                 &test_args(),
@@ -1828,6 +1999,7 @@ mod tests {
                 &mut errors,
                 None,
                 &mut visited_paths,
+                None,
             )?;
             for (dir, stat) in sub_stats {
                 merge_directory_stats(&mut merged, dir, stat);
@@ -2150,7 +2322,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut entries = 0usize;
         let mut errors = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
         let stats = scan_directory_impl(
             &nested,
             &args,
@@ -2161,6 +2333,7 @@ mod tests {
             &mut errors,
             None,
             &mut visited_paths,
+            None,
         )?;
 
         assert!(
@@ -3273,7 +3446,7 @@ mod tests {
     fn test_rust_line_counting() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "test.rs", "fn main() {\n// Line comment\n/* Block comment */\n/// Doc comment\n//! Module comment\nprintln!(\"Hello\");\n}\n")?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("test.rs").as_path())?;
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("test.rs").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 4);
         assert_eq!(stats.blank_lines, 0);
@@ -3288,7 +3461,7 @@ mod tests {
             "blank.rs",
             "fn main() {\n\n    println!(\"hi\");\n}\n",
         )?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("blank.rs").as_path())?;
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("blank.rs").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank lines to be counted: {stats:?}"
@@ -3304,9 +3477,10 @@ mod tests {
             "trail.rs",
             "fn main() {\nlet value = 1; /* comment */ println!(\"{}\", value);\n}\n",
         )?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("trail.rs").as_path())?;
-        assert_eq!(stats.code_lines, 4);
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("trail.rs").as_path()))?;
+        assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 1);
+        assert_eq!(stats.overlap_lines, 1);
         Ok(())
     }
 
@@ -3319,7 +3493,7 @@ mod tests {
             "fn annotate() {\nlet value = 1; /* block */ // trailing comment\n}\n",
         )?;
         let (stats, _total_lines) =
-            count_rust_lines(temp_dir.path().join("close_line.rs").as_path())?;
+            count_rust_lines(&mut lines_of(temp_dir.path().join("close_line.rs").as_path()))?;
         assert_eq!(
             stats.code_lines, 3,
             "code lines should not double-count after block close: {stats:?}"
@@ -3339,7 +3513,7 @@ mod tests {
             "mix.rs",
             "fn noisy() {\nlet value = 1; /* block */ // trailing comment\n}\n",
         )?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("mix.rs").as_path())?;
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("mix.rs").as_path()))?;
         assert_eq!(stats.code_lines, 3, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -3353,7 +3527,7 @@ mod tests {
             "multi.rs",
             "fn tricky() {\n/* start\nstill comment */ // trailing\nlet x = 1;\n}\n",
         )?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("multi.rs").as_path())?;
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("multi.rs").as_path()))?;
         assert!(stats.code_lines >= 3, "stats: {:?}", stats);
         assert!(stats.comment_lines >= 2, "stats: {:?}", stats);
         Ok(())
@@ -3368,7 +3542,7 @@ mod tests {
             "fn value() {\n/* start\n  middle */ let x = 1;\n}\n",
         )?;
         let (stats, _total_lines) =
-            count_rust_lines(temp_dir.path().join("inline_close.rs").as_path())?;
+            count_rust_lines(&mut lines_of(temp_dir.path().join("inline_close.rs").as_path()))?;
         assert!(
             stats.code_lines >= 2,
             "expected trailing code after block close counted as code: {stats:?}"
@@ -3395,10 +3569,11 @@ fn decorated() {
 }
 "#,
         )?;
-        let (stats, _total_lines) = count_rust_lines(temp_dir.path().join("attr.rs").as_path())?;
-        assert_eq!(stats.code_lines, 7, "stats: {:?}", stats);
+        let (stats, _total_lines) = count_rust_lines(&mut lines_of(temp_dir.path().join("attr.rs").as_path()))?;
+        assert_eq!(stats.code_lines, 6, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 4, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
+        assert_eq!(stats.overlap_lines, 3, "stats: {:?}", stats);
         Ok(())
     }
 
@@ -3410,7 +3585,7 @@ fn decorated() {
             "test.py",
             "def main():\n# Line comment\n'''Block\ncomment'''\nprint('Hello')\n\n",
         )?;
-        let (stats, _total_lines) = count_python_lines(temp_dir.path().join("test.py").as_path())?;
+        let (stats, _total_lines) = count_python_lines(&mut lines_of(temp_dir.path().join("test.py").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 3);
         assert_eq!(stats.blank_lines, 1);
@@ -3426,7 +3601,7 @@ fn decorated() {
             "def main():\n\"\"\"Block\ncomment\"\"\"\nprint('Hello')\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("test_ddq.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("test_ddq.py").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         Ok(())
@@ -3441,7 +3616,7 @@ fn decorated() {
             "def inline():\n\"\"\"doc\"\"\" print('after') # trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("inline_doc.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("inline_doc.py").as_path()))?;
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -3456,7 +3631,7 @@ fn decorated() {
             "def note():\n\"\"\"doc\"\"\" # trailing comment\npass\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("inline_comment.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("inline_comment.py").as_path()))?;
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -3472,7 +3647,7 @@ fn decorated() {
             "\"\"\"doc start\nbody\nend\"\"\" value = 42\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("doc_with_code.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("doc_with_code.py").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected multiline docstring counted as comments: {stats:?}"
@@ -3492,7 +3667,7 @@ fn decorated() {
             "mixed.py",
             "def doc():\n\"\"\"Doc\"\"\" # inline\nvalue = \"hello\" \\\n# comment on continuation\n'''Inline''' print('done')\n",
         )?;
-        let (stats, _total_lines) = count_python_lines(temp_dir.path().join("mixed.py").as_path())?;
+        let (stats, _total_lines) = count_python_lines(&mut lines_of(temp_dir.path().join("mixed.py").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -3507,7 +3682,7 @@ fn decorated() {
             "def tricky():\nvalue = \"line\" \\\n\"\"\"not doc\"\"\"\nprint('done')\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("continuation.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("continuation.py").as_path()))?;
         assert!(
             stats.comment_lines == 0,
             "continuation should prevent docstring counting as comment: {:?}",
@@ -3526,7 +3701,7 @@ fn decorated() {
             "\"\"\"doc\"\"\" # trailing comment only\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("doc_comment.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("doc_comment.py").as_path()))?;
         assert_eq!(
             stats.code_lines, 0,
             "code should not be counted when trailing segment is comment: {stats:?}"
@@ -3544,7 +3719,7 @@ fn decorated() {
             "\"\"\"doc\\nbody\\nend\"\"\" value = 42 # note\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("doc_with_code_comment.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("doc_with_code_comment.py").as_path()))?;
         assert!(
             stats.comment_lines >= 1,
             "expected docstring lines counted as comments: {stats:?}"
@@ -3565,7 +3740,7 @@ fn decorated() {
             "\"\"\"doc\"\"\"\n\nprint('done')\n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("doc_with_blank.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("doc_with_blank.py").as_path()))?;
         assert!(
             stats.comment_lines >= 1,
             "expected docstring to count as comment: {stats:?}"
@@ -3590,7 +3765,7 @@ fn decorated() {
             "\"\"\"doc\"\"\"\n    \n",
         )?;
         let (stats, _total_lines) =
-            count_python_lines(temp_dir.path().join("doc_with_whitespace.py").as_path())?;
+            count_python_lines(&mut lines_of(temp_dir.path().join("doc_with_whitespace.py").as_path()))?;
         assert_eq!(
             stats.comment_lines, 1,
             "docstring should count as comment: {stats:?}"
@@ -3614,11 +3789,10 @@ fn decorated() {
             "doc_with_whitespace_code.py",
             "\"\"\"doc\"\"\"\n    \nprint('done')\n",
         )?;
-        let (stats, _total_lines) = count_python_lines(
-            temp_dir
+        let (stats, _total_lines) = count_python_lines(&mut lines_of(temp_dir
                 .path()
                 .join("doc_with_whitespace_code.py")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 1,
@@ -3643,11 +3817,10 @@ fn decorated() {
             "doc_with_whitespace_comment.py",
             "\"\"\"doc\"\"\"\n    \n# trailing comment\n",
         )?;
-        let (stats, _total_lines) = count_python_lines(
-            temp_dir
+        let (stats, _total_lines) = count_python_lines(&mut lines_of(temp_dir
                 .path()
                 .join("doc_with_whitespace_comment.py")
-                .as_path(),
+                .as_path()),
         )?;
         assert!(
             stats.comment_lines >= 2,
@@ -3666,7 +3839,7 @@ fn decorated() {
             "sample.yaml",
             "# leading comment\nkey: value\n\n# trailing\n",
         )?;
-        let (stats, total) = count_yaml_lines(temp_dir.path().join("sample.yaml").as_path())?;
+        let (stats, total) = count_yaml_lines(&mut lines_of(temp_dir.path().join("sample.yaml").as_path()))?;
         assert_eq!(total, 4);
         assert_eq!(stats.comment_lines, 2);
         assert_eq!(stats.code_lines, 1);
@@ -3678,7 +3851,7 @@ fn decorated() {
     fn test_yaml_inline_hash_after_code() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "inline.yaml", "key: value # comment\n")?;
-        let (stats, total) = count_yaml_lines(temp_dir.path().join("inline.yaml").as_path())?;
+        let (stats, total) = count_yaml_lines(&mut lines_of(temp_dir.path().join("inline.yaml").as_path()))?;
         assert_eq!(total, 1);
         assert_eq!(stats.code_lines, 1);
         assert_eq!(stats.comment_lines, 0);
@@ -3693,7 +3866,7 @@ fn decorated() {
             "config.toml",
             "title = \"test\"\n# note\n\nvalue = 1\n",
         )?;
-        let (stats, total) = count_toml_lines(temp_dir.path().join("config.toml").as_path())?;
+        let (stats, total) = count_toml_lines(&mut lines_of(temp_dir.path().join("config.toml").as_path()))?;
         assert_eq!(total, 4);
         assert_eq!(stats.comment_lines, 1);
         assert_eq!(stats.code_lines, 2);
@@ -3705,7 +3878,7 @@ fn decorated() {
     fn test_toml_inline_hash_after_code() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "inline.toml", "value = 1 # note\n")?;
-        let (stats, total) = count_toml_lines(temp_dir.path().join("inline.toml").as_path())?;
+        let (stats, total) = count_toml_lines(&mut lines_of(temp_dir.path().join("inline.toml").as_path()))?;
         assert_eq!(total, 1);
         assert_eq!(stats.code_lines, 1);
         assert_eq!(stats.comment_lines, 0);
@@ -3720,7 +3893,7 @@ fn decorated() {
             "multi.hcl",
             "resource \"x\" \"y\" {\n  /* start\n     still comment */ value = 1\n}\n",
         )?;
-        let (stats, _total_lines) = count_hcl_lines(temp_dir.path().join("multi.hcl").as_path())?;
+        let (stats, _total_lines) = count_hcl_lines(&mut lines_of(temp_dir.path().join("multi.hcl").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected block comment lines counted: {stats:?}"
@@ -3741,7 +3914,7 @@ fn decorated() {
             "variable \"x\" {\n  value = 1 /* start\n     still comment\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("unterminated.hcl").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("unterminated.hcl").as_path()))?;
         assert!(
             stats.comment_lines >= 1,
             "unterminated block should count comment lines: {stats:?}"
@@ -3764,7 +3937,7 @@ fn decorated() {
             "<# start\nstill comment #> Write-Host 'post'\nWrite-Host 'mid' <# open #> more <# again\nmulti #> done\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("complex.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("complex.ps1").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 3);
         Ok(())
@@ -3779,7 +3952,7 @@ fn decorated() {
             "<# note #> Write-Host 'done'\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("inline_comment.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("inline_comment.ps1").as_path()))?;
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -3794,7 +3967,7 @@ fn decorated() {
             "<# start\nstill comment\n#>\nWrite-Host 'after'\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("multiline_comment.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("multiline_comment.ps1").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected multiline comment lines counted: {stats:?}"
@@ -3815,7 +3988,7 @@ fn decorated() {
             "Write-Host 'mix'<#block#>Write-Host 'after'<#open\ncontinued\n#># trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("interleaved.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("interleaved.ps1").as_path()))?;
         assert!(
             stats.comment_lines >= 3,
             "expected multiple comment segments: {stats:?}"
@@ -3841,6 +4014,7 @@ fn decorated() {
                     comment_lines: 1,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -3853,12 +4027,20 @@ fn decorated() {
                     comment_lines: 2,
                     blank_lines: 1,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
         stats_map.insert(temp_dir.path().to_path_buf(), dir_stats);
 
-        let report = build_analysis_report(temp_dir.path(), &stats_map, 3, 11, 1, false);
+        let report = build_analysis_report(
+            temp_dir.path(),
+            &stats_map,
+            3,
+            11,
+            1,
+            &ReportOptions::default(),
+        );
         assert!(
             report.contains("Totals by language:"),
             "report should include totals header: {report}"
@@ -3878,6 +4060,36 @@ fn decorated() {
         Ok(())
     }
 
+    #[test]
+    fn test_format_totals_line_sums_all_directories() {
+        let mut stats_map = HashMap::new();
+        for (dir, files, code) in [("a", 2, 10), ("b", 1, 5)] {
+            let mut dir_stats = DirectoryStats::default();
+            dir_stats.language_stats.insert(
+                "Rust".to_string(),
+                language_entry(
+                    files,
+                    LanguageStats {
+                        code_lines: code,
+                        comment_lines: 3,
+                        blank_lines: 1,
+                        overlap_lines: 1,
+                        ..Default::default()
+                    },
+                ),
+            );
+            stats_map.insert(PathBuf::from(dir), dir_stats);
+        }
+        assert_eq!(
+            format_totals_line(&stats_map),
+            "code=15 comments=6 blank=2 mixed=2 files=3"
+        );
+        assert_eq!(
+            format_totals_line(&HashMap::new()),
+            "code=0 comments=0 blank=0 mixed=0 files=0"
+        );
+    }
+
     #[test]
     fn test_build_analysis_report_role_breakdown_no_data() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -3892,13 +4104,17 @@ fn decorated() {
                     comment_lines: 0,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
         stats_map.insert(temp_dir.path().to_path_buf(), dir_stats);
 
-        let report =
-            build_analysis_report(temp_dir.path(), &stats_map, 1, 2, 0, true);
+        let options = ReportOptions {
+            role_breakdown: true,
+            ..Default::default()
+        };
+        let report = build_analysis_report(temp_dir.path(), &stats_map, 1, 2, 0, &options);
         assert!(
             report.contains("Role breakdown (Mainline)"),
             "expected mainline section: {report}"
@@ -3914,7 +4130,14 @@ fn decorated() {
     fn test_build_analysis_report_handles_zero_totals() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         let stats_map: HashMap<PathBuf, DirectoryStats> = HashMap::new();
-        let report = build_analysis_report(temp_dir.path(), &stats_map, 0, 0, 0, false);
+        let report = build_analysis_report(
+            temp_dir.path(),
+            &stats_map,
+            0,
+            0,
+            0,
+            &ReportOptions::default(),
+        );
         assert!(
             report.contains("Detailed source code analysis"),
             "report should always include table header: {report}"
@@ -3954,6 +4177,7 @@ fn decorated() {
                     comment_lines: 2,
                     blank_lines: 1,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -3966,6 +4190,7 @@ fn decorated() {
                     comment_lines: 0,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -3986,6 +4211,7 @@ fn decorated() {
                     comment_lines: 1,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -3994,7 +4220,14 @@ fn decorated() {
         stats_map.insert(docs_dir.clone(), docs_stats);
         stats_map.insert(outside_dir.clone(), outside_stats);
 
-        let report = build_analysis_report(current, &stats_map, 4, 13, 0, false);
+        let report = build_analysis_report(
+            current,
+            &stats_map,
+            4,
+            13,
+            0,
+            &ReportOptions::default(),
+        );
 
         assert!(
             report.contains("src"),
@@ -4044,12 +4277,13 @@ fn decorated() {
                     comment_lines: 0,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
         stats_map.insert(long_dir.clone(), dir_stats);
 
-        let display = super::format_directory_display(&long_dir, base);
+        let display = super::format_directory_display(&long_dir, base, DIR_WIDTH);
         assert!(
             display.starts_with("..."),
             "long directory display should be truncated with ellipsis: {display}"
@@ -4059,7 +4293,7 @@ fn decorated() {
             "truncated display should not exceed DIR_WIDTH: {display}"
         );
 
-        let report = build_analysis_report(base, &stats_map, 1, 3, 0, false);
+        let report = build_analysis_report(base, &stats_map, 1, 3, 0, &ReportOptions::default());
         assert!(
             report.contains(&display),
             "report should contain truncated directory display: {report}"
@@ -4086,6 +4320,7 @@ fn decorated() {
                     comment_lines: 0,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -4098,12 +4333,20 @@ fn decorated() {
                     comment_lines: 0,
                     blank_lines: 0,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
         stats_map.insert(temp_dir.path().to_path_buf(), dir_stats);
 
-        let report = build_analysis_report(temp_dir.path(), &stats_map, 2, 8, 0, false);
+        let report = build_analysis_report(
+            temp_dir.path(),
+            &stats_map,
+            2,
+            8,
+            0,
+            &ReportOptions::default(),
+        );
         let ada_idx = report.find("Ada");
         let zig_idx = report.find("Zig");
         assert!(
@@ -4122,7 +4365,7 @@ fn decorated() {
             "Write-Host 'start'\n<# header #> Write-Host 'after'\nWrite-Host 'open' <# comment\nstill comment\n#> Write-Host 'tail' # annotate\nWrite-Host 'line mix' # trailing <# unreachable #>\nWrite-Host 'closing' <# comment #> # trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("mixed.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("mixed.ps1").as_path()))?;
         assert_eq!(stats.code_lines, 6, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 8, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -4138,7 +4381,7 @@ fn decorated() {
             "Write-Host 'alpha' # inline comment <# block #> Write-Host 'beta'\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("order.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("order.ps1").as_path()))?;
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -4152,7 +4395,7 @@ fn decorated() {
             "doc.py",
             "\"\"\"heading\"\"\" # title\nprint('body')  # trailing\n",
         )?;
-        let (stats, _total_lines) = count_python_lines(temp_dir.path().join("doc.py").as_path())?;
+        let (stats, _total_lines) = count_python_lines(&mut lines_of(temp_dir.path().join("doc.py").as_path()))?;
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -4163,7 +4406,7 @@ fn decorated() {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "test.js", "function main() {\n// Line comment\n/* Block comment */\n/* Multi-line\ncomment */\n<!-- JSX comment -->\nconsole.log('Hello');\n}\n")?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("test.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("test.js").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 5);
         assert_eq!(stats.blank_lines, 0);
@@ -4179,7 +4422,7 @@ fn decorated() {
             "const markup = '<div>';\n<!-- jsx\ncomment --> <span>done</span>\nlet value = 1; /* block */ console.log(value);\n/* open\ncomment */\nconsole.log('after');\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("jsx.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("jsx.js").as_path()))?;
         assert!(stats.code_lines >= 3);
         assert!(stats.comment_lines >= 4);
         Ok(())
@@ -4194,7 +4437,7 @@ fn decorated() {
             "const a = 1; /* inline */ const b = 2;\n/* multi\ncomment */ const c = 3;\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("mix.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("mix.js").as_path()))?;
         assert_eq!(stats.code_lines, 3, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 3, "stats: {:?}", stats);
         Ok(())
@@ -4209,7 +4452,7 @@ fn decorated() {
             "function demo() {\n  const value = 1; /* block */ // trailing\n  return value;\n}\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("close_line.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("close_line.js").as_path()))?;
         assert!(stats.code_lines >= 4, "stats: {:?}", stats);
         assert!(stats.comment_lines <= 1, "stats: {:?}", stats);
         Ok(())
@@ -4224,7 +4467,7 @@ fn decorated() {
             "const view = () => {\n    return <div />;\n};\n<!-- jsx start\nstill comment --> const resumed = true;\n<!-- inline --> const inline = true;\n/* block start\nstill block */ const next = 1;\n/* inline block */ const tail = 2;\nconst trailing = 3; // inline comment\n// header\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("jsx_mix.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("jsx_mix.js").as_path()))?;
         assert_eq!(stats.code_lines, 8, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 8, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -4240,7 +4483,7 @@ fn decorated() {
             "const value = 1; // comment /* not a block */\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("line_vs_block.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("line_vs_block.js").as_path()))?;
         assert_eq!(
             stats.code_lines, 1,
             "expected code before // counted: {stats:?}"
@@ -4261,7 +4504,7 @@ fn decorated() {
             "const header = '<div>'; <!-- comment --> const footer = '</div>';\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("jsx_prefix.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("jsx_prefix.js").as_path()))?;
         assert!(
             stats.code_lines >= 2,
             "expected code before and after JSX comment: {stats:?}"
@@ -4282,7 +4525,7 @@ fn decorated() {
             "// header comment\n\nconst view = () => <div>ok</div>; /* inline */\n<!-- jsx block\ncontinues --> <span>tail</span>\n",
         )?;
         let (stats, _total_lines) =
-            count_javascript_lines(temp_dir.path().join("blank_mix.js").as_path())?;
+            count_javascript_lines(&mut lines_of(temp_dir.path().join("blank_mix.js").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank line counted: {stats:?}"
@@ -4302,7 +4545,7 @@ fn decorated() {
     fn test_perl_line_counting() -> io::Result<()> { // migrated to CLI totals
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "test.pl", "#!/usr/bin/perl\n# Line comment\n=pod\nDocumentation block\n=cut\nprint \"Hello\";\n\n")?;
-        let (stats, _total_lines) = count_perl_lines(temp_dir.path().join("test.pl").as_path())?;
+        let (stats, _total_lines) = count_perl_lines(&mut lines_of(temp_dir.path().join("test.pl").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 4);
         assert_eq!(stats.blank_lines, 1);
@@ -4313,7 +4556,7 @@ fn decorated() {
     fn test_ruby_line_counting() -> io::Result<()> { // migrated to CLI totals
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "test.rb", "#!/usr/bin/env ruby\n# This is a comment\nputs 'Hello, world!'\n=begin\nThis is a block comment\n=end\nputs 'Goodbye'\n")?;
-        let (stats, _total_lines) = count_ruby_lines(temp_dir.path().join("test.rb").as_path())?;
+        let (stats, _total_lines) = count_ruby_lines(&mut lines_of(temp_dir.path().join("test.rb").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 4);
         Ok(())
@@ -4327,7 +4570,7 @@ fn decorated() {
             "test.sh",
             "#!/bin/bash\n# This is a comment\necho \"Hello, world!\"\n",
         )?;
-        let (stats, _total_lines) = count_shell_lines(temp_dir.path().join("test.sh").as_path())?;
+        let (stats, _total_lines) = count_shell_lines(&mut lines_of(temp_dir.path().join("test.sh").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 1);
         Ok(())
@@ -4337,7 +4580,7 @@ fn decorated() {
     fn test_pascal_line_counting() -> io::Result<()> { // migrated to CLI totals
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "test.pas", "program Test;\n// This is a line comment\n{ This is a block comment }\nwriteln('Hello, world!');\n(* Another block comment\nspanning multiple lines *)\nwriteln('Goodbye');\n")?;
-        let (stats, _total_lines) = count_pascal_lines(temp_dir.path().join("test.pas").as_path())?;
+        let (stats, _total_lines) = count_pascal_lines(&mut lines_of(temp_dir.path().join("test.pas").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 4);
         Ok(())
@@ -4352,7 +4595,7 @@ fn decorated() {
             "{ block } writeln('a');\n(* another *) writeln('b'); // trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_pascal_lines(temp_dir.path().join("mixed.pas").as_path())?;
+            count_pascal_lines(&mut lines_of(temp_dir.path().join("mixed.pas").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -4367,12 +4610,87 @@ fn decorated() {
             "{ comment } writeln('done');\n(* block *) writeln('after');\n",
         )?;
         let (stats, _total_lines) =
-            count_pascal_lines(temp_dir.path().join("nested.pas").as_path())?;
+            count_pascal_lines(&mut lines_of(temp_dir.path().join("nested.pas").as_path()))?;
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         Ok(())
     }
 
+    #[test]
+    fn test_ocaml_nested_comment_with_trailing_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.ml",
+            "(* outer\n   (* inner *)\n   still outer *) let x = 1\nlet y = 2 (* trailing *)\n\n",
+        )?;
+        let (stats, total_lines) = count_lines_with_stats(&temp_dir.path().join("lib.ml"))?;
+        assert_eq!(total_lines, 5);
+        assert_eq!(stats.comment_lines, 4, "stats: {:?}", stats);
+        assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
+        assert_eq!(stats.blank_lines, 1, "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsharp_line_and_block_comments() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "Program.fs",
+            "// line comment\nlet add a b = a + b // trailing\n(* block (* nested *) end *)\nprintfn \"%d\" (add 1 2)\n",
+        )?;
+        let (stats, _total_lines) = count_lines_with_stats(&temp_dir.path().join("Program.fs"))?;
+        assert_eq!(stats.comment_lines, 3, "stats: {:?}", stats);
+        assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
+        assert_eq!(get_language_from_extension("Program.fs"), Some("F#"));
+        assert_eq!(get_language_from_extension("types.mli"), Some("OCaml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ocaml_comment_opener_inside_string_is_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "strings.ml",
+            "let open_ = \"(*\"\nlet s = \"(* no *)\" in\nlet q = '\"' (* real *)\nlet e = \"esc \\\" (*\"\n",
+        )?;
+        let (stats, _total_lines) = count_lines_with_stats(&temp_dir.path().join("strings.ml"))?;
+        assert_eq!(stats.code_lines, 4, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsharp_line_comment_token_inside_string_is_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "Urls.fs",
+            "let url = \"http://example.com\"\nlet c = '\"' // quote char\nlet done_ = true\n",
+        )?;
+        let (stats, _total_lines) = count_lines_with_stats(&temp_dir.path().join("Urls.fs"))?;
+        assert_eq!(stats.code_lines, 3, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pascal_comment_openers_inside_strings_are_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "strings.pas",
+            "WriteLn('{not a comment}');\nWriteLn('(* nor this *)');\nWriteLn('it''s { still'' a string');\nx := '//'; { real }\nWriteLn('done');\n",
+        )?;
+        let (stats, _total_lines) =
+            count_pascal_lines(&mut lines_of(temp_dir.path().join("strings.pas").as_path()))?;
+        assert_eq!(stats.code_lines, 5, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
+        Ok(())
+    }
+
     #[test]
     fn test_pascal_nested_block_exit_counts() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -4382,7 +4700,7 @@ fn decorated() {
             "program Blocks;\n{ outer\n{ inner }\nstill } writeln('after brace');\n(* level\n(* inner *)\n*) writeln('after paren');\n(* open only\nstill comment\n*) // trailing comment\nwriteln('done');\nend.\n",
         )?;
         let (stats, _total_lines) =
-            count_pascal_lines(temp_dir.path().join("blocks.pas").as_path())?;
+            count_pascal_lines(&mut lines_of(temp_dir.path().join("blocks.pas").as_path()))?;
         assert_eq!(stats.code_lines, 5, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 9, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -4398,7 +4716,7 @@ fn decorated() {
             "program Blank;\n\nbegin\nvalue := 1; { brace } tail;\nvalue := 2; (* paren *) tail2;\nend.\n",
         )?;
         let (stats, _total_lines) =
-            count_pascal_lines(temp_dir.path().join("blank_tails.pas").as_path())?;
+            count_pascal_lines(&mut lines_of(temp_dir.path().join("blank_tails.pas").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank line counted: {stats:?}"
@@ -4420,7 +4738,7 @@ fn decorated() {
         let cases: Vec<(
             &str,
             &str,
-            fn(&Path) -> io::Result<(LanguageStats, u64)>,
+            fn(&mut LossyLineReader) -> io::Result<(LanguageStats, u64)>,
             &str,
         )> = vec![
             (
@@ -4476,7 +4794,7 @@ fn decorated() {
         for (file_name, contents, counter, label) in cases {
             let temp_dir = TempDir::new()?;
             create_test_file(temp_dir.path(), file_name, contents)?;
-            let (stats, _total_lines) = counter(&temp_dir.path().join(file_name))?;
+            let (stats, _total_lines) = counter(&mut lines_of(&temp_dir.path().join(file_name)))?;
             assert!(
                 stats.blank_lines >= 1,
                 "{label} should count at least one blank line, stats: {stats:?}"
@@ -4489,8 +4807,173 @@ fn decorated() {
         Ok(())
     }
 
+    #[test]
+    fn test_dedupe_by_content_skips_identical_copies() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vendor = temp_dir.path().join("vendor");
+        fs::create_dir(&vendor)?;
+        let body = "fn shared() {}\n// copied\n";
+        create_test_file(temp_dir.path(), "shared.rs", body)?;
+        create_test_file(&vendor, "shared_copy.rs", body)?;
+        create_test_file(&vendor, "other.rs", "fn other() {}\n")?;
+
+        let scan = |dedupe_by_content: bool| -> io::Result<(u64, u64)> {
+            let args = Args {
+                dedupe_by_content,
+                ..test_args()
+            };
+            let mut metrics = test_metrics();
+            let mut error_count = 0;
+            let mut entries_count = 0usize;
+            let stats = scan_directory(
+                temp_dir.path(),
+                &args,
+                temp_dir.path(),
+                &mut metrics,
+                0,
+                &mut entries_count,
+                &mut error_count,
+            )?;
+            let (files, _) = compute_grand_totals(&stats);
+            Ok((files, metrics.duplicate_files))
+        };
+        assert_eq!(scan(false)?, (3, 0));
+        assert_eq!(scan(true)?, (2, 1));
+        assert_ne!(fnv1a_64(b"a"), fnv1a_64(b"b"));
+        assert_eq!(fnv1a_64(b""), 0xcbf2_9ce4_8422_2325);
+        Ok(())
+    }
+
+    #[test]
+    fn test_perl_data_section_is_not_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "report.pl",
+            "# totals\nwhile (<DATA>) { print }\n  __END__ not a token\n__DATA__\nalpha 1\n=pod\n# not a comment\n\n",
+        )?;
+        let (stats, total_lines) = count_perl_lines(&mut lines_of(&temp_dir.path().join("report.pl")))?;
+        assert_eq!(total_lines, 8);
+        assert_eq!(stats.code_lines, 3, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
+        assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
+        assert_eq!(stats.ignored_lines, 4, "stats: {:?}", stats);
+        Ok(())
+    }
+
     // --- New Tests ---
 
+    #[test]
+    fn test_ignore_markers_bracket_three_lines() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "vendored.c",
+            "int a;\n// mdkloc:ignore-start\nint b;\n/* pasted */\n\n// mdkloc:ignore-end\nint c;\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "vendored.py",
+            "x = 1\n# mdkloc:ignore-start\ny = 2\n'''\nz = 3\n# mdkloc:ignore-end\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "vendored.rs",
+            "fn a() {}\n// mdkloc:ignore-start\nfn b() {}\nfn c() {}\n/* open\n// mdkloc:ignore-end\nfn d() {}\n",
+        )?;
+
+        let (c_stats, c_total) = count_c_style_lines(&mut lines_of(&temp_dir.path().join("vendored.c")))?;
+        assert_eq!(c_total, 7);
+        assert_eq!(c_stats.ignored_lines, 3);
+        assert_eq!(c_stats.code_lines, 2);
+        assert_eq!(c_stats.comment_lines, 2);
+        assert_eq!(c_stats.blank_lines, 0);
+
+        // The unterminated `'''` inside the range must not leak past the end marker.
+        let (py_stats, _) = count_python_lines(&mut lines_of(&temp_dir.path().join("vendored.py")))?;
+        assert_eq!(py_stats.ignored_lines, 3);
+        assert_eq!(py_stats.code_lines, 1);
+        assert_eq!(py_stats.comment_lines, 2);
+
+        let split = count_lines_with_roles(
+            &temp_dir.path().join("vendored.rs"),
+            FileRoleHint::Unknown,
+            &test_args(),
+        )?;
+        let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.ignored_lines, 3);
+        assert_eq!(bucket.stats.code_lines, 2);
+        assert_eq!(bucket.stats.comment_lines, 2);
+        assert_eq!(bucket.total_lines, 7);
+        let normalized = normalize_stats(bucket.stats, bucket.total_lines);
+        assert_eq!(normalized.blank_lines, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_markers_outside_comments_are_not_honoured() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "marker.rs",
+            "const S: &str = \"mdkloc:ignore-start\";\n/// Mentions `mdkloc:ignore-start` in prose.\nfn a() {}\nfn b() {} // mdkloc:ignore-start\nfn c() {}\n",
+        )?;
+        let (stats, total) = count_rust_lines(&mut lines_of(&temp_dir.path().join("marker.rs")))?;
+        assert_eq!(total, 5);
+        assert_eq!(stats.ignored_lines, 0, "stats: {:?}", stats);
+        assert_eq!(stats.code_lines, 4, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
+
+        // A Python `#` marker is not a comment token in C.
+        create_test_file(temp_dir.path(), "marker.c", "int a;\n# mdkloc:ignore-start\nint b;\n")?;
+        let (c_stats, _) = count_c_style_lines(&mut lines_of(&temp_dir.path().join("marker.c")))?;
+        assert_eq!(c_stats.ignored_lines, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_runtime_budget_keeps_partial_stats() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let sub_dir = temp_dir.path().join("sub");
+        fs::create_dir(&sub_dir)?;
+        create_test_file(temp_dir.path(), "a.rs", "fn a() {}\n")?;
+        create_test_file(&sub_dir, "b.rs", "fn b() {}\n")?;
+        let args = Args {
+            max_runtime: Some(Duration::ZERO),
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        assert!(metrics.runtime_exhausted);
+        assert!(stats.is_empty());
+        assert_eq!(error_count, 0);
+
+        // Once latched, the budget stays exhausted even for a generous limit.
+        assert!(metrics.runtime_exceeded(Some(Duration::from_secs(3600))));
+        let mut fresh = test_metrics();
+        assert!(!fresh.runtime_exceeded(Some(Duration::from_secs(3600))));
+        assert!(!fresh.runtime_exceeded(None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_runtime_budget() {
+        assert_eq!(parse_runtime_budget("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_runtime_budget("0"), Ok(Duration::ZERO));
+        assert!(parse_runtime_budget("-1").is_err());
+        assert!(parse_runtime_budget("soon").is_err());
+    }
+
     #[test]
     fn test_merge_directory_stats_accumulates() {
         let mut target = HashMap::new();
@@ -4506,6 +4989,7 @@ fn decorated() {
                     comment_lines: 2,
                     blank_lines: 1,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -4521,6 +5005,7 @@ fn decorated() {
                     comment_lines: 3,
                     blank_lines: 0,
                     overlap_lines: 1,
+                    ..Default::default()
                 },
             ),
         );
@@ -4533,6 +5018,7 @@ fn decorated() {
                     comment_lines: 1,
                     blank_lines: 2,
                     overlap_lines: 0,
+                    ..Default::default()
                 },
             ),
         );
@@ -4571,7 +5057,7 @@ fn decorated() {
         let mut metrics = test_metrics();
         let mut entries_count = 0usize;
         let mut error_count = 0usize;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
         let stats = scan_directory_impl(
             &file_path,
             &test_args(),
@@ -4582,6 +5068,7 @@ fn decorated() {
             &mut error_count,
             None,
             &mut visited_paths,
+            None,
         )?;
 
         assert_eq!(error_count, 0);
@@ -4611,7 +5098,7 @@ fn decorated() {
     fn test_get_language_from_extension_multipart_and_unknown() {
         assert_eq!(
             get_language_from_extension("component.d.ts"),
-            Some("TypeScript")
+            Some("TypeScript (decl)")
         );
         assert_eq!(get_language_from_extension("layout.view.jsx"), Some("JSX"));
         assert_eq!(get_language_from_extension("CONFIG.CFG"), Some("INI"));
@@ -4620,20 +5107,377 @@ fn decorated() {
     }
 
     #[test]
-    fn test_dotfile_language_detection() {
-        assert_eq!(get_language_from_extension(".bashrc"), Some("Shell"));
-        assert_eq!(get_language_from_extension(".zprofile"), Some("Shell"));
-        assert_eq!(get_language_from_extension(".kshrc"), Some("Shell"));
-        assert_eq!(get_language_from_extension(".cshrc"), Some("Shell"));
+    fn test_custom_language_from_config_counts_dsl() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "mdkloc.ini",
+            "# in-house languages\n[language.Lisp DSL]\nextensions = .dsl lspx\nline_comment = ;;\nblock_comment = #| |#\nnested = true\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "rules.DSL",
+            ";; header\n(defrule a) ;; trailing\n#| outer\n  #| inner |#\nstill comment |# (defrule b)\n\n(defrule c)\n",
+        )?;
+        let args = Args {
+            custom_languages: load_custom_languages(&temp_dir.path().join("mdkloc.ini"))?,
+            ..test_args()
+        };
         assert_eq!(
-            get_language_from_extension("Dockerfile.prod"),
-            Some("Dockerfile")
-        );
-        assert_eq!(get_language_from_extension("CMakeLists.txt"), Some("CMake"));
+            args.custom_languages,
+            vec![CustomLanguage {
+                name: "Lisp DSL".to_string(),
+                extensions: vec!["dsl".to_string(), "lspx".to_string()],
+                line_comments: vec![";;".to_string()],
+                block_comments: vec![("#|".to_string(), "|#".to_string())],
+                nested: true,
+            }]
+        );
+        assert_eq!(language_for_file("rules.DSL", &args), Some("Lisp DSL"));
+        assert_eq!(language_for_file("main.rs", &args), Some("Rust"));
+
+        let split = count_lines_with_roles(
+            &temp_dir.path().join("rules.DSL"),
+            FileRoleHint::Unknown,
+            &args,
+        )?;
+        let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.code_lines, 3, "{:?}", bucket.stats);
+        assert_eq!(bucket.stats.comment_lines, 5, "{:?}", bucket.stats);
+        assert_eq!(bucket.stats.blank_lines, 1, "{:?}", bucket.stats);
+        Ok(())
     }
 
     #[test]
-    fn test_args_parsing_flags() {
+    fn test_parse_custom_languages_rejects_bad_config() {
+        let cases = [
+            ("extensions = x\n", "outside a [language.<name>] section"),
+            ("[language.]\n", "expected [language.<name>]"),
+            ("[language.A]\nline_comment = //\n", "has no extensions"),
+            ("[language.A]\nextensions = a\nblock_comment = {\n", "block_comment needs"),
+            ("[language.A]\nextensions = a\nnested = maybe\n", "nested must be true or false"),
+            ("[language.A]\nextensions = a\ncolour = red\n", "unknown key 'colour'"),
+            ("[language.A]\nno equals sign\n", "line 2: expected key = value"),
+        ];
+        for (text, expected) in cases {
+            let err = parse_custom_languages(text).expect_err(text);
+            assert!(err.contains(expected), "{text:?} => {err}");
+        }
+    }
+
+    #[test]
+    fn test_min_lines_hides_rows_but_keeps_totals() {
+        let base = Path::new("/project");
+        let mut stats_map = HashMap::new();
+        let mut big = DirectoryStats::default();
+        big.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    code_lines: 8,
+                    comment_lines: 1,
+                    blank_lines: 1,
+                    ..Default::default()
+                },
+            ),
+        );
+        let mut stub = DirectoryStats::default();
+        stub.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    code_lines: 1,
+                    ..Default::default()
+                },
+            ),
+        );
+        stats_map.insert(base.join("big"), big);
+        stats_map.insert(base.join("stub"), stub);
+
+        let options = ReportOptions {
+            min_lines: 2,
+            ..Default::default()
+        };
+        let report = build_analysis_report(base, &stats_map, 2, 11, 0, &options);
+        assert!(report.lines().any(|l| l.starts_with("big ")), "{report}");
+        assert!(!report.lines().any(|l| l.starts_with("stub ")), "{report}");
+        assert!(
+            report.contains("(1 row(s) under --min-lines 2 hidden; still included in totals)"),
+            "{report}"
+        );
+        let totals_row = format_language_stats_line(
+            "",
+            "Rust",
+            2,
+            &LanguageStats {
+                code_lines: 9,
+                comment_lines: 1,
+                blank_lines: 1,
+                ..Default::default()
+            },
+            TableLayout::default(),
+        );
+        assert!(report.contains(&totals_row), "{report}");
+
+        let unfiltered =
+            build_analysis_report(base, &stats_map, 2, 11, 0, &ReportOptions::default());
+        assert!(unfiltered.lines().any(|l| l.starts_with("stub ")), "{unfiltered}");
+        assert!(!unfiltered.contains("hidden"), "{unfiltered}");
+    }
+
+    #[test]
+    fn test_bytes_flag_records_size_per_language() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        // "é" is two bytes but one char; the invalid byte decodes to one char.
+        fs::write(temp_dir.path().join("notes.py"), b"# h\xc3\xa9llo\nx = '\xff'\n")?;
+        let mut lines = lines_of(&temp_dir.path().join("notes.py"));
+        count_source_lines("notes.py", &mut lines)?;
+        assert_eq!(lines.finish_size(), (17, 16));
+        // A BOM is stripped from the first line but still counted.
+        let mut lines = LossyLineReader::with_reader(io::Cursor::new(b"\xEF\xBB\xBFx\n".to_vec()));
+        assert_eq!(lines.next().transpose()?.as_deref(), Some("x"));
+        assert_eq!(lines.finish_size(), (5, 3));
+
+        let args = Args {
+            path: temp_dir.path().to_string_lossy().to_string(),
+            bytes: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        let root_canon = fs::canonicalize(temp_dir.path())?;
+        let dir_stats = stats
+            .get(&root_canon)
+            .or_else(|| stats.get(temp_dir.path()))
+            .unwrap();
+        let (_, py_stats) = dir_stats.language_stats["Python"].summary();
+        assert_eq!((py_stats.bytes, py_stats.chars), (17, 16));
+
+        let report = build_analysis_report(
+            temp_dir.path(),
+            &stats,
+            1,
+            2,
+            0,
+            &ReportOptions::from_args(&args),
+        );
+        assert!(report.contains("Size by language:"), "{report}");
+        let plain = build_analysis_report(
+            temp_dir.path(),
+            &stats,
+            1,
+            2,
+            0,
+            &ReportOptions::default(),
+        );
+        assert!(!plain.contains("Size by language:"), "{plain}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apportion_file_size_splits_by_role_lines() {
+        let mut pending = vec![
+            (
+                CodeRole::Mainline,
+                LanguageStats {
+                    code_lines: 3,
+                    ..Default::default()
+                },
+            ),
+            (
+                CodeRole::Test,
+                LanguageStats {
+                    code_lines: 1,
+                    comment_lines: 1,
+                    ..Default::default()
+                },
+            ),
+        ];
+        apportion_file_size(&mut pending, 101, 50);
+        assert_eq!((pending[0].1.bytes, pending[0].1.chars), (60, 30));
+        assert_eq!((pending[1].1.bytes, pending[1].1.chars), (41, 20));
+    }
+
+    #[test]
+    fn test_jsonc_and_json5_honour_comments_outside_strings() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let body = "// editor settings\n{\n  /* schema */ \"$schema\": \"http://example.com/s.json\",\n  'glob': '/* not a comment */', // trailing\n  /* multi\n     line */\n}\n";
+        create_test_file(temp_dir.path(), "settings.jsonc", body)?;
+        create_test_file(temp_dir.path(), "settings.json5", body)?;
+        create_test_file(temp_dir.path(), "tsconfig.json", body)?;
+        assert_eq!(get_language_from_extension("settings.jsonc"), Some("JSONC"));
+        assert_eq!(get_language_from_extension("settings.json5"), Some("JSON5"));
+
+        for name in ["settings.jsonc", "settings.json5"] {
+            let (stats, total_lines) = count_lines_with_stats(&temp_dir.path().join(name))?;
+            assert_eq!(total_lines, 7, "{name}");
+            assert_eq!(stats.code_lines, 4, "{name}: {stats:?}");
+            assert_eq!(stats.comment_lines, 5, "{name}: {stats:?}");
+        }
+
+        // Plain `.json` keeps the no-comments behaviour unless `--jsonc` is set.
+        let json_path = temp_dir.path().join("tsconfig.json");
+        let plain = count_lines_with_roles(&json_path, FileRoleHint::Unknown, &test_args())?;
+        let bucket = plain.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.code_lines, 7);
+        assert_eq!(bucket.stats.comment_lines, 0);
+        let args = Args {
+            jsonc: true,
+            ..test_args()
+        };
+        let commented = count_lines_with_roles(&json_path, FileRoleHint::Unknown, &args)?;
+        let bucket = commented.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.code_lines, 4);
+        assert_eq!(bucket.stats.comment_lines, 5);
+        assert_eq!(language_for_file("tsconfig.json", &args), Some("JSON"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lockfiles_bucketed_unless_count_lockfiles() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "Cargo.lock",
+            "# generated\n[[package]]\nname = \"mdkloc\"\n\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "yarn.lock",
+            "# yarn lockfile v1\n\"left-pad@^1.3.0\":\n  version \"1.3.0\"\n",
+        )?;
+        let args = test_args();
+        assert_eq!(language_for_file("Cargo.lock", &args), Some("Lockfile"));
+        assert_eq!(language_for_file("yarn.lock", &args), Some("Lockfile"));
+        assert_eq!(language_for_file("package-lock.json", &args), Some("Lockfile"));
+        assert_eq!(language_for_file("other.lock", &args), None);
+
+        // Lockfiles use generic counting, so `#` lines are not comments.
+        for (name, code, blank) in [("Cargo.lock", 3, 1), ("yarn.lock", 3, 0)] {
+            let split = count_lines_with_roles(
+                &temp_dir.path().join(name),
+                FileRoleHint::Unknown,
+                &args,
+            )?;
+            let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+            assert_eq!(bucket.stats.code_lines, code, "{name}");
+            assert_eq!(bucket.stats.comment_lines, 0, "{name}");
+            assert_eq!(bucket.stats.blank_lines, blank, "{name}");
+        }
+
+        let native = Args {
+            count_lockfiles: true,
+            ..test_args()
+        };
+        assert_eq!(language_for_file("package-lock.json", &native), Some("JSON"));
+        assert_eq!(language_for_file("pnpm-lock.yaml", &native), Some("YAML"));
+        assert_eq!(language_for_file("Cargo.lock", &native), Some("TOML"));
+        assert_eq!(language_for_file("yarn.lock", &native), Some("YAML"));
+        assert_eq!(language_for_file("go.sum", &native), Some("Lockfile"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_lockfiles_keeps_every_lockfile_under_a_language() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "Cargo.lock", "# generated\n[[package]]\nname = \"mdkloc\"\n")?;
+        create_test_file(temp_dir.path(), "yarn.lock", "# yarn lockfile v1\nleft-pad@^1.3.0:\n  version \"1.3.0\"\n")?;
+        create_test_file(temp_dir.path(), "package-lock.json", "{\n  \"lockfileVersion\": 3\n}\n")?;
+        let scan = |args: &Args| -> io::Result<HashMap<String, (u64, LanguageStats)>> {
+            let stats = scan_directory(temp_dir.path(), args, temp_dir.path(), &mut test_metrics(), 0, &mut 0, &mut 0)?;
+            Ok(totals_by_language(&stats))
+        };
+        let bucketed = scan(&test_args())?;
+        assert_eq!(bucketed.keys().collect::<Vec<_>>(), vec!["Lockfile"]);
+        assert_eq!(bucketed["Lockfile"].0, 3);
+
+        let native = scan(&Args {
+            count_lockfiles: true,
+            ..test_args()
+        })?;
+        let files: u64 = native.values().map(|(files, _)| files).sum();
+        assert_eq!(files, 3);
+        // Natively counted, `#` lines in TOML and YAML are comments.
+        let (_, toml) = &native["TOML"];
+        assert_eq!((toml.code_lines, toml.comment_lines), (2, 1));
+        let (_, yaml) = &native["YAML"];
+        assert_eq!((yaml.code_lines, yaml.comment_lines), (2, 1));
+        assert_eq!(native["JSON"].1.code_lines, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_m_files_default_to_objective_c() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "View.m",
+            "// view\n#import \"View.h\"\n/* impl */\n@implementation View\n@end\n",
+        )?;
+        let args = test_args();
+        assert_eq!(language_for_file("View.m", &args), Some("Objective-C"));
+        assert_eq!(language_for_file("Bridge.mm", &args), Some("Objective-C++"));
+        assert_eq!(language_for_file("View.h", &args), Some("C/C++"));
+        let split = count_lines_with_roles(
+            &temp_dir.path().join("View.m"),
+            FileRoleHint::Unknown,
+            &args,
+        )?;
+        let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.code_lines, 3);
+        assert_eq!(bucket.stats.comment_lines, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_m_files_as_matlab() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "solve.m",
+            "% solve a system\nfunction x = solve(A, b)\n%{\nblock comment\n%}\nx = A \\ b;\nend\n",
+        )?;
+        let args = Args::parse_from(["mdkloc", "--m-is", "matlab"]);
+        assert_eq!(language_for_file("solve.m", &args), Some("MATLAB"));
+        assert_eq!(language_for_file("Bridge.mm", &args), Some("Objective-C++"));
+        let split = count_lines_with_roles(
+            &temp_dir.path().join("solve.m"),
+            FileRoleHint::Unknown,
+            &args,
+        )?;
+        let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!(bucket.stats.code_lines, 3);
+        assert_eq!(bucket.stats.comment_lines, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dotfile_language_detection() {
+        assert_eq!(get_language_from_extension(".bashrc"), Some("Shell"));
+        assert_eq!(get_language_from_extension(".zprofile"), Some("Shell"));
+        assert_eq!(get_language_from_extension(".kshrc"), Some("Shell"));
+        assert_eq!(get_language_from_extension(".cshrc"), Some("Shell"));
+        assert_eq!(
+            get_language_from_extension("Dockerfile.prod"),
+            Some("Dockerfile")
+        );
+        assert_eq!(get_language_from_extension("CMakeLists.txt"), Some("CMake"));
+    }
+
+    #[test]
+    fn test_args_parsing_flags() {
         let args = Args::parse_from([
             "mdkloc",
             "--non-recursive",
@@ -4693,7 +5537,7 @@ fn decorated() {
         create_test_file(temp_dir.path(), "file.xyz", content)?;
 
         let (stats, _total_lines) =
-            count_generic_lines(temp_dir.path().join("file.xyz").as_path())?;
+            count_generic_lines(&mut lines_of(temp_dir.path().join("file.xyz").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.blank_lines, 2);
         // Generic counting does not track comment lines
@@ -4729,15 +5573,15 @@ fn decorated() {
         let nested = base.join("nested");
         fs::create_dir_all(&nested)?;
 
-        let display_root = format_directory_display(&base, &base);
+        let display_root = format_directory_display(&base, &base, DIR_WIDTH);
         assert_eq!(display_root, ".");
 
-        let display_nested = format_directory_display(&nested, &base);
+        let display_nested = format_directory_display(&nested, &base, DIR_WIDTH);
         assert_eq!(display_nested, "nested");
 
         let external_dir = TempDir::new()?;
         let external = fs::canonicalize(external_dir.path())?;
-        let display_external = format_directory_display(&external, &base);
+        let display_external = format_directory_display(&external, &base, DIR_WIDTH);
         let tail = external
             .file_name()
             .and_then(|name| name.to_str())
@@ -4765,7 +5609,7 @@ fn decorated() {
             "# comment\n\nall:\n\t@echo hello # inline\n",
         )?;
         let (stats, _total_lines) =
-            count_makefile_lines(temp_dir.path().join("Makefile").as_path())?;
+            count_makefile_lines(&mut lines_of(temp_dir.path().join("Makefile").as_path()))?;
         assert_eq!(stats.code_lines, 2); // all:, recipe line
         assert_eq!(stats.comment_lines, 1);
         assert_eq!(stats.blank_lines, 1);
@@ -4787,7 +5631,7 @@ fn decorated() {
             "# comment\nFROM alpine\nRUN echo hi\n",
         )?;
         let (stats, _total_lines) =
-            count_dockerfile_lines(temp_dir.path().join("Dockerfile").as_path())?;
+            count_dockerfile_lines(&mut lines_of(temp_dir.path().join("Dockerfile").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 1);
         Ok(())
@@ -4805,7 +5649,7 @@ fn decorated() {
             "main.tf",
             "# comment\n// also comment\nresource \"x\" \"y\" {\n  a = 1 /* inline */\n}\n/*\nblock\n*/\n",
         )?;
-        let (stats, _total_lines) = count_hcl_lines(temp_dir.path().join("main.tf").as_path())?;
+        let (stats, _total_lines) = count_hcl_lines(&mut lines_of(temp_dir.path().join("main.tf").as_path()))?;
         assert!(stats.code_lines >= 3);
         assert!(stats.comment_lines >= 4);
         Ok(())
@@ -4819,7 +5663,7 @@ fn decorated() {
             "inline.tf",
             "resource \"x\" \"y\" { /* block */ name = \"demo\" }\nvalue = 1 /* comment */\n/* open\n comment */ value = 2\n",
         )?;
-        let (stats, _total_lines) = count_hcl_lines(temp_dir.path().join("inline.tf").as_path())?;
+        let (stats, _total_lines) = count_hcl_lines(&mut lines_of(temp_dir.path().join("inline.tf").as_path()))?;
         assert!(stats.code_lines >= 3);
         assert!(stats.comment_lines >= 3);
         Ok(())
@@ -4844,7 +5688,7 @@ fn decorated() {
             "resource \"x\" \"y\" {\n\n  value = 1\n}\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("blank_lines.tf").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("blank_lines.tf").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank separator to count as blank: {stats:?}"
@@ -4866,7 +5710,7 @@ fn decorated() {
             "value = 1 /* block */ value2 // trailing line comment\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("inline_block_line.tf").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("inline_block_line.tf").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected block and line comments counted: {stats:?}"
@@ -4887,7 +5731,7 @@ fn decorated() {
             "value = 1 /* block */ value2 # trailing hash comment\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("inline_block_hash.tf").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("inline_block_hash.tf").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected block and hash comments counted: {stats:?}"
@@ -4908,7 +5752,7 @@ fn decorated() {
             "value = 1 /* block */ value2 ## trailing doc\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("inline_block_doc.tf").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("inline_block_doc.tf").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected block and doc comments counted: {stats:?}"
@@ -4929,7 +5773,7 @@ fn decorated() {
             "resource \"x\" \"y\" {\n  attr = 1 /* block\n     still comment */ value = 2 // trailing line comment\n}\n",
         )?;
         let (stats, _total_lines) =
-            count_hcl_lines(temp_dir.path().join("block_close_code_line.tf").as_path())?;
+            count_hcl_lines(&mut lines_of(temp_dir.path().join("block_close_code_line.tf").as_path()))?;
         assert!(
             stats.code_lines >= 4,
             "expected resource, assignments, and closing brace counted as code: {stats:?}"
@@ -4949,11 +5793,10 @@ fn decorated() {
             "block_close_comment_variants.tf",
             "/* doc block\n   continues */ ## doc comment\n/* another block\n   runs */ // trailing line comment\n/* hash block\n   persists */ # trailing hash\nresource \"x\" \"y\" {}\n",
         )?;
-        let (stats, _total_lines) = count_hcl_lines(
-            temp_dir
+        let (stats, _total_lines) = count_hcl_lines(&mut lines_of(temp_dir
                 .path()
                 .join("block_close_comment_variants.tf")
-                .as_path(),
+                .as_path()),
         )?;
         assert!(
             stats.comment_lines >= 9,
@@ -4979,7 +5822,7 @@ fn decorated() {
             "# top\ncmake_minimum_required(VERSION 3.25)\nproject(demo)\n# end\n",
         )?;
         let (stats, _total_lines) =
-            count_cmake_lines(temp_dir.path().join("CMakeLists.txt").as_path())?;
+            count_cmake_lines(&mut lines_of(temp_dir.path().join("CMakeLists.txt").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         Ok(())
@@ -4994,7 +5837,7 @@ fn decorated() {
             "# line\nWrite-Host 'hi'\n<# block\ncomment #> Write-Host 'after'\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("script.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("script.ps1").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5009,7 +5852,7 @@ fn decorated() {
             "Write-Host 1 <# inline #> # trailing\n<# block\ncontinues\n#>\nWrite-Host 2\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("mixed.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("mixed.ps1").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5023,7 +5866,7 @@ fn decorated() {
             "run.bat",
             "REM header\n:: also comment\n@echo on\nset X=1\n",
         )?;
-        let (stats, _total_lines) = count_batch_lines(temp_dir.path().join("run.bat").as_path())?;
+        let (stats, _total_lines) = count_batch_lines(&mut lines_of(temp_dir.path().join("run.bat").as_path()))?;
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.code_lines, 2);
         Ok(())
@@ -5037,7 +5880,7 @@ fn decorated() {
             "prog.tcl",
             "#! /usr/bin/env tclsh\n# comment\nputs \"hello\"\n",
         )?;
-        let (stats, _total_lines) = count_tcl_lines(temp_dir.path().join("prog.tcl").as_path())?;
+        let (stats, _total_lines) = count_tcl_lines(&mut lines_of(temp_dir.path().join("prog.tcl").as_path()))?;
         assert_eq!(stats.code_lines, 2); // shebang + puts
         assert_eq!(stats.comment_lines, 1);
         Ok(())
@@ -5051,7 +5894,7 @@ fn decorated() {
             "doc.rst",
             "Title\n=====\n\n.. comment\n\nParagraph text.\n",
         )?;
-        let (stats, _total_lines) = count_rst_lines(temp_dir.path().join("doc.rst").as_path())?;
+        let (stats, _total_lines) = count_rst_lines(&mut lines_of(temp_dir.path().join("doc.rst").as_path()))?;
         assert_eq!(stats.blank_lines, 2);
         assert_eq!(stats.comment_lines, 0);
         assert_eq!(stats.code_lines, 4);
@@ -5067,7 +5910,7 @@ fn decorated() {
             "## line comment\nHello #* block *# World\n#* multi\nline *#\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template.vm").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5082,7 +5925,7 @@ fn decorated() {
             "Hello\n\n#* block start\nstill comment\n*# tail code\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template_blank.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template_blank.vm").as_path()))?;
         assert_eq!(
             stats.blank_lines, 1,
             "expected single blank separator: {stats:?}"
@@ -5107,7 +5950,7 @@ fn decorated() {
             "Hello #* block *# ## trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template_trailing.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template_trailing.vm").as_path()))?;
         assert!(
             stats.code_lines >= 1,
             "expected leading code counted: {stats:?}"
@@ -5128,7 +5971,7 @@ fn decorated() {
             "Hello\n#* block start\nstill comment\n*# ## trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template_block_line.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template_block_line.vm").as_path()))?;
         assert!(
             stats.comment_lines >= 3,
             "expected block lines and trailing line comment counted: {stats:?}"
@@ -5149,7 +5992,7 @@ fn decorated() {
             "Hello\n#* block start\nstill comment\n*#   \nValue\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template_block_only.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template_block_only.vm").as_path()))?;
         assert!(
             stats.comment_lines >= 3,
             "expected block comment lines counted: {stats:?}"
@@ -5169,11 +6012,10 @@ fn decorated() {
             "template_block_whitespace.vm",
             "#* comment-only block *#   \nNext\n",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_whitespace.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 1,
@@ -5195,7 +6037,7 @@ fn decorated() {
             "#* inline block *#\nValue\n",
         )?;
         let (stats, _total_lines) =
-            count_velocity_lines(temp_dir.path().join("template_block_inline.vm").as_path())?;
+            count_velocity_lines(&mut lines_of(temp_dir.path().join("template_block_inline.vm").as_path()))?;
         assert_eq!(
             stats.comment_lines, 1,
             "expected single block comment counted: {stats:?}"
@@ -5215,11 +6057,10 @@ fn decorated() {
             "template_block_inline_ws_tail.vm",
             "Hello #* inline block *#   ",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_inline_ws_tail.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 1,
@@ -5244,11 +6085,10 @@ fn decorated() {
             "template_block_inline_tail.vm",
             "Hello #* inline block *#Tail\n",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_inline_tail.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 1,
@@ -5270,11 +6110,10 @@ fn decorated() {
             "template_block_code_whitespace.vm",
             "Hello #* comment *#   \nNext\n",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_code_whitespace.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert!(
             stats.code_lines >= 2,
@@ -5295,11 +6134,10 @@ fn decorated() {
             "template_block_close_code.vm",
             "#* block start\nstill comment\n*# Tail\n",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_close_code.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 3,
@@ -5320,11 +6158,10 @@ fn decorated() {
             "template_block_close_comment.vm",
             "#* block start\nstill comment\n*#   ## trailing\n",
         )?;
-        let (stats, _total_lines) = count_velocity_lines(
-            temp_dir
+        let (stats, _total_lines) = count_velocity_lines(&mut lines_of(temp_dir
                 .path()
                 .join("template_block_close_comment.vm")
-                .as_path(),
+                .as_path()),
         )?;
         assert_eq!(
             stats.comment_lines, 4,
@@ -5346,7 +6183,7 @@ fn decorated() {
             "{{! top }}\nHello {{name}}\n{{! multi\n line }}\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("view.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("view.mustache").as_path()))?;
         assert!(stats.code_lines >= 1);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5361,7 +6198,7 @@ fn decorated() {
             "Hello {{name}}\n\n{{! trailing }}\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("view_blank.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("view_blank.mustache").as_path()))?;
         assert_eq!(
             stats.blank_lines, 1,
             "expected blank line counted: {stats:?}"
@@ -5386,7 +6223,7 @@ fn decorated() {
             "{{! comment }} tail\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("view_trailing.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("view_trailing.mustache").as_path()))?;
         assert_eq!(
             stats.comment_lines, 1,
             "expected comment counted: {stats:?}"
@@ -5407,7 +6244,7 @@ fn decorated() {
             "{{! comment only }}\nHello\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("comment_only.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("comment_only.mustache").as_path()))?;
         assert_eq!(
             stats.comment_lines, 1,
             "expected lone comment line counted: {stats:?}"
@@ -5428,7 +6265,7 @@ fn decorated() {
             "prefix {{! inline note }} suffix\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("view_inline.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("view_inline.mustache").as_path()))?;
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         assert!(
             stats.code_lines >= 2,
@@ -5446,7 +6283,7 @@ fn decorated() {
             "{{! start\ncontinues\n}} tail\n",
         )?;
         let (stats, _total_lines) =
-            count_mustache_lines(temp_dir.path().join("view_block.mustache").as_path())?;
+            count_mustache_lines(&mut lines_of(temp_dir.path().join("view_block.mustache").as_path()))?;
         assert!(
             stats.comment_lines >= 3,
             "expected each line of the block comment counted: {stats:?}"
@@ -5466,11 +6303,10 @@ fn decorated() {
             "view_block_no_tail.mustache",
             "{{! start\ncontinues\n}}\nHello\n",
         )?;
-        let (stats, _total_lines) = count_mustache_lines(
-            temp_dir
+        let (stats, _total_lines) = count_mustache_lines(&mut lines_of(temp_dir
                 .path()
                 .join("view_block_no_tail.mustache")
-                .as_path(),
+                .as_path()),
         )?;
         assert!(
             stats.comment_lines >= 3,
@@ -5492,7 +6328,7 @@ fn decorated() {
             "// comment\n/* block */\nsyntax = \"proto3\";\n",
         )?;
         let (stats, _total_lines) =
-            count_c_style_lines(temp_dir.path().join("msg.proto").as_path())?;
+            count_c_style_lines(&mut lines_of(temp_dir.path().join("msg.proto").as_path()))?;
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         Ok(())
@@ -5508,9 +6344,10 @@ fn decorated() {
             "y.c",
             "int a; /* c1 */ mid /* c2 */ end;\n",
         )?;
-        let (stats, _total_lines) = count_c_style_lines(temp_dir.path().join("y.c").as_path())?;
-        assert!(stats.code_lines >= 3);
-        assert!(stats.comment_lines >= 2);
+        let (stats, _total_lines) = count_c_style_lines(&mut lines_of(temp_dir.path().join("y.c").as_path()))?;
+        assert_eq!(stats.code_lines, 1);
+        assert_eq!(stats.comment_lines, 1);
+        assert_eq!(stats.overlap_lines, 1);
         Ok(())
     }
 
@@ -5522,7 +6359,7 @@ fn decorated() {
             "mixed.c",
             "int a = 0; // comment /* ignored */\nint b = 0; /* block */ // trailing\n",
         )?;
-        let (stats, _total_lines) = count_c_style_lines(temp_dir.path().join("mixed.c").as_path())?;
+        let (stats, _total_lines) = count_c_style_lines(&mut lines_of(temp_dir.path().join("mixed.c").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5536,7 +6373,7 @@ fn decorated() {
             "block.c",
             "int value = 0; /* start\ncontinues */ value += 1;\n",
         )?;
-        let (stats, _total_lines) = count_c_style_lines(temp_dir.path().join("block.c").as_path())?;
+        let (stats, _total_lines) = count_c_style_lines(&mut lines_of(temp_dir.path().join("block.c").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 1);
         Ok(())
@@ -5552,7 +6389,7 @@ fn decorated() {
             "combo.c",
             "int main() {\n/* comment opens\ncontinues */ // trailing\nreturn 0;\n}\n",
         )?;
-        let (stats, _total_lines) = count_c_style_lines(temp_dir.path().join("combo.c").as_path())?;
+        let (stats, _total_lines) = count_c_style_lines(&mut lines_of(temp_dir.path().join("combo.c").as_path()))?;
         assert!(stats.comment_lines >= 2, "stats: {:?}", stats);
         assert!(stats.code_lines >= 3, "stats: {:?}", stats);
         Ok(())
@@ -5567,7 +6404,7 @@ fn decorated() {
             "int value = 42; // trailing comment\n",
         )?;
         let (stats, _total_lines) =
-            count_c_style_lines(temp_dir.path().join("line_comment.c").as_path())?;
+            count_c_style_lines(&mut lines_of(temp_dir.path().join("line_comment.c").as_path()))?;
         assert_eq!(
             stats.code_lines, 1,
             "expected code before // counted: {stats:?}"
@@ -5588,7 +6425,7 @@ fn decorated() {
             "int start = 0; /* begin // still comment\n*/ int done = 1;\n",
         )?;
         let (stats, _total_lines) =
-            count_c_style_lines(temp_dir.path().join("block_unterminated.c").as_path())?;
+            count_c_style_lines(&mut lines_of(temp_dir.path().join("block_unterminated.c").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected multi-line block comment recorded: {stats:?}"
@@ -5609,7 +6446,7 @@ fn decorated() {
             "int a = 0;\n\n/* block starts\nstill comment\n*/ int b = 1;\n",
         )?;
         let (stats, _total_lines) =
-            count_c_style_lines(temp_dir.path().join("blank_block.c").as_path())?;
+            count_c_style_lines(&mut lines_of(temp_dir.path().join("blank_block.c").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank line counted: {stats:?}"
@@ -5633,7 +6470,7 @@ fn decorated() {
             "x.php",
             "<?php\n$y = 1; /* c */ $z = 2;\n?>\n",
         )?;
-        let (stats, _total_lines) = count_php_lines(temp_dir.path().join("x.php").as_path())?;
+        let (stats, _total_lines) = count_php_lines(&mut lines_of(temp_dir.path().join("x.php").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 1);
         Ok(())
@@ -5647,7 +6484,7 @@ fn decorated() {
             "y.php",
             "<?php\n$foo = 1; /* block */ # trailing\n?>\n",
         )?;
-        let (stats, _total_lines) = count_php_lines(temp_dir.path().join("y.php").as_path())?;
+        let (stats, _total_lines) = count_php_lines(&mut lines_of(temp_dir.path().join("y.php").as_path()))?;
         assert!(stats.comment_lines >= 1); // block + hash comment
         assert!(stats.code_lines >= 1);
         Ok(())
@@ -5661,7 +6498,7 @@ fn decorated() {
             "inline.php",
             "<?php\n$value = 1; /* start\nstill comment */ $value++;\n?>\n",
         )?;
-        let (stats, _total_lines) = count_php_lines(temp_dir.path().join("inline.php").as_path())?;
+        let (stats, _total_lines) = count_php_lines(&mut lines_of(temp_dir.path().join("inline.php").as_path()))?;
         assert!(stats.code_lines >= 2);
         assert!(stats.comment_lines >= 1);
         Ok(())
@@ -5675,7 +6512,7 @@ fn decorated() {
             "complex.php",
             "<?php\n$val = 1; /* comment */ $other = 2; # trailing\n/* opening\nstill comment\n*/ # suppressed\necho 'done'; /* inline */ echo 'more';\n$final = true; /* keep */ // rest after comment\n# shell style comment\n?>\n",
         )?;
-        let (stats, _total_lines) = count_php_lines(temp_dir.path().join("complex.php").as_path())?;
+        let (stats, _total_lines) = count_php_lines(&mut lines_of(temp_dir.path().join("complex.php").as_path()))?;
         assert_eq!(stats.code_lines, 7, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 7, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -5691,8 +6528,8 @@ fn decorated() {
             "sheet.xsl",
             "<xsl:stylesheet><!-- c --></xsl:stylesheet>\n",
         )?;
-        let (svg_stats, _) = count_xml_like_lines(temp_dir.path().join("pic.svg").as_path())?;
-        let (xsl_stats, _) = count_xml_like_lines(temp_dir.path().join("sheet.xsl").as_path())?;
+        let (svg_stats, _) = count_xml_like_lines(&mut lines_of(temp_dir.path().join("pic.svg").as_path()))?;
+        let (xsl_stats, _) = count_xml_like_lines(&mut lines_of(temp_dir.path().join("sheet.xsl").as_path()))?;
         assert!(svg_stats.code_lines >= 1 && svg_stats.comment_lines >= 1);
         assert!(xsl_stats.code_lines >= 1 && xsl_stats.comment_lines >= 1);
         Ok(())
@@ -5702,7 +6539,7 @@ fn decorated() {
     fn test_xml_multiple_pairs_one_line() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "z.xml", "<a><!--c1--><b/><!--c2--></a>\n")?;
-        let (stats, _total) = count_xml_like_lines(temp_dir.path().join("z.xml").as_path())?;
+        let (stats, _total) = count_xml_like_lines(&mut lines_of(temp_dir.path().join("z.xml").as_path()))?;
         assert!(stats.code_lines >= 1);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -5824,7 +6661,7 @@ fn decorated() {
             "#[cfg(not(test))]".to_string(),
             "fn prod_only() {}".to_string(),
         ];
-        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown);
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
         assert_eq!(roles, vec![CodeRole::Mainline, CodeRole::Mainline]);
     }
 
@@ -5835,12 +6672,136 @@ fn decorated() {
             "mod tests;".to_string(),
             "fn mainline() {}".to_string(),
         ];
-        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown);
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
         assert_eq!(roles[0], CodeRole::Test);
         assert_eq!(roles[1], CodeRole::Test);
         assert_eq!(roles[2], CodeRole::Mainline);
     }
 
+    #[test]
+    fn test_detect_rust_line_roles_fn_scope_keeps_module_code_mainline() {
+        let lines: Vec<String> = [
+            "#[cfg(test)]",
+            "mod tests {",
+            "    fn helper() -> i32 { 2 }",
+            "",
+            "    #[test]",
+            "    fn adds() {",
+            "        assert_eq!(helper(), 2);",
+            "    }",
+            "}",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+
+        let cfg_roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
+        assert_eq!(cfg_roles, vec![CodeRole::Test; lines.len()]);
+
+        let fn_roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Fn, false);
+        assert_eq!(
+            fn_roles,
+            vec![
+                CodeRole::Mainline,
+                CodeRole::Mainline,
+                CodeRole::Mainline,
+                CodeRole::Mainline,
+                CodeRole::Test,
+                CodeRole::Test,
+                CodeRole::Test,
+                CodeRole::Test,
+                CodeRole::Mainline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_rust_line_roles_fn_scope_honours_async_test_attributes() {
+        let lines: Vec<String> = [
+            "#[cfg(test)]",
+            "mod tests {",
+            "    #[tokio::test]",
+            "    async fn runs() {",
+            "        work().await;",
+            "    }",
+            "    #[tokio::test(flavor = \"multi_thread\")]",
+            "    async fn threaded() {",
+            "        work().await;",
+            "    }",
+            "    #[async_std::test ]",
+            "    async fn std_runs() {}",
+            "}",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Fn, false);
+        assert_eq!(roles[1], CodeRole::Mainline);
+        assert_eq!(roles[2], CodeRole::Test);
+        assert_eq!(roles[4], CodeRole::Test);
+        assert_eq!(roles[6], CodeRole::Test);
+        assert_eq!(roles[8], CodeRole::Test);
+        assert_eq!(roles[11], CodeRole::Test);
+        assert_eq!(roles[12], CodeRole::Mainline);
+    }
+
+    #[test]
+    fn test_rust_role_counter_streaming_matches_per_block_counts() -> io::Result<()> {
+        let block = r#"/// Adds numbers.
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b /* inline */
+}
+
+/* multi-line
+   block comment */
+#[cfg(test)]
+mod tests {
+    // helper comment
+    #[test]
+    fn adds() {
+        let s = r"{ not a scope";
+        assert_eq!(super::add(1, 2), 3);
+    }
+}
+
+"#;
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        create_test_file(root, "single.rs", block)?;
+        let repeats = 5_000u64;
+        create_test_file(root, "large.rs", &block.repeat(repeats as usize))?;
+
+        let single = count_rust_lines_role_aware(&mut lines_of(&root.join("single.rs")),
+            FileRoleHint::Unknown,
+            TestScope::Cfg,
+            false,
+        )?;
+        let large = count_rust_lines_role_aware(&mut lines_of(&root.join("large.rs")),
+            FileRoleHint::Unknown,
+            TestScope::Cfg,
+            false,
+        )?;
+
+        assert_eq!(large.total_lines(), single.total_lines() * repeats);
+        for role in [CodeRole::Mainline, CodeRole::Test] {
+            let one = single.bucket(role).expect("single-block bucket");
+            let many = large.bucket(role).expect("large-file bucket");
+            assert_eq!(many.stats.code_lines, one.stats.code_lines * repeats);
+            assert_eq!(many.stats.comment_lines, one.stats.comment_lines * repeats);
+            assert_eq!(many.stats.blank_lines, one.stats.blank_lines * repeats);
+            assert_eq!(many.total_lines, one.total_lines * repeats);
+        }
+
+        let lines: Vec<String> = block.lines().map(str::to_string).collect();
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
+        let test_lines = roles.iter().filter(|role| **role == CodeRole::Test).count() as u64;
+        assert_eq!(
+            single.bucket(CodeRole::Test).map(|bucket| bucket.total_lines),
+            Some(test_lines)
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_detect_rust_line_roles_handles_raw_strings() {
         let lines = vec![
@@ -5848,7 +6809,7 @@ fn decorated() {
             "    let s = r#\"#[cfg(test)]\"#;".to_string(),
             "}".to_string(),
         ];
-        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown);
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
         assert_eq!(roles, vec![CodeRole::Mainline; 3]);
     }
 
@@ -5859,7 +6820,7 @@ fn decorated() {
             "    let c = '#';".to_string(),
             "}".to_string(),
         ];
-        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown);
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
         assert_eq!(roles, vec![CodeRole::Mainline; 3]);
     }
 
@@ -5886,7 +6847,7 @@ mod tests {
 }
 "#,
         )?;
-        let split = count_rust_lines_role_aware(&file_path, FileRoleHint::Unknown)?;
+        let split = count_rust_lines_role_aware(&mut lines_of(&file_path), FileRoleHint::Unknown, TestScope::Cfg, false)?;
         let main = split
             .bucket(CodeRole::Mainline)
             .expect("mainline stats missing");
@@ -5914,7 +6875,7 @@ mod tests {
         fs::create_dir_all(&tests_dir)?;
         create_test_file(&tests_dir, "empty.rs", "")?;
         let file_path = tests_dir.join("empty.rs");
-        let split = count_rust_lines_role_aware(&file_path, FileRoleHint::TestFile)?;
+        let split = count_rust_lines_role_aware(&mut lines_of(&file_path), FileRoleHint::TestFile, TestScope::Cfg, false)?;
         assert!(
             split.bucket(CodeRole::Mainline).is_none(),
             "empty integration test should not have mainline stats"
@@ -5956,11 +6917,17 @@ mod tests {
 
     #[test]
     fn test_attribute_indicates_test_variants() {
-        assert!(attribute_indicates_test("#[cfg(test)]"));
-        assert!(attribute_indicates_test("#[cfg(any(test, feature = \"x\"))]"));
-        assert!(!attribute_indicates_test("#[cfg(not(test))]"));
-        assert!(attribute_indicates_test("#[test]"));
-        assert!(attribute_indicates_test("#[tokio::test]"));
+        assert!(attribute_indicates_test("#[cfg(test)]", TestScope::Cfg));
+        assert!(attribute_indicates_test(
+            "#[cfg(any(test, feature = \"x\"))]",
+            TestScope::Cfg
+        ));
+        assert!(!attribute_indicates_test("#[cfg(not(test))]", TestScope::Cfg));
+        assert!(attribute_indicates_test("#[test]", TestScope::Cfg));
+        assert!(attribute_indicates_test("#[tokio::test]", TestScope::Cfg));
+        assert!(!attribute_indicates_test("#[cfg(test)]", TestScope::Fn));
+        assert!(attribute_indicates_test("#[test]", TestScope::Fn));
+        assert!(attribute_indicates_test("#[tokio::test]", TestScope::Fn));
     }
 
     #[test]
@@ -5987,7 +6954,7 @@ mod tests {
 }
 "#,
         )?;
-        let split = count_lines_with_roles(&file_path, FileRoleHint::Unknown)?;
+        let split = count_lines_with_roles(&file_path, FileRoleHint::Unknown, &test_args())?;
         let main = split
             .bucket(CodeRole::Mainline)
             .expect("expected mainline bucket");
@@ -6017,7 +6984,7 @@ mod tests {
         let file_path = tests_dir.join("integration.rs");
         let hint = infer_role_from_path(root, &file_path);
         assert_eq!(hint, FileRoleHint::TestFile);
-        let split = count_lines_with_roles(&file_path, hint)?;
+        let split = count_lines_with_roles(&file_path, hint, &test_args())?;
         assert!(
             split.bucket(CodeRole::Mainline).is_none(),
             "integration tests should count as test role only"
@@ -6262,10 +7229,10 @@ mod tests {
         let stats = normalize_stats(raw_stats, total_lines);
         assert_eq!(total_lines, 2);
         assert_eq!(
-            stats.code_lines + stats.comment_lines + stats.blank_lines,
+            stats.code_lines + stats.comment_lines + stats.blank_lines - stats.overlap_lines,
             total_lines
         );
-        assert!(stats.code_lines >= 1);
+        assert_eq!((stats.code_lines, stats.overlap_lines), (1, 1));
         Ok(())
     }
 
@@ -6318,7 +7285,7 @@ mod tests {
             "demo.alg",
             "begin\nCOMMENT this is a comment;\nend\n",
         )?;
-        let (stats, _total) = count_algol_lines(temp_dir.path().join("demo.alg").as_path())?;
+        let (stats, _total) = count_algol_lines(&mut lines_of(temp_dir.path().join("demo.alg").as_path()))?;
         assert_eq!(stats.code_lines, 2); // begin/end
         assert_eq!(stats.comment_lines, 1);
         Ok(())
@@ -6332,7 +7299,7 @@ mod tests {
             "variants.alg",
             "COMMENT block without semicolon\nstill comment;\nco inline co\n# hash comment\nbegin\nend\n",
         )?;
-        let (stats, _total) = count_algol_lines(temp_dir.path().join("variants.alg").as_path())?;
+        let (stats, _total) = count_algol_lines(&mut lines_of(temp_dir.path().join("variants.alg").as_path()))?;
         assert!(stats.comment_lines >= 2);
         assert!(stats.code_lines >= 2);
         Ok(())
@@ -6347,7 +7314,7 @@ mod tests {
             "Write-Host \"start\"\n\n<# open\nstill comment\n#>\nWrite-Host \"after\"\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("blank_block.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("blank_block.ps1").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank line counted: {stats:?}"
@@ -6372,7 +7339,7 @@ mod tests {
             "Write-Host 1 <# start block # trailing\n#> Write-Host 2\n",
         )?;
         let (stats, _total_lines) =
-            count_powershell_lines(temp_dir.path().join("line_block.ps1").as_path())?;
+            count_powershell_lines(&mut lines_of(temp_dir.path().join("line_block.ps1").as_path()))?;
         assert!(
             stats.comment_lines >= 2,
             "expected block and line comments counted: {stats:?}"
@@ -6392,7 +7359,7 @@ mod tests {
             "inline.alg",
             "COMMENT single line;\nbegin\n  real x;\nend\n",
         )?;
-        let (stats, _total) = count_algol_lines(temp_dir.path().join("inline.alg").as_path())?;
+        let (stats, _total) = count_algol_lines(&mut lines_of(temp_dir.path().join("inline.alg").as_path()))?;
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
         assert!(stats.code_lines >= 3, "stats: {:?}", stats);
         Ok(())
@@ -6406,7 +7373,7 @@ mod tests {
             "mixed.alg",
             "begin\n\n# hash comment\nco inline co\nCOMMENT block\nstill comment;\nend\n",
         )?;
-        let (stats, _total) = count_algol_lines(temp_dir.path().join("mixed.alg").as_path())?;
+        let (stats, _total) = count_algol_lines(&mut lines_of(temp_dir.path().join("mixed.alg").as_path()))?;
         assert_eq!(
             stats.blank_lines, 1,
             "expected single blank line: {stats:?}"
@@ -6430,7 +7397,7 @@ mod tests {
             "prog.cob",
             "       IDENTIFICATION DIVISION.\n      * comment in col 7\n       PROGRAM-ID. DEMO.\n       *> free comment\n",
         )?;
-        let (stats, _total) = count_cobol_lines(temp_dir.path().join("prog.cob").as_path())?;
+        let (stats, _total) = count_cobol_lines(&mut lines_of(temp_dir.path().join("prog.cob").as_path()))?;
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         assert!(stats.code_lines >= 2);
         Ok(())
@@ -6444,7 +7411,7 @@ mod tests {
             "mixed.cob",
             "       IDENTIFICATION DIVISION.\n\n      * Column seven star\n      *> free comment\n       PROGRAM-ID. SAMPLE.\n",
         )?;
-        let (stats, _total) = count_cobol_lines(temp_dir.path().join("mixed.cob").as_path())?;
+        let (stats, _total) = count_cobol_lines(&mut lines_of(temp_dir.path().join("mixed.cob").as_path()))?;
         assert_eq!(stats.blank_lines, 1, "expected blank separator: {stats:?}");
         assert!(
             stats.comment_lines >= 2,
@@ -6465,7 +7432,7 @@ mod tests {
             "m.f90",
             "! comment\nprogram x\nprint *, 'hi'\nend\n",
         )?;
-        let (stats, _total) = count_fortran_lines(temp_dir.path().join("m.f90").as_path())?;
+        let (stats, _total) = count_fortran_lines(&mut lines_of(temp_dir.path().join("m.f90").as_path()))?;
         assert_eq!(stats.comment_lines, 1);
         assert_eq!(stats.code_lines, 3);
         Ok(())
@@ -6479,7 +7446,7 @@ mod tests {
             "mixed.f90",
             "C legacy comment\n      PROGRAM TEST\n\n      ! full line\n      INTEGER :: X ! inline comment\n      X = 3\n      END PROGRAM TEST\n",
         )?;
-        let (stats, _total) = count_fortran_lines(temp_dir.path().join("mixed.f90").as_path())?;
+        let (stats, _total) = count_fortran_lines(&mut lines_of(temp_dir.path().join("mixed.f90").as_path()))?;
         assert_eq!(
             stats.blank_lines, 1,
             "expected single blank line: {stats:?}"
@@ -6499,7 +7466,7 @@ mod tests {
     fn test_asm_line_counting() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "x.asm", "; c\n# also c\nmov eax, eax\n")?;
-        let (stats, _total) = count_asm_lines(temp_dir.path().join("x.asm").as_path())?;
+        let (stats, _total) = count_asm_lines(&mut lines_of(temp_dir.path().join("x.asm").as_path()))?;
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -6513,7 +7480,7 @@ mod tests {
             "proc.com",
             "$! comment\n$ write sys$output \"hi\"\n",
         )?;
-        let (stats, _total) = count_dcl_lines(temp_dir.path().join("proc.com").as_path())?;
+        let (stats, _total) = count_dcl_lines(&mut lines_of(temp_dir.path().join("proc.com").as_path()))?;
         assert_eq!(stats.comment_lines, 1);
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -6523,7 +7490,7 @@ mod tests {
     fn test_dcl_non_dcl_com_file_sniff() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "not_dcl.com", "echo hi\n")?;
-        let (stats, _total) = count_dcl_lines(temp_dir.path().join("not_dcl.com").as_path())?;
+        let (stats, _total) = count_dcl_lines(&mut lines_of(temp_dir.path().join("not_dcl.com").as_path()))?;
         assert_eq!(stats.code_lines, 0);
         assert_eq!(stats.comment_lines, 0);
         Ok(())
@@ -6546,7 +7513,9 @@ mod tests {
                 comment_lines: 5,
                 blank_lines: 6,
                 overlap_lines: 2,
+                ..Default::default()
             },
+            TableLayout::default(),
         );
         // No ANSI escape
         assert!(!line.contains('\u{1b}'));
@@ -6586,7 +7555,7 @@ mod tests {
     fn test_iplan_line_counting() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "calc.ipl", "/* c */\n! c\nSET X = 1\n")?;
-        let (stats, _total) = count_iplan_lines(temp_dir.path().join("calc.ipl").as_path())?;
+        let (stats, _total) = count_iplan_lines(&mut lines_of(temp_dir.path().join("calc.ipl").as_path()))?;
         assert!(stats.comment_lines >= 2);
         assert_eq!(stats.code_lines, 1, "stats: {:?}", stats);
         Ok(())
@@ -6600,7 +7569,7 @@ mod tests {
             "mix.ipl",
             "SET X = 1 /* inline */ ! trailing\n/* block\ncontinues */ ! next\nVALUE\n",
         )?;
-        let (stats, _total) = count_iplan_lines(temp_dir.path().join("mix.ipl").as_path())?;
+        let (stats, _total) = count_iplan_lines(&mut lines_of(temp_dir.path().join("mix.ipl").as_path()))?;
         assert!(stats.code_lines >= 1);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -6615,7 +7584,7 @@ mod tests {
             "SET J = 1\n/* start\n! nested comment\n*/ ! still comment\nVALUE /* inline */ ! comment\nVALUE ! inline comment\n! trailing only\nVALUE2\n",
         )?;
         let (stats, _total_lines) =
-            count_iplan_lines(temp_dir.path().join("comment.ipl").as_path())?;
+            count_iplan_lines(&mut lines_of(temp_dir.path().join("comment.ipl").as_path()))?;
         assert_eq!(stats.code_lines, 4, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 5, "stats: {:?}", stats);
         assert_eq!(stats.blank_lines, 0, "stats: {:?}", stats);
@@ -6631,7 +7600,7 @@ mod tests {
             "SET BASE = 1\n\n/* start\ncontinues */ VALUE\n/* reopen\nstill comment\n*/ VALUE2\n! trailing\n",
         )?;
         let (stats, _total_lines) =
-            count_iplan_lines(temp_dir.path().join("blank_block.ipl").as_path())?;
+            count_iplan_lines(&mut lines_of(temp_dir.path().join("blank_block.ipl").as_path()))?;
         assert!(
             stats.blank_lines >= 1,
             "expected blank line counted: {stats:?}"
@@ -6656,7 +7625,7 @@ mod tests {
             "/* header */ VALUE1\nVALUE2 /* close */ VALUE3\n",
         )?;
         let (stats, _total_lines) =
-            count_iplan_lines(temp_dir.path().join("inline_tail.ipl").as_path())?;
+            count_iplan_lines(&mut lines_of(temp_dir.path().join("inline_tail.ipl").as_path()))?;
         assert_eq!(
             stats.comment_lines, 2,
             "expected two comment lines: {stats:?}"
@@ -6676,7 +7645,7 @@ mod tests {
             "Main.scala",
             "object Main {\n// comment\n/* block */\nval x = 1\n}\n",
         )?;
-        let (stats, _total_lines) = count_c_style_lines(&temp_dir.path().join("Main.scala"))?;
+        let (stats, _total_lines) = count_c_style_lines(&mut lines_of(&temp_dir.path().join("Main.scala")))?;
         assert_eq!(stats.code_lines, 3);
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
         Ok(())
@@ -6689,11 +7658,11 @@ mod tests {
         let temp_dir = TempDir::new()?;
         // Short line (<7 chars) should not be treated as comment
         create_test_file(temp_dir.path(), "short.cob", "*\n")?;
-        let (stats1, _) = count_cobol_lines(temp_dir.path().join("short.cob").as_path())?;
+        let (stats1, _) = count_cobol_lines(&mut lines_of(temp_dir.path().join("short.cob").as_path()))?;
         assert_eq!(stats1.code_lines, 1);
         // Leading spaces then '*' in column 1 is code (not fixed-form comment)
         create_test_file(temp_dir.path(), "lead.cob", "   * TEXT\n")?;
-        let (stats2, _) = count_cobol_lines(temp_dir.path().join("lead.cob").as_path())?;
+        let (stats2, _) = count_cobol_lines(&mut lines_of(temp_dir.path().join("lead.cob").as_path()))?;
         assert_eq!(stats2.code_lines, 1);
         Ok(())
     }
@@ -6703,15 +7672,15 @@ mod tests {
         let temp_dir = TempDir::new()?;
         // Fixed-form comment indicator in col 1
         create_test_file(temp_dir.path(), "f1.f", "C comment\n")?;
-        let (s1, _) = count_fortran_lines(temp_dir.path().join("f1.f").as_path())?;
+        let (s1, _) = count_fortran_lines(&mut lines_of(temp_dir.path().join("f1.f").as_path()))?;
         assert_eq!(s1.comment_lines, 1);
         // Leading space then 'C' is code (free form)
         create_test_file(temp_dir.path(), "f2.f", " C not comment\n")?;
-        let (s2, _) = count_fortran_lines(temp_dir.path().join("f2.f").as_path())?;
+        let (s2, _) = count_fortran_lines(&mut lines_of(temp_dir.path().join("f2.f").as_path()))?;
         assert_eq!(s2.code_lines, 1);
         // Inline '!' split
         create_test_file(temp_dir.path(), "f3.f90", "print *, 'x' ! trailing\n")?;
-        let (s3, _) = count_fortran_lines(temp_dir.path().join("f3.f90").as_path())?;
+        let (s3, _) = count_fortran_lines(&mut lines_of(temp_dir.path().join("f3.f90").as_path()))?;
         assert_eq!(s3.code_lines, 1);
         assert_eq!(s3.comment_lines, 1);
         Ok(())
@@ -6721,7 +7690,7 @@ mod tests {
     fn test_hcl_multiple_pairs_inline() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "x.tf", "a=1 /*c*/ b=2 /*d*/ c=3\n")?;
-        let (stats, _) = count_hcl_lines(temp_dir.path().join("x.tf").as_path())?;
+        let (stats, _) = count_hcl_lines(&mut lines_of(temp_dir.path().join("x.tf").as_path()))?;
         assert!(stats.code_lines >= 3);
         assert!(stats.comment_lines >= 2);
         Ok(())
@@ -6735,7 +7704,7 @@ mod tests {
             "ps.ps1",
             "Write-Host 'a' <# c #> 'b' <# d #> 'c'\n",
         )?;
-        let (s1, _) = count_powershell_lines(temp_dir.path().join("ps.ps1").as_path())?;
+        let (s1, _) = count_powershell_lines(&mut lines_of(temp_dir.path().join("ps.ps1").as_path()))?;
         assert!(s1.code_lines >= 3);
         assert!(s1.comment_lines >= 2);
         create_test_file(
@@ -6743,7 +7712,7 @@ mod tests {
             "ps2.ps1",
             "Write-Host 'x'\n<#\nblock\n#> Write-Host 'y'\n",
         )?;
-        let (s2, _) = count_powershell_lines(temp_dir.path().join("ps2.ps1").as_path())?;
+        let (s2, _) = count_powershell_lines(&mut lines_of(temp_dir.path().join("ps2.ps1").as_path()))?;
         assert!(s2.code_lines >= 2);
         assert!(s2.comment_lines >= 2);
         Ok(())
@@ -6757,7 +7726,7 @@ mod tests {
             "p.pas",
             "{c1} (*c2*) code\n(* multi\nline *) code2\n",
         )?;
-        let (stats, _) = count_pascal_lines(temp_dir.path().join("p.pas").as_path())?;
+        let (stats, _) = count_pascal_lines(&mut lines_of(temp_dir.path().join("p.pas").as_path()))?;
         assert!(stats.comment_lines >= 2);
         assert!(stats.code_lines >= 2);
         Ok(())
@@ -6771,7 +7740,7 @@ mod tests {
             "p.pl",
             "print 'x';\n=pod\nthis is pod\n=cut\nprint 'y';\n",
         )?;
-        let (stats, _) = count_perl_lines(temp_dir.path().join("p.pl").as_path())?;
+        let (stats, _) = count_perl_lines(&mut lines_of(temp_dir.path().join("p.pl").as_path()))?;
         assert!(stats.comment_lines >= 2);
         assert_eq!(stats.code_lines, 2);
         Ok(())
@@ -6781,19 +7750,19 @@ mod tests {
     fn test_inline_hash_is_code_for_hash_langs() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "a.yaml", "key: 1 # inline\n")?;
-        let (yml, _) = count_yaml_lines(temp_dir.path().join("a.yaml").as_path())?;
+        let (yml, _) = count_yaml_lines(&mut lines_of(temp_dir.path().join("a.yaml").as_path()))?;
         assert_eq!(yml.code_lines, 1);
         create_test_file(temp_dir.path(), "a.toml", "name='x' # inline\n")?;
-        let (toml, _) = count_toml_lines(temp_dir.path().join("a.toml").as_path())?;
+        let (toml, _) = count_toml_lines(&mut lines_of(temp_dir.path().join("a.toml").as_path()))?;
         assert_eq!(toml.code_lines, 1);
         create_test_file(temp_dir.path(), "a.ini", "name=value ; inline\n")?;
-        let (ini, _) = count_ini_lines(temp_dir.path().join("a.ini").as_path())?;
+        let (ini, _) = count_ini_lines(&mut lines_of(temp_dir.path().join("a.ini").as_path()))?;
         assert_eq!(ini.code_lines, 1);
         create_test_file(temp_dir.path(), "CMakeLists.txt", "set(X 1) # inline\n")?;
-        let (cmake, _) = count_cmake_lines(temp_dir.path().join("CMakeLists.txt").as_path())?;
+        let (cmake, _) = count_cmake_lines(&mut lines_of(temp_dir.path().join("CMakeLists.txt").as_path()))?;
         assert_eq!(cmake.code_lines, 1);
         create_test_file(temp_dir.path(), "Makefile", "VAR=1 # inline\n")?;
-        let (mk, _) = count_makefile_lines(temp_dir.path().join("Makefile").as_path())?;
+        let (mk, _) = count_makefile_lines(&mut lines_of(temp_dir.path().join("Makefile").as_path()))?;
         assert_eq!(mk.code_lines, 1);
         Ok(())
     }
@@ -6807,7 +7776,7 @@ mod tests {
             "# header\nvalue: 1\n\n  # indented\nnext: 2 # trailing\n",
         )?;
         let (stats, total) =
-            count_hash_comment_lines(temp_dir.path().join("mixed.hash").as_path())?;
+            count_hash_comment_lines(&mut lines_of(temp_dir.path().join("mixed.hash").as_path()))?;
         assert_eq!(total, 5);
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
@@ -6824,7 +7793,7 @@ mod tests {
             "title: demo # inline\n\n# comment only\nvalue: 42\n",
         )?;
         let (stats, total) =
-            count_hash_comment_lines(temp_dir.path().join("trailing.yaml").as_path())?;
+            count_hash_comment_lines(&mut lines_of(temp_dir.path().join("trailing.yaml").as_path()))?;
         assert_eq!(total, 4);
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
@@ -6837,7 +7806,7 @@ mod tests {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "comments.hash", "# comment\n# another\n")?;
         let (stats, total) =
-            count_hash_comment_lines(temp_dir.path().join("comments.hash").as_path())?;
+            count_hash_comment_lines(&mut lines_of(temp_dir.path().join("comments.hash").as_path()))?;
         assert_eq!(total, 2);
         assert_eq!(stats.code_lines, 0);
         assert_eq!(stats.comment_lines, 2);
@@ -6850,7 +7819,7 @@ mod tests {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "blank.hash", "\n\n")?;
         let (stats, total) =
-            count_hash_comment_lines(temp_dir.path().join("blank.hash").as_path())?;
+            count_hash_comment_lines(&mut lines_of(temp_dir.path().join("blank.hash").as_path()))?;
         assert_eq!(total, 2);
         assert_eq!(stats.code_lines, 0);
         assert_eq!(stats.comment_lines, 0);
@@ -6874,7 +7843,7 @@ mod tests {
             "Makefile",
             "# comment\n\nall:\n\t@echo done\n",
         )?;
-        let (stats, total) = count_makefile_lines(temp_dir.path().join("Makefile").as_path())?;
+        let (stats, total) = count_makefile_lines(&mut lines_of(temp_dir.path().join("Makefile").as_path()))?;
         assert_eq!(total, 4);
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
@@ -6886,7 +7855,7 @@ mod tests {
     fn test_makefile_comment_only() -> io::Result<()> { // migrated to CLI totals
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "Makefile", "# comment\n# another\n")?;
-        let (stats, total) = count_makefile_lines(temp_dir.path().join("Makefile").as_path())?;
+        let (stats, total) = count_makefile_lines(&mut lines_of(temp_dir.path().join("Makefile").as_path()))?;
         assert_eq!(total, 2);
         assert_eq!(stats.code_lines, 0);
         assert_eq!(stats.comment_lines, 2);
@@ -6902,7 +7871,7 @@ mod tests {
             "Dockerfile",
             "FROM alpine\n# comment\n\nRUN echo hi\n",
         )?;
-        let (stats, total) = count_dockerfile_lines(temp_dir.path().join("Dockerfile").as_path())?;
+        let (stats, total) = count_dockerfile_lines(&mut lines_of(temp_dir.path().join("Dockerfile").as_path()))?;
         assert_eq!(total, 4);
         assert_eq!(stats.code_lines, 2, "stats: {:?}", stats);
         assert_eq!(stats.comment_lines, 1, "stats: {:?}", stats);
@@ -6914,7 +7883,7 @@ mod tests {
     fn test_dockerfile_comment_only() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
         create_test_file(temp_dir.path(), "Dockerfile", "# comment\n# another\n")?;
-        let (stats, total) = count_dockerfile_lines(temp_dir.path().join("Dockerfile").as_path())?;
+        let (stats, total) = count_dockerfile_lines(&mut lines_of(temp_dir.path().join("Dockerfile").as_path()))?;
         assert_eq!(total, 2);
         assert_eq!(stats.code_lines, 0);
         assert_eq!(stats.comment_lines, 2);
@@ -6992,7 +7961,7 @@ mod tests {
             "hello.braw",
             "# This is a comment\nscreive(\"Hello\")\n\n# Another comment\nscreive(\"World\")\n",
         )?;
-        let (stats, total) = count_mdhavers_lines(temp_dir.path().join("hello.braw").as_path())?;
+        let (stats, total) = count_mdhavers_lines(&mut lines_of(temp_dir.path().join("hello.braw").as_path()))?;
         assert_eq!(total, 5, "expected 5 total lines");
         assert_eq!(stats.code_lines, 2, "expected 2 code lines");
         assert_eq!(stats.comment_lines, 2, "expected 2 comment lines");
@@ -7033,7 +8002,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut stats = HashMap::new();
         let mut error_count = 0;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let mut ctx = ProcCtx {
             args: &args,
@@ -7042,7 +8011,7 @@ mod tests {
             stats: &mut stats,
             error_count: &mut error_count,
             filespec: None,
-            visited_real_paths: &mut visited_paths,
+            visited: &mut visited_paths,
         };
 
         handle_symlink(&mut ctx, &link)?;
@@ -7063,11 +8032,11 @@ mod tests {
         let link = root.join("link.rs");
         symlink(&target_file, &link)?;
 
-        let mut args = test_args();
+        let args = test_args();
         let mut metrics = test_metrics();
         let mut stats = HashMap::new();
         let mut error_count = 0;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let mut ctx = ProcCtx {
             args: &args,
@@ -7076,7 +8045,7 @@ mod tests {
             stats: &mut stats,
             error_count: &mut error_count,
             filespec: None,
-            visited_real_paths: &mut visited_paths,
+            visited: &mut visited_paths,
         };
 
         handle_symlink(&mut ctx, &link)?;
@@ -7109,7 +8078,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut stats = HashMap::new();
         let mut error_count = 0;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
 
         let mut ctx = ProcCtx {
             args: &args,
@@ -7118,7 +8087,7 @@ mod tests {
             stats: &mut stats,
             error_count: &mut error_count,
             filespec: None,
-            visited_real_paths: &mut visited_paths,
+            visited: &mut visited_paths,
         };
 
         handle_symlink(&mut ctx, &link)?;
@@ -7135,7 +8104,7 @@ mod tests {
             "    let c = '\\'';".to_string(),
             "}".to_string(),
         ];
-        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown);
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, false);
         // All should be Mainline
         for role in roles {
             assert_eq!(role, CodeRole::Mainline);
@@ -7172,7 +8141,7 @@ mod tests {
             "complex.js",
             "/* block */ var x = 1; /* block2 */\n<!-- jsx --> var y = 2;\n",
         )?;
-        let (stats, _) = count_javascript_lines(temp_dir.path().join("complex.js").as_path())?;
+        let (stats, _) = count_javascript_lines(&mut lines_of(temp_dir.path().join("complex.js").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 2);
         Ok(())
@@ -7187,7 +8156,7 @@ mod tests {
             "complex.ps1",
             "Write-Host 'a' # c1\nWrite-Host 'b' <# c2 #>\n<# c3 #> Write-Host 'c'\n",
         )?;
-        let (stats, _) = count_powershell_lines(temp_dir.path().join("complex.ps1").as_path())?;
+        let (stats, _) = count_powershell_lines(&mut lines_of(temp_dir.path().join("complex.ps1").as_path()))?;
         assert_eq!(stats.code_lines, 3);
         
         // Multi-line block
@@ -7196,7 +8165,7 @@ mod tests {
             "multiline.ps1",
             "Write-Host 'd'\n<#\nstart\n#>\n",
         )?;
-        let (stats2, _) = count_powershell_lines(temp_dir.path().join("multiline.ps1").as_path())?;
+        let (stats2, _) = count_powershell_lines(&mut lines_of(temp_dir.path().join("multiline.ps1").as_path()))?;
         assert_eq!(stats2.code_lines, 1);
         assert!(stats2.comment_lines >= 3);
         Ok(())
@@ -7210,7 +8179,7 @@ mod tests {
             "nested.pas",
             "{ { nested } } code\n(* (* nested *) *) code\n",
         )?;
-        let (stats, _) = count_pascal_lines(temp_dir.path().join("nested.pas").as_path())?;
+        let (stats, _) = count_pascal_lines(&mut lines_of(temp_dir.path().join("nested.pas").as_path()))?;
         assert_eq!(stats.code_lines, 2);
         assert_eq!(stats.comment_lines, 2);
         Ok(())
@@ -7233,7 +8202,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut stats = HashMap::new();
         let mut error_count = 0;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
         
         {
             let mut ctx = ProcCtx {
@@ -7243,7 +8212,7 @@ mod tests {
                 stats: &mut stats,
                 error_count: &mut error_count,
                 filespec: None,
-                visited_real_paths: &mut visited_paths,
+                visited: &mut visited_paths,
             };
             
             handle_symlink(&mut ctx, &target_dir)?;
@@ -7264,7 +8233,7 @@ mod tests {
                 stats: &mut stats,
                 error_count: &mut error_count,
                 filespec: None,
-                visited_real_paths: &mut visited_paths,
+                visited: &mut visited_paths,
             };
             handle_symlink(&mut ctx, &target_file)?;
         }
@@ -7288,7 +8257,7 @@ mod tests {
         let mut metrics = test_metrics();
         let mut stats = HashMap::new();
         let mut error_count = 0;
-        let mut visited_paths = HashSet::new();
+        let mut visited_paths = VisitedFiles::default();
         
         let mut ctx = ProcCtx {
             args: &args,
@@ -7297,10 +8266,3260 @@ mod tests {
             stats: &mut stats,
             error_count: &mut error_count,
             filespec: None,
-            visited_real_paths: &mut visited_paths,
+            visited: &mut visited_paths,
         };
         
         handle_symlink(&mut ctx, &fail_path)?;
         assert_eq!(error_count, 1);
         Ok(())
     }
+
+    #[test]
+    fn test_markdown_fences_counted_under_fence_language() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "guide.md",
+            "# Guide\n\n```rust\n// entry point\nfn main() {}\n\n```\n\
+             ~~~text\nplain\n~~~\n```python\nx = 1\n",
+        )?;
+        let scan = |markdown_fences: bool| {
+            let args = Args {
+                markdown_fences,
+                ..test_args()
+            };
+            let mut metrics = test_metrics();
+            let mut error_count = 0;
+            let mut entries_count = 0usize;
+            let stats = scan_directory(
+                temp_dir.path(),
+                &args,
+                temp_dir.path(),
+                &mut metrics,
+                0,
+                &mut entries_count,
+                &mut error_count,
+            )?;
+            let by_language: HashMap<String, (u64, LanguageStats)> = stats
+                .values()
+                .flat_map(|dir| dir.language_stats.iter())
+                .map(|(lang, entry)| (lang.clone(), entry.summary()))
+                .collect();
+            Ok::<_, io::Error>(by_language)
+        };
+        assert!(scan(false)?.is_empty(), "Markdown is not counted by default");
+
+        let by_language = scan(true)?;
+        let (md_files, md) = by_language["Markdown"];
+        assert_eq!(md_files, 1);
+        // Heading, 5 fence delimiters and the untagged `plain` line; 1 blank.
+        assert_eq!((md.code_lines, md.comment_lines, md.blank_lines), (7, 0, 1));
+        let (_, rust) = by_language["Rust"];
+        assert_eq!((rust.code_lines, rust.comment_lines, rust.blank_lines), (1, 1, 1));
+        // The unterminated python fence runs to end of file.
+        let (_, python) = by_language["Python"];
+        assert_eq!(python.code_lines, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_skipped_groups_unknown_files_by_extension() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        create_test_file(temp_dir.path(), "a.foo", "x\n")?;
+        create_test_file(temp_dir.path(), "b.FOO", "y\n")?;
+        create_test_file(temp_dir.path(), "LICENSE", "MIT\n")?;
+        let args = Args {
+            print_skipped: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        assert_eq!(metrics.skipped_files.len(), 2);
+        assert_eq!(metrics.skipped_files[".foo"].0, 2);
+        assert_eq!(metrics.skipped_files["(no extension)"].0, 1);
+        let summary = format_skipped_files(&metrics.skipped_files);
+        assert!(summary.contains("Skipped files (no recognised language): 3"), "{summary}");
+        let lines: Vec<&str> = summary.lines().skip(2).collect();
+        assert!(lines[0].trim_start().starts_with(".foo"), "{summary}");
+        assert!(lines[1].contains("e.g. LICENSE"), "{summary}");
+
+        let many: HashMap<String, (u64, PathBuf)> = (0..SKIPPED_EXTENSION_LIMIT + 3)
+            .map(|i| (format!(".x{i:02}"), (1, PathBuf::from(format!("f.x{i:02}")))))
+            .collect();
+        let capped = format_skipped_files(&many);
+        assert_eq!(capped.lines().count(), 2 + SKIPPED_EXTENSION_LIMIT + 1);
+        assert!(capped.contains("... and 3 more extension(s) (3 file(s))"), "{capped}");
+
+        let mut quiet_metrics = test_metrics();
+        scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut quiet_metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        assert!(quiet_metrics.skipped_files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sql_comments_outside_string_literals() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "schema.sql",
+            "-- users table\nSELECT 'a--b', \"x--y\" FROM t; -- trailing\n\
+             /* block\n   still block */\nINSERT INTO t VALUES ('it''s -- fine');\n\
+             SELECT 'multi\n-- inside string\n' FROM dual;\n\n",
+        )?;
+        assert_eq!(get_language_from_extension("schema.sql"), Some("SQL"));
+        for name in ["pkg.pls", "pkg.pks", "pkg.PKB"] {
+            assert_eq!(get_language_from_extension(name), Some("PL/SQL"), "{name}");
+        }
+        let (stats, total_lines) = count_sql_lines(&mut lines_of(&temp_dir.path().join("schema.sql")))?;
+        assert_eq!(total_lines, 9);
+        // The trailing `--` makes line 2 mixed; `'it''s -- fine'` stays code.
+        assert_eq!(stats.code_lines, 5);
+        assert_eq!(stats.comment_lines, 4);
+        assert_eq!(stats.blank_lines, 1);
+
+        create_test_file(
+            temp_dir.path(),
+            "pkg.pkb",
+            "CREATE OR REPLACE PACKAGE BODY p AS\n  -- helper\nEND p;\n",
+        )?;
+        let (stats, _) = count_lines_with_stats(&temp_dir.path().join("pkg.pkb"))?;
+        assert_eq!((stats.code_lines, stats.comment_lines), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_reuses_counts_for_unchanged_files() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src)?;
+        create_test_file(&src, "lib.rs", "fn a() {}\nfn b() {}\n")?;
+        create_test_file(&src, "notes.py", "# note\nx = 1\n")?;
+        let cache_path = temp_dir.path().join("mdkloc.cache");
+        let args = Args {
+            cache: Some(cache_path.clone()),
+            ..test_args()
+        };
+        let scan = |args: &Args| -> io::Result<String> {
+            let mut metrics = test_metrics();
+            let mut error_count = 0;
+            let mut entries_count = 0usize;
+            let stats = scan_directory(
+                &src,
+                args,
+                &src,
+                &mut metrics,
+                0,
+                &mut entries_count,
+                &mut error_count,
+            )?;
+            assert_eq!(error_count, 0);
+            Ok(format_totals_line(&stats))
+        };
+
+        let first = scan(&args)?;
+        assert_eq!(first, "code=3 comments=1 blank=0 mixed=0 files=2");
+        let cache_text = fs::read_to_string(&cache_path)?;
+        assert!(cache_text.starts_with(CACHE_HEADER), "{cache_text}");
+
+        // Same size and mtime, different contents: the cached counts win,
+        // proving the file was not re-read.
+        let lib = src.join("lib.rs");
+        let modified = fs::metadata(&lib)?.modified()?;
+        fs::write(&lib, "// a() {}\n// b() {}\n")?;
+        File::options().write(true).open(&lib)?.set_modified(modified)?;
+        assert_eq!(scan(&args)?, first);
+
+        // A different size invalidates the entry.
+        fs::write(&lib, "// only a comment\n")?;
+        assert_eq!(scan(&args)?, "code=1 comments=2 blank=0 mixed=0 files=2");
+
+        // Changing counting options discards the whole cache.
+        fs::write(&cache_path, cache_text.replace("jsonc=false", "jsonc=true"))?;
+        let reloaded = ScanCache::load(&cache_path, cache_options_key(&args));
+        assert!(reloaded.previous.is_empty());
+        fs::write(&cache_path, format!("{CACHE_HEADER}\nbogus\n"))?;
+        assert!(parse_cache("not a cache", "").is_err());
+        assert_eq!(scan(&args)?, "code=1 comments=2 blank=0 mixed=0 files=2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_report_keys_are_relative_by_default() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let nested = temp_dir.path().join("pkg").join("core");
+        fs::create_dir_all(&nested)?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        create_test_file(&nested, "lib.py", "# doc\nx = 1\n")?;
+        let args = Args::parse_from(["mdkloc", "--format", "json"]);
+        assert!(args.relative_paths);
+        let mut metrics = test_metrics();
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let root_str = root.to_string_lossy().into_owned();
+
+        let relative = build_json_report(&root, &stats, 2, 3, 0, &ReportOptions::from_args(&args));
+        assert!(!relative.contains(&root_str), "{relative}");
+        assert!(!relative.contains("\"root\""), "{relative}");
+        let core_key = json_string(&Path::new("pkg").join("core").to_string_lossy());
+        assert!(relative.contains(&format!("    {core_key}: {{")), "{relative}");
+        assert!(relative.contains("    \".\": {"), "{relative}");
+        assert!(relative.contains(
+            "\"total\": {\"files\": 2, \"code\": 2, \"comments\": 1, \"blank\": 0, \"mixed\": 0, \
+             \"ignored\": 0}"
+        ));
+
+        let absolute_args =
+            Args::parse_from(["mdkloc", "--format", "json", "--relative-paths=false"]);
+        let absolute = build_json_report(
+            &root,
+            &stats,
+            2,
+            3,
+            0,
+            &ReportOptions::from_args(&absolute_args),
+        );
+        assert!(absolute.contains(&json_string(&root_str)), "{absolute}");
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_start_tiny_widths() {
+        let long_str = "deeply/nested/source/directory";
+        let expected = ["", ".", "..", "...", "...y", "...ry"];
+        for (max_len, want) in expected.iter().enumerate() {
+            let truncated = truncate_start(long_str, max_len);
+            assert_eq!(truncated, *want, "max_len {max_len}");
+            assert_eq!(truncated.chars().count(), max_len);
+        }
+        // Short strings still pass through untouched at tiny widths.
+        assert_eq!(truncate_start("ab", 2), "ab");
+        assert_eq!(truncate_start("", 0), "");
+    }
+
+    #[test]
+    fn test_custom_table_widths_flow_into_report() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path();
+        let deep = base.join("a_rather_long_directory_name").join("nested_module");
+        let mut stats_map = HashMap::new();
+        let mut dir_stats = DirectoryStats::default();
+        dir_stats.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    code_lines: 4,
+                    ..Default::default()
+                },
+            ),
+        );
+        stats_map.insert(deep.clone(), dir_stats);
+
+        let args = Args::parse_from(["mdkloc", "--dir-width", "60", "--lang-width", "10"]);
+        let options = ReportOptions::from_args(&args);
+        assert_eq!(
+            options.layout,
+            TableLayout {
+                dir_width: 60,
+                lang_width: 10,
+                doc_comments: false,
+                averages: false,
+                mixed_column: true
+            }
+        );
+        let report = build_analysis_report(base, &stats_map, 1, 4, 0, &options);
+        let relative = deep.strip_prefix(base).unwrap().to_string_lossy().into_owned();
+        let row = report
+            .lines()
+            .find(|l| l.starts_with(&relative))
+            .expect("untruncated directory row");
+        assert_eq!(row.chars().count(), options.layout.rule_width());
+        assert!(row[60..].starts_with(" Rust   "), "{row}");
+        let rule = "-".repeat(60 + 1 + 10 + 1 + 8 + 44);
+        assert!(report.lines().any(|l| l == rule), "{report}");
+        assert!(!report.contains(&"-".repeat(rule.len() + 1)), "{report}");
+
+        // The default width truncates the same path from the start.
+        let narrow = build_analysis_report(base, &stats_map, 1, 4, 0, &ReportOptions::default());
+        assert!(narrow.lines().any(|l| l.starts_with("...")), "{narrow}");
+        assert_eq!(TableLayout::default().rule_width(), DIR_WIDTH + LANG_WIDTH + 54);
+
+        // Columns narrower than the report can draw are rejected up front.
+        for (flag, min) in [("--dir-width", MIN_DIR_WIDTH), ("--lang-width", MIN_LANG_WIDTH)] {
+            let below = (min - 1).to_string();
+            assert!(Args::try_parse_from(["mdkloc", flag, below.as_str()]).is_err());
+            let at = min.to_string();
+            assert!(Args::try_parse_from(["mdkloc", flag, at.as_str()]).is_ok());
+        }
+        assert!(Args::try_parse_from(["mdkloc", "--lang-width", "0"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_elixir_and_erlang_comments() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "greeter.ex",
+            "# Greets people\ndefmodule Greeter do\n  @doc \"\"\"\n  Says hi.\n  \"\"\"\n\
+             \n  def hi, do: :ok # inline\nend\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "greeter.erl",
+            "%%% Module header\n-module(greeter).\n\n  % indented note\nhi() -> ok. % trailing\n",
+        )?;
+        assert_eq!(get_language_from_extension("greeter.ex"), Some("Elixir"));
+        assert_eq!(get_language_from_extension("mix.exs"), Some("Elixir"));
+        assert_eq!(get_language_from_extension("greeter.erl"), Some("Erlang"));
+        assert_eq!(get_language_from_extension("records.hrl"), Some("Erlang"));
+
+        let (elixir, total) = count_lines_with_stats(&temp_dir.path().join("greeter.ex"))?;
+        assert_eq!(total, 8);
+        assert_eq!((elixir.code_lines, elixir.comment_lines, elixir.blank_lines), (6, 1, 1));
+
+        let (erlang, total) = count_lines_with_stats(&temp_dir.path().join("greeter.erl"))?;
+        assert_eq!(total, 5);
+        assert_eq!((erlang.code_lines, erlang.comment_lines, erlang.blank_lines), (2, 2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_subtotal_rows() {
+        let base = Path::new("/project");
+        let mut stats_map = HashMap::new();
+        let mut mixed = DirectoryStats::default();
+        for (lang, code) in [("Python", 3), ("Rust", 5)] {
+            mixed.language_stats.insert(
+                lang.to_string(),
+                language_entry(
+                    1,
+                    LanguageStats {
+                        code_lines: code,
+                        comment_lines: 1,
+                        ..Default::default()
+                    },
+                ),
+            );
+        }
+        stats_map.insert(base.join("mixed"), mixed);
+        let mut single = DirectoryStats::default();
+        single.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                2,
+                LanguageStats {
+                    code_lines: 7,
+                    ..Default::default()
+                },
+            ),
+        );
+        stats_map.insert(base.join("single"), single);
+
+        let options = ReportOptions::from_args(&test_args());
+        let report = build_analysis_report(base, &stats_map, 4, 17, 0, &options);
+        let subtotal = format_language_stats_line(
+            "mixed",
+            "(subtotal)",
+            2,
+            &LanguageStats {
+                code_lines: 8,
+                comment_lines: 2,
+                ..Default::default()
+            },
+            options.layout,
+        );
+        let lines: Vec<&str> = report.lines().collect();
+        let position = lines.iter().position(|l| *l == subtotal).expect("subtotal row");
+        assert!(lines[position - 1].starts_with("mixed") && lines[position - 1].contains("Rust"));
+        assert_eq!(report.matches("(subtotal)").count(), 1, "single-language dirs: {report}");
+
+        let compact = ReportOptions::from_args(&Args {
+            no_subtotals: true,
+            ..test_args()
+        });
+        let report = build_analysis_report(base, &stats_map, 4, 17, 0, &compact);
+        assert!(!report.contains("(subtotal)"), "{report}");
+    }
+
+    #[test]
+    fn test_compat_check_flags_line_invariant_breaks() -> io::Result<()> {
+        assert_eq!(reference_line_count(b""), 0);
+        assert_eq!(reference_line_count(b"a\nb\n"), 2);
+        assert_eq!(reference_line_count(b"a\r\nb"), 2);
+
+        let split = |stats: LanguageStats, total_lines| {
+            vec![(
+                "Rust".to_string(),
+                RoleSplit::single(CodeRole::Mainline, stats, total_lines),
+            )]
+        };
+        let mixed = LanguageStats {
+            code_lines: 2,
+            comment_lines: 1,
+            ..Default::default()
+        };
+        assert!(compat_issues(&split(mixed, 2), 2).is_empty(), "mixed line is fine");
+        let dropped = LanguageStats {
+            code_lines: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            compat_issues(&split(dropped, 3), 3),
+            ["2 line(s) left unclassified (padded as blank)"]
+        );
+        let issues = compat_issues(&split(mixed, 3), 2);
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        assert!(issues[0].starts_with("counter saw 3 line(s), file has 2"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "ok.py", "# c\nx = 1\n\n")?;
+        create_test_file(temp_dir.path(), "ok.sh", "echo hi # trailing")?;
+        let args = Args {
+            compat_check: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut error_count = 0;
+        let mut entries_count = 0usize;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        assert_eq!(metrics.compat_checked, 2);
+        assert!(metrics.compat_mismatches.is_empty(), "{:?}", metrics.compat_mismatches);
+
+        let report = format_compat_report(
+            2,
+            &[(PathBuf::from("bad.rs"), vec!["counter saw 3 line(s), file has 2".into()])],
+        );
+        assert!(report.contains("2 file(s) checked, 1 mismatch(es)"), "{report}");
+        assert!(report.contains("  bad.rs: counter saw 3"), "{report}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_graphql_descriptions_count_as_comments() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "schema.graphql",
+            "# Root schema\n\"\"\"\nA user of the service.\nMay be anonymous.\n\"\"\"\ntype User {\n\
+             \n  \"\"\"Unique id\"\"\"\n  id: ID!\n  name: String\n}\n",
+        )?;
+        assert_eq!(get_language_from_extension("schema.graphql"), Some("GraphQL"));
+        assert_eq!(get_language_from_extension("query.gql"), Some("GraphQL"));
+        let (stats, total_lines) = count_lines_with_stats(&temp_dir.path().join("schema.graphql"))?;
+        assert_eq!(total_lines, 11);
+        assert_eq!(stats.comment_lines, 6);
+        assert_eq!(stats.code_lines, 4);
+        assert_eq!(stats.blank_lines, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_depth_rolls_rows_up_without_changing_totals() {
+        let root = Path::new("/repo");
+        let rust = |code_lines| {
+            let mut dir_stats = DirectoryStats::default();
+            dir_stats.language_stats.insert(
+                "Rust".to_string(),
+                language_entry(
+                    1,
+                    LanguageStats {
+                        code_lines,
+                        ..Default::default()
+                    },
+                ),
+            );
+            dir_stats
+        };
+        let build = || {
+            let mut stats = HashMap::new();
+            stats.insert(root.to_path_buf(), rust(1));
+            stats.insert(root.join("src"), rust(2));
+            stats.insert(root.join("src/parser/lexer"), rust(4));
+            stats.insert(root.join("tests"), rust(8));
+            stats.insert(PathBuf::from("/elsewhere"), rust(16));
+            stats
+        };
+        let original_totals = format_totals_line(&build());
+
+        let depth_one = aggregate_to_depth(build(), root, 1);
+        let mut keys: Vec<_> = depth_one.keys().cloned().collect();
+        keys.sort();
+        let expected: Vec<PathBuf> = vec![
+            PathBuf::from("/elsewhere"),
+            root.to_path_buf(),
+            root.join("src"),
+            root.join("tests"),
+        ];
+        assert_eq!(keys, expected);
+        let (src_files, src_stats) = depth_one[&root.join("src")].language_stats["Rust"].summary();
+        assert_eq!((src_files, src_stats.code_lines), (2, 6));
+        assert_eq!(format_totals_line(&depth_one), original_totals);
+
+        let collapsed = aggregate_to_depth(build(), root, 0);
+        assert_eq!(collapsed.len(), 2, "root row plus the outside directory");
+        let (files, root_stats) = collapsed[root].language_stats["Rust"].summary();
+        assert_eq!((files, root_stats.code_lines), (4, 15));
+        assert_eq!(format_totals_line(&collapsed), original_totals);
+    }
+
+    #[test]
+    fn test_render_report_dispatches_on_format() -> io::Result<()> {
+        let root = PathBuf::from("/repo");
+        let mut stats = HashMap::new();
+        let mut dir_stats = DirectoryStats::default();
+        dir_stats.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    code_lines: 3,
+                    ..Default::default()
+                },
+            ),
+        );
+        stats.insert(root.join("src"), dir_stats);
+        let mut result = AnalysisResult {
+            root_dir: root.clone(),
+            display_dir: root.clone(),
+            stats,
+            files_processed: 1,
+            lines_processed: 3,
+            error_count: 0,
+            role_counters: Some([(1, 3, 3), (0, 0, 0), (0, 0, 0)]),
+            options: ReportOptions::from_args(&test_args()),
+        };
+
+        let text = render_report(&result, OutputFormat::Text)?;
+        let expected = build_analysis_report(&root, &result.stats, 1, 3, 0, &result.options);
+        assert_eq!(text, expected, "no role summary unless requested");
+        let json = render_report(&result, OutputFormat::Json)?;
+        assert!(json.starts_with("{\n") && json.contains("    \"src\": {"), "{json}");
+
+        result.options.role_summary = true;
+        let with_roles = render_report(&result, OutputFormat::Text)?;
+        assert!(with_roles.starts_with(&expected));
+        assert!(with_roles.contains("Role Summary:"), "{with_roles}");
+
+        result.files_processed = 0;
+        let empty = render_report(&result, OutputFormat::Text)?;
+        assert!(empty.contains("No source code files found."), "{empty}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_r_and_r_markdown() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "model.R",
+            "# Fit a model\nfit <- lm(y ~ x)\n\nsummary(fit)\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "report.Rmd",
+            "# Results\nThe fit is shown below.\n\n```{r setup, echo=FALSE}\n# load data\n\
+             df <- read.csv(\"d.csv\")\n```\n```\nnot a chunk\n```\n",
+        )?;
+        assert_eq!(get_language_from_extension("model.R"), Some("R"));
+        assert_eq!(get_language_from_extension("model.r"), Some("R"));
+        assert_eq!(get_language_from_extension("report.Rmd"), Some("R Markdown"));
+        assert_eq!(get_language_from_extension("report.rmd"), Some("R Markdown"));
+
+        let (r, total) = count_lines_with_stats(&temp_dir.path().join("model.R"))?;
+        assert_eq!(total, 4);
+        assert_eq!((r.code_lines, r.comment_lines, r.blank_lines), (2, 1, 1));
+
+        // Prose, including the `# Results` heading and plain fences, is comment.
+        let (rmd, total) = count_lines_with_stats(&temp_dir.path().join("report.Rmd"))?;
+        assert_eq!(total, 10);
+        assert_eq!((rmd.code_lines, rmd.comment_lines, rmd.blank_lines), (3, 6, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_recurse_into_counts_only_the_matched_directory() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vendor = temp_dir.path().join("vendor");
+        fs::create_dir_all(vendor.join("sub"))?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        create_test_file(&vendor, "keep.rs", "fn keep() {}\nfn more() {}\n")?;
+        create_test_file(&vendor.join("sub"), "skip.rs", "fn skip() {}\n")?;
+
+        let args = Args {
+            no_recurse_into: vec!["vendor".to_string()],
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries_count = 0;
+        let mut error_count = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        let vendor_key = fs::canonicalize(&vendor)?;
+        let (files, rust) = stats[&vendor_key].language_stats["Rust"].summary();
+        assert_eq!((files, rust.code_lines), (1, 2));
+        assert!(!stats.contains_key(&fs::canonicalize(vendor.join("sub"))?));
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 2);
+
+        let bad = Args {
+            no_recurse_into: vec!["[".to_string()],
+            ..test_args()
+        };
+        let err = scan_directory(
+            temp_dir.path(),
+            &bad,
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut 0,
+            &mut 0,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_comment_is_a_true_mixed_line() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "mixed.rs",
+            "let x = 1; // note\nlet url = \"http://example.com\";\n// only a comment\n",
+        )?;
+        create_test_file(temp_dir.path(), "mixed.c", "int x = 1; // note\nint y;\n")?;
+        create_test_file(
+            temp_dir.path(),
+            "mixed.sh",
+            "#!/bin/sh\necho hi # note\necho \"# not a comment\" $#\n",
+        )?;
+
+        let (rust, total) = count_rust_lines(&mut lines_of(&temp_dir.path().join("mixed.rs")))?;
+        assert_eq!(total, 3);
+        assert_eq!((rust.code_lines, rust.comment_lines, rust.overlap_lines), (2, 2, 1));
+        let (c, _) = count_c_style_lines(&mut lines_of(&temp_dir.path().join("mixed.c")))?;
+        assert_eq!((c.code_lines, c.comment_lines, c.overlap_lines), (2, 1, 1));
+        let (sh, _) = count_shell_lines(&mut lines_of(&temp_dir.path().join("mixed.sh")))?;
+        assert_eq!((sh.code_lines, sh.comment_lines, sh.overlap_lines), (3, 1, 1));
+
+        let role_split = count_rust_lines_role_aware(&mut lines_of(&temp_dir.path().join("mixed.rs")),
+            FileRoleHint::Unknown,
+            TestScope::Cfg,
+            false,
+        )?;
+        assert_eq!(role_split.total_lines(), 3);
+
+        // Counters that already report mixed lines survive normalisation as-is.
+        let normalized = normalize_stats(rust, total);
+        assert_eq!((normalized.overlap_lines, normalized.blank_lines), (1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_label_names_the_report_heading() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        let mut metrics = test_metrics();
+        let stats = scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut 0,
+            &mut 0,
+        )?;
+        let mut result = AnalysisResult {
+            root_dir: temp_dir.path().to_path_buf(),
+            display_dir: temp_dir.path().to_path_buf(),
+            stats,
+            files_processed: 1,
+            lines_processed: 1,
+            error_count: 0,
+            role_counters: None,
+            options: ReportOptions::from_args(&Args {
+                path: "services/api".to_string(),
+                ..test_args()
+            }),
+        };
+        let text = render_report(&result, OutputFormat::Text)?;
+        assert!(text.contains("Detailed source code analysis of services/api:"), "{text}");
+        let json = render_report(&result, OutputFormat::Json)?;
+        assert!(!json.contains("\"label\""), "{json}");
+
+        result.options.root_label = Some("API \"v2\"".to_string());
+        let text = render_report(&result, OutputFormat::Text)?;
+        assert!(text.contains("Detailed source code analysis of API \"v2\":"), "{text}");
+        let json = render_report(&result, OutputFormat::Json)?;
+        assert!(json.contains("  \"label\": \"API \\\"v2\\\"\",\n"), "{json}");
+
+        let args = Args::parse_from(["mdkloc", ".", "--root-label", "nightly"]);
+        assert_eq!(args.root_label.as_deref(), Some("nightly"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vhdl_and_verilog_comments() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "counter.vhd",
+            "-- 4-bit counter\nentity counter is\n\n  port (clk : in std_logic); -- clock\n\
+             q <= \"--\" & '-'; -- not the code part\nend if clk'event;\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "top.sv",
+            "// top level\nmodule top; /* ports */\n  assign x = y;\nendmodule\n",
+        )?;
+        assert_eq!(get_language_from_extension("counter.vhd"), Some("VHDL"));
+        assert_eq!(get_language_from_extension("counter.vhdl"), Some("VHDL"));
+        assert_eq!(get_language_from_extension("top.v"), Some("Verilog"));
+        assert_eq!(get_language_from_extension("top.sv"), Some("Verilog"));
+        assert_eq!(get_language_from_extension("defs.svh"), Some("Verilog"));
+
+        let (vhdl, total) = count_lines_with_stats(&temp_dir.path().join("counter.vhd"))?;
+        assert_eq!(total, 6);
+        assert_eq!(
+            (vhdl.code_lines, vhdl.comment_lines, vhdl.blank_lines, vhdl.overlap_lines),
+            (4, 3, 1, 2)
+        );
+
+        let (verilog, total) = count_lines_with_stats(&temp_dir.path().join("top.sv"))?;
+        assert_eq!(total, 4);
+        assert_eq!(
+            (verilog.code_lines, verilog.comment_lines, verilog.overlap_lines),
+            (3, 2, 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_jsonl_record_is_a_single_line_object() {
+        let stats = LanguageStats {
+            code_lines: 3,
+            comment_lines: 1,
+            blank_lines: 2,
+            ..LanguageStats::default()
+        };
+        assert_eq!(
+            format_jsonl_record("src/a \"b\".rs", "Rust", CodeRole::Test, &stats),
+            "{\"path\": \"src/a \\\"b\\\".rs\", \"language\": \"Rust\", \"role\": \"test\", \
+             \"code\": 3, \"comment\": 1, \"blank\": 2}"
+        );
+        let args = Args::parse_from(["mdkloc", ".", "--format", "jsonl"]);
+        assert_eq!(args.format, OutputFormat::Jsonl);
+    }
+
+    #[test]
+    fn test_makefile_recipe_hash_is_shell_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "Makefile",
+            "# build rules\nCC := cc # compiler\n\nall: main.o\n\t# shell comment\n\
+             \t$(CC) -o app main.o\n  # indented make comment\n",
+        )?;
+        let (stats, total) = count_makefile_lines(&mut lines_of(temp_dir.path().join("Makefile").as_path()))?;
+        assert_eq!(total, 7);
+        assert_eq!(stats.code_lines, 4, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 2, "stats: {:?}", stats);
+        assert_eq!(stats.blank_lines, 1, "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_doc_comments_are_tallied_separately() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "//! Crate docs\n/// Adds one.\n//// banner, not a doc comment\n// plain\n\
+             fn add_one(x: u32) -> u32 {\n    x + 1 // trailing\n}\n",
+        )?;
+        let path = temp_dir.path().join("lib.rs");
+        let (stats, _) = count_rust_lines(&mut lines_of(&path))?;
+        assert_eq!(stats.comment_lines, 5, "stats: {:?}", stats);
+        assert_eq!(stats.doc_comment_lines, 2, "stats: {:?}", stats);
+        let split = count_rust_lines_role_aware(&mut lines_of(&path), FileRoleHint::Unknown, TestScope::Cfg, false)?;
+        let docs: u64 = split.iter().map(|(_, bucket)| bucket.stats.doc_comment_lines).sum();
+        assert_eq!(docs, 2);
+
+        let mut metrics = test_metrics();
+        let args = Args {
+            doc_comments: true,
+            ..test_args()
+        };
+        let stats_map = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut 0,
+            &mut 0,
+        )?;
+        let options = ReportOptions::from_args(&args);
+        let report = build_analysis_report(temp_dir.path(), &stats_map, 1, 7, 0, &options);
+        let header = report.lines().find(|l| l.starts_with("Directory")).unwrap();
+        assert!(header.ends_with("      Blank        Doc"), "{header}");
+        let row = report.lines().find(|l| l.starts_with(". ")).unwrap();
+        assert_eq!(row.chars().count(), options.layout.rule_width());
+        assert!(row.ends_with(" 2"), "{row}");
+
+        let default_options = ReportOptions::default();
+        let plain = build_analysis_report(temp_dir.path(), &stats_map, 1, 7, 0, &default_options);
+        assert!(!plain.contains(" Doc"), "{plain}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_report_counts_traversal_and_skips() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let sub = temp_dir.path().join("sub");
+        fs::create_dir(&sub)?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        create_test_file(temp_dir.path(), "notes.xyz", "??\n")?;
+        create_test_file(&sub, "lib.rs", "fn lib() {}\n")?;
+        create_test_file(&sub, "run.py", "print(1)\n")?;
+
+        let args = Args {
+            filespec: Some("*.rs".to_string()),
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries_count = 0;
+        let mut error_count = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries_count,
+            &mut error_count,
+        )?;
+        assert_eq!(
+            metrics.entry_counts,
+            EntryCounts {
+                directories: 2,
+                filtered: 2,
+                unrecognised: 0,
+                duplicate_targets: 0,
+                hardlink_duplicates: 0,
+                submodules_skipped: 0,
+                minified: 0,
+            }
+        );
+
+        let report = format_entries_report(entries_count, &metrics.entry_counts, 2, 0, 0);
+        assert!(report.starts_with("\nScan entries:\n"), "{report}");
+        assert!(
+            report.contains(&format!("  {:<30} {:>10}\n", "Entries visited", entries_count)),
+            "{report}"
+        );
+        assert!(report.contains(&format!("  {:<30} {:>10}\n", "Skipped (--filespec)", 2)));
+        assert!(report.contains(&format!("  {:<30} {:>10}\n", "Errors", 0)));
+
+        let args = Args::parse_from(["mdkloc", ".", "--entries-report"]);
+        assert!(args.entries_report);
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_path_argument_expands_across_subdirectories() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        fs::create_dir_all(root.join("src/deep"))?;
+        create_test_file(&root, "top.rs", "fn top() {}\n")?;
+        create_test_file(&root.join("src"), "lib.rs", "fn lib() {}\n")?;
+        create_test_file(&root.join("src/deep"), "mod.rs", "fn deep() {}\nfn more() {}\n")?;
+        create_test_file(&root.join("src"), "tool.py", "print(1)\n")?;
+
+        let pattern = format!("{}/src/**/*.rs", root.display());
+        let path = Path::new(&pattern);
+        assert_eq!(path_glob(path), Some(pattern.as_str()));
+        assert_eq!(glob_base(&pattern), root.join("src"));
+        assert_eq!(glob_base("*.rs"), PathBuf::from("."));
+        assert_eq!(path_glob(&root), None);
+
+        let mut metrics = test_metrics();
+        let stats = scan_directory(path, &test_args(), &root, &mut metrics, 0, &mut 0, &mut 0)?;
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 2);
+        let (files, rust) = stats[&root.join("src/deep")].language_stats["Rust"].summary();
+        assert_eq!((files, rust.code_lines), (1, 2));
+        assert!(!stats[&root.join("src")].language_stats.contains_key("Python"));
+        assert!(!stats.contains_key(&root));
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_totals_match_a_full_run() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("a/b"))?;
+        create_test_file(root, "main.rs", "// entry\nfn main() {}\n")?;
+        create_test_file(&root.join("a"), "util.rs", "fn util() {}\n\n")?;
+        create_test_file(&root.join("a/b"), "deep.py", "# note\nx = 1\n")?;
+        create_test_file(&root.join("a/b"), "more.rs", "fn more() {} // tail\n")?;
+
+        let scan = |args: &Args| {
+            scan_directory(root, args, root, &mut test_metrics(), 0, &mut 0, &mut 0)
+        };
+        let full = scan(&test_args())?;
+        let streaming_args = Args {
+            streaming_totals: true,
+            ..test_args()
+        };
+        let streamed = scan(&streaming_args)?;
+        assert!(full.len() > 1);
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(format_totals_line(&streamed), format_totals_line(&full));
+        for lang in ["Rust", "Python"] {
+            let merged = &streamed.values().next().unwrap().language_stats[lang];
+            let (files, stats) = merged.summary();
+            let (full_files, full_code) = full
+                .values()
+                .filter_map(|dir| dir.language_stats.get(lang))
+                .map(|entry| entry.summary())
+                .fold((0, 0), |(f, c), (files, s)| (f + files, c + s.code_lines));
+            assert_eq!((files, stats.code_lines), (full_files, full_code), "{lang}");
+        }
+
+        let options = ReportOptions::from_args(&streaming_args);
+        let report = build_analysis_report(root, &streamed, 4, 7, 0, &options);
+        assert!(report.contains("(per-directory detail not kept with --streaming-totals)"));
+        let table = report.split("Totals by language:").next().unwrap();
+        assert!(!table.contains(" Rust "), "{table}");
+
+        // JSON keeps the language totals but claims no directory layout.
+        let json = build_json_report(root, &streamed, 4, 7, 0, &options);
+        assert!(json.contains("  \"directories\": {\n  },\n"), "{json}");
+        assert!(json.contains("    \"Python\": {\"files\": 1"), "{json}");
+        let csv_args = Args::parse_from([
+            "mdkloc",
+            root.to_str().unwrap(),
+            "--streaming-totals",
+            "--format",
+            "csv",
+        ]);
+        let err = run_cli_with_metrics(csv_args, &mut test_metrics()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("--streaming-totals"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_style_overrides_conf_tokens() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "app.conf",
+            "// listener settings\nport = 8080\n# legacy note\n\n; not a comment here\n",
+        )?;
+        let args = Args::parse_from(["mdkloc", ".", "--comment-style", "conf=//,#"]);
+        assert_eq!(
+            args.comment_styles,
+            [CustomLanguage {
+                name: "INI".to_string(),
+                extensions: vec!["conf".to_string()],
+                line_comments: vec!["//".to_string(), "#".to_string()],
+                ..Default::default()
+            }]
+        );
+        let args = Args {
+            custom_languages: args.comment_styles.clone(),
+            ..test_args()
+        };
+        assert_eq!(language_for_file("app.conf", &args), Some("INI"));
+        let split = count_lines_with_roles(
+            &temp_dir.path().join("app.conf"),
+            FileRoleHint::Unknown,
+            &args,
+        )?;
+        let stats = split.iter().next().expect("one bucket").1.stats;
+        assert_eq!((stats.code_lines, stats.comment_lines, stats.blank_lines), (2, 2, 1));
+
+        assert!(parse_comment_style("conf").is_err());
+        assert!(parse_comment_style("conf=").is_err());
+        assert_eq!(parse_comment_style(".dsl=;;").map(|lang| lang.name), Ok("DSL".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_python_triple_quotes_inside_strings_are_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "embedded.py",
+            "x = \"contains \\\"\\\"\\\" triple\"\ny = '\"\"\"'\n# real comment\nz = 3\n",
+        )?;
+        let (stats, total) = count_python_lines(&mut lines_of(&temp_dir.path().join("embedded.py")))?;
+        assert_eq!(total, 4);
+        assert_eq!((stats.code_lines, stats.comment_lines), (3, 1), "stats: {:?}", stats);
+
+        // An assigned (f-)string spanning lines is code; `#` and `"""` inside
+        // it must not be taken for a comment or a docstring.
+        create_test_file(
+            temp_dir.path(),
+            "assigned.py",
+            "def f():\n    \"\"\"Docstring.\"\"\"\n    query = f\"\"\"\n# not a comment\n\
+             \"\"\"\n    return query\n",
+        )?;
+        let (stats, total) = count_python_lines(&mut lines_of(&temp_dir.path().join("assigned.py")))?;
+        assert_eq!(total, 6);
+        assert_eq!((stats.code_lines, stats.comment_lines), (5, 1), "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_role_share_splits_code_between_mainline_and_test() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "fn a() {}\nfn b() {}\nfn c() {}\n\n#[cfg(test)]\nmod tests {\n    #[test]\n\
+             fn t() {}\n}\n",
+        )?;
+        let args = Args {
+            role_breakdown: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut 0,
+            &mut 0,
+        )?;
+        let counters = metrics.role_counters();
+        let (main_code, test_code) = (counters[0].2, counters[1].2);
+        assert_eq!((main_code, test_code), (3, 5));
+
+        let share = format_role_share(&counters);
+        assert_eq!(
+            share,
+            "\nRole share of code lines:\nMainline:   37.5% (3)\nTest:       62.5% (5)\n"
+        );
+
+        let result = AnalysisResult {
+            root_dir: temp_dir.path().to_path_buf(),
+            display_dir: temp_dir.path().to_path_buf(),
+            stats,
+            files_processed: 1,
+            lines_processed: 9,
+            error_count: 0,
+            role_counters: Some(counters),
+            options: ReportOptions::from_args(&args),
+        };
+        let report = render_report(&result, OutputFormat::Text)?;
+        assert!(report.ends_with(&share), "{report}");
+        let plain = AnalysisResult {
+            options: ReportOptions::default(),
+            ..result
+        };
+        assert!(!render_report(&plain, OutputFormat::Text)?.contains("Role share"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_test_and_only_test_filter_roles() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "fn a() {}\nfn b() {}\nfn c() {}\n\n#[cfg(test)]\nmod tests {\n    #[test]\n\
+             fn t() {}\n}\n",
+        )?;
+        create_test_file(temp_dir.path(), "tool.py", "print(1)\n")?;
+        let run = |args: Args| -> io::Result<(u64, String)> {
+            let mut metrics = test_metrics();
+            let stats = scan_directory(
+                temp_dir.path(),
+                &args,
+                temp_dir.path(),
+                &mut metrics,
+                0,
+                &mut 0,
+                &mut 0,
+            )?;
+            let files = metrics.files_processed.load(Ordering::Relaxed);
+            Ok((files, format_totals_line(&stats)))
+        };
+
+        let (files, totals) = run(Args {
+            exclude_test: true,
+            ..test_args()
+        })?;
+        assert_eq!(files, 2);
+        assert_eq!(totals, "code=4 comments=0 blank=1 mixed=0 files=2");
+
+        let (files, totals) = run(Args {
+            only_test: true,
+            ..test_args()
+        })?;
+        assert_eq!(files, 1);
+        assert_eq!(totals, "code=5 comments=0 blank=0 mixed=0 files=1");
+
+        assert!(Args::try_parse_from(["mdkloc", "--exclude-test", "--only-test"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_markdown_front_matter_counts_as_yaml_or_toml() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "post.md",
+            "---\n# draft settings\ntitle: Hello\ntags: [a, b]\n---\n# Heading\n\nBody text.\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "page.md",
+            "+++\n# theme override\ntheme = \"dark\"\n+++\nText.\n",
+        )?;
+        let args = Args {
+            markdown_fences: true,
+            ..test_args()
+        };
+        let summary = |splits: &[(String, RoleSplit)], lang: &str| {
+            let (_, split) = splits.iter().find(|(l, _)| l == lang).expect(lang);
+            let stats = split.iter().next().unwrap().1.stats;
+            (stats.code_lines, stats.comment_lines, stats.blank_lines)
+        };
+
+        let splits = count_markdown_with_fences(&mut lines_of(&temp_dir.path().join("post.md")), &args)?;
+        assert_eq!(summary(&splits, "YAML"), (2, 1, 0));
+        assert_eq!(summary(&splits, "Markdown"), (4, 0, 1));
+        let lines: u64 = splits.iter().map(|(_, split)| split.total_lines()).sum();
+        assert_eq!(lines, 8);
+
+        let splits = count_markdown_with_fences(&mut lines_of(&temp_dir.path().join("page.md")), &args)?;
+        assert_eq!(summary(&splits, "TOML"), (1, 1, 0));
+        assert_eq!(summary(&splits, "Markdown"), (3, 0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_checksum_is_stable_and_order_independent() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("src"))?;
+        create_test_file(temp_dir.path(), "src/main.rs", "// entry\nfn main() {}\n\n")?;
+        create_test_file(temp_dir.path(), "build.py", "# build\nprint('hi')\n")?;
+        let args = Args {
+            path: temp_dir.path().to_string_lossy().into_owned(),
+            checksum: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let mut records = metrics.checksum_records.clone();
+        records.sort();
+        assert_eq!(
+            records,
+            vec![
+                ("build.py".to_string(), "Python".to_string(), 1, 1, 0),
+                ("src/main.rs".to_string(), "Rust".to_string(), 1, 1, 1),
+            ]
+        );
+        assert_eq!(format!("{:016x}", scan_checksum(&records)), "242773e0e72f7f2a");
+        records.reverse();
+        assert_eq!(scan_checksum(&records), scan_checksum(&metrics.checksum_records));
+        Ok(())
+    }
+
+    #[test]
+    fn test_asm_trailing_comments_are_mixed_lines() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "x.asm",
+            "mov eax, 1 ; set value\nmsg db 'a;b', \";\", 0\ncmp al, ';' ; semicolon\n\
+             add x0, x0, #1\nnop # gas comment\nret // done\n",
+        )?;
+        let (stats, total) = count_asm_lines(&mut lines_of(temp_dir.path().join("x.asm").as_path()))?;
+        assert_eq!(total, 6);
+        assert_eq!(stats.code_lines, 6, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 4, "stats: {:?}", stats);
+        assert_eq!(stats.overlap_lines, 4, "stats: {:?}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_phase_times_reports_seconds_and_shares() {
+        let times = PhaseTimes {
+            traversal: Duration::from_millis(250),
+            counting: Duration::from_millis(700),
+            report: Duration::from_millis(50),
+        };
+        assert_eq!(
+            format_phase_times(&times),
+            "\nPhase timings:\n  Traversal      0.250s   25.0%\n  \
+             Counting       0.700s   70.0%\n  Report         0.050s    5.0%\n"
+        );
+        let idle = format_phase_times(&PhaseTimes::default());
+        assert!(idle.contains("Counting       0.000s    0.0%"), "{idle}");
+    }
+
+    #[test]
+    fn test_blame_porcelain_keys_authors_by_final_line() {
+        let porcelain = "abc 1 1 2\nauthor Alice\nsummary first\n\tfn a() {}\n\
+                         abc 2 2\nauthor Alice\n\t\n\
+                         def 3 3 1\nauthor Bob\nsummary 9 9 9\n\tfn b() {}\n\
+                         abc 4 4\nauthor Alice\n\t// note\n";
+        let blamed = parse_blame_porcelain(porcelain);
+        let expected: Vec<(usize, String)> = [(0, "Alice"), (1, "Alice"), (2, "Bob"), (3, "Alice")]
+            .into_iter().map(|(idx, author)| (idx, author.to_string())).collect();
+        assert_eq!(blamed, expected);
+        assert!(parse_blame_porcelain("").is_empty());
+    }
+
+    #[test]
+    fn test_blame_code_lines_use_whole_file_records() -> io::Result<()> {
+        // Bob's lines are inside Alice's block comment and stay comments.
+        let source = "fn a() {}\n/*\nlet x = 1;\n*/\n#[test]\nfn t() {}\n";
+        let mut lines = LossyLineReader::with_reader(io::Cursor::new(source)).recording();
+        count_file_splits("lib.rs", &mut lines, "Rust", None, FileRoleHint::Unknown, &test_args())?;
+        let records = lines.take_records();
+        let blamed: Vec<(usize, String)> = ["Alice", "Alice", "Bob", "Alice", "Bob", "Bob"]
+            .into_iter().enumerate().map(|(idx, author)| (idx, author.to_string())).collect();
+        let by_author = |args: &Args| blame_code_lines(&blamed, &records, "Rust", args, false);
+        assert_eq!(by_author(&test_args()), [("Alice".to_string(), 1), ("Bob".to_string(), 2)]);
+        let args = Args { exclude_test: true, ..test_args() };
+        assert_eq!(by_author(&args), [("Alice".to_string(), 1), ("Bob".to_string(), 0)]);
+        // Fixture files count no code for anyone.
+        assert_eq!(blame_code_lines(&blamed, &records, "Rust", &test_args(), true),
+                   [("Alice".to_string(), 0), ("Bob".to_string(), 0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_roles_flags_unbalanced_rust_files() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "ok.rs",
+            "fn main() {}\n\n#[cfg(test)]\nmod tests {\n    #[test]\n    fn t() {}\n}\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "broken.rs",
+            "fn a() {\n    let s = \"}\";\n    if true {\n}\n\n#[test]\n",
+        )?;
+        create_test_file(temp_dir.path(), "extra.rs", "fn b() {}\n}\n")?;
+        let args = Args {
+            strict_roles: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let mut flagged = metrics.role_warnings.clone();
+        flagged.sort();
+        assert_eq!(
+            flagged,
+            vec![
+                (
+                    PathBuf::from("broken.rs"),
+                    vec![
+                        "1 scope(s) still open at end of file".to_string(),
+                        "test attribute not followed by an item body".to_string(),
+                    ]
+                ),
+                (
+                    PathBuf::from("extra.rs"),
+                    vec!["1 closing brace(s) without a matching open".to_string()]
+                ),
+            ]
+        );
+        let report = format_role_warnings(&flagged);
+        assert!(report.starts_with("\nRole detection warnings: 2 file(s)\n"), "{report}");
+        assert!(report.contains("  extra.rs: 1 closing brace(s)"), "{report}");
+
+        // A dangling feature gate only matters when feature roles are tracked.
+        create_test_file(temp_dir.path(), "gated.rs", "fn c() {}\n#[cfg(feature = \"x\")]\n")?;
+        let gated = temp_dir.path().join("gated.rs");
+        let warnings = |feature_roles| -> io::Result<Vec<String>> {
+            let split = count_rust_lines_role_aware(&mut lines_of(&gated), FileRoleHint::Unknown, TestScope::Cfg, feature_roles)?;
+            Ok(split.warnings)
+        };
+        assert!(warnings(false)?.is_empty());
+        assert_eq!(warnings(true)?, vec!["feature attribute not followed by an item body".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lua_level_matched_block_comments() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "x.lua",
+            "-- header\nlocal s = \"--not a comment\"\n--[==[\nstill ]] comment\n]==] x = 1\n\
+             local t = [[\n-- inside string\n]]\nprint(s) -- trailing\n",
+        )?;
+        let (stats, total) = count_lua_lines(&mut lines_of(&temp_dir.path().join("x.lua")), true)?;
+        assert_eq!(total, 9);
+        assert_eq!(stats.code_lines, 6, "stats: {:?}", stats);
+        assert_eq!(stats.comment_lines, 5, "stats: {:?}", stats);
+        assert_eq!(stats.overlap_lines, 2, "stats: {:?}", stats);
+
+        create_test_file(temp_dir.path(), "x.moon", "--[[ line only\nx = 1\n]]\n")?;
+        let (stats, _) = count_lua_lines(&mut lines_of(&temp_dir.path().join("x.moon")), false)?;
+        assert_eq!((stats.code_lines, stats.comment_lines), (2, 1), "stats: {:?}", stats);
+        assert_eq!(get_language_from_extension("init.lua"), Some("Lua"));
+        assert_eq!(get_language_from_extension("app.moon"), Some("MoonScript"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_largest_files_ranks_by_code_then_path() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("src"))?;
+        create_test_file(temp_dir.path(), "src/big.rs", "fn a() {}\nfn b() {}\nfn c() {}\n")?;
+        create_test_file(temp_dir.path(), "b.py", "x = 1\ny = 2\n# note\n")?;
+        create_test_file(temp_dir.path(), "a.py", "x = 1\ny = 2\n")?;
+        create_test_file(temp_dir.path(), "tiny.rs", "// only a comment\nfn t() {}\n")?;
+        let args = Args {
+            largest_files: Some(3),
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(metrics.largest_files.len(), 3);
+        let big = Path::new("src").join("big.rs");
+        assert_eq!(
+            format_largest_files(&metrics.largest_files),
+            format!(
+                "\nLargest files by code lines:\n    1.          3  {}\n    \
+                 2.          2  a.py\n    3.          2  b.py\n",
+                big.display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_policies_on_over_and_under_counts() {
+        let over = LanguageStats {
+            code_lines: 6,
+            comment_lines: 3,
+            blank_lines: 2,
+            ..Default::default()
+        };
+        let under = LanguageStats {
+            code_lines: 4,
+            comment_lines: 1,
+            ..Default::default()
+        };
+        let summary = |stats: LanguageStats| {
+            (
+                stats.code_lines,
+                stats.comment_lines,
+                stats.blank_lines,
+                stats.overlap_lines,
+            )
+        };
+
+        let policy = NormalizePolicy::Default;
+        assert_eq!(summary(normalize_stats_with(over, 8, policy)), (6, 3, 0, 1));
+        assert_eq!(summary(normalize_stats_with(under, 8, policy)), (4, 1, 3, 0));
+
+        let policy = NormalizePolicy::Strict;
+        assert_eq!(summary(normalize_stats_with(over, 8, policy)), (6, 3, 0, 1));
+        assert_eq!(summary(normalize_stats_with(under, 8, policy)), (4, 1, 0, 0));
+
+        let policy = NormalizePolicy::Off;
+        assert_eq!(summary(normalize_stats_with(over, 8, policy)), (6, 3, 2, 0));
+        assert_eq!(summary(normalize_stats_with(under, 8, policy)), (4, 1, 0, 0));
+
+        let args = Args::parse_from(["mdkloc", ".", "--normalize", "none"]);
+        assert_eq!(args.normalize, NormalizePolicy::Off);
+        let args = Args::parse_from(["mdkloc", "."]);
+        assert_eq!(args.normalize, NormalizePolicy::Default);
+    }
+
+    #[test]
+    fn test_exclude_fixtures_reports_lines_as_ignored() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("fixtures"))?;
+        create_test_file(temp_dir.path(), "fixtures/data.json", "{\n  \"a\": 1\n}\n\n")?;
+        create_test_file(temp_dir.path(), "config.json", "{\"b\": 2}\n")?;
+        let args = Args {
+            exclude_fixtures: vec!["fixtures/*.json".to_string()],
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 2);
+        let root = fs::canonicalize(temp_dir.path())?;
+        let fixture = stats[&root.join("fixtures")].language_stats["JSON"].summary().1;
+        assert_eq!(
+            (fixture.code_lines, fixture.comment_lines, fixture.blank_lines),
+            (0, 0, 0)
+        );
+        assert_eq!(fixture.ignored_lines, 4);
+        let config = stats[&root].language_stats["JSON"].summary().1;
+        assert_eq!((config.code_lines, config.ignored_lines), (1, 0));
+
+        let bad = Args {
+            exclude_fixtures: vec!["[".to_string()],
+            ..test_args()
+        };
+        let err = scan_directory(
+            temp_dir.path(),
+            &bad,
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("--exclude-fixtures"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_case_variants_folds_case_variant_keys() {
+        let rust = |code_lines| {
+            let mut dir_stats = DirectoryStats::default();
+            dir_stats.language_stats.insert(
+                "Rust".to_string(),
+                language_entry(
+                    1,
+                    LanguageStats {
+                        code_lines,
+                        ..Default::default()
+                    },
+                ),
+            );
+            dir_stats
+        };
+        let mut stats = HashMap::new();
+        stats.insert(PathBuf::from("/repo/src/foo"), rust(2));
+        stats.insert(PathBuf::from("/repo/src/Foo"), rust(3));
+        stats.insert(PathBuf::from("/repo/src/bar"), rust(5));
+        let original_totals = format_totals_line(&stats);
+
+        let merged = merge_case_variants(stats);
+        let mut keys: Vec<_> = merged.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![PathBuf::from("/repo/src/Foo"), PathBuf::from("/repo/src/bar")]
+        );
+        let (files, foo) = merged[Path::new("/repo/src/Foo")].language_stats["Rust"].summary();
+        assert_eq!((files, foo.code_lines), (2, 5));
+        assert_eq!(format_totals_line(&merged), original_totals);
+    }
+
+    #[test]
+    fn test_include_ext_counts_custom_extension_as_builtin_language() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "view.rsx",
+            "// component\nfn view() {}\n\n#[test]\nfn renders() {}\n",
+        )?;
+        create_test_file(temp_dir.path(), "notes.zzz", "plain\n")?;
+        let args = Args::parse_from(["mdkloc", ".", "--include-ext", ".RSX=rust"]);
+        assert_eq!(
+            args.include_ext,
+            vec![ExtensionMapping {
+                ext: "rsx".to_string(),
+                language: "Rust",
+                builtin_ext: "rs",
+            }]
+        );
+        assert_eq!(language_for_file("view.rsx", &args), Some("Rust"));
+        assert_eq!(language_for_file("notes.zzz", &args), None);
+
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 1);
+        let root = fs::canonicalize(temp_dir.path())?;
+        let rust = &stats[&root].language_stats["Rust"];
+        let (_, summary) = rust.summary();
+        assert_eq!((summary.code_lines, summary.comment_lines), (3, 1));
+        assert_eq!(rust.role_summary(CodeRole::Test).map(|(_, s)| s.code_lines), Some(2));
+
+        assert!(parse_extension_mapping("rsx=Klingon").is_err());
+        assert!(parse_extension_mapping("rsx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_representative_extensions_map_back_to_their_language() {
+        for (language, ext) in REPRESENTATIVE_EXTENSIONS {
+            assert_eq!(
+                get_language_from_extension(&format!("file.{}", ext)),
+                Some(*language),
+                "extension {ext}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_flag_empty_counts_empty_and_whitespace_only_files() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "empty.rs", "")?;
+        create_test_file(temp_dir.path(), "blank.py", "\n   \n\t\n")?;
+        create_test_file(temp_dir.path(), "comment.py", "\n# only a comment\n")?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        let args = Args {
+            flag_empty: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 4);
+        assert_eq!((metrics.empty_files, metrics.whitespace_only_files), (1, 1));
+        assert_eq!(
+            format_empty_files(metrics.empty_files, metrics.whitespace_only_files),
+            "\nEmpty files: 1\nWhitespace-only files: 1\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_decorations_are_ascii() -> io::Result<()> {
+        control::set_override(false);
+        let root = PathBuf::from("/repo");
+        let report = |dir: &str, args: &Args| {
+            let mut dir_stats = DirectoryStats::default();
+            dir_stats.language_stats.insert(
+                "Rust".to_string(),
+                language_entry(
+                    1,
+                    LanguageStats {
+                        code_lines: 3,
+                        comment_lines: 1,
+                        ..Default::default()
+                    },
+                ),
+            );
+            let result = AnalysisResult {
+                root_dir: root.clone(),
+                display_dir: root.clone(),
+                stats: HashMap::from([(root.join(dir), dir_stats)]),
+                files_processed: 1,
+                lines_processed: 4,
+                error_count: 0,
+                role_counters: Some([(1, 4, 3), (0, 0, 0), (0, 0, 0)]),
+                options: ReportOptions::from_args(args),
+            };
+            render_report(&result, OutputFormat::Text)
+        };
+        let args = Args {
+            role_breakdown: true,
+            ..test_args()
+        };
+        // Rules are plain `-`, so ASCII paths give a pure-ASCII report.
+        let plain = report("src", &args)?;
+        assert!(plain.is_ascii(), "{plain}");
+
+        // Paths and labels are printed as they are.
+        let labelled = Args {
+            root_label: Some("Проект".to_string()),
+            ..args
+        };
+        let named = report("données", &labelled)?;
+        assert!(named.contains("données") && named.contains("Проект"), "{named}");
+        assert!(!named.is_ascii(), "{named}");
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let mut metrics = PerformanceMetrics::with_writer(Box::new(CaptureWriter::new(buffer.clone())), true);
+        metrics.print_progress();
+        metrics.print_final_stats();
+        let progress = CaptureWriter::into_string(buffer);
+        assert!(progress.contains("lines/sec)..."), "{progress}");
+        assert!(progress.is_ascii(), "{progress}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefer_shebang_overrides_extension_language() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "tool.txt",
+            "#!/usr/bin/env -S python3 -u\n# helper\nprint('hi')\n",
+        )?;
+        create_test_file(temp_dir.path(), "run.pl", "#!/bin/bash\n# wrapper\necho hi\n")?;
+        create_test_file(temp_dir.path(), "plain.txt", "just text\n")?;
+        assert_eq!(
+            shebang_language(&temp_dir.path().join("tool.txt")),
+            Some(("Python", "py"))
+        );
+        assert_eq!(shebang_language(&temp_dir.path().join("plain.txt")), None);
+
+        let scan = |args: &Args| -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+            let mut entries = 0;
+            let mut errors = 0;
+            scan_directory(
+                temp_dir.path(),
+                args,
+                temp_dir.path(),
+                &mut test_metrics(),
+                0,
+                &mut entries,
+                &mut errors,
+            )
+        };
+        let root = fs::canonicalize(temp_dir.path())?;
+
+        let stats = scan(&test_args())?;
+        let languages: Vec<&String> = stats[&root].language_stats.keys().collect();
+        assert_eq!(languages, vec!["Perl"], "the .txt file is skipped without the flag");
+
+        let args = Args {
+            prefer_shebang: true,
+            ..test_args()
+        };
+        let stats = scan(&args)?;
+        let mut languages: Vec<&String> = stats[&root].language_stats.keys().collect();
+        languages.sort();
+        assert_eq!(languages, vec!["Python", "Shell"]);
+        let (files, python) = stats[&root].language_stats["Python"].summary();
+        assert_eq!((files, python.code_lines, python.comment_lines), (1, 1, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_language_leaderboard_lists_found_languages_by_code() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("src"))?;
+        create_test_file(temp_dir.path(), "src/main.rs", "fn a() {}\nfn b() {}\n")?;
+        create_test_file(temp_dir.path(), "src/lib.rs", "fn c() {}\n")?;
+        create_test_file(temp_dir.path(), "tool.py", "x = 1\n# c\n\ny = 2\nz = 3\n")?;
+        create_test_file(temp_dir.path(), "a.sh", "echo 1\necho 2\n")?;
+        create_test_file(temp_dir.path(), "b.go", "package b\nfunc B() {}\n")?;
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(
+            format_language_leaderboard(&stats),
+            "Python                          3\nRust                            3\n\
+             Go                              2\nShell                           2\n"
+        );
+        assert!(Args::try_parse_from(["mdkloc", ".", "--languages-with-counts", "--totals"])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_too_long_canonicalize_failure_still_counts_file() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let deep = temp_dir.path().join("gen");
+        fs::create_dir(&deep)?;
+        create_test_file(&deep, super::CANONICALIZE_FAIL_TAG, "fn deep() {}\n")?;
+        create_test_file(&deep, "ok.rs", "fn ok() {}\n")?;
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        assert_eq!(errors, 0);
+        assert_eq!(metrics.files_processed.load(Ordering::Relaxed), 2);
+        let root = fs::canonicalize(temp_dir.path())?;
+        let (files, rust) = stats[&root.join("gen")].language_stats["Rust"].summary();
+        assert_eq!((files, rust.code_lines), (2, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_feature_roles_attribute_feature_gated_scopes() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("lib.rs");
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "pub fn core() {}\n\n#[cfg(feature = \"serde\")]\nmod serde_impl {\n    \
+             // glue\n    pub fn ser() {}\n\n    #[test]\n    fn t() {}\n}\n\n\
+             #[cfg(feature = \"cli\")]\npub use cli::run;\n",
+        )?;
+        let split = count_rust_lines_role_aware(&mut lines_of(&path), FileRoleHint::Unknown, TestScope::Cfg, true)?;
+        let gated = split.bucket(CodeRole::FeatureGated).expect("feature bucket").stats;
+        assert_eq!(
+            (gated.code_lines, gated.comment_lines, gated.blank_lines),
+            (6, 1, 1),
+            "{gated:?}"
+        );
+        let test = split.bucket(CodeRole::Test).expect("test bucket").stats;
+        assert_eq!(test.code_lines, 2);
+        let main = split.bucket(CodeRole::Mainline).expect("main bucket").stats;
+        assert_eq!((main.code_lines, main.blank_lines), (1, 2));
+        assert_eq!(split.total_lines(), 13);
+
+        let plain = count_rust_lines_role_aware(&mut lines_of(&path), FileRoleHint::Unknown, TestScope::Cfg, false)?;
+        assert!(plain.bucket(CodeRole::FeatureGated).is_none());
+        assert_eq!(plain.bucket(CodeRole::Mainline).unwrap().stats.code_lines, 7);
+
+        // Only a `feature = ...` key that is not negated gates on a Cargo feature.
+        let lines: Vec<String> = [
+            "#[cfg(target_feature = \"avx2\")]",
+            "fn fast() {}",
+            "#[cfg(not(feature = \"std\"))]",
+            "fn no_std_path() {}",
+            "#[cfg(all(unix, not(test), feature = \"cli\"))]",
+            "fn gated() {}",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+        let roles = detect_rust_line_roles(&lines, FileRoleHint::Unknown, TestScope::Cfg, true);
+        assert_eq!(roles[1], CodeRole::Mainline);
+        assert_eq!(roles[3], CodeRole::Mainline);
+        assert_eq!(roles[5], CodeRole::FeatureGated);
+        assert!(!attribute_indicates_feature("#[cfg(any(not(feature = \"a\")))]"));
+        assert!(attribute_indicates_feature("#[cfg(any(not(unix), feature = \"a\"))]"));
+
+        let counters = [(1, 6, 1), (1, 2, 2), (1, 8, 6)];
+        let share = format_role_share(&counters);
+        assert!(share.contains("Feature:    66.7% (6)"), "{share}");
+        assert!(!format_role_share(&[(1, 3, 3), (0, 0, 0), (0, 0, 0)]).contains("Feature"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sniff_content_guesses_extensionless_scripts() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "build_docs",
+            "import os\nfrom pathlib import Path\n\n# entry point\ndef main():\n    \
+             print(Path.cwd())\n\nif __name__ == \"__main__\":\n    main()\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "deploy",
+            "set -eu\nexport TARGET=prod\n# push it\nif [ -n \"$1\" ]; then\n  \
+             echo \"$1\"\nfi\nfor f in *.tar; do\n  scp \"$f\" host:\ndone\n",
+        )?;
+        create_test_file(temp_dir.path(), "NOTES", "import this later\nfi?\ndone.\n")?;
+        assert_eq!(
+            sniff_language(&temp_dir.path().join("build_docs")),
+            Some(("Python", "py"))
+        );
+        assert_eq!(
+            sniff_language(&temp_dir.path().join("deploy")),
+            Some(("Shell", "sh"))
+        );
+        assert_eq!(sniff_language(&temp_dir.path().join("NOTES")), None);
+
+        let scan = |args: &Args| -> io::Result<HashMap<PathBuf, DirectoryStats>> {
+            let mut entries = 0;
+            let mut errors = 0;
+            scan_directory(
+                temp_dir.path(),
+                args,
+                temp_dir.path(),
+                &mut test_metrics(),
+                0,
+                &mut entries,
+                &mut errors,
+            )
+        };
+        let root = fs::canonicalize(temp_dir.path())?;
+        assert!(scan(&test_args())?.get(&root).is_none_or(|dir| dir.language_stats.is_empty()));
+
+        let args = Args {
+            sniff_content: true,
+            ..test_args()
+        };
+        let stats = scan(&args)?;
+        let mut languages: Vec<&String> = stats[&root].language_stats.keys().collect();
+        languages.sort();
+        assert_eq!(languages, vec!["Python", "Shell"]);
+        let (files, python) = stats[&root].language_stats["Python"].summary();
+        assert_eq!((files, python.code_lines, python.comment_lines), (1, 6, 1));
+        let (files, shell) = stats[&root].language_stats["Shell"].summary();
+        assert_eq!((files, shell.code_lines, shell.comment_lines), (1, 8, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_report_quotes_awkward_directory_names() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("a,\"b\""))?;
+        fs::create_dir(temp_dir.path().join("semi;colon"))?;
+        create_test_file(temp_dir.path(), "a,\"b\"/lib.rs", "// doc\nfn a() {}\n\n")?;
+        create_test_file(temp_dir.path(), "semi;colon/run.py", "x = 1\n")?;
+        let args = Args {
+            format: OutputFormat::Csv,
+            ..test_args()
+        };
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let options = ReportOptions::from_args(&args);
+        assert_eq!(
+            build_csv_report(&root, &stats, &options),
+            "directory,language,files,code,comments,blank,mixed,ignored\r\n\
+             \"a,\"\"b\"\"\",Rust,1,1,1,1,0,0\r\n\
+             semi;colon,Python,1,1,0,0,0,0\r\n"
+        );
+
+        let args = Args::parse_from(["mdkloc", "--format", "csv", "--csv-delimiter", ";"]);
+        let csv = build_csv_report(&root, &stats, &ReportOptions::from_args(&args));
+        assert!(csv.contains("\r\n\"a,\"\"b\"\"\";Rust;"), "{csv}");
+        assert!(csv.contains("\r\n\"semi;colon\";Python;"), "{csv}");
+        assert_eq!(parse_csv_delimiter("\\t"), Ok('\t'));
+        assert!(Args::try_parse_from(["mdkloc", "--csv-delimiter", "\""]).is_err());
+        assert!(Args::try_parse_from(["mdkloc", "--csv-delimiter", ",,"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_types_split_line_and_block_comments() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        let c_path = temp_dir.path().join("mix.c");
+        create_test_file(
+            temp_dir.path(),
+            "mix.c",
+            "// header\n/* banner\n * more\n */\nint x; // trailing\n\
+             int y; /* inline */\n/* a */ // b\nint z;\n",
+        )?;
+        let (stats, _) = count_c_style_lines(&mut lines_of(&c_path))?;
+        assert_eq!(stats.comment_lines, 7);
+        assert_eq!((stats.line_comment_lines, stats.block_comment_lines), (2, 5));
+
+        let rs_path = temp_dir.path().join("mix.rs");
+        create_test_file(
+            temp_dir.path(),
+            "mix.rs",
+            "/// doc\n/* block\n   body */\nfn a() {} // tail\n",
+        )?;
+        let (stats, _) = count_rust_lines(&mut lines_of(&rs_path))?;
+        assert_eq!((stats.line_comment_lines, stats.block_comment_lines), (2, 2));
+        let split = count_rust_lines_role_aware(&mut lines_of(&rs_path), FileRoleHint::Unknown, TestScope::Cfg, false)?;
+        let main = split.bucket(CodeRole::Mainline).unwrap().stats;
+        assert_eq!((main.line_comment_lines, main.block_comment_lines), (2, 2));
+
+        let ps_path = temp_dir.path().join("mix.ps1");
+        create_test_file(temp_dir.path(), "mix.ps1", "# note\n<#\nhelp\n#>\nWrite-Host 1\n")?;
+        let (stats, _) = count_powershell_lines(&mut lines_of(&ps_path))?;
+        assert_eq!((stats.line_comment_lines, stats.block_comment_lines), (1, 3));
+
+        let mut dir_stats = DirectoryStats::default();
+        dir_stats.language_stats.insert(
+            "C/C++".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    comment_lines: 7,
+                    line_comment_lines: 2,
+                    block_comment_lines: 5,
+                    ..Default::default()
+                },
+            ),
+        );
+        let mut total = LanguageStats::default();
+        total.add_assign(&dir_stats.language_stats["C/C++"].summary().1);
+        assert_eq!((total.line_comment_lines, total.block_comment_lines), (2, 5));
+        let stats_map = HashMap::from([(temp_dir.path().to_path_buf(), dir_stats)]);
+        let table = format_comment_types(totals_by_language(&stats_map));
+        assert!(table.contains("Comment lines by type:"), "{table}");
+        assert!(
+            table.contains(&format!("{:<20} {:>12} {:>12} {:>6.1}%", "C/C++", "2", "5", 71.4)),
+            "{table}"
+        );
+        assert!(format_comment_types(HashMap::new()).contains("No typed comments found."));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_roles_prefixes_each_line_with_its_role() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("lib.rs");
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "fn main() {}\n#[cfg(feature = \"x\")]\nfn gated() {}\n\n#[cfg(test)]\nmod tests {\n    \
+             #[test]\n    fn t() {}\n}\n",
+        )?;
+        assert_eq!(
+            format_role_dump(&path, FileRoleHint::Unknown, TestScope::Cfg, true)?,
+            "    1 Mainline | fn main() {}\n\
+             \x20   2 Feature  | #[cfg(feature = \"x\")]\n\
+             \x20   3 Feature  | fn gated() {}\n\
+             \x20   4 Mainline | \n\
+             \x20   5 Test     | #[cfg(test)]\n\
+             \x20   6 Test     | mod tests {\n\
+             \x20   7 Test     |     #[test]\n\
+             \x20   8 Test     |     fn t() {}\n\
+             \x20   9 Test     | }\n"
+        );
+        let plain = format_role_dump(&path, FileRoleHint::Unknown, TestScope::Cfg, false)?;
+        assert!(plain.contains("    3 Mainline | fn gated() {}"), "{plain}");
+        let args = Args::parse_from(["mdkloc", "--dump-roles", "lib.rs"]);
+        assert_eq!(args.dump_roles.as_deref(), Some(Path::new("lib.rs")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dotenv_and_ini_comment_markers() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        for name in [".env", ".env.local", "app.env", ".ENV.Production"] {
+            assert_eq!(get_language_from_extension(name), Some("Dotenv"), "{name}");
+        }
+        assert_eq!(get_language_from_extension(".editorconfig"), Some("INI"));
+        assert_eq!(get_language_from_extension(".envrc"), None);
+
+        create_test_file(
+            temp_dir.path(),
+            ".env.local",
+            "# database\nDB_HOST=localhost\n;not-a-comment=1\n\nexport COLOR=#fff # brand\n",
+        )?;
+        let (env, total) = count_lines_with_stats(&temp_dir.path().join(".env.local"))?;
+        assert_eq!(total, 5);
+        assert_eq!(
+            (env.code_lines, env.comment_lines, env.blank_lines, env.overlap_lines),
+            (3, 1, 1, 0),
+            "{env:?}"
+        );
+
+        create_test_file(
+            temp_dir.path(),
+            "hash.ini",
+            "# a\n[core]\nurl = http://host/#top\nname = x # kept in the value\n",
+        )?;
+        create_test_file(temp_dir.path(), "semi.ini", "; a\n; b\n[x]\nk=v ; inline\n")?;
+        create_test_file(temp_dir.path(), ".editorconfig", "root = true\n# all\n[*]\n")?;
+        let cases = [("hash.ini", (3, 1)), ("semi.ini", (2, 2)), (".editorconfig", (2, 1))];
+        for (name, expected) in cases {
+            let (stats, _) = count_lines_with_stats(&temp_dir.path().join(name))?;
+            assert_eq!((stats.code_lines, stats.comment_lines), expected, "{name}: {stats:?}");
+            assert_eq!(stats.overlap_lines, 0, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_by_extension_separates_headers_from_sources() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "a.cpp", "int a() {\n  return 1;\n}\n")?;
+        create_test_file(temp_dir.path(), "a.h", "// decl\nint a();\n")?;
+        create_test_file(temp_dir.path(), "B.H", "int b();\n")?;
+        create_test_file(temp_dir.path(), "Makefile", "all:\n\ttrue\n")?;
+        let args = Args {
+            group_by_extension: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut metrics,
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let key = |ext: &str, lang: &str| (ext.to_string(), lang.to_string());
+        let totals = &metrics.extension_totals;
+        assert_eq!(totals.len(), 3, "{totals:?}");
+        let (files, header) = totals[&key(".h", "C/C++")];
+        assert_eq!((files, header.code_lines, header.comment_lines), (2, 2, 1));
+        let (files, source) = totals[&key(".cpp", "C/C++")];
+        assert_eq!((files, source.code_lines), (1, 3));
+        assert_eq!(totals[&key("(none)", "Makefile")].0, 1);
+
+        let table = format_extension_totals(totals);
+        let rows: Vec<&str> = table.lines().skip(3).collect();
+        assert_eq!(
+            rows,
+            vec![
+                format!("  {:<12} {:<16} {:>8} {:>12} {:>12} {:>12}", ".cpp", "C/C++", 1, 3, 0, 0),
+                format!("  {:<12} {:<16} {:>8} {:>12} {:>12} {:>12}", ".h", "C/C++", 2, 2, 1, 0),
+                format!("  {:<12} {:<16} {:>8} {:>12} {:>12} {:>12}", "(none)", "Makefile", 1, 2, 0, 0),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf8_bom_is_stripped_before_the_first_line() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let script = temp_dir.path().join("deploy");
+        fs::write(&script, b"\xEF\xBB\xBF#!/bin/sh\n# setup\necho hi\n")?;
+        let mut lines = read_file_lines_lossy(&script)?;
+        assert_eq!(lines.next().transpose()?.as_deref(), Some("#!/bin/sh"));
+        assert_eq!(shebang_language(&script), Some(("Shell", "sh")));
+
+        let sh = temp_dir.path().join("bom.sh");
+        fs::write(&sh, b"\xEF\xBB\xBF# leading comment\necho \xEF\xBB\xBF\n")?;
+        let (stats, _) = count_shell_lines(&mut lines_of(&sh))?;
+        assert_eq!((stats.code_lines, stats.comment_lines), (1, 1), "{stats:?}");
+
+        let fortran = temp_dir.path().join("old.f");
+        fs::write(&fortran, b"\xEF\xBB\xBFC     FIXED-FORM COMMENT\n      PRINT *, 1\n")?;
+        let (stats, _) = count_fortran_lines(&mut lines_of(&fortran))?;
+        assert_eq!((stats.code_lines, stats.comment_lines), (1, 1), "{stats:?}");
+
+        let args = Args {
+            sniff_content: true,
+            ..test_args()
+        };
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let (files, shell) = stats[&root].language_stats["Shell"].summary();
+        assert_eq!((files, shell.code_lines, shell.comment_lines), (2, 3, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_width_fits_tables_to_a_narrow_width() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        let base = temp_dir.path();
+        let mut dir_stats = DirectoryStats::default();
+        dir_stats.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(
+                1,
+                LanguageStats {
+                    code_lines: 12,
+                    comment_lines: 3,
+                    blank_lines: 2,
+                    ..Default::default()
+                },
+            ),
+        );
+        let stats_map = HashMap::from([(base.join("a/very/deeply/nested/module"), dir_stats)]);
+        let args = Args::parse_from(["mdkloc", "--report-width", "80"]);
+        assert_eq!(args.report_width, Some(ReportWidth::Columns(80)));
+        let options = ReportOptions::from_args(&args);
+        assert_eq!((options.layout.dir_width, options.layout.lang_width), (16, 10));
+        assert_eq!(options.layout.rule_width(), 80);
+
+        let report = build_analysis_report(base, &stats_map, 1, 17, 0, &options);
+        let rule = "-".repeat(80);
+        assert!(report.lines().any(|line| line == rule), "{report}");
+        let header = report
+            .lines()
+            .find(|line| line.starts_with("Directory"))
+            .expect("header row");
+        assert_eq!(header.len(), 80, "{header:?}");
+        for line in report.lines().filter(|line| line.contains("Rust")) {
+            assert!(line.chars().count() <= 80, "row wider than 80: {line:?}");
+        }
+
+        let doc = TableLayout {
+            doc_comments: true,
+            ..TableLayout::default()
+        }
+        .fitting(100);
+        assert_eq!((doc.dir_width, doc.lang_width, doc.rule_width()), (24, 11, 100));
+        let tight = TableLayout::default().fitting(40);
+        assert_eq!((tight.dir_width, tight.lang_width), (MIN_DIR_WIDTH, MIN_LANG_WIDTH));
+        assert_eq!(parse_report_width("AUTO"), Ok(ReportWidth::Auto));
+        assert!(parse_report_width("wide").is_err());
+        assert!(
+            Args::try_parse_from(["mdkloc", "--report-width", "80", "--dir-width", "30"]).is_err()
+        );
+
+        // Widths the count columns cannot fit are refused up front.
+        for width in ["0", "10", "42"] {
+            assert!(Args::try_parse_from(["mdkloc", "--report-width", width]).is_err(), "{width}");
+        }
+        assert_eq!(parse_report_width("43"), Ok(ReportWidth::Columns(43)));
+
+        // Subtotal rows line up even at the narrowest language column.
+        let mut mixed = DirectoryStats::default();
+        for language in ["Rust", "Python"] {
+            mixed.language_stats.insert(
+                language.to_string(),
+                language_entry(1, LanguageStats { code_lines: 4, ..Default::default() }),
+            );
+        }
+        let stats_map = HashMap::from([(base.join("pkg"), mixed)]);
+        let options = ReportOptions::from_args(&Args::parse_from(["mdkloc", "--report-width", "43"]));
+        assert_eq!(options.layout.lang_width, SUBTOTAL_LABEL.len());
+        let report = build_analysis_report(base, &stats_map, 2, 8, 0, &options);
+        let subtotal = report
+            .lines()
+            .find(|line| line.contains(SUBTOTAL_LABEL))
+            .expect("subtotal row");
+        let rust = report.lines().find(|line| line.contains("Rust")).expect("Rust row");
+        assert_eq!(subtotal.chars().count(), rust.chars().count(), "{report}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_gradle_scripts_count_as_groovy_and_kotlin() -> io::Result<()> {
+        assert_eq!(get_language_from_extension("build.gradle"), Some("Groovy"));
+        assert_eq!(get_language_from_extension("Util.groovy"), Some("Groovy"));
+        assert_eq!(get_language_from_extension("build.gradle.kts"), Some("Kotlin"));
+        assert_eq!(get_language_from_extension("settings.GRADLE.KTS"), Some("Kotlin"));
+        assert_eq!(get_language_from_extension("Main.kt"), Some("Kotlin"));
+        assert_eq!(get_language_from_extension(".gradle.kts"), Some("Kotlin"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "build.gradle",
+            "// root build\nplugins {\n    id 'java' /* core */\n}\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "build.gradle.kts",
+            "/*\n * Kotlin DSL\n */\nplugins { java }\n\n",
+        )?;
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let (files, groovy) = stats[&root].language_stats["Groovy"].summary();
+        assert_eq!((files, groovy.code_lines, groovy.comment_lines), (1, 3, 2));
+        let (files, kotlin) = stats[&root].language_stats["Kotlin"].summary();
+        assert_eq!(
+            (files, kotlin.code_lines, kotlin.comment_lines, kotlin.blank_lines),
+            (1, 1, 3, 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_mixed_folds_mixed_lines_into_code() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "// header\nfn a() {} // tail\nfn b() {} /* note */\n\nfn c() {}\n",
+        )?;
+        let scan = |args: &Args| -> io::Result<LanguageStats> {
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(
+                temp_dir.path(),
+                args,
+                temp_dir.path(),
+                &mut test_metrics(),
+                0,
+                &mut entries,
+                &mut errors,
+            )?;
+            let root = fs::canonicalize(temp_dir.path())?;
+            Ok(stats[&root].language_stats["Rust"].summary().1)
+        };
+        let total = |s: &LanguageStats| s.code_lines + s.comment_lines + s.blank_lines - s.overlap_lines;
+
+        let mixed = scan(&test_args())?;
+        assert_eq!(
+            (mixed.code_lines, mixed.comment_lines, mixed.overlap_lines, mixed.blank_lines),
+            (3, 3, 2, 1)
+        );
+        let args = Args {
+            no_mixed: true,
+            ..test_args()
+        };
+        let folded = scan(&args)?;
+        assert_eq!(
+            (folded.code_lines, folded.comment_lines, folded.overlap_lines, folded.blank_lines),
+            (3, 1, 0, 1)
+        );
+        assert_eq!(total(&mixed), 5);
+        assert_eq!(total(&folded), 5);
+
+        let options = ReportOptions::from_args(&Args::parse_from(["mdkloc", "--no-mixed"]));
+        assert!(!options.layout.mixed_column);
+        assert_eq!(options.layout.rule_width(), TableLayout::default().rule_width() - 11);
+        let mut dir_stats = DirectoryStats::default();
+        dir_stats
+            .language_stats
+            .insert("Rust".to_string(), language_entry(1, folded));
+        let stats_map = HashMap::from([(temp_dir.path().to_path_buf(), dir_stats)]);
+        let report = build_analysis_report(temp_dir.path(), &stats_map, 1, 5, 0, &options);
+        assert!(!report.contains("Mixed"), "{report}");
+        let header = report.lines().find(|l| l.starts_with("Directory")).unwrap();
+        assert!(header.ends_with("Comments      Blank"), "{header:?}");
+        assert!(build_analysis_report(
+            temp_dir.path(),
+            &stats_map,
+            1,
+            5,
+            0,
+            &ReportOptions::from_args(&test_args())
+        )
+        .contains("Mixed lines:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jinja2_and_erb_comment_tags() -> io::Result<()> {
+        assert_eq!(get_language_from_extension("nginx.conf.j2"), Some("Jinja2"));
+        assert_eq!(get_language_from_extension("base.JINJA2"), Some("Jinja2"));
+        assert_eq!(get_language_from_extension("show.html.erb"), Some("ERB"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "site.j2",
+            "{# Rendered by ansible.\n   Do not edit. #}\nserver {\n  \
+             listen {{ port }}; {# http #}\n{# a #}{# b #}\n\n}\n",
+        )?;
+        let (jinja, total) = count_lines_with_stats(&temp_dir.path().join("site.j2"))?;
+        assert_eq!(total, 7);
+        assert_eq!(
+            (jinja.code_lines, jinja.comment_lines, jinja.overlap_lines, jinja.blank_lines),
+            (3, 4, 1, 1),
+            "{jinja:?}"
+        );
+
+        create_test_file(
+            temp_dir.path(),
+            "show.html.erb",
+            "<%# header partial %>\n<h1><%= @title %></h1>\n<%#\n  multi\n%>\n<% if x %>ok<% end %>\n",
+        )?;
+        let (erb, total) = count_lines_with_stats(&temp_dir.path().join("show.html.erb"))?;
+        assert_eq!(total, 6);
+        assert_eq!((erb.code_lines, erb.comment_lines, erb.overlap_lines), (2, 4, 0), "{erb:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_result_language_totals_support_share_assertions() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::create_dir(temp_dir.path().join("src"))?;
+        fs::create_dir(temp_dir.path().join("tests"))?;
+        create_test_file(&temp_dir.path().join("src"), "lib.rs", "fn a() {}\nfn b() {}\n")?;
+        create_test_file(&temp_dir.path().join("tests"), "it.rs", "// check\nfn t() {}\n")?;
+        create_test_file(temp_dir.path(), "build.py", "x = 1\n")?;
+
+        let args = test_args();
+        let root = fs::canonicalize(temp_dir.path())?;
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(&root, &args, &root, &mut metrics, 0, &mut entries, &mut errors)?;
+        let result = AnalysisResult {
+            root_dir: root.clone(),
+            display_dir: root,
+            stats,
+            files_processed: 3,
+            lines_processed: 5,
+            error_count: 0,
+            role_counters: None,
+            options: ReportOptions::from_args(&args),
+        };
+
+        let totals = result.language_totals();
+        let (rust_files, rust) = &totals["Rust"];
+        assert_eq!((*rust_files, rust.code_lines, rust.comment_lines), (2, 3, 1));
+        let all_code: u64 = totals.values().map(|(_, lang)| lang.code_lines).sum();
+        assert!(rust.code_lines * 100 >= all_code * 30, "Rust is at least 30% of code");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mid_file_read_failure_keeps_partial_stats() -> io::Result<()> {
+        struct FailingRead;
+
+        impl Read for FailingRead {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("network hiccup"))
+            }
+        }
+
+        let path = PathBuf::from("remote/big.rs");
+        let reader: Box<dyn Read + Send> =
+            Box::new(io::Cursor::new(b"// header\nfn a() {}\n\nfn b() {}\n".to_vec()).chain(FailingRead));
+        let mut lines = LossyLineReader::from_reader(reader);
+        let (stats, total) = count_source_lines("big.rs", &mut lines)?;
+        assert_eq!(total, 4);
+        assert_eq!(
+            (stats.code_lines, stats.comment_lines, stats.blank_lines),
+            (2, 1, 1),
+            "lines before the failure are kept"
+        );
+        let error = lines.take_error().expect("the failure is recorded");
+        assert_eq!(error.to_string(), "network hiccup");
+
+        // Callers without a partial-read report fail instead of stopping quietly.
+        let reader: Box<dyn Read + Send> = Box::new(io::Cursor::new(b"fn a() {}\n".to_vec()).chain(FailingRead));
+        let stdin = count_lines_with_stats_from_reader("rust", reader, &test_args());
+        assert_eq!(stdin.unwrap_err().to_string(), "network hiccup");
+
+        let summary = format_partial_reads(&[(path, error.to_string())]);
+        assert_eq!(
+            summary,
+            "\nPartial reads (counted up to the failure): 1 file(s)\n  remote/big.rs: network hiccup\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_blank_runs_track_longest_gap() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "airy.rs",
+            "fn a() {}\n\nfn b() {}\n\n\n  \nfn c() {}\n\n",
+        )?;
+        create_test_file(temp_dir.path(), "dense.rs", "fn d() {}\n")?;
+        let mut lines = lines_of(&temp_dir.path().join("airy.rs")).recording();
+        count_source_lines("airy.rs", &mut lines)?;
+        assert_eq!(blank_line_runs(&lines.take_records()), (3, 3));
+        // Blank lines the counter ignores are not a run.
+        create_test_file(
+            temp_dir.path(),
+            "quiet.c",
+            "int a;\n// mdkloc:ignore-start\n\n\n\n\n// mdkloc:ignore-end\nint b;\n",
+        )?;
+
+        let args = Args {
+            blank_runs: true,
+            ..test_args()
+        };
+        let mut metrics = test_metrics();
+        let mut entries = 0;
+        let mut errors = 0;
+        scan_directory(temp_dir.path(), &args, temp_dir.path(), &mut metrics, 0, &mut entries, &mut errors)?;
+        assert_eq!(metrics.blank_runs, vec![(PathBuf::from("airy.rs"), 3, 3)]);
+        assert_eq!(
+            format_blank_runs(&metrics.blank_runs),
+            "\nBlank-line runs: 3 in 1 file(s)\n  longest      3  runs        3  airy.rs\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_records_agree_with_counted_stats() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let samples = [
+            ("lib.rs", "//! crate\nfn a() {} // tail\n\n/*\n*/\n#[cfg(test)]\nmod t {\n    fn b() {}\n}\n"),
+            ("x.py", "\"\"\"doc\n\"\"\"\nx = 1  # c\n\n# only\n"),
+            ("y.c", "int x;\n/* a\n b */ int y;\n\n// mdkloc:ignore-start\nint z;\n// mdkloc:ignore-end\n"),
+            ("q.sql", "-- c\nSELECT 1;\n\n"),
+        ];
+        for (name, contents) in samples {
+            create_test_file(temp_dir.path(), name, contents)?;
+            let mut lines = lines_of(&temp_dir.path().join(name)).recording();
+            let split = count_source_with_roles(name, &mut lines, FileRoleHint::Unknown, &test_args())?;
+            let records = lines.take_records();
+            assert_eq!(records.len() as u64, split.total_lines(), "{name}");
+            for (role, bucket) in split.iter() {
+                let mine: Vec<_> = records.iter().filter(|r| r.role == Some(role)).collect();
+                let stats = bucket.stats;
+                assert_eq!(mine.iter().filter(|r| r.code).count() as u64, stats.code_lines, "{name} {role:?}");
+                assert_eq!(mine.iter().filter(|r| r.comment).count() as u64, stats.comment_lines, "{name} {role:?}");
+                assert_eq!(mine.iter().filter(|r| r.is_blank()).count() as u64, stats.blank_lines, "{name} {role:?}");
+                assert_eq!(mine.iter().filter(|r| r.ignored).count() as u64, stats.ignored_lines, "{name} {role:?}");
+            }
+        }
+
+        // Fenced code is counted at the closing fence; its records land on
+        // the fenced lines.
+        create_test_file(temp_dir.path(), "doc.md", "# T\n```rust\n// c\nfn a() {}\n\n```\n")?;
+        let mut lines = lines_of(&temp_dir.path().join("doc.md")).recording();
+        count_markdown_with_fences(&mut lines, &Args { markdown_fences: true, ..test_args() })?;
+        let kinds: Vec<(bool, bool, bool)> =
+            lines.take_records().iter().map(|r| (r.code, r.comment, r.is_blank())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (true, false, false),
+                (true, false, false),
+                (false, true, false),
+                (true, false, false),
+                (false, false, true),
+                (true, false, false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_region_markers_tracked_and_excluded() -> io::Result<()> {
+        assert!(is_region_marker("#region Properties"));
+        assert!(is_region_marker("# endregion"));
+        assert!(!is_region_marker("#regionless"));
+        assert!(!is_region_marker("#if DEBUG"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "Widget.cs",
+            "class Widget {\n    #region Fields\n    int size; // px\n    #endregion\n}\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "build.ps1",
+            "#region Setup\n$x = 1 # one\n#endregion\n",
+        )?;
+        let (cs, _) = count_lines_with_stats(&temp_dir.path().join("Widget.cs"))?;
+        assert_eq!((cs.code_lines, cs.comment_lines, cs.region_marker_lines), (5, 1, 2));
+        let (ps, _) = count_lines_with_stats(&temp_dir.path().join("build.ps1"))?;
+        assert_eq!((ps.code_lines, ps.comment_lines, ps.region_marker_lines), (1, 3, 2));
+
+        let cs = cs.exclude_region_markers(false);
+        assert_eq!((cs.code_lines, cs.comment_lines, cs.ignored_lines), (3, 1, 2));
+        let ps = ps.exclude_region_markers(true);
+        assert_eq!((ps.code_lines, ps.comment_lines, ps.ignored_lines), (1, 1, 2));
+
+        let args = Args {
+            exclude_regions: true,
+            ..test_args()
+        };
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &args,
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let (_, csharp) = stats[&root].language_stats["C#"].summary();
+        assert_eq!((csharp.code_lines, csharp.ignored_lines), (3, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_submodules_skipped_unless_following_gitlinks() -> io::Result<()> {
+        assert_eq!(
+            parse_gitmodule_paths("[submodule \"lib\"]\n\tpath = vendor/lib\n\turl = ../lib.git\n"),
+            vec!["vendor/lib"]
+        );
+
+        let temp_dir = TempDir::new()?;
+        let checkout = temp_dir.path().join("ext");
+        let listed = temp_dir.path().join("vendor").join("lib");
+        fs::create_dir(&checkout)?;
+        fs::create_dir_all(&listed)?;
+        create_test_file(temp_dir.path(), "main.rs", "fn main() {}\n")?;
+        create_test_file(&checkout, ".git", "gitdir: ../.git/modules/ext\n")?;
+        create_test_file(&checkout, "ext.rs", "fn ext() {}\n")?;
+        create_test_file(&listed, "lib.rs", "fn lib() {}\n")?;
+        create_test_file(temp_dir.path(), ".gitmodules", "[submodule \"lib\"]\n  path = vendor/lib\n")?;
+
+        let rust_files = |args: &Args| -> io::Result<(u64, u64)> {
+            let mut metrics = test_metrics();
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(
+                temp_dir.path(),
+                args,
+                temp_dir.path(),
+                &mut metrics,
+                0,
+                &mut entries,
+                &mut errors,
+            )?;
+            let files = totals_by_language(&stats)["Rust"].0;
+            Ok((files, metrics.entry_counts.submodules_skipped))
+        };
+        assert_eq!(rust_files(&test_args())?, (1, 2));
+        let follow = Args {
+            follow_gitlinks: true,
+            ..test_args()
+        };
+        assert_eq!(rust_files(&follow)?, (3, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tf_json_bucketed_as_hcl() -> io::Result<()> {
+        assert_eq!(get_language_from_extension("main.tf.json"), Some("HCL"));
+        assert_eq!(get_language_from_extension("Vars.TF.JSON"), Some("HCL"));
+        assert_eq!(get_language_from_extension(".tf.json"), Some("JSON"));
+        assert_eq!(get_language_from_extension("package.json"), Some("JSON"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "main.tf.json",
+            "{\n  \"resource\": {}\n}\n",
+        )?;
+        create_test_file(temp_dir.path(), "main.tf", "# vpc\nresource \"a\" \"b\" {}\n")?;
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(
+            temp_dir.path(),
+            &test_args(),
+            temp_dir.path(),
+            &mut test_metrics(),
+            0,
+            &mut entries,
+            &mut errors,
+        )?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let languages = &stats[&root].language_stats;
+        assert!(!languages.contains_key("JSON"), "{:?}", languages.keys());
+        let (files, hcl) = languages["HCL"].summary();
+        assert_eq!((files, hcl.code_lines, hcl.comment_lines), (2, 4, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reports_independent_of_merge_order() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        for dir in ["a", "b", "c/d"] {
+            let sub = temp_dir.path().join(dir);
+            fs::create_dir_all(&sub)?;
+            create_test_file(&sub, "lib.rs", "// doc\nfn f() {}\n")?;
+            create_test_file(&sub, "run.py", "print(1)\n\n")?;
+        }
+        let root = fs::canonicalize(temp_dir.path())?;
+        let sorted_dirs = || -> io::Result<Vec<(PathBuf, DirectoryStats)>> {
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats =
+                scan_directory(&root, &test_args(), &root, &mut test_metrics(), 0, &mut entries, &mut errors)?;
+            let mut dirs: Vec<_> = stats.into_iter().collect();
+            dirs.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Ok(dirs)
+        };
+
+        // Rebuild the map in opposite insertion orders, as worker results
+        // arriving in a different order would.
+        let forward: HashMap<_, _> = sorted_dirs()?.into_iter().collect();
+        let backward: HashMap<_, _> = sorted_dirs()?.into_iter().rev().collect();
+        let result = |stats| AnalysisResult {
+            root_dir: root.clone(),
+            display_dir: root.clone(),
+            stats,
+            files_processed: 6,
+            lines_processed: 9,
+            error_count: 0,
+            role_counters: None,
+            options: ReportOptions::from_args(&test_args()),
+        };
+        let (forward, backward) = (result(forward), result(backward));
+        for format in [OutputFormat::Text, OutputFormat::Json, OutputFormat::Csv] {
+            assert_eq!(
+                render_report(&forward, format)?,
+                render_report(&backward, format)?,
+                "{format:?} report depends on merge order"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_starlark_bazel_files() -> io::Result<()> {
+        assert_eq!(get_language_from_extension("BUILD"), Some("Starlark"));
+        assert_eq!(get_language_from_extension("WORKSPACE"), Some("Starlark"));
+        assert_eq!(get_language_from_extension("BUILD.bazel"), Some("Starlark"));
+        assert_eq!(get_language_from_extension("defs.bzl"), Some("Starlark"));
+        assert_eq!(get_language_from_extension("build"), None);
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "BUILD",
+            "# Library target\nload(\"//tools:defs.bzl\", \"lib\")\n\nlib(name = \"core\")  # main\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "defs.bzl",
+            "def lib(name):\n    # wraps cc_library\n    native.cc_library(name = name)\n",
+        )?;
+        let (build, total) = count_lines_with_stats(&temp_dir.path().join("BUILD"))?;
+        assert_eq!(total, 4);
+        assert_eq!(
+            (build.code_lines, build.comment_lines, build.blank_lines, build.overlap_lines),
+            (2, 1, 1, 0),
+            "a trailing `#` stays part of the code line"
+        );
+        let (bzl, _) = count_lines_with_stats(&temp_dir.path().join("defs.bzl"))?;
+        assert_eq!((bzl.code_lines, bzl.comment_lines), (2, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_single_language_directory_has_one_row() {
+        control::set_override(false);
+        let base = PathBuf::from("/repo");
+        let mut stats_map = HashMap::new();
+        let mut single = DirectoryStats::default();
+        single.language_stats.insert(
+            "Rust".to_string(),
+            language_entry(2, LanguageStats { code_lines: 10, ..Default::default() }),
+        );
+        stats_map.insert(base.join("lib"), single);
+        let mut mixed = DirectoryStats::default();
+        for lang in ["Python", "Shell"] {
+            mixed.language_stats.insert(
+                lang.to_string(),
+                language_entry(1, LanguageStats { code_lines: 3, ..Default::default() }),
+            );
+        }
+        stats_map.insert(base.join("tools"), mixed);
+
+        let options = ReportOptions::from_args(&test_args());
+        let report = build_analysis_report(&base, &stats_map, 4, 16, 0, &options);
+        let rows = |dir: &str| {
+            report
+                .lines()
+                .filter(|line| line.split_whitespace().next() == Some(dir))
+                .count()
+        };
+        assert_eq!(rows("lib"), 1, "no separate directory or subtotal row:\n{report}");
+        assert_eq!(rows("tools"), 3, "two languages plus a subtotal:\n{report}");
+    }
+
+    #[test]
+    fn test_verbatim_windows_prefix_stripped_for_display() {
+        assert_eq!(
+            strip_verbatim_prefix(Path::new(r"\\?\C:\work\repo")),
+            Path::new(r"C:\work\repo")
+        );
+        assert_eq!(
+            strip_verbatim_prefix(Path::new(r"\\?\UNC\server\share\repo")),
+            Path::new(r"\\server\share\repo")
+        );
+        assert_eq!(strip_verbatim_prefix(Path::new("/home/repo")), Path::new("/home/repo"));
+
+        let display = format_directory_display(Path::new(r"\\?\D:\elsewhere"), Path::new("/cwd"), 40);
+        assert_eq!(display, r"D:\elsewhere");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_verbatim_paths_relative_to_plain_current_dir() {
+        let display = format_directory_display(
+            Path::new(r"\\?\C:\work\repo\src"),
+            Path::new(r"C:\work\repo"),
+            40,
+        );
+        assert_eq!(display, "src");
+    }
+
+    #[test]
+    fn test_count_mode_logical_folds_multi_line_calls() -> io::Result<()> {
+        let mut groups = GroupDepth::default();
+        assert!(!groups.continues("let x = f(\")\", ')',"));
+        assert!(groups.continues("    b, // (not counted"));
+        assert!(groups.continues(");"));
+        assert!(!groups.continues("g();"));
+
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "call.rs",
+            "fn main() {\n    run(\n        1,\n        2);\n    let v = [\n        3, // three\n    ];\n}\n",
+        )?;
+        create_test_file(
+            temp_dir.path(),
+            "call.c",
+            "int main() {\n    printf(\"%d %d\",\n           a,\n           b);\n    return 0;\n}\n",
+        )?;
+        let (rust, _) = count_lines_with_stats(&temp_dir.path().join("call.rs"))?;
+        assert_eq!((rust.code_lines, rust.continuation_lines), (8, 3));
+        let (c, _) = count_lines_with_stats(&temp_dir.path().join("call.c"))?;
+        assert_eq!((c.code_lines, c.continuation_lines), (6, 2));
+
+        let scan = |mode: CountMode| -> io::Result<HashMap<String, (u64, LanguageStats)>> {
+            let args = Args {
+                count_mode: mode,
+                ..test_args()
+            };
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(
+                temp_dir.path(),
+                &args,
+                temp_dir.path(),
+                &mut test_metrics(),
+                0,
+                &mut entries,
+                &mut errors,
+            )?;
+            Ok(totals_by_language(&stats))
+        };
+        let physical = scan(CountMode::Physical)?;
+        assert_eq!((physical["Rust"].1.code_lines, physical["C/C++"].1.code_lines), (8, 6));
+        let logical = scan(CountMode::Logical)?;
+        // `printf(` over 3 physical lines is one logical line; the `// three`
+        // line stays, since it is also a comment line.
+        assert_eq!((logical["Rust"].1.code_lines, logical["C/C++"].1.code_lines), (5, 4));
+        assert_eq!(logical["Rust"].1.blank_lines, 0, "folded lines are not padded as blanks");
+
+        let args = Args::parse_from(["mdkloc", ".", "--count-mode", "logical"]);
+        assert_eq!(args.count_mode, CountMode::Logical);
+        Ok(())
+    }
+
+    /// Just enough of a JSON parser to check documents the tool writes.
+    #[derive(Debug, PartialEq)]
+    enum JsonValue {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<JsonValue>),
+        Object(Vec<(String, JsonValue)>),
+    }
+
+    impl JsonValue {
+        fn get(&self, key: &str) -> Option<&JsonValue> {
+            match self {
+                JsonValue::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            match self {
+                JsonValue::Object(members) => members.iter().map(|(k, _)| k.as_str()).collect(),
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    fn parse_json(text: &str) -> Result<JsonValue, String> {
+        fn skip_ws(bytes: &[u8], pos: &mut usize) {
+            while bytes.get(*pos).is_some_and(|b| b.is_ascii_whitespace()) {
+                *pos += 1;
+            }
+        }
+        fn expect(bytes: &[u8], pos: &mut usize, byte: u8) -> Result<(), String> {
+            skip_ws(bytes, pos);
+            if bytes.get(*pos) == Some(&byte) {
+                *pos += 1;
+                Ok(())
+            } else {
+                Err(format!("expected '{}' at byte {}", byte as char, pos))
+            }
+        }
+        fn string(text: &str, pos: &mut usize) -> Result<String, String> {
+            expect(text.as_bytes(), pos, b'"')?;
+            let mut out = String::new();
+            let mut chars = text[*pos..].char_indices();
+            while let Some((offset, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        *pos += offset + 1;
+                        return Ok(out);
+                    }
+                    '\\' => match chars.next().map(|(_, c)| c) {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some('r') => out.push('\r'),
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                            let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                        other => return Err(format!("bad escape {other:?}")),
+                    },
+                    c if (c as u32) < 0x20 => return Err("control character in string".into()),
+                    c => out.push(c),
+                }
+            }
+            Err("unterminated string".into())
+        }
+        fn value(text: &str, pos: &mut usize) -> Result<JsonValue, String> {
+            let bytes = text.as_bytes();
+            skip_ws(bytes, pos);
+            match bytes.get(*pos) {
+                Some(b'{') => {
+                    *pos += 1;
+                    let mut members = Vec::new();
+                    skip_ws(bytes, pos);
+                    if bytes.get(*pos) == Some(&b'}') {
+                        *pos += 1;
+                        return Ok(JsonValue::Object(members));
+                    }
+                    loop {
+                        let key = string(text, pos)?;
+                        expect(bytes, pos, b':')?;
+                        members.push((key, value(text, pos)?));
+                        skip_ws(bytes, pos);
+                        match bytes.get(*pos) {
+                            Some(b',') => *pos += 1,
+                            Some(b'}') => {
+                                *pos += 1;
+                                return Ok(JsonValue::Object(members));
+                            }
+                            _ => return Err(format!("expected ',' or '}}' at byte {pos}")),
+                        }
+                    }
+                }
+                Some(b'[') => {
+                    *pos += 1;
+                    let mut items = Vec::new();
+                    skip_ws(bytes, pos);
+                    if bytes.get(*pos) == Some(&b']') {
+                        *pos += 1;
+                        return Ok(JsonValue::Array(items));
+                    }
+                    loop {
+                        items.push(value(text, pos)?);
+                        skip_ws(bytes, pos);
+                        match bytes.get(*pos) {
+                            Some(b',') => *pos += 1,
+                            Some(b']') => {
+                                *pos += 1;
+                                return Ok(JsonValue::Array(items));
+                            }
+                            _ => return Err(format!("expected ',' or ']' at byte {pos}")),
+                        }
+                    }
+                }
+                Some(b'"') => string(text, pos).map(JsonValue::String),
+                _ => {
+                    let rest = &text[*pos..];
+                    for (word, parsed) in [
+                        ("true", JsonValue::Bool(true)),
+                        ("false", JsonValue::Bool(false)),
+                        ("null", JsonValue::Null),
+                    ] {
+                        if rest.starts_with(word) {
+                            *pos += word.len();
+                            return Ok(parsed);
+                        }
+                    }
+                    let len = rest
+                        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                        .unwrap_or(rest.len());
+                    let number = rest[..len].parse().map_err(|_| format!("bad value at byte {pos}"))?;
+                    *pos += len;
+                    Ok(JsonValue::Number(number))
+                }
+            }
+        }
+        let mut pos = 0;
+        let parsed = value(text, &mut pos)?;
+        skip_ws(text.as_bytes(), &mut pos);
+        match pos == text.len() {
+            true => Ok(parsed),
+            false => Err(format!("trailing data at byte {pos}")),
+        }
+    }
+
+    #[test]
+    fn test_print_schema_describes_json_report() -> io::Result<()> {
+        let schema = parse_json(JSON_REPORT_SCHEMA).expect("schema is valid JSON");
+        let properties = schema.get("properties").expect("top-level properties");
+        for key in [
+            "version",
+            "label",
+            "root",
+            "files_processed",
+            "lines_processed",
+            "errors",
+            "directories",
+            "languages",
+            "roles",
+            "total",
+        ] {
+            assert!(properties.get(key).is_some(), "schema documents {key}");
+        }
+        let stats_schema = schema.get("$defs").and_then(|defs| defs.get("stats")).expect("stats");
+        let stat_keys = stats_schema.get("properties").expect("stats properties").keys();
+
+        // Every key of an actual report, with all optional fields on, is in the schema.
+        let temp_dir = TempDir::new()?;
+        create_test_file(temp_dir.path(), "main.rs", "// hi\nfn main() {}\n")?;
+        let args = Args {
+            bytes: true,
+            root_label: Some("demo".to_string()),
+            relative_paths: false,
+            ..test_args()
+        };
+        let mut entries = 0;
+        let mut errors = 0;
+        let stats = scan_directory(temp_dir.path(), &args, temp_dir.path(), &mut test_metrics(), 0, &mut entries, &mut errors)?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let report = build_json_report(&root, &stats, 1, 2, 0, &ReportOptions::from_args(&args));
+        let report = parse_json(&report).expect("report is valid JSON");
+        let mut top_keys = report.keys();
+        top_keys.sort_unstable();
+        let mut schema_keys = properties.keys();
+        schema_keys.sort_unstable();
+        assert_eq!(top_keys, schema_keys);
+        let total_keys = report.get("total").expect("total").keys();
+        assert_eq!(total_keys, stat_keys);
+
+        let args = Args::parse_from(["mdkloc", "--print-schema"]);
+        assert!(args.print_schema);
+        Ok(())
+    }
+
+    #[test]
+    fn test_minified_files_flagged_and_excludable() -> io::Result<()> {
+        assert!(looks_minified("JavaScript", 5 * 1024, 1));
+        assert!(!looks_minified("JavaScript", 5 * 1024, 120), "ordinary line lengths");
+        assert!(!looks_minified("JavaScript", 600, 1), "too small to matter");
+        assert!(!looks_minified("Markdown", 5 * 1024, 1), "long prose lines");
+        assert!(!looks_minified("JSON", 5 * 1024, 1), "single-line data");
+
+        let temp_dir = TempDir::new()?;
+        let bundle = format!("{}\n", "var a=1;".repeat(640));
+        create_test_file(temp_dir.path(), "app.min.js", &bundle)?;
+        create_test_file(temp_dir.path(), "app.js", "// entry\nconst a = 1;\n")?;
+        create_test_file(temp_dir.path(), "notes.md", &format!("{}\n", "word ".repeat(1024)))?;
+        create_test_file(temp_dir.path(), "data.json", &format!("[{}0]\n", "1,".repeat(2048)))?;
+
+        let scan = |args: &Args| -> io::Result<(u64, u64)> {
+            let mut metrics = test_metrics();
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(
+                temp_dir.path(),
+                args,
+                temp_dir.path(),
+                &mut metrics,
+                0,
+                &mut entries,
+                &mut errors,
+            )?;
+            let files = totals_by_language(&stats)["JavaScript"].0;
+            Ok((files, metrics.entry_counts.minified))
+        };
+        assert_eq!(scan(&test_args())?, (2, 1), "flagged but still counted");
+        let args = Args {
+            exclude_minified: true,
+            ..test_args()
+        };
+        assert_eq!(scan(&args)?, (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_breadth_first_traversal_matches_depth_first() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        for (dir, file) in [("", "top.rs"), ("a", "x.py"), ("a/b/c", "deep.rs"), ("z", "z.sh"), ("z/y", "y.rs")] {
+            let sub = temp_dir.path().join(dir);
+            fs::create_dir_all(&sub)?;
+            create_test_file(&sub, file, "# note\n// note\nlet x = 1;\n\n")?;
+        }
+        create_test_file(&temp_dir.path().join("a/b"), "skip.rs", "fn s() {}\n")?;
+        let root = fs::canonicalize(temp_dir.path())?;
+
+        let run = |traversal: Traversal, max_depth: usize| -> io::Result<(String, usize, usize)> {
+            let args = Args {
+                traversal,
+                max_depth,
+                ignore: vec!["c".to_string()],
+                ..test_args()
+            };
+            let mut metrics = test_metrics();
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(&root, &args, &root, &mut metrics, 0, &mut entries, &mut errors)?;
+            let files = metrics.files_processed.load(Ordering::Relaxed);
+            let lines = metrics.lines_processed.load(Ordering::Relaxed);
+            let report = build_analysis_report(&root, &stats, files, lines, errors, &ReportOptions::from_args(&args));
+            Ok((report, entries, errors))
+        };
+        for max_depth in [100, 1] {
+            let (dfs, dfs_entries, dfs_errors) = run(Traversal::Dfs, max_depth)?;
+            let (bfs, bfs_entries, bfs_errors) = run(Traversal::Bfs, max_depth)?;
+            assert_eq!(dfs, bfs, "max_depth {max_depth}");
+            assert_eq!((dfs_entries, dfs_errors), (bfs_entries, bfs_errors));
+        }
+        let (full, _, _) = run(Traversal::Bfs, 100)?;
+        assert!(full.contains("a/b") && !full.contains("a/b/c"), "{full}");
+
+        let args = Args {
+            traversal: Traversal::Bfs,
+            max_entries: 3,
+            ..test_args()
+        };
+        let mut entries = 0;
+        let mut errors = 0;
+        let limited = scan_directory(&root, &args, &root, &mut test_metrics(), 0, &mut entries, &mut errors);
+        assert!(limited.is_err(), "entry limit still aborts the scan");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlink_dedupe_counts_inode_once() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        create_test_file(root, "original.rs", "fn main() {}\n")?;
+        fs::create_dir(root.join("copy"))?;
+        fs::hard_link(root.join("original.rs"), root.join("copy").join("linked.rs"))?;
+
+        let scan = |hardlink_dedupe: bool| -> io::Result<(u64, u64)> {
+            let args = Args {
+                hardlink_dedupe,
+                ..test_args()
+            };
+            let mut metrics = test_metrics();
+            let mut entries = 0;
+            let mut errors = 0;
+            let stats = scan_directory(root, &args, root, &mut metrics, 0, &mut entries, &mut errors)?;
+            let files = totals_by_language(&stats)["Rust"].0;
+            Ok((files, metrics.entry_counts.hardlink_duplicates))
+        };
+        assert_eq!(scan(false)?, (2, 0), "distinct canonical paths by default");
+        assert_eq!(scan(true)?, (1, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_report_role_totals_match_role_breakdown_text() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        create_test_file(
+            temp_dir.path(),
+            "lib.rs",
+            "fn a() {}\nfn b() {}\n\n#[cfg(test)]\nmod tests {\n    #[test]\n    fn t() {}\n}\n",
+        )?;
+        fs::create_dir(temp_dir.path().join("src"))?;
+        create_test_file(&temp_dir.path().join("src"), "more.rs", "// util\nfn c() {}\n")?;
+        let args = Args {
+            role_breakdown: true,
+            ..test_args()
+        };
+        let stats = scan_directory(temp_dir.path(), &args, temp_dir.path(), &mut test_metrics(), 0, &mut 0, &mut 0)?;
+        let options = ReportOptions::from_args(&args);
+
+        let text = build_analysis_report(temp_dir.path(), &stats, 2, 10, 0, &options);
+        let section = text.split("Totals by language (Test):\n").nth(1).expect("test totals section");
+        let row: Vec<&str> = section.lines().next().expect("Rust row").split_whitespace().collect();
+        assert_eq!(row[0], "Rust");
+        let text_test_code: f64 = row[2].parse().expect("code column");
+
+        let json = build_json_report(temp_dir.path(), &stats, 2, 10, 0, &options);
+        let report = parse_json(&json).expect("report is valid JSON");
+        let roles = report.get("roles").expect("roles object");
+        assert_eq!(roles.keys(), vec!["mainline", "test"]);
+        let test_code = roles.get("test").and_then(|test| test.get("code"));
+        assert!(matches!(test_code, Some(JsonValue::Number(code)) if *code == text_test_code && *code == 5.0), "{json}");
+        let main_files = roles.get("mainline").and_then(|main| main.get("files"));
+        assert!(matches!(main_files, Some(JsonValue::Number(files)) if *files == 2.0), "{json}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_exclude_vendor_skips_vendor_directories() -> io::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        create_test_file(root, "main.rs", "fn main() {}\n")?;
+        for dir in ["vendor", "third_party"] {
+            fs::create_dir(root.join(dir))?;
+            create_test_file(&root.join(dir), "dep.rs", "fn dep() {}\nfn more() {}\n")?;
+        }
+
+        let code_for = |args: &Args| -> io::Result<u64> {
+            let stats = scan_directory(root, args, root, &mut test_metrics(), 0, &mut 0, &mut 0)?;
+            Ok(totals_by_language(&stats).values().map(|(_, stats)| stats.code_lines).sum())
+        };
+        assert_eq!(code_for(&test_args())?, 5);
+        let args = Args {
+            auto_exclude_vendor: true,
+            ..test_args()
+        };
+        assert_eq!(code_for(&args)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_averages_column_shows_code_lines_per_file() -> io::Result<()> {
+        control::set_override(false);
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        create_test_file(root, "a.rs", "fn a() {}\nfn b() {}\n")?;
+        create_test_file(root, "b.rs", "fn c() {}\nfn d() {}\n// note\n")?;
+        create_test_file(root, "c.rs", "fn e() {}\nfn f() {}\nfn g() {}\nfn h() {}\nfn i() {}\n")?;
+        let args = Args {
+            averages: true,
+            ..test_args()
+        };
+        let stats = scan_directory(root, &args, root, &mut test_metrics(), 0, &mut 0, &mut 0)?;
+        let report = build_analysis_report(root, &stats, 3, 10, 0, &ReportOptions::from_args(&args));
+
+        let header = report.lines().find(|line| line.starts_with("Directory")).expect("header");
+        assert!(header.trim_end().ends_with("Avg"), "{header:?}");
+        let totals = report.split("Totals by language:\n").nth(1).expect("totals section");
+        let row: Vec<&str> = totals.lines().next().expect("Rust row").split_whitespace().collect();
+        assert_eq!((row[0], row[1], row[2]), ("Rust", "3", "9"));
+        assert_eq!(row.last(), Some(&"3.0"));
+        assert_eq!(safe_average(9, 0), 0.0);
+
+        let wide = LanguageStats { code_lines: 4513, ..Default::default() };
+        let line = format_language_stats_line("", "Rust", 2, &wide, ReportOptions::from_args(&args).layout);
+        assert!(line.contains("4,513") && line.ends_with("   2,256.5"), "{line:?}");
+
+        let plain = build_analysis_report(root, &stats, 3, 10, 0, &ReportOptions::from_args(&test_args()));
+        assert!(!plain.contains("Avg"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_lines_from_reader_dispatches_by_language_name() -> io::Result<()> {
+        let source = b"# comment\nx = 1\n\ny = 2  # trailing\n".to_vec();
+        let (stats, total) = count_lines_with_stats_from_reader("python", Box::new(io::Cursor::new(source)), &test_args())?;
+        assert_eq!((total, stats.code_lines, stats.comment_lines, stats.blank_lines), (4, 2, 1, 1));
+        let err = count_lines_with_stats_from_reader("Klingon", Box::new(io::empty()), &test_args()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_source_never_reads_a_file_of_the_same_name() -> io::Result<()> {
+        // The crate's own Cargo.toml sits in the test working directory.
+        assert!(Path::new("Cargo.toml").is_file());
+        let mut outer = LossyLineReader::with_reader(io::empty());
+        let split = count_embedded_source("Cargo.toml", b"# one\nkey = 1\n".to_vec(), &test_args(), &mut outer, 0)?;
+        let bucket = split.bucket(CodeRole::Mainline).expect("mainline bucket");
+        assert_eq!((bucket.total_lines, bucket.stats.code_lines, bucket.stats.comment_lines), (2, 1, 1));
+        Ok(())
+    }
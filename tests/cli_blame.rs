@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+fn mdkloc_bin() -> &'static str {
+    env!("CARGO_BIN_EXE_mdkloc")
+}
+
+fn write_file(path: &Path, contents: &str) {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    fs::write(path, contents).expect("failed to write test file");
+}
+
+fn git_as(dir: &Path, author: &str, args: &[&str]) {
+    let name = format!("user.name={author}");
+    let email = format!("user.email={}@example.com", author.to_lowercase());
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", &name, "-c", &email])
+        .args(args)
+        .env("GIT_CEILING_DIRECTORIES", dir.parent().unwrap_or(dir))
+        .status()
+        .expect("failed to run git");
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+fn blame_rows(root: &Path) -> Vec<Vec<String>> {
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--blame", "--totals"])
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(
+        output.status.success(),
+        "status: {:?}, stderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let table = stderr
+        .split("Code lines by author:\n")
+        .nth(1)
+        .unwrap_or_else(|| panic!("no blame table: {stderr}"));
+    table
+        .lines()
+        .map(|line| line.split_whitespace().map(str::to_string).collect())
+        .collect()
+}
+
+#[test]
+fn cli_blame_attributes_code_lines_to_authors() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("src/lib.rs"), "fn a() {}\nfn b() {}\n");
+    git_as(root, "Alice", &["init", "-q"]);
+    git_as(root, "Alice", &["add", "-A"]);
+    git_as(root, "Alice", &["commit", "-q", "-m", "first"]);
+
+    // Bob's comments are not code, so a split by touched lines would
+    // hand him part of Alice's code.
+    write_file(
+        &root.join("src/lib.rs"),
+        "fn a() {}\nfn b() {}\nfn c() {}\n// note\n// more\n",
+    );
+    write_file(&root.join("untracked.rs"), "fn skipped() {}\n");
+    git_as(root, "Bob", &["add", "src/lib.rs"]);
+    git_as(root, "Bob", &["commit", "-q", "-m", "second"]);
+
+    assert_eq!(blame_rows(root), [["Alice", "2"], ["Bob", "1"]]);
+}
+
+#[test]
+fn cli_blame_keeps_block_comment_spanning_authors_as_comment() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("lib.rs"), "fn a() {}\n/*\n*/\n");
+    git_as(root, "Alice", &["init", "-q"]);
+    git_as(root, "Alice", &["add", "-A"]);
+    git_as(root, "Alice", &["commit", "-q", "-m", "first"]);
+
+    // Bob's lines sit inside Alice's block comment; on their own they
+    // would read as code.
+    write_file(
+        &root.join("lib.rs"),
+        "fn a() {}\n/*\nlet x = 1;\nfn b() {}\n*/\n",
+    );
+    git_as(root, "Bob", &["commit", "-q", "-am", "second"]);
+
+    assert_eq!(blame_rows(root), [["Alice", "1"], ["Bob", "0"]]);
+}
+
+#[test]
+fn cli_blame_outside_git_repo_errors() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("main.rs"), "fn main() {}\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .arg("--blame")
+        .env("GIT_CEILING_DIRECTORIES", root.parent().unwrap_or(root))
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(!output.status.success(), "expected failure outside git");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--blame requires a git repository"),
+        "stderr: {stderr}"
+    );
+}
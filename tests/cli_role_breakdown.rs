@@ -121,3 +121,103 @@ fn integration_runs() {
         "test stats should be non-zero: files={test_files}, code={test_code}"
     );
 }
+
+#[test]
+fn cli_test_scope_fn_counts_module_helpers_as_mainline() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+
+    write_file(
+        &root.join("lib.rs"),
+        r#"pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> i32 {
+        2
+    }
+
+    #[test]
+    fn adds_numbers() {
+        assert_eq!(add(fixture(), 2), 4);
+    }
+}
+"#,
+    );
+
+    let run = |scope: &str| {
+        let output = Command::new(mdkloc_bin())
+            .arg(root)
+            .arg("-r")
+            .arg("--test-scope")
+            .arg(scope)
+            .output()
+            .expect("failed to execute mdkloc");
+        assert!(
+            output.status.success(),
+            "expected success: {:?}",
+            output.status
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let cfg_stdout = run("cfg");
+    let fn_stdout = run("fn");
+
+    let (_, cfg_main) = parse_role_totals(&cfg_stdout, "Mainline")["Rust"];
+    let (_, cfg_test) = parse_role_totals(&cfg_stdout, "Test")["Rust"];
+    let (_, fn_main) = parse_role_totals(&fn_stdout, "Mainline")["Rust"];
+    let (_, fn_test) = parse_role_totals(&fn_stdout, "Test")["Rust"];
+
+    assert_eq!((cfg_main, cfg_test), (3, 11), "cfg scope:\n{cfg_stdout}");
+    assert_eq!((fn_main, fn_test), (10, 4), "fn scope:\n{fn_stdout}");
+}
+
+#[test]
+fn cli_group_roles_prints_side_by_side_columns() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(
+        &root.join("lib.rs"),
+        "// adds\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\n#[cfg(test)]\nmod tests {\n    // check\n    #[test]\n    fn adds() {}\n}\n",
+    );
+    write_file(&root.join("tool.py"), "# helper\nprint('hi')\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .arg("--group-roles")
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        !stdout.contains("Role breakdown (Mainline)"),
+        "grouped view replaces per-role tables:\n{stdout}"
+    );
+
+    let mut rows: HashMap<String, Vec<u64>> = HashMap::new();
+    let mut iter = stdout.lines();
+    for line in iter.by_ref() {
+        if line.contains("Totals by language (grouped):") {
+            break;
+        }
+    }
+    for line in iter {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 5 {
+            break;
+        }
+        let values = parts[1..]
+            .iter()
+            .map(|v| v.parse().expect("numeric column"))
+            .collect();
+        rows.insert(parts[0].to_string(), values);
+    }
+    // Columns: code main, code test, comment main, comment test.
+    assert_eq!(rows.get("Rust"), Some(&vec![3, 5, 1, 1]), "{stdout}");
+    assert_eq!(rows.get("Python"), Some(&vec![1, 0, 1, 0]), "{stdout}");
+}
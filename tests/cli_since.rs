@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+fn mdkloc_bin() -> &'static str {
+    env!("CARGO_BIN_EXE_mdkloc")
+}
+
+fn write_file(path: &Path, contents: &str) {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    fs::write(path, contents).expect("failed to write test file");
+}
+
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "-c",
+            "user.name=mdkloc",
+            "-c",
+            "user.email=mdkloc@example.com",
+        ])
+        .args(args)
+        .env("GIT_CEILING_DIRECTORIES", dir.parent().unwrap_or(dir))
+        .status()
+        .expect("failed to run git");
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+#[test]
+fn cli_since_counts_only_changed_files() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("src/changed.rs"), "fn a() {}\n");
+    write_file(&root.join("src/untouched.rs"), "fn b() {}\nfn c() {}\n");
+    write_file(&root.join("gone.py"), "x = 1\n");
+    git(root, &["init", "-q"]);
+    git(root, &["add", "-A"]);
+    git(root, &["commit", "-q", "-m", "base"]);
+
+    write_file(
+        &root.join("src/changed.rs"),
+        "// now documented\nfn a() {}\nfn d() {}\n",
+    );
+    fs::remove_file(root.join("gone.py")).expect("failed to delete file");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--since", "HEAD", "--totals"])
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(
+        output.status.success(),
+        "status: {:?}, stderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "code=2 comments=1 blank=0 mixed=0 files=1\n"
+    );
+}
+
+#[test]
+fn cli_since_counts_changed_files_with_non_ascii_names() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("é.rs"), "fn e() {}\n");
+    write_file(&root.join("plain.rs"), "fn p() {}\n");
+    write_file(&root.join("dir with space/x.rs"), "fn x() {}\n");
+    git(root, &["init", "-q"]);
+    git(root, &["add", "-A"]);
+    git(root, &["commit", "-q", "-m", "base"]);
+
+    write_file(&root.join("é.rs"), "fn e() {}\nfn f() {}\n");
+    write_file(&root.join("plain.rs"), "fn p() {}\nfn q() {}\n");
+    write_file(&root.join("dir with space/x.rs"), "fn x() {}\nfn y() {}\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--since", "HEAD", "--totals"])
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(
+        output.status.success(),
+        "status: {:?}, stderr: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "code=6 comments=0 blank=0 mixed=0 files=3\n"
+    );
+}
+
+#[test]
+fn cli_since_outside_git_repo_errors() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("main.rs"), "fn main() {}\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--since", "main"])
+        .env("GIT_CEILING_DIRECTORIES", root.parent().unwrap_or(root))
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(!output.status.success(), "expected failure outside git");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--since requires a git repository"),
+        "stderr: {stderr}"
+    );
+}
@@ -40,9 +40,10 @@ fn cli_prints_summary_for_basic_run() {
     );
 
     let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
     assert!(
-        stdout.contains("Performance Summary"),
-        "stdout missing summary: {stdout}"
+        stderr.contains("Performance Summary"),
+        "stderr missing summary: {stderr}"
     );
     assert!(
         stdout.contains("Detailed source code analysis"),
@@ -485,8 +486,67 @@ fn cli_reports_warning_summary_when_errors_occur() {
         "stderr missing metadata warning: {stderr}"
     );
     assert!(
-        stdout.contains("Warning") && stdout.contains("Performance Summary"),
-        "stdout missing warning summary or performance section: {stdout}"
+        stdout.contains("Warning"),
+        "stdout missing warning summary: {stdout}"
+    );
+    assert!(
+        stderr.contains("Performance Summary"),
+        "stderr missing performance section: {stderr}"
+    );
+}
+
+#[test]
+fn cli_fail_on_error_exits_non_zero_after_report() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("main.rs"), "fn main() {}\n");
+    fs::create_dir(root.join("__mdkloc_metadata_fail__"))
+        .expect("failed to create sentinel directory");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .arg("--fail-on-error")
+        .env("MDKLOC_ENABLE_FAULTS", "1")
+        .output()
+        .expect("failed to execute mdkloc");
+
+    assert!(!output.status.success(), "expected failure exit status");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stdout.contains("Overall Summary:"),
+        "report should print before exiting: {stdout}"
+    );
+    assert!(
+        stderr.contains("1 error(s) occurred during the scan"),
+        "stderr should carry the error count: {stderr}"
+    );
+
+    let clean = Command::new(mdkloc_bin())
+        .arg(root.join("main.rs"))
+        .arg("--fail-on-error")
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(clean.status.success(), "status: {:?}", clean.status);
+}
+
+#[test]
+fn cli_max_runtime_reports_partial_results() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    write_file(&root.join("main.rs"), "fn main() {}\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--max-runtime", "0"])
+        .output()
+        .expect("failed to execute mdkloc");
+
+    assert!(output.status.success(), "status: {:?}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("--max-runtime budget of 0.0s elapsed; results are partial."),
+        "stdout missing partial-results note: {stdout}"
     );
 }
 
@@ -700,9 +760,10 @@ fn cli_color_filespec_ignore_combination() {
     );
 
     let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
     assert!(
-        stdout.contains("Performance Summary"),
-        "stdout should include performance summary when color is disabled: {stdout}"
+        stderr.contains("Performance Summary"),
+        "stderr should include performance summary when color is disabled: {stderr}"
     );
     assert!(
         stdout.to_ascii_uppercase().contains("RUST"),
@@ -738,10 +799,284 @@ fn cli_processes_symlink_to_external_file() {
         .expect("failed to execute mdkloc");
 
     assert!(output.status.success(), "status: {:?}", output.status);
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
     // Symlinked files are followed and processed (only symlinked directories are skipped)
     assert!(
-        stdout.contains("Files processed: 1"),
-        "symlinked external file should be processed: {stdout}"
+        stderr.contains("Files processed: 1"),
+        "symlinked external file should be processed: {stderr}"
+    );
+}
+
+#[test]
+fn cli_quiet_prints_only_the_report() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    write_file(
+        &temp_dir.path().join("main.rs"),
+        "fn main() {}\n// comment\n",
+    );
+
+    let output = Command::new(mdkloc_bin())
+        .arg(temp_dir.path())
+        .arg("--quiet")
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        !stdout.contains(env!("CARGO_PKG_VERSION")),
+        "quiet output should omit the banner: {stdout}"
+    );
+    assert!(
+        !stdout.contains("Starting source code analysis"),
+        "quiet output should omit the start message: {stdout}"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        !stdout.contains("Performance Summary") && !stderr.contains("Performance Summary"),
+        "quiet output should omit performance stats: {stdout}{stderr}"
+    );
+    assert!(
+        stdout.contains("Detailed source code analysis") && stdout.contains("Rust"),
+        "quiet output should still contain the report: {stdout}"
+    );
+}
+
+#[test]
+fn cli_quiet_conflicts_with_verbose() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let output = Command::new(mdkloc_bin())
+        .arg(temp_dir.path())
+        .arg("--quiet")
+        .arg("--verbose")
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(
+        !output.status.success(),
+        "--quiet with --verbose should be rejected"
+    );
+}
+
+#[test]
+fn cli_totals_prints_single_key_value_line() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    write_file(
+        &temp_dir.path().join("main.rs"),
+        "fn main() {}\n// comment\n\n",
+    );
+    write_file(&temp_dir.path().join("tool.py"), "# header\nprint(1)\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(temp_dir.path())
+        .arg("--totals")
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout, "code=2 comments=2 blank=1 mixed=0 files=2\n");
+}
+
+#[test]
+fn cli_format_json_prints_only_the_document() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    fs::create_dir(root.join("src")).expect("failed to create src dir");
+    write_file(&root.join("src/main.rs"), "// entry\nfn main() {}\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--format", "json"])
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("{\n"), "stdout: {stdout}");
+    assert!(stdout.ends_with("}\n"), "stdout: {stdout}");
+    assert!(stdout.contains("\"src\": {"), "stdout: {stdout}");
+    let canonical = fs::canonicalize(root).expect("failed to canonicalize root");
+    assert!(
+        !stdout.contains(&*canonical.to_string_lossy()),
+        "relative keys should not leak the scan root: {stdout}"
+    );
+}
+
+#[test]
+fn cli_format_jsonl_streams_one_object_per_file() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    fs::create_dir(root.join("src")).expect("failed to create src dir");
+    write_file(&root.join("src/main.rs"), "// entry\nfn main() {}\n");
+    write_file(&root.join("build.py"), "x = 1\n\n");
+
+    let output = Command::new(mdkloc_bin())
+        .arg(root)
+        .args(["--format", "jsonl"])
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    lines.sort_unstable();
+    assert_eq!(
+        lines,
+        [
+            "{\"path\": \"build.py\", \"language\": \"Python\", \"role\": \"mainline\", \
+             \"code\": 1, \"comment\": 0, \"blank\": 1}",
+            "{\"path\": \"src/main.rs\", \"language\": \"Rust\", \"role\": \"mainline\", \
+             \"code\": 1, \"comment\": 1, \"blank\": 0}",
+        ],
+        "stdout: {stdout}"
+    );
+}
+
+/// Parses a flat JSON object of string and integer values, as `--format jsonl`
+/// writes; `None` when `line` is anything else.
+fn parse_flat_json_object(line: &str) -> Option<Vec<(String, String)>> {
+    fn string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(value),
+                '\\' => value.push(chars.next()?),
+                c => value.push(c),
+            }
+        }
+    }
+    let mut chars = line.chars().peekable();
+    let mut fields = Vec::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        let key = string(&mut chars)?;
+        if chars.next()? != ':' || chars.next()? != ' ' {
+            return None;
+        }
+        let value = if chars.peek() == Some(&'"') {
+            string(&mut chars)?
+        } else {
+            let mut digits = String::new();
+            while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                digits.push(c);
+            }
+            if digits.is_empty() {
+                return None;
+            }
+            digits
+        };
+        fields.push((key, value));
+        match chars.next()? {
+            ',' if chars.next()? == ' ' => {}
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+#[test]
+fn cli_format_jsonl_keeps_verbose_and_totals_off_stdout() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    let root = temp_dir.path();
+    fs::create_dir(root.join("src")).expect("failed to create src dir");
+    write_file(&root.join("src/main.rs"), "// entry\nfn main() {}\n");
+    write_file(&root.join("build.py"), "x = 1\n\n");
+
+    for extra in [&["--verbose"][..], &["--totals"]] {
+        let output = Command::new(mdkloc_bin())
+            .arg(root)
+            .args(["--format", "jsonl"])
+            .args(extra)
+            .output()
+            .expect("failed to execute mdkloc");
+        assert!(output.status.success(), "status: {:?}", output.status);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stdout.lines().count(), 2, "{extra:?} stdout: {stdout}");
+        for line in stdout.lines() {
+            let fields = parse_flat_json_object(line)
+                .unwrap_or_else(|| panic!("{extra:?}: not a JSON object: {line:?}"));
+            assert_eq!(fields[0].0, "path", "{line}");
+        }
+        let moved = if extra == ["--totals"] {
+            "code="
+        } else {
+            "Code lines:"
+        };
+        assert!(stderr.contains(moved), "{extra:?} stderr: {stderr}");
+    }
+}
+
+#[test]
+fn cli_relocate_root_makes_reports_identical_across_checkouts() {
+    let checkouts = [
+        TempDir::new().expect("failed to create temp dir"),
+        TempDir::new().expect("failed to create temp dir"),
+    ];
+    for checkout in &checkouts {
+        fs::create_dir(checkout.path().join("src")).expect("failed to create dir");
+        write_file(&checkout.path().join("build.py"), "# build\nprint(1)\n");
+        write_file(&checkout.path().join("src/main.rs"), "fn main() {}\n");
+    }
+    let run = |root: &Path, format: &str| {
+        let output = Command::new(mdkloc_bin())
+            .arg(root)
+            .args(["--relocate-root", "repo", "--quiet", "--format", format])
+            .output()
+            .expect("failed to execute mdkloc");
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).expect("utf-8 report")
+    };
+    for format in ["text", "json", "jsonl"] {
+        let first = run(checkouts[0].path(), format);
+        let second = run(checkouts[1].path(), format);
+        assert_eq!(first, second, "{format} reports differ");
+        let real_root = checkouts[0].path().to_string_lossy().into_owned();
+        assert!(!first.contains(&real_root), "{format}: {first}");
+    }
+    let text = run(checkouts[0].path(), "text");
+    assert!(
+        text.contains("Detailed source code analysis of repo:"),
+        "{text}"
+    );
+    assert!(text.contains("repo/src"), "{text}");
+}
+
+#[test]
+fn cli_progress_stays_off_redirected_stdout() {
+    let temp_dir = TempDir::new().expect("failed to create temp dir");
+    for idx in 0..50 {
+        write_file(
+            &temp_dir.path().join(format!("file{idx}.rs")),
+            "fn main() {}\n// comment\n",
+        );
+    }
+
+    let output = Command::new(mdkloc_bin())
+        .arg(temp_dir.path())
+        .output()
+        .expect("failed to execute mdkloc");
+    assert!(output.status.success(), "status: {:?}", output.status);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stdout.contains("Detailed source code analysis"),
+        "stdout should hold the report: {stdout}"
+    );
+    assert!(
+        !stdout.contains('\r') && !stdout.contains("files/sec"),
+        "stdout should carry no progress fragments: {stdout:?}"
+    );
+    assert!(
+        stderr.contains("Performance Summary") && stderr.contains("files/sec"),
+        "stderr should carry the metrics: {stderr}"
     );
 }
@@ -0,0 +1,64 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn mdkloc_bin() -> &'static str {
+    env!("CARGO_BIN_EXE_mdkloc")
+}
+
+fn run_with_stdin(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(mdkloc_bin())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to execute mdkloc");
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input.as_bytes())
+        .expect("failed to write stdin");
+    child.wait_with_output().expect("failed to wait for mdkloc")
+}
+
+const RUST_SOURCE: &str = "// entry point\nfn main() {\n\n    let x = 1; // one\n}\n";
+
+#[test]
+fn cli_counts_stdin_with_lang_hint() {
+    let output = run_with_stdin(&["--lang", "rust", "-"], RUST_SOURCE);
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("<stdin> (Rust)\n"), "{stdout}");
+    for line in [
+        "Lines:    5",
+        "Code:     3",
+        "Comments: 2",
+        "Mixed:    1",
+        "Blank:    1",
+    ] {
+        assert!(stdout.contains(line), "missing {line:?} in {stdout}");
+    }
+
+    let output = run_with_stdin(&["--lang", "rust", "--format", "json", "-"], RUST_SOURCE);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"language\": \"Rust\""), "{stdout}");
+    assert!(
+        stdout.contains(
+            "\"total\": {\"files\": 1, \"code\": 3, \"comments\": 2, \"blank\": 1, \"mixed\": 1, \"ignored\": 0}"
+        ),
+        "{stdout}"
+    );
+}
+
+#[test]
+fn cli_stdin_requires_lang() {
+    let output = run_with_stdin(&["-"], RUST_SOURCE);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs --lang"));
+}